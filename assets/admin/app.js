@@ -0,0 +1,126 @@
+// Calls the JSON API of the server hosting the page. The tenant and token
+// only live in the session storage of the browser.
+const settings = document.getElementById("settings");
+const editor = document.getElementById("editor");
+const rows = document.getElementById("users");
+const message = document.getElementById("message");
+
+settings.tenant.value = sessionStorage.getItem("tenant") || "";
+settings.token.value = sessionStorage.getItem("token") || "";
+
+function show(text, isError) {
+  message.textContent = text;
+  message.className = isError ? "error" : "";
+}
+
+async function api(method, path, body) {
+  const headers = { Accept: "application/json" };
+  const tenant = sessionStorage.getItem("tenant");
+  const token = sessionStorage.getItem("token");
+  if (tenant) headers["X-Tenant-Id"] = tenant;
+  if (token) headers["Authorization"] = "Bearer " + token;
+  if (body !== undefined) headers["Content-Type"] = "application/json";
+  const response = await fetch(path, {
+    method,
+    headers,
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  if (!response.ok) {
+    let detail = await response.text();
+    try {
+      detail = JSON.parse(detail).detail || detail;
+    } catch (e) {}
+    throw new Error(response.status + " " + detail);
+  }
+  return response.status === 204 ? null : response.json();
+}
+
+function cell(text) {
+  const td = document.createElement("td");
+  td.textContent = text;
+  return td;
+}
+
+function button(text, onClick) {
+  const b = document.createElement("button");
+  b.textContent = text;
+  b.addEventListener("click", onClick);
+  return b;
+}
+
+async function load() {
+  try {
+    const users = await api("GET", "/users");
+    rows.replaceChildren(
+      ...users.map((user) => {
+        const tr = document.createElement("tr");
+        const actions = document.createElement("td");
+        actions.append(
+          button("Edit", () => edit(user)),
+          button("Delete", () => remove(user))
+        );
+        tr.append(
+          cell(user.id),
+          cell(user.name),
+          cell(user.email),
+          cell(user.email_verified ? "yes" : "no"),
+          cell(user.status),
+          actions
+        );
+        return tr;
+      })
+    );
+    show(users.length + " user(s)");
+  } catch (e) {
+    show(e.message, true);
+  }
+}
+
+function edit(user) {
+  editor.id.value = user.id;
+  editor.name.value = user.name;
+  editor.email.value = user.email;
+  editor.password.value = "";
+  document.getElementById("editor-title").textContent = "Edit user " + user.id;
+}
+
+async function remove(user) {
+  if (!confirm("Delete " + user.email + "?")) return;
+  try {
+    await api("DELETE", "/users/" + user.id);
+    await load();
+  } catch (e) {
+    show(e.message, true);
+  }
+}
+
+settings.addEventListener("submit", (event) => {
+  event.preventDefault();
+  sessionStorage.setItem("tenant", settings.tenant.value.trim());
+  sessionStorage.setItem("token", settings.token.value.trim());
+  load();
+});
+
+editor.addEventListener("reset", () => {
+  editor.id.value = "";
+  document.getElementById("editor-title").textContent = "New user";
+});
+
+editor.addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const user = { name: editor.name.value, email: editor.email.value };
+  if (editor.password.value) user.password = editor.password.value;
+  try {
+    if (editor.id.value) {
+      await api("PUT", "/users/" + editor.id.value, user);
+    } else {
+      await api("POST", "/users", user);
+    }
+    editor.reset();
+    await load();
+  } catch (e) {
+    show(e.message, true);
+  }
+});
+
+load();
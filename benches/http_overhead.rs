@@ -0,0 +1,117 @@
+// Throughput and latency of the HTTP stack alone, against a server started
+// with --bench-mode so that no database is involved. Each client thread
+// sends its requests back to back on one keep-alive connection.
+//
+// cargo run --release -- --bench-mode
+// BENCH_ADDR=127.0.0.1:8080 cargo bench --bench http_overhead
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Measured on a single core VM, release server with HTTP_WORKERS=1 and
+// RUST_LOG=warn, 8 connections for 10 seconds, client on the same core
+const BASELINE: [(&str, &str); 2] = [
+    ("GET /users/{id}", "~36000 req/s, p99 0.45 ms"),
+    ("GET /users/count", "~25000 req/s, p99 0.70 ms"),
+];
+
+fn main() {
+    let addr = env::var("BENCH_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    let concurrency: usize = setting("BENCH_CONCURRENCY", 8);
+    let duration = Duration::from_secs(setting("BENCH_DURATION_SECS", 10));
+    // ids looked up in turn, within the generated dataset
+    let users: usize = setting("BENCH_USERS", 10000);
+
+    println!(
+        "{} connections for {:?} against {}",
+        concurrency, duration, addr
+    );
+    let user = measure(&addr, concurrency, duration, move |n| {
+        format!("/users/{}", n % users + 1)
+    });
+    report(BASELINE[0], &user, duration);
+    let count = measure(&addr, concurrency, duration, |_| "/users/count".to_string());
+    report(BASELINE[1], &count, duration);
+}
+
+fn setting<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// Latencies of the requests `concurrency` clients sent back to back for
+// `duration`, each to the path `target` gives for the request number
+fn measure<F>(addr: &str, concurrency: usize, duration: Duration, target: F) -> Vec<Duration>
+where
+    F: Fn(usize) -> String + Copy + Send + 'static,
+{
+    let deadline = Instant::now() + duration;
+    let clients: Vec<_> = (0..concurrency)
+        .map(|client| {
+            let addr = addr.to_string();
+            thread::spawn(move || {
+                let stream =
+                    TcpStream::connect(&addr).expect("Is the server running with --bench-mode?");
+                stream.set_nodelay(true).unwrap();
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut latencies = Vec::new();
+                let mut n = client;
+                while Instant::now() < deadline {
+                    let start = Instant::now();
+                    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", target(n), addr);
+                    writer.write_all(request.as_bytes()).unwrap();
+                    read_response(&mut reader);
+                    latencies.push(start.elapsed());
+                    n += concurrency;
+                }
+                latencies
+            })
+        })
+        .collect();
+    let mut latencies: Vec<Duration> = clients
+        .into_iter()
+        .flat_map(|client| client.join().unwrap())
+        .collect();
+    latencies.sort();
+    latencies
+}
+
+// Reads a response with a Content-Length, panicking on anything but a 2xx
+fn read_response(reader: &mut BufReader<TcpStream>) {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.contains(" 2"), "Unexpected response: {}", line.trim());
+    let mut length = 0;
+    loop {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        let header = line.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+}
+
+fn report((label, baseline): (&str, &str), latencies: &[Duration], duration: Duration) {
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{:>17}: {:>7.0} req/s, p50 {:>5.2} ms, p99 {:>5.2} ms (baseline {})",
+        label,
+        latencies.len() as f64 / duration.as_secs_f64(),
+        percentile(50).as_secs_f64() * 1e3,
+        percentile(99).as_secs_f64() * 1e3,
+        baseline
+    );
+}
@@ -0,0 +1,77 @@
+// Latency of the hot user lookup when the statement is planned on every call
+// versus prepared once and reused, as the repository statement cache does.
+//
+// DATABASE_URL=postgres://... cargo bench --bench statement_cache
+use std::env;
+use std::time::{Duration, Instant};
+
+use tokio_postgres::{Client, NoTls};
+
+const QUERY: &str = "SELECT * FROM users WHERE id = $1";
+
+#[actix_web::main]
+async fn main() {
+    let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let iterations: u32 = env::var("BENCH_ITERATIONS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(5000);
+
+    let (client, connection) = tokio_postgres::connect(&url, NoTls)
+        .await
+        .expect("Failed to connect to DB");
+    actix_web::rt::spawn(connection);
+    let id = sample_id(&client).await;
+
+    let unprepared = measure(iterations, || async {
+        client.query_opt(QUERY, &[&id]).await.unwrap();
+    })
+    .await;
+
+    let statement = client.prepare(QUERY).await.unwrap();
+    let prepared = measure(iterations, || async {
+        client.query_opt(&statement, &[&id]).await.unwrap();
+    })
+    .await;
+
+    println!("{} lookups", iterations);
+    report("planned per request", unprepared, iterations);
+    report("cached statement", prepared, iterations);
+    println!(
+        "speedup: {:.2}x",
+        unprepared.as_secs_f64() / prepared.as_secs_f64()
+    );
+}
+
+async fn sample_id(client: &Client) -> i64 {
+    client
+        .query_opt("SELECT id FROM users LIMIT 1", &[])
+        .await
+        .expect("users table is missing, start the server once to create it")
+        .map(|row| row.get(0))
+        .unwrap_or(0)
+}
+
+async fn measure<F, Fut>(iterations: u32, mut op: F) -> Duration
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    // warm up the connection and the server side caches
+    for _ in 0..100 {
+        op().await;
+    }
+    let start = Instant::now();
+    for _ in 0..iterations {
+        op().await;
+    }
+    start.elapsed()
+}
+
+fn report(label: &str, total: Duration, iterations: u32) {
+    println!(
+        "{:>20}: {:>8.1} µs/query",
+        label,
+        total.as_secs_f64() * 1e6 / iterations as f64
+    );
+}
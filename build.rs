@@ -0,0 +1,88 @@
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Captures what `GET /version` reports: the Git commit, the time of the
+// build and the enabled features, as BUILD_* variables of the compilation
+
+// The commit checked out, GIT_SHA when building outside a repository (the
+// Docker image is built without `.git`)
+fn git_commit() -> String {
+    if let Ok(sha) = env::var("GIT_SHA") {
+        if !sha.trim().is_empty() {
+            return sha.trim().to_string();
+        }
+    }
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let output = Command::new("git")
+        .args(["-C", &dir, "rev-parse", "HEAD"])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+// RFC 3339 in UTC, SOURCE_DATE_EPOCH standing for now in reproducible builds
+fn timestamp() -> String {
+    let seconds = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default()
+        });
+    // days since the epoch to a civil date, after Howard Hinnant
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+// The features of this build, as named in Cargo.toml
+fn features() -> String {
+    let mut features: Vec<_> = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_ascii_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    features.join(",")
+}
+
+fn main() {
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", git_commit());
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp());
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features());
+    // a new commit or a new GIT_SHA is a new build, other changes keep the
+    // timestamp of the last one
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
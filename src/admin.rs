@@ -0,0 +1,61 @@
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorForbidden, ErrorUnauthorized};
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use std::future::{ready, Ready};
+use subtle::ConstantTimeEq;
+
+use crate::api_keys::{Authenticated, Scope};
+use crate::config::Config;
+use crate::signatures::Signed;
+
+// Proof that the request carries the admin token: `Authorization: Bearer
+// $ADMIN_TOKEN`, or an API key or a partner signature with the admin scope.
+// Without ADMIN_TOKEN, admin routes are only open to those.
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(key) = req.extensions().get::<Authenticated>() {
+            return ready(if key.has(Scope::Admin) {
+                Ok(Admin)
+            } else {
+                Err(ErrorForbidden("API key lacks the admin scope"))
+            });
+        }
+        if let Some(partner) = req.extensions().get::<Signed>() {
+            return ready(if partner.has(Scope::Admin) {
+                Ok(Admin)
+            } else {
+                Err(ErrorForbidden("Partner lacks the admin scope"))
+            });
+        }
+        let config = match req.app_data::<web::Data<Config>>() {
+            Some(config) if config.admin_token.is_some() => config,
+            _ => return ready(Err(ErrorForbidden("Admin API is disabled"))),
+        };
+        if has_admin_token(req, config) {
+            ready(Ok(Admin))
+        } else {
+            ready(Err(ErrorUnauthorized("Invalid admin token")))
+        }
+    }
+}
+
+// Whether the request carries `Authorization: Bearer $ADMIN_TOKEN`
+pub fn has_admin_token(req: &HttpRequest, config: &Config) -> bool {
+    let expected = match &config.admin_token {
+        Some(token) => token,
+        None => return false,
+    };
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    bool::from(provided.as_bytes().ct_eq(expected.as_bytes()))
+}
@@ -0,0 +1,41 @@
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse, Responder};
+use rust_embed::RustEmbed;
+
+use crate::fallback;
+use crate::static_site::content_type;
+
+// A page listing, creating, editing and deleting users through the JSON
+// API, compiled into the binary
+#[derive(RustEmbed)]
+#[folder = "assets/admin/"]
+struct Assets;
+
+// The page loads its own script and style and talks to this server only
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; script-src 'self'; style-src 'self'; \
+     connect-src 'self'; form-action 'self'; frame-ancestors 'none'";
+
+fn serve(path: &str) -> HttpResponse {
+    match Assets::get(path) {
+        Some(file) => HttpResponse::Ok()
+            .content_type(content_type(path))
+            .insert_header((header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY))
+            .body(file.data.into_owned()),
+        None => fallback::problem(StatusCode::NOT_FOUND, &format!("No asset {}", path)),
+    }
+}
+
+#[get("/admin")]
+async fn index() -> impl Responder {
+    serve("index.html")
+}
+
+#[get("/admin/assets/{file}")]
+async fn asset(path: web::Path<String>) -> impl Responder {
+    serve(&path)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(index).service(asset);
+}
@@ -0,0 +1,266 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{
+    ErrorForbidden, ErrorGatewayTimeout, ErrorInternalServerError, ErrorServiceUnavailable,
+    ErrorUnauthorized,
+};
+use actix_web::http::Method;
+use actix_web::{delete, get, post, web, HttpMessage, HttpResponse, Responder};
+use log::{error, info};
+use serde_json::json;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::admin::Admin;
+use crate::crypto;
+use crate::db::{Cluster, DbError};
+use crate::jsonapi::Format;
+use crate::repository::ApiKey;
+use crate::tenant::Tenant;
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+// The routes any key may call, which tell about the server rather than its
+// users
+const OPEN_ROUTES: [&str; 5] = [
+    "/healthz",
+    "/readyz",
+    "/version",
+    "/api/v1/openapi.json",
+    "/api/v1/schema",
+];
+
+// What a key may call: reading users, changing them, or the admin API
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    UsersRead,
+    UsersWrite,
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::UsersRead, Scope::UsersWrite, Scope::Admin];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::UsersRead => "users:read",
+            Scope::UsersWrite => "users:write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Scope> {
+        Scope::ALL.into_iter().find(|scope| scope.name() == name)
+    }
+
+    // The scope a route needs, None for the open routes. The ones not
+    // listed, those added since included, need the admin scope.
+    pub fn required(method: &Method, path: &str) -> Option<Scope> {
+        if path == "/users" || path.starts_with("/users/") {
+            match *method {
+                Method::GET | Method::HEAD => Some(Scope::UsersRead),
+                _ => Some(Scope::UsersWrite),
+            }
+        } else if OPEN_ROUTES.contains(&path) {
+            None
+        } else {
+            Some(Scope::Admin)
+        }
+    }
+}
+
+// The key a request authenticated with, in the request extensions
+pub struct Authenticated(pub ApiKey);
+
+impl Authenticated {
+    pub fn has(&self, scope: Scope) -> bool {
+        self.0.scopes.iter().any(|name| name == scope.name())
+    }
+}
+
+// Accepts `X-Api-Key` as a credential: an unknown or revoked key is
+// rejected, a known one only reaches the routes its scopes cover. Requests
+// without the header go through untouched.
+pub struct ApiKeys;
+
+impl<S, B> Transform<S, ServiceRequest> for ApiKeys
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = ApiKeysMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiKeysMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct ApiKeysMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiKeysMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let key = match req.headers().get(API_KEY_HEADER) {
+                Some(value) => value
+                    .to_str()
+                    .map_err(|_| ErrorUnauthorized("Invalid API key"))?
+                    .to_string(),
+                None => return service.call(req).await,
+            };
+            let db = req
+                .app_data::<web::Data<Cluster>>()
+                .ok_or_else(|| ErrorInternalServerError("Missing database"))?
+                .clone();
+            let key_hash = &crypto::sha256_hex(key.as_bytes());
+            let api_key = db
+                .primary()
+                .run(|client| async move { client.api_keys().authenticate(key_hash).await })
+                .await
+                .map_err(|e| match e {
+                    DbError::Unavailable => ErrorServiceUnavailable("Database unavailable"),
+                    DbError::Timeout => ErrorGatewayTimeout("Database query timed out"),
+                    DbError::Query(e) => {
+                        error!("Failed to check API key: {}", e);
+                        ErrorInternalServerError("Failed to check API key")
+                    }
+                })?
+                .ok_or_else(|| ErrorUnauthorized("Invalid API key"))?;
+            let authenticated = Authenticated(api_key);
+            if let Some(scope) = Scope::required(req.method(), req.path()) {
+                if !authenticated.has(scope) {
+                    return Err(ErrorForbidden(format!(
+                        "API key lacks the {} scope",
+                        scope.name()
+                    )));
+                }
+            }
+            req.extensions_mut().insert(authenticated);
+            service.call(req).await
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct NewApiKey {
+    name: String,
+    scopes: Vec<String>,
+    // API_KEY_QUOTA when left out, 0 for no quota
+    quota: Option<i64>,
+}
+
+#[post("/admin/api-keys")]
+async fn create_api_key(
+    _admin: Admin,
+    body: web::Json<NewApiKey>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let new_key = body.into_inner();
+    if let Some(unknown) = new_key.scopes.iter().find(|s| Scope::parse(s).is_none()) {
+        let scopes: Vec<_> = Scope::ALL.iter().map(Scope::name).collect();
+        return HttpResponse::BadRequest().body(format!(
+            "Unknown scope '{}', expected any of {}",
+            unknown,
+            scopes.join(", ")
+        ));
+    }
+    if new_key.quota.is_some_and(|quota| quota < 0) {
+        return HttpResponse::BadRequest().body("quota must be positive, or 0 for no quota");
+    }
+    let key = format!("key_{}", crypto::random_token(32));
+    let prefix = &key[..12];
+    let key_hash = &crypto::sha256_hex(key.as_bytes());
+    let (new_key, tenant) = (&new_key, &tenant);
+    let result = db
+        .primary()
+        .run(move |client| async move {
+            client
+                .api_keys()
+                .create(
+                    &new_key.name,
+                    prefix,
+                    key_hash,
+                    &new_key.scopes,
+                    tenant.as_str(),
+                    new_key.quota,
+                )
+                .await
+        })
+        .await;
+    match result {
+        Ok(api_key) => {
+            info!("Minted API key {} ({})", api_key.id, api_key.name);
+            // the key is only ever disclosed here
+            HttpResponse::Created().json(json!({
+                "id": api_key.id,
+                "name": api_key.name,
+                "prefix": api_key.prefix,
+                "scopes": api_key.scopes,
+                "tenant_id": api_key.tenant_id,
+                "quota": api_key.quota,
+                "key": key,
+            }))
+        }
+        Err(e) => Format::Json.db_error(e, "Failed to create API key"),
+    }
+}
+
+#[get("/admin/api-keys")]
+async fn get_api_keys(_admin: Admin, tenant: Tenant, db: web::Data<Cluster>) -> impl Responder {
+    let tenant = &tenant;
+    let result = db
+        .primary()
+        .run(|client| async move { client.api_keys().list(tenant).await })
+        .await;
+    match result {
+        Ok(api_keys) => HttpResponse::Ok().json(api_keys),
+        Err(e) => Format::Json.db_error(e, "Failed to retrieve API keys"),
+    }
+}
+
+#[delete("/admin/api-keys/{id}")]
+async fn revoke_api_key(
+    _admin: Admin,
+    path: web::Path<i32>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let (id, tenant) = (path.into_inner(), &tenant);
+    let result = db
+        .primary()
+        .run(|client| async move { client.api_keys().revoke(tenant, id).await })
+        .await;
+    match result {
+        Ok(true) => {
+            info!("Revoked API key {}", id);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().body(format!("API key {} not found", id)),
+        Err(e) => Format::Json.db_error(e, &format!("Failed to revoke API key {}", id)),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_api_key)
+        .service(get_api_keys)
+        .service(revoke_api_key);
+}
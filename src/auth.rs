@@ -0,0 +1,795 @@
+use actix_web::dev::Payload;
+use actix_web::error::{
+    ErrorGatewayTimeout, ErrorInternalServerError, ErrorServiceUnavailable, ErrorUnauthorized,
+};
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use log::{error, info, warn};
+use serde_json::json;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_postgres::GenericClient;
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::crypto;
+use crate::db::{Cluster, DbError};
+use crate::jobs::{self, JobKind};
+use crate::jsonapi::Format;
+use crate::lockout::{self, LockoutMetrics};
+use crate::models::AccountStatus;
+use crate::repository::RefreshTokenRepository;
+use crate::settings::{RuntimeSettings, Settings};
+use crate::tenant::{NamedTenant, Tenant};
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+// HS256 access token claims. `ver` is the session version of the user when
+// the token was issued, bumping it revokes every token issued before. `sid`
+// is the refresh token family of the login, revoked on logout. `tid` is the
+// tenant of the user.
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    tid: String,
+    ver: i32,
+    sid: String,
+    iat: u64,
+    exp: u64,
+}
+
+pub fn issue_access_token(
+    config: &Config,
+    tenant: &Tenant,
+    user_id: i64,
+    session_version: i32,
+    family: &str,
+) -> String {
+    let iat = now();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        tid: tenant.to_string(),
+        ver: session_version,
+        sid: family.to_string(),
+        iat,
+        exp: iat + config.access_token_ttl.as_secs(),
+    };
+    jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(config.secret_key.as_bytes()),
+    )
+    .expect("Claims serialize to JSON")
+}
+
+fn decode_claims(config: &Config, token: &str) -> Option<Claims> {
+    jsonwebtoken::decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(config.secret_key.as_bytes()),
+        &Validation::new(Algorithm::HS256),
+    )
+    .ok()
+    .map(|data| data.claims)
+}
+
+// Tenant of a valid access token, whether or not its session is still live
+pub fn token_tenant(config: &Config, token: &str) -> Option<Tenant> {
+    decode_claims(config, token).and_then(|claims| Tenant::parse(&claims.tid))
+}
+
+// `Authorization: Bearer <access token>`, or the session cookie in
+// session mode
+pub fn access_token(req: &HttpRequest, config: &Config) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(String::from)
+        .or_else(|| crate::session::access_token(req, config))
+}
+
+// The id of the user a request was authenticated as, in its extensions:
+// they see all of their own fields, see field_policy.rs
+pub struct SignedIn(pub i64);
+
+// The user a valid access token belongs to
+pub struct Auth {
+    pub tenant: Tenant,
+    pub user_id: i64,
+    pub session: String,
+    pub expires_at: u64,
+}
+
+impl FromRequest for Auth {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let config = req
+                .app_data::<web::Data<Config>>()
+                .ok_or_else(|| ErrorInternalServerError("Missing configuration"))?;
+            let db = req
+                .app_data::<web::Data<Cluster>>()
+                .ok_or_else(|| ErrorInternalServerError("Missing database"))?;
+            let token = access_token(&req, config)
+                .ok_or_else(|| ErrorUnauthorized("Missing bearer token"))?;
+            let claims = decode_claims(config, &token)
+                .ok_or_else(|| ErrorUnauthorized("Invalid or expired token"))?;
+            let user_id: i64 = claims
+                .sub
+                .parse()
+                .map_err(|_| ErrorUnauthorized("Invalid or expired token"))?;
+            let tenant = Tenant::parse(&claims.tid)
+                .ok_or_else(|| ErrorUnauthorized("Invalid or expired token"))?;
+            // the primary, so a revocation is seen at once
+            let sid = &claims.sid;
+            let result: Result<(Option<i32>, bool), DbError> = db
+                .primary()
+                .transaction(&tenant, |uow| {
+                    Box::pin(async move {
+                        let version = uow.users().session_version(user_id).await?;
+                        let active = uow.refresh_tokens().is_active(sid).await?;
+                        Ok((version, active))
+                    })
+                })
+                .await;
+            let (version, active) = result.map_err(|e| match e {
+                DbError::Unavailable => ErrorServiceUnavailable("Database unavailable"),
+                DbError::Timeout => ErrorGatewayTimeout("Database query timed out"),
+                DbError::Query(e) => {
+                    error!("Failed to check session: {}", e);
+                    ErrorInternalServerError("Failed to check session")
+                }
+            })?;
+            if version != Some(claims.ver) || !active {
+                return Err(ErrorUnauthorized("Invalid or expired token"));
+            }
+            req.extensions_mut().insert(SignedIn(user_id));
+            Ok(Auth {
+                tenant,
+                user_id,
+                session: claims.sid,
+                expires_at: claims.exp,
+            })
+        })
+    }
+}
+
+// Whether the caller may `action` user `id` of `tenant`: only the user,
+// signed in, or an admin may, since changing the address of an account is
+// enough to reset its password
+pub fn authorize_user(
+    format: Format,
+    admin: &Option<Admin>,
+    auth: &Option<Auth>,
+    id: i64,
+    tenant: &Tenant,
+    action: &str,
+) -> Result<(), HttpResponse> {
+    match (admin, auth) {
+        (Some(_), _) => Ok(()),
+        (None, Some(auth)) if auth.user_id == id && auth.tenant == *tenant => Ok(()),
+        (None, Some(_)) => Err(format.error(
+            StatusCode::FORBIDDEN,
+            &format!("Only the user or an admin may {}", action),
+        )),
+        (None, None) => {
+            Err(format.error(StatusCode::UNAUTHORIZED, "Sign in as the user or an admin"))
+        }
+    }
+}
+
+pub fn check_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "Password must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+// Argon2 is CPU bound, keep it off the async workers
+pub async fn hash_password(password: String) -> Result<String, String> {
+    web::block(move || crypto::hash_password(&password))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+// Verified in place of the hash of an account that doesn't exist or has no
+// password, so that the login takes as long as with a wrong password and
+// doesn't tell which addresses have an account
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+// False without a hash, once a dummy one was verified
+async fn verify_password(password: String, hash: Option<String>) -> bool {
+    web::block(move || match hash {
+        Some(hash) => crypto::verify_password(&password, &hash),
+        None => {
+            let dummy = DUMMY_HASH.get_or_init(|| {
+                crypto::hash_password(&crypto::random_token(16))
+                    .expect("Failed to hash the dummy password")
+            });
+            crypto::verify_password(&password, dummy);
+            false
+        }
+    })
+    .await
+    .unwrap_or(false)
+}
+
+// Response of a login or refresh: a short lived access token and the
+// refresh token to get the next one, as cookies in session mode
+pub fn token_pair(config: &Config, tokens: &SessionTokens) -> HttpResponse {
+    if config.session_cookies {
+        return crate::session::token_cookies(config, &tokens.access_token, &tokens.refresh_token);
+    }
+    HttpResponse::Ok().json(json!({
+        "access_token": tokens.access_token,
+        "token_type": "Bearer",
+        "expires_in": config.access_token_ttl.as_secs(),
+        "refresh_token": tokens.refresh_token,
+    }))
+}
+
+pub struct SessionTokens {
+    access_token: String,
+    refresh_token: String,
+}
+
+// Issue the next refresh token of `family` along with an access token
+pub async fn issue_tokens<C: GenericClient>(
+    refresh_tokens: RefreshTokenRepository<'_, C>,
+    config: &Config,
+    tenant: &Tenant,
+    user_id: i64,
+    session_version: i32,
+    family: &str,
+) -> Result<SessionTokens, tokio_postgres::Error> {
+    let refresh_token = crypto::random_token(32);
+    refresh_tokens
+        .create(
+            user_id,
+            family,
+            &crypto::sha256_hex(refresh_token.as_bytes()),
+            config.refresh_token_ttl,
+        )
+        .await?;
+    Ok(SessionTokens {
+        access_token: issue_access_token(config, tenant, user_id, session_version, family),
+        refresh_token,
+    })
+}
+
+#[derive(Deserialize)]
+struct Login {
+    email: String,
+    password: String,
+}
+
+// Each failure counts against the account and the client address: the
+// account is locked after LOGIN_MAX_FAILURES within the window, the address
+// refused after LOGIN_MAX_FAILURES_PER_IP. The limits follow CONFIG_FILE.
+#[post("/auth/login")]
+#[allow(clippy::too_many_arguments)]
+async fn login(
+    req: HttpRequest,
+    body: web::Json<Login>,
+    format: Format,
+    NamedTenant(tenant): NamedTenant,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+    settings: web::Data<RuntimeSettings>,
+    metrics: web::Data<LockoutMetrics>,
+) -> impl Responder {
+    let login = body.into_inner();
+    let limits = settings.current();
+    let ip = req
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    let window = limits.login_failure_window();
+    let (address, email) = (ip.as_str(), login.email.as_str());
+    let checked: Result<_, DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let ip_failures = uow.login_failures().count_for_ip(address, window).await?;
+                let failures = uow.login_failures().count_for_email(email, window).await?;
+                let credentials = uow.users().credentials(email).await?;
+                Ok((ip_failures, failures, credentials))
+            })
+        })
+        .await;
+    let (ip_failures, failures, credentials) = match checked {
+        Ok(checked) => checked,
+        Err(e) => return format.db_error(e, "Failed to log in"),
+    };
+    if ip_failures >= limits.login_max_failures_per_ip {
+        warn!("Refused login from {}, too many failures", ip);
+        metrics.address_throttled();
+        return lockout::too_many_attempts(format, window.as_secs());
+    }
+    // checked before the password, which can't be guessed while locked. An
+    // address without an account is refused as an account would be.
+    let locked_for = match &credentials {
+        Some(found) => found.locked_for.map(|seconds| seconds.max(1) as u64),
+        None if failures >= limits.login_max_failures => Some(limits.login_lockout().as_secs()),
+        None => None,
+    };
+    if let Some(seconds) = locked_for {
+        return lockout::too_many_attempts(format, seconds);
+    }
+    let hash = credentials
+        .as_ref()
+        .and_then(|found| found.password_hash.clone());
+    let valid = verify_password(login.password, hash).await;
+    let credentials = match credentials {
+        Some(credentials) if valid => credentials,
+        credentials => {
+            let user_id = credentials.and_then(|found| found.user.id);
+            let failure = Failure {
+                email: &login.email,
+                ip: &ip,
+                user_id,
+            };
+            return match record_failure(&db, &limits, &tenant, failure, &metrics).await {
+                Ok(()) => format.error(StatusCode::UNAUTHORIZED, "Invalid email or password"),
+                Err(e) => format.db_error(e, "Failed to log in"),
+            };
+        }
+    };
+    // only once the password is known to be right, not to reveal the status
+    let status = credentials.user.status;
+    if status != AccountStatus::Active {
+        return format.error(
+            StatusCode::FORBIDDEN,
+            &format!("Account is {}", status.name()),
+        );
+    }
+    let user_id = credentials.user.id.unwrap_or_default();
+    let version = credentials.session_version;
+    let family = crypto::random_token(16);
+    let (config_ref, tenant_ref, family) = (&config, &tenant, &family);
+    let tokens: Result<SessionTokens, DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let tokens = issue_tokens(
+                    uow.refresh_tokens(),
+                    config_ref,
+                    tenant_ref,
+                    user_id,
+                    version,
+                    family,
+                )
+                .await?;
+                uow.login_failures().clear(email).await?;
+                Ok(tokens)
+            })
+        })
+        .await;
+    match tokens {
+        Ok(tokens) => {
+            info!("User {} logged in", user_id);
+            token_pair(&config, &tokens)
+        }
+        Err(e) => format.db_error(e, "Failed to log in"),
+    }
+}
+
+struct Failure<'a> {
+    email: &'a str,
+    ip: &'a str,
+    // None when no user has this email address
+    user_id: Option<i64>,
+}
+
+async fn record_failure(
+    db: &Cluster,
+    limits: &Settings,
+    tenant: &Tenant,
+    failure: Failure<'_>,
+    metrics: &LockoutMetrics,
+) -> Result<(), DbError> {
+    let failure = &failure;
+    // the failures when the user was locked, logged once committed
+    let locked = db
+        .primary()
+        .transaction(tenant, |uow| {
+            Box::pin(async move {
+                let failures = uow
+                    .login_failures()
+                    .record(failure.email, failure.ip, limits.login_failure_window())
+                    .await?;
+                match failure.user_id {
+                    Some(user_id) if failures >= limits.login_max_failures => {
+                        uow.users().lock(user_id, limits.login_lockout()).await?;
+                        Ok(Some((user_id, failures)))
+                    }
+                    _ => Ok(None),
+                }
+            })
+        })
+        .await?;
+    if let Some((user_id, failures)) = locked {
+        warn!(
+            "User {} locked after {} failed logins, the last from {}",
+            user_id, failures, failure.ip
+        );
+        metrics.account_locked();
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Refresh {
+    refresh_token: String,
+}
+
+// Trade a refresh token for a new pair, the refresh token is used up. In
+// session mode it comes from its cookie when the body doesn't hold one.
+#[post("/auth/refresh")]
+async fn refresh(
+    req: HttpRequest,
+    body: Option<web::Json<Refresh>>,
+    format: Format,
+    NamedTenant(tenant): NamedTenant,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let refresh_token = match body
+        .map(|body| body.into_inner().refresh_token)
+        .or_else(|| crate::session::refresh_token(&req, &config))
+    {
+        Some(token) => token,
+        None => return format.error(StatusCode::BAD_REQUEST, "Missing refresh token"),
+    };
+    let token_hash = crypto::sha256_hex(refresh_token.as_bytes());
+    let (token_hash, config_ref, tenant_ref) = (token_hash.as_str(), &config, &tenant);
+    let result: Result<Option<SessionTokens>, DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let (user_id, family) = match uow.refresh_tokens().consume(token_hash).await? {
+                    Some(token) => token,
+                    None => {
+                        if uow.refresh_tokens().revoke_family_of(token_hash).await? {
+                            warn!("Refresh token reused, login revoked");
+                        }
+                        return Ok(None);
+                    }
+                };
+                // also None when the token was issued in another tenant,
+                // which keeps it
+                let version = match uow.users().session_version(user_id).await? {
+                    Some(version) => version,
+                    None => {
+                        uow.discard();
+                        return Ok(None);
+                    }
+                };
+                let tokens = issue_tokens(
+                    uow.refresh_tokens(),
+                    config_ref,
+                    tenant_ref,
+                    user_id,
+                    version,
+                    &family,
+                )
+                .await?;
+                Ok(Some(tokens))
+            })
+        })
+        .await;
+    match result {
+        Ok(Some(tokens)) => token_pair(&config, &tokens),
+        Ok(None) => format.error(StatusCode::UNAUTHORIZED, "Invalid or expired refresh token"),
+        Err(e) => format.db_error(e, "Failed to refresh token"),
+    }
+}
+
+// Revoke the login the access token belongs to, refresh tokens included
+#[post("/auth/logout")]
+async fn logout(
+    auth: Auth,
+    format: Format,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let family = &auth.session;
+    let result = db
+        .primary()
+        .run(|client| async move { client.refresh_tokens().revoke_family(family).await })
+        .await;
+    match result {
+        Ok(()) => {
+            info!("User {} logged out", auth.user_id);
+            let mut response = HttpResponse::NoContent();
+            if config.session_cookies {
+                crate::session::clear_cookies(&config, &mut response);
+            }
+            response.finish()
+        }
+        Err(e) => format.db_error(e, "Failed to log out"),
+    }
+}
+
+// Who the token belongs to and until when it is valid
+#[get("/auth/session")]
+async fn session(auth: Auth) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "user_id": auth.user_id,
+        "tenant_id": auth.tenant.as_str(),
+        "expires_at": auth.expires_at,
+    }))
+}
+
+#[derive(Deserialize)]
+struct ForgotPassword {
+    email: String,
+}
+
+// Always 202, whether or not the address is known
+#[post("/auth/forgot-password")]
+async fn forgot_password(
+    body: web::Json<ForgotPassword>,
+    format: Format,
+    NamedTenant(tenant): NamedTenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let email = &body.email;
+    let result: Result<(), DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                // reset links only go to addresses proven to belong to the user
+                if let Some(user) = uow.users().find_by_email(email).await? {
+                    if user.email_verified {
+                        uow.jobs()
+                            .enqueue(JobKind::PasswordResetEmail, &jobs::for_user(&user))
+                            .await?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .await;
+    match result {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => format.db_error(e, "Failed to queue password reset email"),
+    }
+}
+
+#[derive(Deserialize)]
+struct ResetPassword {
+    token: String,
+    password: String,
+}
+
+#[post("/auth/reset-password")]
+async fn reset_password(
+    body: web::Json<ResetPassword>,
+    format: Format,
+    NamedTenant(tenant): NamedTenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let reset = body.into_inner();
+    if let Err(e) = check_password(&reset.password) {
+        return format.error(StatusCode::BAD_REQUEST, &e);
+    }
+    let password_hash = match hash_password(reset.password).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            return format.error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to reset password",
+            );
+        }
+    };
+    let token_hash = crypto::sha256_hex(reset.token.as_bytes());
+    let (token_hash, password_hash) = (token_hash.as_str(), password_hash.as_str());
+    let result: Result<bool, DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let user_id = match uow.password_resets().consume(token_hash).await? {
+                    Some(user_id) => user_id,
+                    None => return Ok(false),
+                };
+                // signs the user out of every session, rolled back when the
+                // token was issued in another tenant
+                if !uow.users().set_password(user_id, password_hash).await? {
+                    uow.discard();
+                    return Ok(false);
+                }
+                uow.refresh_tokens().revoke_user(user_id).await?;
+                uow.password_resets().revoke_all(user_id).await?;
+                info!("Password of user {} reset", user_id);
+                Ok(true)
+            })
+        })
+        .await;
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => format.error(StatusCode::BAD_REQUEST, "Invalid or expired token"),
+        Err(e) => format.db_error(e, "Failed to reset password"),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(login)
+        .service(refresh)
+        .service(logout)
+        .service(session)
+        .service(forgot_password)
+        .service(reset_password);
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{self, TestRequest};
+    use rand::Rng;
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+
+    use crate::app;
+    use crate::test_app::{
+        self, admin, create_user, email, login, request, shared, shared_with, signed_in,
+    };
+
+    const PASSWORD: &str = "correct horse battery";
+
+    // A client address of its own, the failures of an address counting
+    // across the tests
+    fn client() -> SocketAddr {
+        let mut rng = rand::thread_rng();
+        SocketAddr::from(([10, rng.gen(), rng.gen(), rng.gen()], 40000))
+    }
+
+    #[actix_web::test]
+    async fn logins_are_locked_after_too_many_failures() {
+        let shared = shared_with(|config| config.login_max_failures = 3).await;
+        let app = test::init_service(app(&shared)).await;
+        let client = client();
+        let send =
+            |request: TestRequest| test::call_service(&app, request.peer_addr(client).to_request());
+        let user = email("locked");
+        let id = create_user(send, &user, PASSWORD).await;
+
+        for _ in 0..3 {
+            let (status, _) = login(send, &user, "wrong password").await;
+            assert_eq!(status, 401);
+        }
+        let (status, _) = login(send, &user, PASSWORD).await;
+        assert_eq!(status, 429, "the right password while locked");
+
+        let response = send(admin("POST", &format!("/users/{}/unlock", id))).await;
+        assert_eq!(response.status(), 200, "unlock");
+        let (status, _) = login(send, &user, PASSWORD).await;
+        assert_eq!(status, 200, "the right password once unlocked");
+
+        // a success forgets the failures before it
+        for _ in 0..2 {
+            login(send, &user, "wrong password").await;
+        }
+        login(send, &user, PASSWORD).await;
+        for _ in 0..2 {
+            login(send, &user, "wrong password").await;
+        }
+        let (status, _) = login(send, &user, PASSWORD).await;
+        assert_eq!(status, 200, "two failures after a success");
+    }
+
+    #[actix_web::test]
+    async fn addresses_without_an_account_are_locked_alike() {
+        let shared = shared_with(|config| config.login_max_failures = 3).await;
+        let app = test::init_service(app(&shared)).await;
+        let client = client();
+        let send =
+            |request: TestRequest| test::call_service(&app, request.peer_addr(client).to_request());
+        let nobody = email("nobody");
+        for _ in 0..3 {
+            let (status, body) = login(send, &nobody, PASSWORD).await;
+            assert_eq!(status, 401);
+            assert_eq!(body["detail"], "Invalid email or password");
+        }
+        let (status, _) = login(send, &nobody, PASSWORD).await;
+        assert_eq!(status, 429);
+    }
+
+    #[actix_web::test]
+    async fn addresses_are_throttled_whatever_the_account() {
+        let shared = shared_with(|config| config.login_max_failures_per_ip = 3).await;
+        let app = test::init_service(app(&shared)).await;
+        let (client, other_client) = (client(), client());
+        let send =
+            |request: TestRequest| test::call_service(&app, request.peer_addr(client).to_request());
+        let user = email("throttled");
+        create_user(send, &user, PASSWORD).await;
+        for name in ["first", "second", "third"] {
+            login(send, &email(name), PASSWORD).await;
+        }
+        let (status, _) = login(send, &user, PASSWORD).await;
+        assert_eq!(status, 429, "the right password from a throttled address");
+        let other = |request: TestRequest| {
+            test::call_service(&app, request.peer_addr(other_client).to_request())
+        };
+        let (status, _) = login(other, &user, PASSWORD).await;
+        assert_eq!(status, 200, "from another address");
+    }
+
+    fn refresh(tokens: &Value) -> TestRequest {
+        request("POST", "/auth/refresh")
+            .set_json(json!({ "refresh_token": tokens["refresh_token"] }))
+    }
+
+    #[actix_web::test]
+    async fn a_reused_refresh_token_revokes_its_login() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let user = email("refresh");
+        create_user(send, &user, PASSWORD).await;
+        let (_, first) = login(send, &user, PASSWORD).await;
+
+        let (status, second) = test_app::json(send(refresh(&first)).await).await;
+        assert_eq!(status, 200, "refresh: {}", second);
+        assert_ne!(
+            second["refresh_token"], first["refresh_token"],
+            "not rotated"
+        );
+        let response = send(signed_in("GET", "/auth/session", &second)).await;
+        assert_eq!(response.status(), 200, "the new access token");
+
+        let response = send(refresh(&first)).await;
+        assert_eq!(response.status(), 401, "the rotated refresh token again");
+        let response = send(refresh(&second)).await;
+        assert_eq!(
+            response.status(),
+            401,
+            "the refresh token issued after the reuse"
+        );
+        for tokens in [&first, &second] {
+            let response = send(signed_in("GET", "/auth/session", tokens)).await;
+            assert_eq!(
+                response.status(),
+                401,
+                "an access token of the revoked login"
+            );
+        }
+
+        // other logins of the user go on
+        let (_, other) = login(send, &user, PASSWORD).await;
+        let response = send(refresh(&other)).await;
+        assert_eq!(response.status(), 200, "another login");
+    }
+
+    #[actix_web::test]
+    async fn a_logout_revokes_its_tokens() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let user = email("logout");
+        create_user(send, &user, PASSWORD).await;
+        let (_, tokens) = login(send, &user, PASSWORD).await;
+        let response = send(signed_in("POST", "/auth/logout", &tokens)).await;
+        assert_eq!(response.status(), 204);
+        let response = send(signed_in("GET", "/auth/session", &tokens)).await;
+        assert_eq!(response.status(), 401, "the access token");
+        let response = send(refresh(&tokens)).await;
+        assert_eq!(response.status(), 401, "the refresh token");
+    }
+}
@@ -0,0 +1,189 @@
+use actix_multipart::Multipart;
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{get, put, web, HttpResponse, Responder};
+use futures_util::StreamExt;
+use log::{error, info};
+
+use crate::admin::Admin;
+use crate::auth::{self, Auth};
+use crate::config::Config;
+use crate::db::{Cluster, DbError};
+use crate::jsonapi::Format;
+use crate::storage::BlobStore;
+use crate::tenant::Tenant;
+
+// Multipart field holding the image
+const AVATAR_FIELD: &str = "avatar";
+
+// Accepted image types and the bytes their files start with
+const IMAGE_TYPES: [(&str, &[u8]); 4] = [
+    ("image/png", b"\x89PNG\r\n\x1a\n"),
+    ("image/jpeg", b"\xff\xd8\xff"),
+    ("image/gif", b"GIF8"),
+    ("image/webp", b"RIFF"),
+];
+
+pub fn key(tenant: &Tenant, user_id: i64) -> String {
+    format!("avatars/{}/{}", tenant, user_id)
+}
+
+// The declared type must be an accepted one and match the file itself
+fn image_type(declared: &str, data: &[u8]) -> Option<&'static str> {
+    IMAGE_TYPES
+        .iter()
+        .find(|(name, magic)| {
+            *name == declared
+                && data.starts_with(magic)
+                && (*name != "image/webp" || data.get(8..12) == Some(b"WEBP"))
+        })
+        .map(|(name, _)| *name)
+}
+
+enum Upload {
+    Image(&'static str, Bytes),
+    Missing,
+    TooLarge,
+    Unsupported,
+}
+
+async fn read_upload(mut payload: Multipart, max_bytes: usize) -> Result<Upload, String> {
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| e.to_string())?;
+        if field.name() != AVATAR_FIELD {
+            continue;
+        }
+        let declared = field
+            .content_type()
+            .map(|mime| mime.essence_str().to_string())
+            .unwrap_or_default();
+        let mut data = BytesMut::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| e.to_string())?;
+            if data.len() + chunk.len() > max_bytes {
+                return Ok(Upload::TooLarge);
+            }
+            data.extend_from_slice(&chunk);
+        }
+        return Ok(match image_type(&declared, &data) {
+            Some(content_type) => Upload::Image(content_type, data.freeze()),
+            None => Upload::Unsupported,
+        });
+    }
+    Ok(Upload::Missing)
+}
+
+// Expects a multipart/form-data body with the image in an `avatar` field,
+// from the user themselves or an admin
+#[put("/users/{id}/avatar")]
+#[allow(clippy::too_many_arguments)]
+async fn put_avatar(
+    admin: Option<Admin>,
+    auth: Option<Auth>,
+    path: web::Path<String>,
+    payload: Multipart,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    store: web::Data<dyn BlobStore>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let id = match crate::parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if let Err(response) =
+        auth::authorize_user(format, &admin, &auth, id, &tenant, "change the avatar")
+    {
+        return response;
+    }
+    let (content_type, data) = match read_upload(payload, config.avatar_max_bytes).await {
+        Ok(Upload::Image(content_type, data)) => (content_type, data),
+        Ok(Upload::Missing) => {
+            return format.error(
+                StatusCode::BAD_REQUEST,
+                &format!("Missing the {} field", AVATAR_FIELD),
+            )
+        }
+        Ok(Upload::TooLarge) => {
+            return format.error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &format!("Avatars are limited to {} bytes", config.avatar_max_bytes),
+            )
+        }
+        Ok(Upload::Unsupported) => {
+            return format.error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Avatars must be PNG, JPEG, GIF or WebP images",
+            )
+        }
+        Err(e) => return format.error(StatusCode::BAD_REQUEST, &e),
+    };
+    // stored first, so that no row stays locked during the upload
+    let key = key(&tenant, id);
+    if let Err(e) = store.put(&key, content_type, data).await {
+        error!("Failed to store the avatar of user {}: {}", id, e);
+        return format.error(StatusCode::BAD_GATEWAY, "Failed to store avatar");
+    }
+    let result: Result<bool, DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move { Ok(uow.users().set_avatar(id, Some(content_type)).await?) })
+        })
+        .await;
+    if !matches!(result, Ok(true)) {
+        // the image the row doesn't describe is removed, rather than served
+        // with the type of the former one
+        if let Err(e) = store.delete(&key).await {
+            error!("Failed to delete the orphaned avatar of user {}: {}", id, e);
+        }
+    }
+    match result {
+        Ok(true) => {
+            info!("Stored the avatar of user {}", id);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => format.error(StatusCode::NOT_FOUND, &format!("User {} not found", id)),
+        Err(e) => format.db_error(e, &format!("Failed to update user {}", id)),
+    }
+}
+
+#[get("/users/{id}/avatar")]
+async fn get_avatar(
+    path: web::Path<String>,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    store: web::Data<dyn BlobStore>,
+) -> impl Responder {
+    let id = match crate::parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let result: Result<Option<String>, DbError> = db
+        .reader()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move { Ok(uow.users().avatar_type(id).await?) })
+        })
+        .await;
+    let not_found = || format.error(StatusCode::NOT_FOUND, &format!("User {} has no avatar", id));
+    let content_type = match result {
+        Ok(Some(content_type)) => content_type,
+        Ok(None) => return not_found(),
+        Err(e) => return format.db_error(e, &format!("Failed to retrieve user {}", id)),
+    };
+    match store.get(&key(&tenant, id)).await {
+        Ok(Some(data)) => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .body(data),
+        Ok(None) => not_found(),
+        Err(e) => {
+            error!("Failed to read the avatar of user {}: {}", id, e);
+            format.error(StatusCode::BAD_GATEWAY, "Failed to read avatar")
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(put_avatar).service(get_avatar);
+}
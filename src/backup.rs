@@ -0,0 +1,603 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{self, Bytes, BytesMut};
+use actix_web::{get, post, HttpRequest, HttpResponse, Responder};
+use futures_util::{stream, StreamExt};
+use log::{error, info};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use tokio_postgres::{Error, Statement, Transaction};
+
+use crate::admin::{self, Admin};
+use crate::config::Config;
+use crate::db::{Cluster, DbError, Naming, PooledClient};
+use crate::jsonapi::Format;
+use crate::repository::{self, SCHEMA_VERSION};
+use crate::tenant::{Tenant, TENANT_HEADER};
+
+// Told apart from other NDJSON files by the `format` of its first line
+const FORMAT: &str = "rust-crud-api-backup";
+
+// Rows read from a table at a time
+const BATCH: usize = 500;
+
+// Left out of the snapshots: the sessions, password resets and signatures
+// seen, the API keys and the partners, and the webhooks whose secrets sign
+// the deliveries. They are created again after a restore.
+const LEFT_OUT: [&str; 7] = [
+    "refresh_tokens",
+    "password_resets",
+    "partner_signatures",
+    "api_keys",
+    "partners",
+    "webhooks",
+    "webhook_deliveries",
+];
+
+// Columns left out of the rows, `(table, column)`: the users restored sign
+// in again through a password reset
+const LEFT_OUT_COLUMNS: [(&str, &str); 1] = [("users", "password_hash")];
+
+// The tables of one tenant, those of the snapshots of a tenant
+const TENANT_TABLES: [&str; 3] = ["users", "user_identities", "login_failures"];
+
+// The tables of the snapshots of `scope`, of every tenant when None, in
+// creation order
+fn backed_up(scope: Option<&Tenant>) -> Vec<&'static str> {
+    repository::tables()
+        .into_iter()
+        .filter(|table| !LEFT_OUT.contains(table))
+        .filter(|table| scope.is_none() || TENANT_TABLES.contains(table))
+        .collect()
+}
+
+// The tenant a backup or a restore is limited to. Only the admin token
+// without X-Tenant-Id reaches every tenant, the API keys and the partners
+// with the admin scope only reach their own.
+fn scope(req: &HttpRequest, config: &Config, tenant: Tenant) -> Option<Tenant> {
+    let every = admin::has_admin_token(req, config) && !req.headers().contains_key(TENANT_HEADER);
+    (!every).then_some(tenant)
+}
+
+// Takes the rows of a snapshot to the table they belong to. The missing
+// columns are NULL, a snapshot is only restored at its own schema version.
+const INSERT: &str = "INSERT INTO {prefix}{table}
+    SELECT * FROM json_populate_record(NULL::{prefix}{table}, $1)
+    ON CONFLICT DO NOTHING";
+
+// The serial columns of a table, whose sequence must go past the restored ids
+const SERIAL_COLUMNS: &str = "SELECT attname::TEXT AS name FROM pg_attribute
+    WHERE attrelid = $1::TEXT::REGCLASS AND attnum > 0 AND NOT attisdropped
+      AND pg_get_serial_sequence($1, attname) IS NOT NULL";
+
+// A connection in the middle of the snapshot transaction, reading one table
+// at a time through a cursor. Closed rather than returned to the pool when
+// dropped before the end, the client having gone away.
+struct Snapshot {
+    client: PooledClient,
+    naming: Naming,
+    // the only tenant read, every one when None
+    scope: Option<Tenant>,
+    tables: std::vec::IntoIter<&'static str>,
+    // whose cursor is open
+    table: Option<&'static str>,
+    finished: bool,
+}
+
+impl Snapshot {
+    // The tables of `scope` as of now. Those of every tenant are read as far
+    // as the row level security lets the database user read them: with
+    // TENANT_RLS it fails rather than leave the users of the other tenants
+    // out, unless the user bypasses it.
+    async fn begin(client: PooledClient, scope: Option<Tenant>) -> Result<Snapshot, Error> {
+        client
+            .client
+            .batch_execute("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY")
+            .await?;
+        match &scope {
+            Some(tenant) => {
+                client
+                    .client
+                    .execute(
+                        "SELECT set_config('app.tenant_id', $1, true)",
+                        &[&tenant.as_str()],
+                    )
+                    .await?;
+            }
+            None => {
+                client
+                    .client
+                    .batch_execute("SET LOCAL row_security = off")
+                    .await?
+            }
+        }
+        Ok(Snapshot {
+            naming: client.statements.naming().clone(),
+            client,
+            tables: backed_up(scope.as_ref()).into_iter(),
+            scope,
+            table: None,
+            finished: false,
+        })
+    }
+
+    // The next lines of the snapshot, None once every table was read
+    async fn next_lines(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            let table = match self.table {
+                Some(table) => table,
+                None => match self.tables.next() {
+                    Some(table) => {
+                        let left_out: Vec<_> = LEFT_OUT_COLUMNS
+                            .iter()
+                            .filter(|(name, _)| *name == table)
+                            .map(|(_, column)| format!("'{}'", column))
+                            .collect();
+                        let row = if left_out.is_empty() {
+                            "row_to_json(t)".to_string()
+                        } else {
+                            format!("to_jsonb(t) - ARRAY[{}]", left_out.join(", "))
+                        };
+                        // tenant ids are letters, digits, - and _ only
+                        let filter = match &self.scope {
+                            Some(tenant) => format!("WHERE t.tenant_id = '{}'", tenant),
+                            None => String::new(),
+                        };
+                        let sql = format!(
+                            "DECLARE backup NO SCROLL CURSOR FOR
+                                 SELECT ({})::TEXT AS row FROM {{prefix}}{} t {}",
+                            row, table, filter
+                        );
+                        self.client
+                            .client
+                            .batch_execute(&self.naming.apply(&sql))
+                            .await?;
+                        self.table = Some(table);
+                        table
+                    }
+                    None => {
+                        self.client.client.batch_execute("COMMIT").await?;
+                        self.finished = true;
+                        return Ok(None);
+                    }
+                },
+            };
+            let rows = self
+                .client
+                .client
+                .query(&format!("FETCH {} FROM backup", BATCH), &[])
+                .await?;
+            if rows.is_empty() {
+                self.client.client.batch_execute("CLOSE backup").await?;
+                self.table = None;
+                continue;
+            }
+            let mut lines = Vec::new();
+            for row in &rows {
+                let row: &str = row.try_get("row")?;
+                // the row is JSON already, spliced in as it is
+                lines.extend_from_slice(
+                    format!("{{\"table\":{},\"row\":{}}}\n", json!(table), row).as_bytes(),
+                );
+            }
+            return Ok(Some(lines));
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.client.discard();
+        }
+    }
+}
+
+// Every row of the tables backed up, streamed as NDJSON: a first line naming
+// the format, the schema version and the tenant, then one `{"table": ...,
+// "row": {...}}` per row, the tables in creation order so that a row comes
+// after those it refers to. Read in one transaction, the snapshot is
+// consistent.
+#[get("/admin/backup")]
+async fn get_backup(
+    _admin: Admin,
+    req: HttpRequest,
+    tenant: Tenant,
+    format: Format,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let scope = scope(&req, &config, tenant);
+    let tables = backed_up(scope.as_ref());
+    let tenant = scope.as_ref().map(Tenant::to_string);
+    let result: Result<_, DbError> = async {
+        let mut snapshot = Snapshot::begin(db.primary().checkout().await?, scope).await?;
+        // a failure before the first line is still answered as one
+        let first = snapshot.next_lines().await?;
+        Ok((snapshot, first))
+    }
+    .await;
+    let (snapshot, first) = match result {
+        Ok(started) => started,
+        Err(e) => return format.db_error(e, "Failed to back up the database"),
+    };
+    info!("Backing up the database");
+    let preamble = format!(
+        "{}\n",
+        json!({
+            "format": FORMAT,
+            "schema_version": SCHEMA_VERSION,
+            "tenant": tenant,
+            "tables": tables,
+        })
+    );
+    let rest = stream::unfold(Some(snapshot), |snapshot| async move {
+        let mut snapshot = snapshot?;
+        match snapshot.next_lines().await {
+            Ok(Some(lines)) => Some((Ok(Bytes::from(lines)), Some(snapshot))),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Backup cut short: {}", e);
+                Some((Err(e), None))
+            }
+        }
+    });
+    let body = stream::iter(
+        std::iter::once(preamble.into_bytes())
+            .chain(first)
+            .map(|lines| Ok(Bytes::from(lines))),
+    )
+    .chain(rest);
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"backup.ndjson\"",
+        ))
+        .streaming(body)
+}
+
+enum RestoreError {
+    // answered 400, the line of the snapshot at fault first
+    Invalid(String),
+    Db(DbError),
+}
+
+impl From<Error> for RestoreError {
+    fn from(e: Error) -> RestoreError {
+        RestoreError::Db(DbError::from(e))
+    }
+}
+
+impl From<DbError> for RestoreError {
+    fn from(e: DbError) -> RestoreError {
+        RestoreError::Db(e)
+    }
+}
+
+#[derive(Default, Serialize)]
+struct Restored {
+    restored: u64,
+    // already there
+    skipped: u64,
+}
+
+// The snapshot being read back, in a transaction committed once the last
+// line is in
+struct Restore<'a> {
+    tx: Transaction<'a>,
+    naming: &'a Naming,
+    // the only tenant whose rows may be restored, any when None
+    scope: Option<Tenant>,
+    inserts: HashMap<&'static str, Statement>,
+    tables: Vec<(&'static str, Restored)>,
+    // users merged into another one, which may come after them
+    merges: Vec<(i64, i64)>,
+    lines: usize,
+}
+
+impl Restore<'_> {
+    async fn line(&mut self, line: &[u8]) -> Result<(), RestoreError> {
+        self.lines += 1;
+        let number = self.lines;
+        let invalid =
+            |message: String| RestoreError::Invalid(format!("line {}: {}", number, message));
+        let line: Value =
+            serde_json::from_slice(line).map_err(|e| invalid(format!("invalid JSON, {}", e)))?;
+        if number == 1 {
+            return match (line["format"].as_str(), line["schema_version"].as_i64()) {
+                (Some(FORMAT), Some(version)) if version == SCHEMA_VERSION as i64 => Ok(()),
+                (Some(FORMAT), Some(version)) => Err(invalid(format!(
+                    "snapshot of schema version {}, this build is at version {}",
+                    version, SCHEMA_VERSION
+                ))),
+                _ => Err(invalid(format!("not a {} snapshot", FORMAT))),
+            };
+        }
+        let name = line["table"].as_str().unwrap_or_default();
+        let table = repository::tables()
+            .into_iter()
+            .find(|table| *table == name)
+            .ok_or_else(|| invalid(format!("unknown table '{}'", name)))?;
+        let mut row = match line.get("row") {
+            Some(Value::Object(row)) => row.clone(),
+            _ => return Err(invalid("expected a row object".to_string())),
+        };
+        if let Some(tenant) = &self.scope {
+            if !TENANT_TABLES.contains(&table) {
+                return Err(invalid(format!(
+                    "table '{}' is not restored for a tenant",
+                    table
+                )));
+            }
+            if row.get("tenant_id").and_then(Value::as_str) != Some(tenant.as_str()) {
+                return Err(invalid(format!("row of another tenant than {}", tenant)));
+            }
+        }
+        if table == "users" {
+            let id = row.get("id").and_then(Value::as_i64);
+            let into = row.get("merged_into").and_then(Value::as_i64);
+            if let (Some(id), Some(into)) = (id, into) {
+                self.merges.push((id, into));
+                row.insert("merged_into".to_string(), Value::Null);
+            }
+        }
+        if !self.inserts.contains_key(table) {
+            let sql = self.naming.apply(&INSERT.replace("{table}", table));
+            let statement = self.tx.prepare(&sql).await?;
+            self.inserts.insert(table, statement);
+            self.tables.push((table, Restored::default()));
+        }
+        let inserted = self
+            .tx
+            .execute(&self.inserts[table], &[&Value::Object(row)])
+            .await
+            .map_err(|e| match e.as_db_error() {
+                // rejected by the table, the snapshot is at fault
+                Some(db_error) => invalid(db_error.message().to_string()),
+                None => RestoreError::from(e),
+            })?;
+        let (_, restored) = self
+            .tables
+            .iter_mut()
+            .find(|(name, _)| *name == table)
+            .expect("restored table not counted");
+        if inserted == 0 {
+            restored.skipped += 1;
+        } else {
+            restored.restored += 1;
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<Map<String, Value>, RestoreError> {
+        if self.lines == 0 {
+            return Err(RestoreError::Invalid("empty snapshot".to_string()));
+        }
+        let merge = self.naming.apply(
+            "UPDATE {prefix}users SET merged_into = $2 WHERE id = $1 AND merged_into IS NULL",
+        );
+        for (id, into) in &self.merges {
+            self.tx.execute(&merge, &[id, into]).await?;
+        }
+        for (table, _) in &self.tables {
+            let table = self.naming.apply(&format!("{{prefix}}{}", table));
+            for column in self.tx.query(SERIAL_COLUMNS, &[&table]).await? {
+                let column: String = column.try_get("name")?;
+                let sql = format!(
+                    "SELECT setval(pg_get_serial_sequence($1, $2), max) \
+                     FROM (SELECT max(\"{column}\") AS max FROM {table}) ids WHERE max IS NOT NULL",
+                );
+                self.tx.execute(&sql, &[&table, &column]).await?;
+            }
+        }
+        self.tx.commit().await?;
+        Ok(self
+            .tables
+            .into_iter()
+            .map(|(table, restored)| (table.to_string(), json!(restored)))
+            .collect())
+    }
+}
+
+// Reads a snapshot of GET /admin/backup back, in one transaction: the rows
+// already there are skipped, restoring the same snapshot again changes
+// nothing. Meant for an empty schema at the version of the snapshot, the
+// sequences go on after the restored ids.
+#[post("/admin/restore")]
+#[allow(clippy::too_many_arguments)]
+async fn restore(
+    _admin: Admin,
+    req: HttpRequest,
+    tenant: Tenant,
+    format: Format,
+    mut body: web::Payload,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let scope = scope(&req, &config, tenant);
+    let result: Result<_, RestoreError> = async {
+        let mut client = db.primary().checkout().await?;
+        let client = &mut *client;
+        let naming = client.statements.naming();
+        let tx = client.client.transaction().await?;
+        match &scope {
+            Some(tenant) => {
+                tx.execute(
+                    "SELECT set_config('app.tenant_id', $1, true)",
+                    &[&tenant.as_str()],
+                )
+                .await?;
+            }
+            None => tx.batch_execute("SET LOCAL row_security = off").await?,
+        }
+        let mut restore = Restore {
+            tx,
+            naming,
+            scope,
+            inserts: HashMap::new(),
+            tables: Vec::new(),
+            merges: Vec::new(),
+            lines: 0,
+        };
+        let mut buffer = BytesMut::new();
+        loop {
+            let chunk = body.next().await.transpose().map_err(|e| {
+                RestoreError::Invalid(format!("Failed to read the snapshot: {}", e))
+            })?;
+            let end = chunk.is_none();
+            buffer.extend_from_slice(&chunk.unwrap_or_default());
+            while let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line = buffer.split_to(newline + 1);
+                if !line.trim_ascii().is_empty() {
+                    restore.line(&line).await?;
+                }
+            }
+            if buffer.len() > config.max_json_bytes {
+                return Err(RestoreError::Invalid(format!(
+                    "line {}: longer than {} bytes",
+                    restore.lines + 1,
+                    config.max_json_bytes
+                )));
+            }
+            if end {
+                break;
+            }
+        }
+        if !buffer.trim_ascii().is_empty() {
+            restore.line(&buffer).await?;
+        }
+        restore.finish().await
+    }
+    .await;
+    match result {
+        Ok(tables) => {
+            info!("Restored a snapshot");
+            HttpResponse::Ok().json(json!({ "tables": tables }))
+        }
+        Err(RestoreError::Invalid(message)) => format.error(StatusCode::BAD_REQUEST, &message),
+        Err(RestoreError::Db(e)) => format.db_error(e, "Failed to restore the snapshot"),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_backup).service(restore);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::MessageBody;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::test::{self, TestRequest};
+
+    use super::*;
+    use crate::api_keys::API_KEY_HEADER;
+    use crate::app;
+    use crate::test_app::{self, admin, create_user, email, request, shared};
+
+    // The first line of a snapshot, then its `(table, row)` lines
+    async fn backup<B: MessageBody>(response: ServiceResponse<B>) -> (Value, Vec<(String, Value)>) {
+        assert_eq!(response.status(), 200, "GET /admin/backup");
+        let body = test::read_body(response).await;
+        let mut lines = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice::<Value>(line).unwrap());
+        let first = lines.next().expect("empty snapshot");
+        let rows = lines
+            .map(|line| {
+                (
+                    line["table"].as_str().unwrap().to_string(),
+                    line["row"].clone(),
+                )
+            })
+            .collect();
+        (first, rows)
+    }
+
+    #[actix_web::test]
+    async fn snapshots_leave_the_credentials_out() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let id = create_user(send, &email("backed-up"), "correct horse battery").await;
+
+        let (first, rows) = backup(send(admin("GET", "/admin/backup")).await).await;
+        assert_eq!(
+            first["tenant"],
+            Value::Null,
+            "the admin token backs up every tenant"
+        );
+        for (table, _) in &rows {
+            assert!(
+                !LEFT_OUT.contains(&table.as_str()),
+                "{} was backed up",
+                table
+            );
+        }
+        let (_, user) = rows
+            .iter()
+            .find(|(table, row)| table == "users" && row["id"] == id)
+            .expect("the user is not in the snapshot");
+        assert!(user.get("password_hash").is_none(), "{}", user);
+        assert!(user.get("email").is_some(), "{}", user);
+    }
+
+    #[actix_web::test]
+    async fn tenant_snapshots_hold_their_tenant_only() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let acme = format!("t{}", crate::ids::event_id().to_lowercase());
+        let in_acme = |request: TestRequest| request.insert_header((TENANT_HEADER, acme.as_str()));
+        let user = json!({ "name": "Jane Doe", "email": email("jane") });
+        let (status, user) =
+            test_app::json(send(in_acme(admin("POST", "/users")).set_json(&user)).await).await;
+        assert_eq!(status, 201, "POST /users: {}", user);
+        create_user(send, &email("elsewhere"), "correct horse battery").await;
+        let new_key = json!({ "name": "backups", "scopes": ["admin"] });
+        let (status, key) = test_app::json(
+            send(in_acme(admin("POST", "/admin/api-keys")).set_json(&new_key)).await,
+        )
+        .await;
+        assert_eq!(status, 201, "POST /admin/api-keys: {}", key);
+        let with_key = |request: TestRequest| {
+            request.insert_header((API_KEY_HEADER, key["key"].as_str().unwrap()))
+        };
+
+        let (first, rows) = backup(send(with_key(request("GET", "/admin/backup"))).await).await;
+        assert_eq!(first["tenant"], json!(acme));
+        assert_eq!(
+            first["tables"],
+            json!(backed_up(Some(&Tenant::parse(&acme).unwrap())))
+        );
+        assert!(rows
+            .iter()
+            .any(|(table, row)| table == "users" && row["id"] == user["id"]));
+        for (table, row) in &rows {
+            assert!(
+                TENANT_TABLES.contains(&table.as_str()),
+                "{} was backed up",
+                table
+            );
+            assert_eq!(row["tenant_id"], json!(acme), "a row of {}", table);
+        }
+
+        let restoring =
+            |snapshot: String| with_key(request("POST", "/admin/restore")).set_payload(snapshot);
+        let mut snapshot = format!("{}\n", first);
+        for (table, row) in &rows {
+            snapshot.push_str(&format!("{}\n", json!({ "table": table, "row": row })));
+        }
+        let (status, restored) = test_app::json(send(restoring(snapshot.clone())).await).await;
+        assert_eq!(
+            status, 200,
+            "POST /admin/restore of the snapshot: {}",
+            restored
+        );
+        assert_eq!(restored["tables"]["users"]["restored"], 0);
+        let mut other = user.clone();
+        other["id"] = json!(-1);
+        other["tenant_id"] = json!(crate::tenant::DEFAULT_TENANT);
+        snapshot.push_str(&format!("{}\n", json!({ "table": "users", "row": other })));
+        let (status, body) = test_app::json(send(restoring(snapshot)).await).await;
+        assert_eq!(status, 400, "a row of another tenant: {}", body);
+    }
+}
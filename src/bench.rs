@@ -0,0 +1,189 @@
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse, Responder};
+use futures_util::stream;
+use serde_json::{json, Map, Value};
+use std::convert::Infallible;
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+use crate::jsonapi::{self, Format};
+use crate::links::Links;
+use crate::models::{AccountStatus, User, UserField};
+use crate::preconditions::{self, Preconditions};
+use crate::tenant::DEFAULT_TENANT;
+use crate::{FieldsQuery, ListQuery, StatusQuery, TOTAL_COUNT_HEADER, USERS};
+
+// Users served by --bench-mode instead of the database, the same on every
+// run: `User <id>` <user<id>@example.com>, every tenth one suspended and
+// every 25th deactivated
+pub struct Dataset {
+    users: Vec<User>,
+}
+
+impl Dataset {
+    pub fn generate(count: usize) -> Dataset {
+        // a fixed date so that Last-Modified doesn't change between runs
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let users = (1..=count as i64)
+            .map(|id| User {
+                id: Some(id),
+                name: format!("User {}", id),
+                email: format!("user{}@example.com", id),
+                email_verified: id % 3 != 0,
+                password: None,
+                status: if id % 25 == 0 {
+                    AccountStatus::Deactivated
+                } else if id % 10 == 0 {
+                    AccountStatus::Suspended
+                } else {
+                    AccountStatus::Active
+                },
+                tenant_id: DEFAULT_TENANT.to_string(),
+                merged_into: None,
+                updated_at: Some(epoch + Duration::from_secs(id as u64)),
+            })
+            .collect();
+        Dataset { users }
+    }
+
+    fn find(&self, id: i64) -> Option<&User> {
+        usize::try_from(id - 1)
+            .ok()
+            .and_then(|index| self.users.get(index))
+    }
+
+    fn with_status(&self, status: AccountStatus) -> impl Iterator<Item = &User> {
+        self.users.iter().filter(move |user| user.status == status)
+    }
+}
+
+// Only the selected fields of `user`, as `UserField::partial_from_row` does
+fn partial(fields: &[UserField], user: &User) -> Value {
+    let all = json!(user);
+    let partial: Map<String, Value> = fields
+        .iter()
+        .filter_map(|field| {
+            let column = field.column();
+            Some((column.to_string(), all.get(column)?.clone()))
+        })
+        .collect();
+    Value::Object(partial)
+}
+
+#[get("/users", name = "users")]
+async fn get_users(
+    query: web::Query<ListQuery>,
+    format: Format,
+    dataset: web::Data<Dataset>,
+    config: web::Data<Config>,
+    links: Links,
+) -> impl Responder {
+    let fields = match crate::parse_fields(format, &query.fields, &query.sparse_fields) {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    let to_value = |user: &User| match &fields {
+        None => links.with_user(user),
+        Some(fields) => links.attach(partial(fields, user)),
+    };
+    if let Some(ids) = &query.ids {
+        let ids = match crate::parse_ids(format, ids) {
+            Ok(ids) => ids,
+            Err(response) => return response,
+        };
+        let (found, missing): (Vec<i64>, Vec<i64>) =
+            ids.into_iter().partition(|id| dataset.find(*id).is_some());
+        let users = found
+            .into_iter()
+            .filter_map(|id| Some((id, to_value(dataset.find(id)?))))
+            .collect();
+        return format.respond_keyed(StatusCode::OK, USERS, users, &missing);
+    }
+    let status = match crate::parse_status(format, &query.status) {
+        Ok(status) => status,
+        Err(response) => return response,
+    };
+    let users: Vec<Value> = dataset.with_status(status).map(to_value).collect();
+    // answered like the database backed route, streamed above the threshold
+    if users.len() <= config.stream_threshold {
+        format.respond(StatusCode::OK, USERS, &users)
+    } else {
+        let items = stream::iter(users.into_iter().map(Ok::<_, Infallible>));
+        format.respond_stream(StatusCode::OK, USERS, items)
+    }
+}
+
+#[get("/users/count")]
+async fn count_users(
+    query: web::Query<StatusQuery>,
+    format: Format,
+    dataset: web::Data<Dataset>,
+) -> impl Responder {
+    let status = match crate::parse_status(format, &query.status) {
+        Ok(status) => status,
+        Err(response) => return response,
+    };
+    let count = dataset.with_status(status).count();
+    let body = match format {
+        Format::Json => json!({ "count": count }),
+        Format::JsonApi => json!({ "meta": { "count": count } }),
+    };
+    let content_type = match format {
+        Format::Json => "application/json",
+        Format::JsonApi => jsonapi::MEDIA_TYPE,
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((TOTAL_COUNT_HEADER, count))
+        .body(body.to_string())
+}
+
+#[get("/users/{id}", name = "user")]
+async fn get_user(
+    path: web::Path<String>,
+    query: web::Query<FieldsQuery>,
+    format: Format,
+    dataset: web::Data<Dataset>,
+    links: Links,
+    preconditions: Preconditions,
+) -> impl Responder {
+    let id = match crate::parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let fields = match crate::parse_fields(format, &query.fields, &query.sparse_fields) {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    let user = match dataset.find(id) {
+        Some(user) => user,
+        None => return format.error(StatusCode::NOT_FOUND, &format!("User {} not found", id)),
+    };
+    match user.updated_at {
+        Some(updated_at) if preconditions.not_modified(updated_at) => {
+            return preconditions::not_modified(updated_at)
+        }
+        _ => (),
+    }
+    let body = match &fields {
+        None => links.with_user(user),
+        Some(fields) => links.attach(partial(fields, user)),
+    };
+    preconditions::last_modified(
+        format.respond(StatusCode::OK, USERS, &body),
+        user.updated_at,
+    )
+}
+
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+// The read only user routes, all that bench mode serves
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_users)
+        .service(count_users)
+        .service(get_user)
+        .service(healthz);
+}
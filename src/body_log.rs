@@ -0,0 +1,249 @@
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{ErrorInternalServerError, PayloadError};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::HttpMessage;
+use futures_util::stream::{self, Stream, StreamExt};
+use log::info;
+use serde_json::Value;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::config::Config;
+
+// Fields whose values never make it to the log, matched anywhere in the
+// lowercased key so `refresh_token` and `new_password` are covered too
+const SENSITIVE: [&str; 5] = ["password", "token", "secret", "key", "authorization"];
+
+// Logs the bodies of requests and responses, to see what a client really
+// sends and gets back. Only JSON, form and text bodies are captured, request
+// bodies up to MAX_JSON_BYTES, responses unless streamed, and the logged
+// text is scrubbed then cut at `max_bytes`. Off unless LOG_BODIES is set in
+// a debug build.
+pub struct BodyLogger {
+    enabled: bool,
+    max_bytes: usize,
+    limit: usize,
+}
+
+impl BodyLogger {
+    pub fn new(config: &Config) -> BodyLogger {
+        BodyLogger {
+            enabled: config.log_bodies,
+            max_bytes: config.log_bodies_max_bytes,
+            limit: config.max_json_bytes,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for BodyLogger
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = BodyLoggerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(BodyLoggerMiddleware {
+            service: Rc::new(service),
+            enabled: self.enabled,
+            max_bytes: self.max_bytes,
+            limit: self.limit,
+        }))
+    }
+}
+
+pub struct BodyLoggerMiddleware<S> {
+    service: Rc<S>,
+    enabled: bool,
+    max_bytes: usize,
+    limit: usize,
+}
+
+impl<S, B> Service<ServiceRequest> for BodyLoggerMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        if !self.enabled {
+            return Box::pin(async move {
+                service
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_boxed_body)
+            });
+        }
+        let max_bytes = self.max_bytes;
+        // the query is left out, it may carry tokens
+        let description = format!("{} {}", req.method(), req.path());
+        let length = content_length(req.headers());
+        let has_body = length.is_some_and(|length| length > 0)
+            || req.headers().contains_key(header::TRANSFER_ENCODING);
+        let captured = has_body
+            && length.is_some_and(|length| length <= self.limit)
+            && is_textual(req.headers());
+        Box::pin(async move {
+            if captured {
+                let mut payload = req.take_payload();
+                let mut bytes = BytesMut::new();
+                while let Some(chunk) = payload.next().await {
+                    bytes.extend_from_slice(&chunk?);
+                }
+                let bytes = bytes.freeze();
+                info!(
+                    "{} request body: {}",
+                    description,
+                    describe(req.headers(), &bytes, max_bytes)
+                );
+                let replay: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+                    Box::pin(stream::once(ready(Ok(bytes))));
+                req.set_payload(Payload::from(replay));
+            } else if has_body {
+                info!("{} request body not captured", description);
+            }
+
+            let response = match service.call(req).await {
+                Ok(response) => response,
+                Err(e) => {
+                    let response = e.error_response();
+                    let status = response.status();
+                    let headers = response.headers().clone();
+                    let bytes = body::to_bytes(response.into_body())
+                        .await
+                        .unwrap_or_default();
+                    info!(
+                        "{} response {} body: {}",
+                        description,
+                        status.as_u16(),
+                        describe(&headers, &bytes, max_bytes)
+                    );
+                    return Err(e);
+                }
+            };
+            let status = response.status();
+            let (request, response) = response.into_parts();
+            let (response, body) = response.into_parts();
+            let sized = matches!(body.size(), BodySize::Sized(length) if length > 0);
+            let response = if sized && is_textual(response.headers()) {
+                let bytes = body::to_bytes(body)
+                    .await
+                    .map_err(|e| ErrorInternalServerError(e.into().to_string()))?;
+                info!(
+                    "{} response {} body: {}",
+                    description,
+                    status.as_u16(),
+                    describe(response.headers(), &bytes, max_bytes)
+                );
+                response.set_body(BoxBody::new(bytes))
+            } else {
+                if matches!(body.size(), BodySize::Stream) {
+                    info!(
+                        "{} response {} streamed, body not captured",
+                        description,
+                        status.as_u16()
+                    );
+                }
+                response.set_body(BoxBody::new(body))
+            };
+            Ok(ServiceResponse::new(request, response))
+        })
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+fn content_type(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+}
+
+fn is_textual(headers: &HeaderMap) -> bool {
+    let content_type = content_type(headers);
+    content_type.contains("json")
+        || content_type.starts_with("text/")
+        || content_type.starts_with("application/x-www-form-urlencoded")
+}
+
+fn is_sensitive(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SENSITIVE.iter().any(|field| key.contains(field))
+}
+
+fn scrub(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                if is_sensitive(key) {
+                    *value = Value::String("***".to_string());
+                } else {
+                    scrub(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(scrub),
+        _ => (),
+    }
+}
+
+// The scrubbed body, as logged. JSON that doesn't parse is not shown since
+// it can't be scrubbed.
+fn describe(headers: &HeaderMap, bytes: &[u8], max_bytes: usize) -> String {
+    let content_type = content_type(headers);
+    let text = if content_type.contains("json") {
+        match serde_json::from_slice::<Value>(bytes) {
+            Ok(mut value) => {
+                scrub(&mut value);
+                value.to_string()
+            }
+            Err(_) => return format!("{} bytes of invalid JSON", bytes.len()),
+        }
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(url::form_urlencoded::parse(bytes).map(|(key, value)| {
+                let value = if is_sensitive(&key) {
+                    "***".into()
+                } else {
+                    value
+                };
+                (key, value)
+            }))
+            .finish()
+    } else {
+        String::from_utf8_lossy(bytes).into_owned()
+    };
+    truncate(text, max_bytes)
+}
+
+fn truncate(mut text: String, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let length = text.len();
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    format!("{}... ({} bytes)", text, length)
+}
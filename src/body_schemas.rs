@@ -0,0 +1,269 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{
+    forward_ready, Payload, ResourceDef, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{HttpMessage, HttpResponse};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde_json::{json, Value};
+use std::fs;
+use std::future::{ready, Future, Ready};
+use std::path::Path;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::fallback;
+use crate::json_schema::{Schema, Violation};
+use crate::jsonapi::{self, Format};
+use crate::payload;
+
+// The top-level member of a schema document listing the routes it applies
+// to, as `METHOD /pattern`, e.g. `["POST /users", "PUT /users/{id}"]`
+const ROUTES_KEYWORD: &str = "x-routes";
+
+// A schema and a route whose bodies must match it
+struct RouteSchema {
+    method: Method,
+    route: ResourceDef,
+    schema: Arc<Schema>,
+}
+
+// The JSON Schemas of REQUEST_SCHEMA_DIR, by route
+pub struct BodySchemas {
+    routes: Vec<RouteSchema>,
+}
+
+impl BodySchemas {
+    // Panics naming the file when a schema can't be read or compiled, the
+    // API shouldn't start accepting bodies it was told to refuse
+    pub fn from_config(config: &Config) -> BodySchemas {
+        match &config.request_schema_dir {
+            Some(dir) => BodySchemas::load(Path::new(dir))
+                .unwrap_or_else(|e| panic!("Invalid request schema: {}", e)),
+            None => BodySchemas { routes: Vec::new() },
+        }
+    }
+
+    // Every `*.json` file of `dir`, in the order of their names
+    fn load(dir: &Path) -> Result<BodySchemas, String> {
+        let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| format!("{}: {}", dir.display(), e))?
+                .path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                files.push(path);
+            }
+        }
+        files.sort();
+        let mut schemas = BodySchemas { routes: Vec::new() };
+        let mut declared: Vec<(String, String)> = Vec::new();
+        for file in files {
+            let name = file.display().to_string();
+            let document: Value = fs::read(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|source| serde_json::from_slice(&source).map_err(|e| e.to_string()))
+                .map_err(|e| format!("{}: {}", name, e))?;
+            let routes = document
+                .get(ROUTES_KEYWORD)
+                .and_then(Value::as_array)
+                .filter(|routes| !routes.is_empty())
+                .ok_or_else(|| format!("{}: {} must list the routes", name, ROUTES_KEYWORD))?;
+            let schema =
+                Arc::new(Schema::compile(&document).map_err(|e| format!("{}: {}", name, e))?);
+            for route in routes {
+                let (method, pattern) = route
+                    .as_str()
+                    .and_then(|route| route.split_once(' '))
+                    .map(|(method, pattern)| (method.trim(), pattern.trim()))
+                    .filter(|(_, pattern)| pattern.starts_with('/'))
+                    .ok_or_else(|| {
+                        format!("{}: invalid route {}, expected METHOD /path", name, route)
+                    })?;
+                let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| format!("{}: invalid method {}", name, method))?;
+                let key = format!("{} {}", method, pattern);
+                if let Some((_, earlier)) = declared.iter().find(|(route, _)| *route == key) {
+                    return Err(format!(
+                        "{}: {} has a schema in {} already",
+                        name, key, earlier
+                    ));
+                }
+                declared.push((key, name.clone()));
+                schemas.routes.push(RouteSchema {
+                    method,
+                    route: ResourceDef::new(pattern),
+                    schema: schema.clone(),
+                });
+            }
+        }
+        Ok(schemas)
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    fn find(&self, method: &Method, path: &str) -> Option<Arc<Schema>> {
+        self.routes
+            .iter()
+            .find(|route| route.method == method && route.route.is_match(path))
+            .map(|route| route.schema.clone())
+    }
+}
+
+// The 422 listing every violation, with its JSON Pointer in the body
+fn refuse(format: Format, violations: &[Violation]) -> HttpResponse {
+    let status = StatusCode::UNPROCESSABLE_ENTITY;
+    let title = status.canonical_reason().unwrap_or_default();
+    match format {
+        Format::JsonApi => {
+            let errors: Vec<_> = violations
+                .iter()
+                .map(|violation| {
+                    json!({
+                        "status": status.as_str(),
+                        "title": title,
+                        "detail": violation.message,
+                        "source": { "pointer": violation.pointer },
+                    })
+                })
+                .collect();
+            HttpResponse::build(status)
+                .content_type(jsonapi::MEDIA_TYPE)
+                .json(json!({ "errors": errors }))
+        }
+        Format::Json => {
+            let errors: Vec<_> = violations
+                .iter()
+                .map(|violation| json!({ "pointer": violation.pointer, "detail": violation.message }))
+                .collect();
+            HttpResponse::build(status)
+                .content_type(fallback::PROBLEM_MEDIA_TYPE)
+                .json(json!({
+                    "type": "about:blank",
+                    "title": title,
+                    "status": status.as_u16(),
+                    "detail": "The body doesn't match the schema of the route",
+                    "errors": errors,
+                }))
+        }
+    }
+}
+
+// Validates the JSON bodies of the routes REQUEST_SCHEMA_DIR has a schema
+// for before they reach a handler, answering 422 with every violation when
+// they don't match. The other routes and the bodies that aren't JSON go
+// through untouched, as do empty bodies, left to the handler to refuse.
+pub struct BodyValidation {
+    schemas: Arc<BodySchemas>,
+    limit: usize,
+}
+
+impl BodyValidation {
+    pub fn new(schemas: Arc<BodySchemas>, config: &Config) -> BodyValidation {
+        BodyValidation {
+            schemas,
+            limit: config.max_json_bytes,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for BodyValidation
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = BodyValidationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(BodyValidationMiddleware {
+            service: Rc::new(service),
+            schemas: self.schemas.clone(),
+            limit: self.limit,
+        }))
+    }
+}
+
+pub struct BodyValidationMiddleware<S> {
+    service: Rc<S>,
+    schemas: Arc<BodySchemas>,
+    limit: usize,
+}
+
+impl<S, B> Service<ServiceRequest> for BodyValidationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let is_json = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(payload::is_json);
+        let schema = match self.schemas.find(req.method(), req.path()) {
+            Some(schema) if is_json => schema,
+            _ => return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) }),
+        };
+        let limit = self.limit;
+        Box::pin(async move {
+            let format = Format::of(req.request());
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                body.extend_from_slice(&chunk?);
+                if body.len() > limit {
+                    let response = fallback::respond(
+                        format,
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        &format!("Bodies are limited to {} bytes", limit),
+                    );
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+            let body = body.freeze();
+            if !body.trim_ascii().is_empty() {
+                let document: Value = match serde_json::from_slice(&body) {
+                    Ok(document) => document,
+                    Err(e) => {
+                        let response = fallback::respond(
+                            format,
+                            StatusCode::BAD_REQUEST,
+                            &format!("Invalid JSON body: {}", e),
+                        );
+                        return Ok(req.into_response(response).map_into_right_body());
+                    }
+                };
+                let violations = schema.validate(&document);
+                if !violations.is_empty() {
+                    let response = refuse(format, &violations);
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+            let replay: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+                Box::pin(stream::once(ready(Ok(body))));
+            req.set_payload(Payload::from(replay));
+            Ok(service.call(req).await?.map_into_left_body())
+        })
+    }
+}
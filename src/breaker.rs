@@ -0,0 +1,107 @@
+use log::warn;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakerState {
+    // calls go through
+    Closed,
+    // calls fail at once until the cooldown is over
+    Open,
+    // a single probe call is let through, its outcome decides
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+struct State {
+    state: BreakerState,
+    consecutive_failures: u32,
+    // when the breaker opened, or when the current probe was let through
+    since: Instant,
+}
+
+// Stops sending calls to a failing dependency: opens after `threshold`
+// failures in a row, then lets a probe through every `cooldown`
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+    pub trips: AtomicU64,
+    pub rejected: AtomicU64,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::new(State {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                since: Instant::now(),
+            }),
+            trips: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state.lock().unwrap().state
+    }
+
+    // Whether a call may go ahead, its outcome must then be recorded
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let allowed = match state.state {
+            BreakerState::Closed => true,
+            // a probe that never reported back doesn't block the next one
+            BreakerState::Open | BreakerState::HalfOpen
+                if state.since.elapsed() >= self.cooldown =>
+            {
+                state.state = BreakerState::HalfOpen;
+                state.since = Instant::now();
+                true
+            }
+            BreakerState::Open | BreakerState::HalfOpen => false,
+        };
+        if !allowed {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.state = BreakerState::Closed;
+        state.consecutive_failures = 0;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        let trip = match state.state {
+            BreakerState::Closed => state.consecutive_failures >= self.threshold,
+            BreakerState::HalfOpen => true,
+            BreakerState::Open => false,
+        };
+        if trip {
+            warn!(
+                "Circuit breaker open after {} failure(s) in a row",
+                state.consecutive_failures
+            );
+            state.state = BreakerState::Open;
+            state.since = Instant::now();
+            self.trips.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
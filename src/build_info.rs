@@ -0,0 +1,32 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde_json::json;
+
+// Captured by build.rs
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// `unknown` when built outside a Git checkout without GIT_SHA
+pub const GIT_COMMIT: &str = env!("BUILD_GIT_COMMIT");
+pub const TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+// comma separated
+const FEATURES: &str = env!("BUILD_FEATURES");
+
+pub fn features() -> Vec<&'static str> {
+    FEATURES
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .collect()
+}
+
+// What was deployed, for scripts checking a rollout
+#[get("/version")]
+async fn get_version() -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "build_timestamp": TIMESTAMP,
+        "features": features(),
+    }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_version);
+}
@@ -0,0 +1,227 @@
+use actix_web::dev::{
+    forward_ready, ResourceDef, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderMap, HeaderValue, HttpDate, TryIntoHeaderValue};
+use actix_web::http::Method;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::api_keys::API_KEY_HEADER;
+use crate::config::Config;
+use crate::routes;
+use crate::session::SESSION_COOKIE;
+use crate::signatures::PARTNER_HEADER;
+use crate::static_site;
+
+// Request headers the API responses depend on, besides the path: the
+// representation, the language of the errors, the envelope, the tenant and
+// the credentials
+const VARY: &str = "Accept, Accept-Language, X-Envelope, X-Tenant-Id, Authorization, Cookie, \
+                    X-Api-Key, X-Partner-Id";
+
+// How long and by whom a response may be reused
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CachePolicy {
+    // never stored, for secrets and personal data
+    NoStore,
+    // stored, but revalidated before every use
+    NoCache,
+    // by the browser only, for `max_age` seconds
+    Private(u64),
+    // by shared caches too, for `max_age` seconds, unless the request was
+    // authenticated
+    Public(u64),
+}
+
+impl CachePolicy {
+    // `no-store`, `no-cache`, `private`, `private:<secs>` or `public:<secs>`
+    pub fn parse(value: &str) -> Option<CachePolicy> {
+        let (name, max_age) = match value.split_once(':') {
+            Some((name, max_age)) => (name, Some(max_age.parse().ok()?)),
+            None => (value, None),
+        };
+        match (name, max_age) {
+            ("no-store", None) => Some(CachePolicy::NoStore),
+            ("no-cache", None) => Some(CachePolicy::NoCache),
+            ("private", max_age) => Some(CachePolicy::Private(max_age.unwrap_or(0))),
+            ("public", Some(max_age)) => Some(CachePolicy::Public(max_age)),
+            _ => None,
+        }
+    }
+
+    // Shared caches must not hand the response of a user to another
+    fn for_request(self, authenticated: bool) -> CachePolicy {
+        match self {
+            CachePolicy::Public(max_age) if authenticated => CachePolicy::Private(max_age),
+            CachePolicy::NoCache if authenticated => CachePolicy::Private(0),
+            policy => policy,
+        }
+    }
+
+    fn cache_control(&self) -> String {
+        match self {
+            CachePolicy::NoStore => "no-store".to_string(),
+            CachePolicy::NoCache => "no-cache".to_string(),
+            CachePolicy::Private(0) => "private, no-cache".to_string(),
+            CachePolicy::Private(max_age) => format!("private, max-age={}", max_age),
+            CachePolicy::Public(max_age) => format!("public, max-age={}", max_age),
+        }
+    }
+
+    // For HTTP/1.0 caches, which ignore Cache-Control
+    fn expires(&self) -> SystemTime {
+        match self {
+            CachePolicy::Private(max_age) | CachePolicy::Public(max_age) if *max_age > 0 => {
+                SystemTime::now() + Duration::from_secs(*max_age)
+            }
+            _ => UNIX_EPOCH,
+        }
+    }
+}
+
+// Policy a route has by default, see routes::ROUTES, those that are kept
+// being kept for CACHE_MAX_AGE
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Caching {
+    // `private, no-cache`, as the paths of no route
+    Unset,
+    NoStore,
+    NoCache,
+    Private,
+    Public,
+}
+
+impl Caching {
+    fn policy(self, max_age: u64) -> CachePolicy {
+        match self {
+            Caching::Unset => CachePolicy::Private(0),
+            Caching::NoStore => CachePolicy::NoStore,
+            Caching::NoCache => CachePolicy::NoCache,
+            Caching::Private => CachePolicy::Private(max_age),
+            Caching::Public => CachePolicy::Public(max_age),
+        }
+    }
+}
+
+// Sets Cache-Control, Expires and Vary on the API responses after the
+// policy of their route, the first of CACHE_POLICIES whose pattern matches,
+// or else the one routes::ROUTES gives the first route matching. Only successful
+// GET and HEAD responses are cacheable, the others get `no-store`. A handler
+// setting Cache-Control itself keeps its own, and the frontend files are
+// left to static_site.
+pub struct CachePolicies {
+    policies: Rc<Vec<(ResourceDef, CachePolicy)>>,
+    max_age: u64,
+}
+
+impl CachePolicies {
+    pub fn new(config: &Config) -> CachePolicies {
+        let configured = config
+            .cache_policies
+            .iter()
+            .map(|(pattern, policy)| (ResourceDef::new(pattern.as_str()), *policy));
+        CachePolicies {
+            policies: Rc::new(configured.collect()),
+            max_age: config.cache_max_age.as_secs(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CachePolicies
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = CachePoliciesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CachePoliciesMiddleware {
+            service: Rc::new(service),
+            policies: self.policies.clone(),
+            max_age: self.max_age,
+        }))
+    }
+}
+
+pub struct CachePoliciesMiddleware<S> {
+    service: Rc<S>,
+    policies: Rc<Vec<(ResourceDef, CachePolicy)>>,
+    max_age: u64,
+}
+
+fn is_authenticated(req: &ServiceRequest) -> bool {
+    req.headers().contains_key(header::AUTHORIZATION)
+        || req.headers().contains_key(API_KEY_HEADER)
+        || req.headers().contains_key(PARTNER_HEADER)
+        || req.cookie(SESSION_COOKIE).is_some()
+}
+
+fn apply(headers: &mut HeaderMap, policy: CachePolicy) {
+    if headers.contains_key(header::CACHE_CONTROL) {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&policy.cache_control()) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+    if let Ok(value) = HttpDate::from(policy.expires()).try_into_value() {
+        headers.insert(header::EXPIRES, value);
+    }
+    headers.append(header::VARY, HeaderValue::from_static(VARY));
+}
+
+impl<S, B> Service<ServiceRequest> for CachePoliciesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        if !static_site::is_api_path(req.path()) {
+            return Box::pin(service.call(req));
+        }
+        let cacheable = matches!(*req.method(), Method::GET | Method::HEAD);
+        let policy = self
+            .policies
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(req.path()))
+            .map(|(_, policy)| *policy)
+            .unwrap_or_else(|| {
+                routes::find(req.path())
+                    .map_or(Caching::Unset, |route| route.caching)
+                    .policy(self.max_age)
+            })
+            .for_request(is_authenticated(&req));
+        Box::pin(async move {
+            match service.call(req).await {
+                Ok(mut response) => {
+                    let status = response.status();
+                    let policy = if cacheable && (status.is_success() || status.as_u16() == 304) {
+                        policy
+                    } else {
+                        CachePolicy::NoStore
+                    };
+                    apply(response.headers_mut(), policy);
+                    Ok(response)
+                }
+                Err(e) => {
+                    let mut response = e.error_response();
+                    apply(response.headers_mut(), CachePolicy::NoStore);
+                    Err(InternalError::from_response(e, response).into())
+                }
+            }
+        })
+    }
+}
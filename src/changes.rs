@@ -0,0 +1,57 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde_json::json;
+
+use crate::admin::Admin;
+use crate::db::Cluster;
+use crate::jsonapi::Format;
+use crate::tenant::Tenant;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: Option<i64>,
+    limit: Option<i64>,
+}
+
+// Events in the order they were sequenced after their commit, for systems
+// syncing from the outbox without a broker: poll again with `since` set to
+// `next` until `changes` is empty. The events committed since the last poll
+// are sequenced first, the outbox isn't scoped by tenant.
+#[get("/changes")]
+async fn get_changes(
+    _admin: Admin,
+    query: web::Query<ChangesQuery>,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let since = query.since.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if since < 0 {
+        return HttpResponse::BadRequest().body("since must be a sequence number");
+    }
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return HttpResponse::BadRequest()
+            .body(format!("limit must be between 1 and {}", MAX_LIMIT));
+    }
+    let result = db
+        .primary()
+        .transaction(&Tenant::default(), |uow| {
+            Box::pin(async move {
+                uow.outbox().sequence(limit).await?;
+                Ok(uow.outbox().changes(since, limit).await?)
+            })
+        })
+        .await;
+    match result {
+        Ok(changes) => {
+            let next = changes.last().map_or(since, |change| change.seq);
+            HttpResponse::Ok().json(json!({ "changes": changes, "next": next }))
+        }
+        Err(e) => Format::Json.db_error(e, "Failed to retrieve changes"),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_changes);
+}
@@ -0,0 +1,177 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use rand::Rng;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::fallback;
+use crate::jsonapi::Format;
+
+// `latency`, `error` or both on the responses a fault was injected in
+pub const FAULT_HEADER: HeaderName = HeaderName::from_static("x-chaos-fault");
+
+// Left alone so that the orchestrator and the scrapes see the real state
+const EXEMPT: [&str; 4] = ["/healthz", "/readyz", "/metrics", "/version"];
+
+static CHAOS: OnceLock<Chaos> = OnceLock::new();
+
+// The faults injected for resilience testing, each rate being the share of
+// the requests or database calls that get it
+#[derive(Clone, Debug)]
+pub struct Chaos {
+    error_rate: f64,
+    error_status: StatusCode,
+    latency_rate: f64,
+    latency: Duration,
+    db_error_rate: f64,
+    db_latency_rate: f64,
+    db_latency: Duration,
+}
+
+impl Chaos {
+    // None unless CHAOS_ENABLED, the rates alone inject nothing
+    pub fn from_config(config: &Config) -> Option<Chaos> {
+        config.chaos_enabled.then_some(Chaos {
+            error_rate: config.chaos_error_rate,
+            error_status: config.chaos_error_status,
+            latency_rate: config.chaos_latency_rate,
+            latency: config.chaos_latency,
+            db_error_rate: config.chaos_db_error_rate,
+            db_latency_rate: config.chaos_db_latency_rate,
+            db_latency: config.chaos_db_latency,
+        })
+    }
+
+    // For the startup log
+    pub fn describe(&self) -> String {
+        format!(
+            "{:.0}% of the requests answered {}, {:.0}% delayed by {:?}, \
+             {:.0}% of the database calls failed, {:.0}% delayed by {:?}",
+            self.error_rate * 100.0,
+            self.error_status.as_u16(),
+            self.latency_rate * 100.0,
+            self.latency,
+            self.db_error_rate * 100.0,
+            self.db_latency_rate * 100.0,
+            self.db_latency
+        )
+    }
+}
+
+// Inject the faults of `chaos` from now on. Set once at startup.
+pub fn install(chaos: Chaos) {
+    if CHAOS.set(chaos).is_err() {
+        panic!("The chaos settings are already installed");
+    }
+}
+
+pub fn is_enabled() -> bool {
+    CHAOS.get().is_some()
+}
+
+fn draw(rate: f64) -> bool {
+    rate > 0.0 && rand::thread_rng().gen_bool(rate)
+}
+
+// Called before every database call: waits for the injected latency, and
+// tells whether the call must fail as if the database were unreachable,
+// which the retries and the circuit breaker handle like a real outage
+pub async fn db_fault() -> bool {
+    let chaos = match CHAOS.get() {
+        Some(chaos) => chaos,
+        None => return false,
+    };
+    if draw(chaos.db_latency_rate) {
+        tokio::time::sleep(chaos.db_latency).await;
+    }
+    draw(chaos.db_error_rate)
+}
+
+// Delays CHAOS_LATENCY_RATE of the requests by CHAOS_LATENCY_MS and answers
+// CHAOS_ERROR_RATE of them CHAOS_ERROR_STATUS without handling them, marked
+// with X-Chaos-Fault. The probes, /metrics and /version are spared. Only
+// wrapped when CHAOS_ENABLED.
+pub struct Faults;
+
+impl<S, B> Transform<S, ServiceRequest> for Faults
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = FaultsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(FaultsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct FaultsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for FaultsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let chaos = match CHAOS.get() {
+            Some(chaos) if !EXEMPT.contains(&req.path()) => chaos,
+            _ => return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) }),
+        };
+        let delayed = draw(chaos.latency_rate);
+        let failed = draw(chaos.error_rate);
+        Box::pin(async move {
+            let mut faults = Vec::new();
+            if delayed {
+                tokio::time::sleep(chaos.latency).await;
+                faults.push("latency");
+            }
+            if failed {
+                faults.push("error");
+            }
+            let fault = HeaderValue::from_str(&faults.join(", ")).expect("fault names are ASCII");
+            if failed {
+                let status = chaos.error_status;
+                let mut response = fallback::respond(
+                    Format::of(req.request()),
+                    status,
+                    "Fault injected by chaos testing",
+                );
+                let headers = response.headers_mut();
+                headers.insert(FAULT_HEADER, fault);
+                if matches!(
+                    status,
+                    StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS
+                ) {
+                    headers.insert(header::RETRY_AFTER, HeaderValue::from(1));
+                }
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            let mut response = service.call(req).await?;
+            if delayed {
+                response.headers_mut().insert(FAULT_HEADER, fault);
+            }
+            Ok(response.map_into_left_body())
+        })
+    }
+}
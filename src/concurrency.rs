@@ -0,0 +1,151 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::web;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::config::Config;
+use crate::fallback;
+use crate::jsonapi::Format;
+
+// Probes and scrapes still answer when the server is overloaded, that is
+// when they matter most
+const EXEMPT: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+// The requests being handled and those waiting for their turn, shared by
+// all the workers
+pub struct Limiter {
+    // 0 for no limit
+    limit: usize,
+    max_queued: usize,
+    retry_after: Duration,
+    permits: Semaphore,
+    queued: AtomicUsize,
+    pub rejected: AtomicU64,
+}
+
+impl Limiter {
+    pub fn new(config: &Config) -> Limiter {
+        Limiter {
+            limit: config.max_concurrent_requests,
+            max_queued: config.max_queued_requests,
+            retry_after: config.overload_retry_after,
+            permits: Semaphore::new(config.max_concurrent_requests),
+            queued: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.limit - self.permits.available_permits()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+// Counts a request as queued until it is dropped, whether it got its turn or
+// its client went away
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Handles at most MAX_CONCURRENT_REQUESTS requests at a time, the following
+// ones wait for their turn in order. Once MAX_QUEUED_REQUESTS are waiting,
+// the others are answered 503 with Retry-After rather than piling up in
+// front of the database. Off unless MAX_CONCURRENT_REQUESTS.
+pub struct ConcurrencyLimit {
+    limiter: web::Data<Limiter>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(limiter: web::Data<Limiter>) -> ConcurrencyLimit {
+        ConcurrencyLimit { limiter }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ConcurrencyLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ConcurrencyLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ConcurrencyLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+        }))
+    }
+}
+
+pub struct ConcurrencyLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: web::Data<Limiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for ConcurrencyLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        if !self.limiter.is_enabled() || EXEMPT.contains(&req.path()) {
+            return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) });
+        }
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            let _permit = match limiter.permits.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let waiting = limiter.queued.fetch_add(1, Ordering::Relaxed);
+                    let queued = Queued(&limiter.queued);
+                    if waiting >= limiter.max_queued {
+                        drop(queued);
+                        limiter.rejected.fetch_add(1, Ordering::Relaxed);
+                        let mut response = fallback::respond(
+                            Format::of(req.request()),
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "Too many requests in progress, try again later",
+                        );
+                        response.headers_mut().insert(
+                            header::RETRY_AFTER,
+                            HeaderValue::from(limiter.retry_after.as_secs().max(1)),
+                        );
+                        return Ok(req.into_response(response).map_into_right_body());
+                    }
+                    let permit = limiter.permits.acquire().await;
+                    drop(queued);
+                    permit.expect("request semaphore closed")
+                }
+            };
+            Ok(service.call(req).await?.map_into_left_body())
+        })
+    }
+}
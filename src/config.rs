@@ -0,0 +1,549 @@
+use actix_web::http::StatusCode;
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::cache::CachePolicy;
+use crate::crypto;
+use crate::db::Naming;
+use crate::email_rules::{self, Rule};
+use crate::field_policy::{self, FieldRule};
+use crate::ids::{self, IdStrategy};
+use crate::listener::Listen;
+use crate::maintenance;
+use crate::normalize::PathMode;
+use crate::oauth::{OAuthProvider, ProviderKind};
+use crate::quotas::QuotaPeriod;
+
+// Runtime configuration, read from the environment at startup
+pub struct Config {
+    pub listen: Listen,
+    pub bench_mode: bool,
+    pub bench_users: usize,
+    pub workers: Option<usize>,
+    pub keep_alive: Option<Duration>,
+    pub client_request_timeout: Duration,
+    pub max_connections: usize,
+    pub backlog: u32,
+    pub database_url: String,
+    pub secrets_refresh: Duration,
+    pub replica_urls: Vec<String>,
+    pub pool_size: usize,
+    pub naming: Naming,
+    pub admin_token: Option<String>,
+    pub secret_key: String,
+    pub email_encryption_key: Option<String>,
+    pub email_encryption_old_keys: Vec<String>,
+    pub email_normalization: Vec<Rule>,
+    pub field_policy: Vec<FieldRule>,
+    pub public_url: String,
+    pub verification_ttl: Duration,
+    pub access_token_ttl: Duration,
+    pub refresh_token_ttl: Duration,
+    pub session_cookies: bool,
+    pub session_cookie_secure: bool,
+    pub password_reset_ttl: Duration,
+    pub login_max_failures: i64,
+    pub login_max_failures_per_ip: i64,
+    pub login_failure_window: Duration,
+    pub login_lockout: Duration,
+    pub oauth_providers: Vec<OAuthProvider>,
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub mail_from: String,
+    pub tenant_rls: bool,
+    pub content_security_policy: String,
+    pub hsts_max_age: Duration,
+    pub max_json_bytes: usize,
+    pub request_schema_dir: Option<String>,
+    pub log_bodies: bool,
+    pub log_bodies_max_bytes: usize,
+    pub path_normalization: PathMode,
+    pub case_insensitive_paths: bool,
+    pub method_override: bool,
+    pub request_timeout: Duration,
+    pub max_concurrent_requests: usize,
+    pub max_queued_requests: usize,
+    pub overload_retry_after: Duration,
+    pub quota_period: QuotaPeriod,
+    pub tenant_quota: i64,
+    pub tenant_quotas: Vec<(String, i64)>,
+    pub api_key_quota: i64,
+    pub ip_quota: Option<i64>,
+    pub quota_flush: Duration,
+    pub maintenance_mode: bool,
+    pub maintenance_message: String,
+    pub chaos_enabled: bool,
+    pub chaos_error_rate: f64,
+    pub chaos_error_status: StatusCode,
+    pub chaos_latency_rate: f64,
+    pub chaos_latency: Duration,
+    pub chaos_db_error_rate: f64,
+    pub chaos_db_latency_rate: f64,
+    pub chaos_db_latency: Duration,
+    pub user_ids: IdStrategy,
+    pub event_ids: IdStrategy,
+    pub id_worker: u64,
+    pub upload_timeout: Duration,
+    pub statement_timeout: Option<Duration>,
+    pub breaker_threshold: u32,
+    pub slow_query_threshold: Option<Duration>,
+    pub stats_cache_ttl: Duration,
+    pub feature_flags_refresh: Duration,
+    pub static_dir: Option<String>,
+    pub static_content_security_policy: String,
+    pub config_file: Option<String>,
+    pub log_level: String,
+    pub cors_allowed_origins: Vec<String>,
+    pub breaker_cooldown: Duration,
+    pub stream_threshold: usize,
+    pub response_envelope: bool,
+    pub cache_policies: Vec<(String, CachePolicy)>,
+    pub cache_max_age: Duration,
+    pub avatar_max_bytes: usize,
+    pub signature_window: Duration,
+    pub upload_dir: String,
+    pub s3_bucket: Option<String>,
+    pub s3_region: String,
+    pub s3_endpoint: Option<String>,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    pub webhook_max_attempts: i32,
+    pub webhook_poll_interval: Duration,
+    pub webhook_timeout: Duration,
+    pub webhook_private_targets: bool,
+    pub job_max_attempts: i32,
+    pub job_poll_interval: Duration,
+    pub job_timeout: Duration,
+    pub kafka_brokers: Option<String>,
+    #[cfg(feature = "kafka")]
+    pub kafka_topic: String,
+    #[cfg(feature = "kafka")]
+    pub kafka_properties: Vec<(String, String)>,
+    pub nats_url: Option<String>,
+    #[cfg(feature = "nats")]
+    pub nats_subject_prefix: String,
+    #[cfg(feature = "outbox-relay")]
+    pub outbox_poll_interval: Duration,
+}
+
+impl Config {
+    // `default_database_url` is the URL baked in at build time, used when
+    // DATABASE_URL is not set in the runtime environment
+    pub fn from_env(default_database_url: Option<&str>) -> Config {
+        let database_url = env::var("DATABASE_URL")
+            .ok()
+            .or_else(|| default_database_url.map(str::to_string))
+            .expect("DATABASE_URL is not set, neither at build nor at run time");
+        // comma separated list of read replica URLs
+        let replica_urls = env::var("DATABASE_REPLICA_URLS")
+            .map(|urls| split_list(&urls))
+            .unwrap_or_default();
+        // dedicated connections per database, used for transactions
+        let pool_size = parse_or("DATABASE_POOL_SIZE", 8);
+        // to share the database with other applications
+        let naming = Naming {
+            schema: env::var("DATABASE_SCHEMA")
+                .ok()
+                .map(|schema| identifier("DATABASE_SCHEMA", schema)),
+            prefix: identifier("TABLE_PREFIX", env::var("TABLE_PREFIX").unwrap_or_default()),
+        };
+        let admin_token = env::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+        // signs the tokens sent by email
+        let secret_key = env::var("SECRET_KEY").unwrap_or_else(|_| {
+            log::warn!("SECRET_KEY is not set, tokens won't survive a restart");
+            crypto::random_token(32)
+        });
+        // bodies may hold personal data, never log them in production
+        let log_bodies = parse_or("LOG_BODIES", false);
+        if log_bodies && !cfg!(debug_assertions) {
+            log::warn!("LOG_BODIES is ignored in release builds");
+        }
+        Config {
+            // `host:port`, `unix:<path>` or `systemd`
+            listen: env::var("LISTEN")
+                .map(|listen| Listen::parse(&listen))
+                .unwrap_or_else(|_| Listen::Tcp("0.0.0.0:8080".to_string())),
+            // generated users in memory instead of the database, for load tests
+            bench_mode: flag("--bench-mode") || parse_or("BENCH_MODE", false),
+            bench_users: parse_or("BENCH_USERS", 10000),
+            // 0 starts one per CPU
+            workers: Some(parse_or("HTTP_WORKERS", 0)).filter(|workers| *workers > 0),
+            // 0 closes connections after each response
+            keep_alive: Some(Duration::from_secs(parse_or("KEEP_ALIVE_SECS", 5)))
+                .filter(|keep_alive| !keep_alive.is_zero()),
+            // to receive the request head, 0 waits forever
+            client_request_timeout: Duration::from_millis(parse_or(
+                "CLIENT_REQUEST_TIMEOUT_MS",
+                5000,
+            )),
+            // per worker
+            max_connections: parse_or("MAX_CONNECTIONS", 25000),
+            backlog: parse_or("LISTEN_BACKLOG", 1024),
+            database_url,
+            // how often a DATABASE_URL read from a secrets provider is
+            // fetched again, 0 never does
+            secrets_refresh: Duration::from_secs(parse_or("SECRETS_REFRESH_SECS", 300)),
+            replica_urls,
+            pool_size,
+            naming,
+            admin_token,
+            secret_key,
+            // base64 AES-256 key, or a file holding it such as one a KMS
+            // agent writes
+            email_encryption_key: env::var("EMAIL_ENCRYPTION_KEY").ok().or_else(|| {
+                env::var("EMAIL_ENCRYPTION_KEY_FILE").ok().map(|path| {
+                    std::fs::read_to_string(&path)
+                        .unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e))
+                })
+            }),
+            // comma separated former keys, until the emails are rotated
+            email_encryption_old_keys: env::var("EMAIL_ENCRYPTION_OLD_KEYS")
+                .map(|keys| split_list(&keys))
+                .unwrap_or_default(),
+            // applied in order, see email_rules.rs
+            email_normalization: env::var("EMAIL_NORMALIZATION")
+                .map(|rules| {
+                    split_list(&rules)
+                        .iter()
+                        .map(|rule| {
+                            Rule::parse(rule).unwrap_or_else(|| {
+                                panic!("Invalid email normalization rule: {}", rule)
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_else(|_| email_rules::default_rules()),
+            // comma separated `field=masked` or `field=hidden`, for the
+            // callers other than the admins and the user themselves
+            field_policy: env::var("FIELD_POLICY")
+                .map(|rules| {
+                    split_pairs(&rules)
+                        .into_iter()
+                        .map(|(field, visibility)| {
+                            FieldRule::parse(&field, &visibility).unwrap_or_else(|| {
+                                panic!("Invalid field policy for {}: {}", field, visibility)
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_else(|_| field_policy::default_rules()),
+            // where the links in emails point to
+            public_url: env::var("PUBLIC_URL")
+                .unwrap_or_else(|_| "http://localhost:8080".to_string()),
+            verification_ttl: Duration::from_secs(parse_or("VERIFICATION_TTL_SECS", 86400)),
+            access_token_ttl: Duration::from_secs(parse_or("ACCESS_TOKEN_TTL_SECS", 900)),
+            refresh_token_ttl: Duration::from_secs(parse_or("REFRESH_TOKEN_TTL_SECS", 2592000)),
+            // tokens in cookies for browsers, with CSRF checks
+            session_cookies: parse_or("SESSION_COOKIES", false),
+            // false to try session mode over plain HTTP
+            session_cookie_secure: parse_or("SESSION_COOKIE_SECURE", true),
+            password_reset_ttl: Duration::from_secs(parse_or("PASSWORD_RESET_TTL_SECS", 3600)),
+            // failed logins tolerated within the window before the account is
+            // locked, or the client address refused
+            login_max_failures: parse_or("LOGIN_MAX_FAILURES", 5),
+            login_max_failures_per_ip: parse_or("LOGIN_MAX_FAILURES_PER_IP", 50),
+            login_failure_window: Duration::from_secs(parse_or("LOGIN_FAILURE_WINDOW_SECS", 900)),
+            login_lockout: Duration::from_secs(parse_or("LOGIN_LOCKOUT_SECS", 900)),
+            oauth_providers: oauth_providers(),
+            smtp_host: env::var("SMTP_HOST").ok(),
+            smtp_port: parse_or("SMTP_PORT", 587),
+            smtp_username: env::var("SMTP_USERNAME").ok(),
+            smtp_password: env::var("SMTP_PASSWORD").ok(),
+            mail_from: env::var("MAIL_FROM").unwrap_or_else(|_| "noreply@localhost".to_string()),
+            // have Postgres enforce tenant isolation too
+            tenant_rls: parse_or("TENANT_RLS", false),
+            // the API serves no pages, nothing needs to load
+            content_security_policy: env::var("CONTENT_SECURITY_POLICY")
+                .unwrap_or_else(|_| "default-src 'none'; frame-ancestors 'none'".to_string()),
+            // 0 leaves out Strict-Transport-Security
+            hsts_max_age: Duration::from_secs(parse_or("HSTS_MAX_AGE_SECS", 31536000)),
+            max_json_bytes: parse_or("MAX_JSON_BYTES", 64 * 1024),
+            // JSON Schemas of request bodies, see body_schemas.rs
+            request_schema_dir: env::var("REQUEST_SCHEMA_DIR").ok(),
+            log_bodies: log_bodies && cfg!(debug_assertions),
+            log_bodies_max_bytes: parse_or("LOG_BODIES_MAX_BYTES", 4096),
+            // `merge`, `redirect` or `off`
+            path_normalization: env::var("PATH_NORMALIZATION")
+                .map(|mode| {
+                    PathMode::parse(&mode)
+                        .unwrap_or_else(|| panic!("Invalid value for PATH_NORMALIZATION: {}", mode))
+                })
+                .unwrap_or(PathMode::Merge),
+            case_insensitive_paths: parse_or("CASE_INSENSITIVE_PATHS", false),
+            // POSTs with X-HTTP-Method-Override, see method_override.rs
+            method_override: parse_or("METHOD_OVERRIDE", false),
+            request_timeout: Duration::from_secs(parse_or("REQUEST_TIMEOUT_SECS", 30)),
+            // 0 handles every request as soon as it comes, see concurrency.rs
+            max_concurrent_requests: parse_or("MAX_CONCURRENT_REQUESTS", 0),
+            max_queued_requests: parse_or("MAX_QUEUED_REQUESTS", 100),
+            overload_retry_after: Duration::from_secs(parse_or("OVERLOAD_RETRY_AFTER_SECS", 1)),
+            // requests per period, 0 for no quota, see quotas.rs
+            quota_period: env::var("QUOTA_PERIOD")
+                .map(|period| {
+                    QuotaPeriod::parse(&period)
+                        .unwrap_or_else(|| panic!("Invalid value for QUOTA_PERIOD: {}", period))
+                })
+                .unwrap_or(QuotaPeriod::Month),
+            tenant_quota: parse_or("TENANT_QUOTA", 0),
+            // comma separated `tenant=requests`, overriding TENANT_QUOTA
+            tenant_quotas: env::var("TENANT_QUOTAS")
+                .map(|quotas| {
+                    split_pairs(&quotas)
+                        .into_iter()
+                        .map(|(tenant, quota)| match quota.parse() {
+                            Ok(quota) => (tenant, quota),
+                            Err(_) => panic!("Invalid quota for tenant {}: {}", tenant, quota),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            api_key_quota: parse_or("API_KEY_QUOTA", 0),
+            // of the anonymous requests, TENANT_QUOTA when unset
+            ip_quota: env::var("IP_QUOTA").ok().map(|quota| {
+                quota
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid value for IP_QUOTA: {}", quota))
+            }),
+            quota_flush: Duration::from_secs(parse_or("QUOTA_FLUSH_SECS", 1)),
+            // writes answered 503 from the start, see maintenance.rs
+            maintenance_mode: parse_or("MAINTENANCE_MODE", false),
+            maintenance_message: env::var("MAINTENANCE_MESSAGE")
+                .unwrap_or_else(|_| maintenance::DEFAULT_MESSAGE.to_string()),
+            // faults injected for resilience tests, nothing unless
+            // CHAOS_ENABLED, see chaos.rs
+            chaos_enabled: parse_or("CHAOS_ENABLED", false),
+            chaos_error_rate: rate("CHAOS_ERROR_RATE"),
+            chaos_error_status: match parse_or("CHAOS_ERROR_STATUS", 503) {
+                status @ 400..=599 => StatusCode::from_u16(status).unwrap(),
+                status => panic!("Invalid value for CHAOS_ERROR_STATUS: {}", status),
+            },
+            chaos_latency_rate: rate("CHAOS_LATENCY_RATE"),
+            chaos_latency: Duration::from_millis(parse_or("CHAOS_LATENCY_MS", 0)),
+            chaos_db_error_rate: rate("CHAOS_DB_ERROR_RATE"),
+            chaos_db_latency_rate: rate("CHAOS_DB_LATENCY_RATE"),
+            chaos_db_latency: Duration::from_millis(parse_or("CHAOS_DB_LATENCY_MS", 0)),
+            // `serial` or `snowflake`, user ids are integers, see ids.rs
+            user_ids: id_strategy(
+                "USER_ID_STRATEGY",
+                [IdStrategy::Serial, IdStrategy::Snowflake],
+            ),
+            // `ulid` or `snowflake`
+            event_ids: id_strategy(
+                "EVENT_ID_STRATEGY",
+                [IdStrategy::Ulid, IdStrategy::Snowflake],
+            ),
+            // unique to every instance generating snowflakes
+            id_worker: match parse_or("ID_WORKER", 0) {
+                worker @ 0..=ids::MAX_WORKER => worker,
+                worker => panic!(
+                    "Invalid value for ID_WORKER: {}, expected at most {}",
+                    worker,
+                    ids::MAX_WORKER
+                ),
+            },
+            upload_timeout: Duration::from_secs(parse_or("UPLOAD_TIMEOUT_SECS", 120)),
+            // 0 lets statements run for as long as they take
+            statement_timeout: Some(Duration::from_secs(parse_or("STATEMENT_TIMEOUT_SECS", 10)))
+                .filter(|timeout| !timeout.is_zero()),
+            breaker_threshold: parse_or("BREAKER_FAILURE_THRESHOLD", 5),
+            // 0 logs no query
+            slow_query_threshold: Some(Duration::from_millis(parse_or(
+                "SLOW_QUERY_THRESHOLD_MS",
+                500,
+            )))
+            .filter(|threshold| !threshold.is_zero()),
+            // 0 computes GET /admin/stats on every call
+            stats_cache_ttl: Duration::from_secs(parse_or("ADMIN_STATS_CACHE_SECS", 0)),
+            // how soon a flag toggled on another instance is seen
+            feature_flags_refresh: Duration::from_secs(parse_or("FEATURE_FLAGS_REFRESH_SECS", 30)),
+            // frontend build served from `/`
+            static_dir: argument("--static-dir").or_else(|| env::var("STATIC_DIR").ok()),
+            static_content_security_policy: env::var("STATIC_CONTENT_SECURITY_POLICY")
+                .unwrap_or_else(|_| "default-src 'self'; frame-ancestors 'none'".to_string()),
+            // settings reloaded while running, see settings.rs
+            config_file: env::var("CONFIG_FILE").ok(),
+            log_level: env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()),
+            // comma separated, `*` allows any origin
+            cors_allowed_origins: env::var("CORS_ALLOWED_ORIGINS")
+                .map(|origins| split_list(&origins))
+                .unwrap_or_default(),
+            breaker_cooldown: Duration::from_secs(parse_or("BREAKER_COOLDOWN_SECS", 10)),
+            // lists with more rows are streamed instead of built in memory
+            stream_threshold: parse_or("STREAM_THRESHOLD", 1000),
+            // `{"data": ..., "meta": ...}` instead of bare bodies, see envelope.rs
+            response_envelope: parse_or("RESPONSE_ENVELOPE", false),
+            // comma separated `pattern=policy`, tried before the defaults of
+            // cache.rs
+            cache_policies: env::var("CACHE_POLICIES")
+                .map(|policies| {
+                    split_pairs(&policies)
+                        .into_iter()
+                        .map(|(pattern, policy)| match CachePolicy::parse(&policy) {
+                            Some(policy) => (pattern, policy),
+                            None => panic!("Invalid cache policy for {}: {}", pattern, policy),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            // of the public lists
+            cache_max_age: Duration::from_secs(parse_or("CACHE_MAX_AGE_SECS", 30)),
+            avatar_max_bytes: parse_or("AVATAR_MAX_BYTES", 1024 * 1024),
+            // how far the timestamp of a partner signature may be from now,
+            // either way, see signatures.rs
+            signature_window: Duration::from_secs(parse_or("SIGNATURE_WINDOW_SECS", 300)),
+            // where uploads go when S3_BUCKET is not set
+            upload_dir: env::var("UPLOAD_DIR").unwrap_or_else(|_| "uploads".to_string()),
+            s3_bucket: env::var("S3_BUCKET").ok(),
+            s3_region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            // for S3 compatible services, AWS when unset
+            s3_endpoint: env::var("S3_ENDPOINT").ok(),
+            s3_access_key_id: env::var("S3_ACCESS_KEY_ID").ok(),
+            s3_secret_access_key: env::var("S3_SECRET_ACCESS_KEY").ok(),
+            webhook_max_attempts: parse_or("WEBHOOK_MAX_ATTEMPTS", 8),
+            webhook_poll_interval: Duration::from_millis(parse_or(
+                "WEBHOOK_POLL_INTERVAL_MS",
+                1000,
+            )),
+            webhook_timeout: Duration::from_secs(parse_or("WEBHOOK_TIMEOUT_SECS", 10)),
+            // callbacks to the loopback and private addresses, for receivers
+            // on the same host or network, see webhooks.rs
+            webhook_private_targets: parse_or("WEBHOOK_PRIVATE_TARGETS", false),
+            job_max_attempts: parse_or("JOB_MAX_ATTEMPTS", 5),
+            job_poll_interval: Duration::from_millis(parse_or("JOB_POLL_INTERVAL_MS", 1000)),
+            job_timeout: Duration::from_secs(parse_or("JOB_TIMEOUT_SECS", 30)),
+            kafka_brokers: env::var("KAFKA_BROKERS").ok(),
+            #[cfg(feature = "kafka")]
+            kafka_topic: env::var("KAFKA_TOPIC").unwrap_or_else(|_| "users".to_string()),
+            // comma separated key=value librdkafka properties
+            #[cfg(feature = "kafka")]
+            kafka_properties: env::var("KAFKA_PROPERTIES")
+                .map(|properties| split_pairs(&properties))
+                .unwrap_or_default(),
+            nats_url: env::var("NATS_URL").ok(),
+            #[cfg(feature = "nats")]
+            nats_subject_prefix: env::var("NATS_SUBJECT_PREFIX")
+                .unwrap_or_else(|_| "events".to_string()),
+            #[cfg(feature = "outbox-relay")]
+            outbox_poll_interval: Duration::from_millis(parse_or("OUTBOX_POLL_INTERVAL_MS", 1000)),
+        }
+    }
+}
+
+// Login providers with both OAUTH_<NAME>_CLIENT_ID and
+// OAUTH_<NAME>_CLIENT_SECRET set
+fn oauth_providers() -> Vec<OAuthProvider> {
+    ProviderKind::ALL
+        .into_iter()
+        .filter_map(|kind| {
+            let prefix = format!("OAUTH_{}", kind.name().to_uppercase());
+            let client_id = env::var(format!("{}_CLIENT_ID", prefix)).ok()?;
+            let client_secret = env::var(format!("{}_CLIENT_SECRET", prefix)).ok()?;
+            Some(OAuthProvider {
+                kind,
+                client_id,
+                client_secret,
+            })
+        })
+        .collect()
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn split_pairs(value: &str) -> Vec<(String, String)> {
+    split_list(value)
+        .iter()
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (key.trim().to_string(), value.trim().to_string()),
+            None => panic!("Invalid key=value pair: {}", pair),
+        })
+        .collect()
+}
+
+// `--name value` or `--name=value` on the command line
+fn argument(name: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+        if let Some(value) = arg
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+// `--name` on the command line
+fn flag(name: &str) -> bool {
+    env::args().skip(1).any(|arg| arg == name)
+}
+
+// Names written as is in the SQL: unquoted identifiers, lowercase as
+// Postgres folds them
+fn identifier(name: &str, value: String) -> String {
+    let valid = value
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && !value.starts_with(|c: char| c.is_ascii_digit());
+    if !valid {
+        panic!(
+            "Invalid value for {}: {}, use lowercase letters, digits and _",
+            name, value
+        );
+    }
+    value
+}
+
+// One of the `supported` strategies, the first one when unset
+fn id_strategy(name: &str, supported: [IdStrategy; 2]) -> IdStrategy {
+    match env::var(name) {
+        Ok(value) => IdStrategy::parse(&value)
+            .filter(|strategy| supported.contains(strategy))
+            .unwrap_or_else(|| {
+                panic!(
+                    "Invalid value for {}: {}, expected {} or {}",
+                    name,
+                    value,
+                    supported[0].name(),
+                    supported[1].name()
+                )
+            }),
+        Err(_) => supported[0],
+    }
+}
+
+// A share between 0 and 1, 0 when unset
+fn rate(name: &str) -> f64 {
+    let rate = parse_or(name, 0.0);
+    if !(0.0..=1.0).contains(&rate) {
+        panic!(
+            "Invalid value for {}: {}, expected between 0 and 1",
+            name, rate
+        );
+    }
+    rate
+}
+
+fn parse_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid value for {}: {}", name, value)),
+        Err(_) => default,
+    }
+}
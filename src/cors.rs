@@ -0,0 +1,116 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{web, HttpResponse};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::settings::RuntimeSettings;
+
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE";
+// headers of our responses that scripts of other origins may read
+const EXPOSED_HEADERS: &str =
+    "X-Total-Count, X-Dry-Run, X-Quota-Limit, X-Quota-Remaining, X-Quota-Reset, Retry-After, Location";
+
+// Lets the browsers of the origins in CORS_ALLOWED_ORIGINS call the API:
+// answers preflight requests, and marks the responses to these origins.
+// The origins are read on every request, they can change at runtime.
+pub struct Cors;
+
+impl<S, B> Transform<S, ServiceRequest> for Cors
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = CorsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CorsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: Rc<S>,
+}
+
+// The origin of the request when it is allowed
+fn allowed_origin(req: &ServiceRequest) -> Option<HeaderValue> {
+    let origin = req.headers().get(header::ORIGIN)?;
+    let settings = req.app_data::<web::Data<RuntimeSettings>>()?.current();
+    let allowed = settings
+        .cors_allowed_origins
+        .iter()
+        .any(|allowed| allowed == "*" || origin.to_str().is_ok_and(|origin| origin == allowed));
+    if allowed {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+fn add_headers(origin: &HeaderValue, headers: &mut HeaderMap) {
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(EXPOSED_HEADERS),
+    );
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+}
+
+impl<S, B> Service<ServiceRequest> for CorsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let origin = allowed_origin(&req);
+        let preflight = *req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if let (true, Some(origin)) = (preflight, &origin) {
+            let mut response = HttpResponse::NoContent();
+            response
+                .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS))
+                .insert_header((header::ACCESS_CONTROL_MAX_AGE, "600"));
+            if let Some(requested) = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+                response.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone()));
+            }
+            let mut response = response.finish();
+            add_headers(origin, response.headers_mut());
+            return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+        }
+        let service = self.service.clone();
+        Box::pin(async move {
+            match (service.call(req).await, origin) {
+                (Ok(mut response), Some(origin)) => {
+                    add_headers(&origin, response.headers_mut());
+                    Ok(response.map_into_left_body())
+                }
+                (Ok(response), None) => Ok(response.map_into_left_body()),
+                // the browser only shows errors to scripts allowed to see them
+                (Err(e), Some(origin)) => {
+                    let mut response = e.error_response();
+                    add_headers(&origin, response.headers_mut());
+                    Err(InternalError::from_response(e, response).into())
+                }
+                (Err(e), None) => Err(e),
+            }
+        })
+    }
+}
@@ -0,0 +1,710 @@
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+use futures_util::future::LocalBoxFuture;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, Error, GenericClient, NoTls, Row, RowStream, Statement};
+
+use crate::breaker::{BreakerState, CircuitBreaker};
+use crate::chaos;
+use crate::dry_run::DryRun;
+use crate::metrics::QueryMetrics;
+use crate::repository::{Attempt, UnitOfWork};
+use crate::tenant::Tenant;
+
+// Retry policy: how hard we try to (re)connect and to replay transient
+// failures, and after how many failures in a row we stop trying for a while
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn next_backoff(&self, current: Duration) -> Duration {
+        std::cmp::min(current * 2, self.max_backoff)
+    }
+}
+
+// Where the tables live, for databases shared with other applications: the
+// `schema` is the search_path of every session and `prefix` goes in front of
+// every table and index name. The SQL of the repositories writes the names
+// as `{prefix}users`.
+#[derive(Clone, Debug, Default)]
+pub struct Naming {
+    pub schema: Option<String>,
+    pub prefix: String,
+}
+
+impl Naming {
+    pub fn apply(&self, sql: &str) -> String {
+        sql.replace("{prefix}", &self.prefix)
+    }
+}
+
+// A value read from a row by column name. A column missing from the row or
+// of another type than expected is returned as an error naming it, answered
+// as a 500, rather than a panic.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, Error>;
+
+    fn from_rows(rows: &[Row]) -> Result<Vec<Self>, Error> {
+        rows.iter().map(Self::from_row).collect()
+    }
+
+    fn from_opt(row: Option<&Row>) -> Result<Option<Self>, Error> {
+        row.map(Self::from_row).transpose()
+    }
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    // No live connection, a reconnection is in progress
+    Unavailable,
+    // Cancelled by the statement timeout
+    Timeout,
+    Query(Error),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Unavailable => write!(f, "database unavailable"),
+            DbError::Timeout => write!(f, "statement timeout"),
+            DbError::Query(e) => write!(f, "query failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<Error> for DbError {
+    fn from(e: Error) -> Self {
+        if e.code() == Some(&SqlState::QUERY_CANCELED) {
+            DbError::Timeout
+        } else {
+            DbError::Query(e)
+        }
+    }
+}
+
+// Serialization failures, deadlocks and dropped connections are worth replaying
+pub fn is_transient(e: &Error) -> bool {
+    if e.is_closed() {
+        return true;
+    }
+    match e.code() {
+        Some(code) => {
+            *code == SqlState::T_R_SERIALIZATION_FAILURE
+                || *code == SqlState::T_R_DEADLOCK_DETECTED
+                || *code == SqlState::ADMIN_SHUTDOWN
+        }
+        None => is_io(e),
+    }
+}
+
+fn is_io(e: &Error) -> bool {
+    std::error::Error::source(e)
+        .map(|source| source.is::<std::io::Error>())
+        .unwrap_or(false)
+}
+
+// Whether the outcome of a call says the database is unreachable, as
+// opposed to a query it answered with an error
+fn is_outage<T>(result: &Result<T, DbError>) -> bool {
+    match result {
+        Err(DbError::Unavailable) => true,
+        Err(DbError::Query(e)) => e.is_closed() || is_io(e),
+        _ => false,
+    }
+}
+
+struct Inner {
+    // replaced when the credentials are rotated, see `set_url`
+    url: RwLock<String>,
+    policy: RetryPolicy,
+    statement_timeout: Option<Duration>,
+    naming: Naming,
+    breaker: CircuitBreaker,
+    metrics: Arc<QueryMetrics>,
+    client: RwLock<Option<Arc<CachedClient>>>,
+    healthy: AtomicBool,
+    reconnecting: AtomicBool,
+    // dedicated connections, handed out exclusively (e.g. for transactions)
+    idle: Mutex<Vec<CachedClient>>,
+    pool_size: usize,
+    permits: Arc<Semaphore>,
+}
+
+// Occupation of the dedicated connections of a database
+pub struct PoolStats {
+    pub size: usize,
+    pub in_use: usize,
+    pub idle: usize,
+}
+
+// Shared database handle, reconnects in the background when the connection dies
+#[derive(Clone)]
+pub struct Database {
+    inner: Arc<Inner>,
+}
+
+impl Database {
+    fn new(
+        url: &str,
+        policy: RetryPolicy,
+        pool_size: usize,
+        statement_timeout: Option<Duration>,
+        naming: Naming,
+        metrics: Arc<QueryMetrics>,
+    ) -> Database {
+        Database {
+            inner: Arc::new(Inner {
+                url: RwLock::new(url.to_string()),
+                breaker: CircuitBreaker::new(policy.breaker_threshold, policy.breaker_cooldown),
+                policy,
+                statement_timeout,
+                naming,
+                metrics,
+                client: RwLock::new(None),
+                healthy: AtomicBool::new(false),
+                reconnecting: AtomicBool::new(false),
+                idle: Mutex::new(Vec::new()),
+                pool_size,
+                permits: Arc::new(Semaphore::new(pool_size)),
+            }),
+        }
+    }
+
+    // `pool_size` bounds the number of dedicated connections, see `checkout`.
+    // Statements running longer than `statement_timeout` are cancelled, the
+    // latency of the others goes to `metrics`. The tables are found with
+    // `naming`.
+    pub async fn connect(
+        url: &str,
+        policy: RetryPolicy,
+        pool_size: usize,
+        statement_timeout: Option<Duration>,
+        naming: Naming,
+        metrics: Arc<QueryMetrics>,
+    ) -> Result<Database, Error> {
+        let db = Database::new(url, policy, pool_size, statement_timeout, naming, metrics);
+        let client = open(&db.inner, Some(Arc::downgrade(&db.inner))).await?;
+        db.inner.install(client);
+        Ok(db)
+    }
+
+    // Don't wait for the first connection, it is established in the background
+    pub fn connect_lazy(
+        url: &str,
+        policy: RetryPolicy,
+        pool_size: usize,
+        statement_timeout: Option<Duration>,
+        naming: Naming,
+        metrics: Arc<QueryMetrics>,
+    ) -> Database {
+        let db = Database::new(url, policy, pool_size, statement_timeout, naming, metrics);
+        connection_lost(&db.inner);
+        db
+    }
+
+    // Connections opened from now on use `url`, the open ones are kept as
+    // Postgres doesn't end sessions when their password changes
+    pub fn set_url(&self, url: &str) {
+        *self.inner.url.write().unwrap() = url.to_string();
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.inner.healthy.load(Ordering::SeqCst)
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.inner.policy
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.inner.breaker
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        PoolStats {
+            size: self.inner.pool_size,
+            in_use: self.inner.pool_size - self.inner.permits.available_permits(),
+            idle: self.inner.idle.lock().unwrap().len(),
+        }
+    }
+
+    // Healthy and not cut off by the circuit breaker
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && self.inner.breaker.state() != BreakerState::Open
+    }
+
+    // Run `op` against the current client, replaying it on transient errors
+    // with exponential backoff. `op` may be invoked several times. Fails at
+    // once while the circuit breaker is open.
+    pub async fn run<T, F, Fut>(&self, op: F) -> Result<T, DbError>
+    where
+        F: Fn(Arc<CachedClient>) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let policy = &self.inner.policy;
+        let breaker = &self.inner.breaker;
+        let mut attempt = 0;
+        let mut backoff = policy.initial_backoff;
+        loop {
+            if !breaker.allow() {
+                return Err(DbError::Unavailable);
+            }
+            let result = match self.inner.current() {
+                _ if chaos::db_fault().await => Err(DbError::Unavailable),
+                Some(client) => op(client).await.map_err(DbError::from),
+                None => Err(DbError::Unavailable),
+            };
+            if is_outage(&result) {
+                breaker.record_failure();
+            } else {
+                breaker.record_success();
+            }
+            let retry = match &result {
+                Ok(_) => false,
+                Err(DbError::Unavailable) => true,
+                Err(DbError::Timeout) => false,
+                Err(DbError::Query(e)) => {
+                    if e.is_closed() {
+                        connection_lost(&self.inner);
+                    }
+                    is_transient(e)
+                }
+            };
+            match result {
+                Err(e) if retry && attempt < policy.max_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Transient database error ({}), retry {}/{} in {:?}",
+                        e, attempt, policy.max_retries, backoff
+                    );
+                }
+                result => return result,
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = policy.next_backoff(backoff);
+        }
+    }
+
+    // Run `op` in a unit of work of `tenant` on a connection of its own, then
+    // commit it. Like `run`, the transaction is replayed from the start on
+    // transient errors, serialization failures and deadlocks included: `op`
+    // may be invoked several times and shouldn't have effects outside of the
+    // database.
+    pub async fn transaction<'env, T, F>(&self, tenant: &Tenant, op: F) -> Result<T, DbError>
+    where
+        F: for<'s, 'c> Fn(&'s Attempt<'c, 'env>) -> LocalBoxFuture<'s, Result<T, DbError>>,
+    {
+        self.transaction_or_dry_run(tenant, DryRun::default(), op)
+            .await
+    }
+
+    // `transaction`, rolled back for a dry run once the deferred
+    // constraints are checked
+    pub async fn transaction_or_dry_run<'env, T, F>(
+        &self,
+        tenant: &Tenant,
+        dry_run: DryRun,
+        op: F,
+    ) -> Result<T, DbError>
+    where
+        F: for<'s, 'c> Fn(&'s Attempt<'c, 'env>) -> LocalBoxFuture<'s, Result<T, DbError>>,
+    {
+        let policy = &self.inner.policy;
+        let mut attempt = 0;
+        let mut backoff = policy.initial_backoff;
+        loop {
+            let result = async {
+                let mut client = self.checkout().await?;
+                let uow = Attempt::new(UnitOfWork::begin(&mut client, tenant).await?);
+                let value = op(&uow).await?;
+                uow.finish(dry_run).await?;
+                Ok(value)
+            }
+            .await;
+            let retry = match &result {
+                Ok(_) | Err(DbError::Timeout) => false,
+                // not while the circuit breaker turns the connections away
+                Err(DbError::Unavailable) => self.inner.breaker.state() != BreakerState::Open,
+                Err(DbError::Query(e)) => is_transient(e),
+            };
+            match result {
+                Err(e) if retry && attempt < policy.max_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Transient database error ({}), replaying the transaction {}/{} in {:?}",
+                        e, attempt, policy.max_retries, backoff
+                    );
+                }
+                result => return result,
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = policy.next_backoff(backoff);
+        }
+    }
+
+    // Borrow a connection for exclusive use, waiting while the pool is
+    // exhausted. Whether the connection still works when it's given back
+    // counts for the circuit breaker.
+    pub async fn checkout(&self) -> Result<PooledClient, DbError> {
+        let breaker = &self.inner.breaker;
+        if !breaker.allow() {
+            return Err(DbError::Unavailable);
+        }
+        if !self.is_healthy() || chaos::db_fault().await {
+            breaker.record_failure();
+            return Err(DbError::Unavailable);
+        }
+        let permit = Arc::clone(&self.inner.permits)
+            .acquire_owned()
+            .await
+            .expect("connection pool semaphore closed");
+        let idle = self.inner.take_idle();
+        let client = match idle {
+            Some(client) => client,
+            None => open(&self.inner, None).await.map_err(|e| {
+                warn!("Failed to open a pooled connection: {}", e);
+                breaker.record_failure();
+                DbError::Unavailable
+            })?,
+        };
+        Ok(PooledClient {
+            client: Some(client),
+            inner: Arc::clone(&self.inner),
+            _permit: permit,
+        })
+    }
+}
+
+// Statements kept prepared on a connection at most. The SQL of the listings
+// depends on their `?fields=` and filters, so clients could otherwise make a
+// connection hold as many statements as they can think of.
+const MAX_STATEMENTS: usize = 256;
+
+// Statements already prepared on a connection, keyed by their SQL, so hot
+// queries are only planned once per connection. Past MAX_STATEMENTS the
+// least recently used one is evicted, and closed on the server once the
+// queries still running it are done with it.
+pub struct StatementCache {
+    statements: Mutex<Statements>,
+    naming: Naming,
+    metrics: Arc<QueryMetrics>,
+}
+
+#[derive(Default)]
+struct Statements {
+    // with the tick they were last used at
+    by_sql: HashMap<String, (Query, u64)>,
+    tick: u64,
+}
+
+impl Statements {
+    fn get(&mut self, sql: &str) -> Option<Query> {
+        self.tick += 1;
+        let (query, used) = self.by_sql.get_mut(sql)?;
+        *used = self.tick;
+        Some(query.clone())
+    }
+
+    fn insert(&mut self, sql: &str, query: Query) {
+        if self.by_sql.len() >= MAX_STATEMENTS && !self.by_sql.contains_key(sql) {
+            let oldest = self
+                .by_sql
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(sql, _)| sql.clone());
+            // dropping the last handle on a statement closes it
+            if let Some(oldest) = oldest {
+                self.by_sql.remove(&oldest);
+            }
+        }
+        self.by_sql.insert(sql.to_string(), (query, self.tick));
+    }
+}
+
+impl StatementCache {
+    fn new(naming: Naming, metrics: Arc<QueryMetrics>) -> StatementCache {
+        StatementCache {
+            statements: Mutex::new(Statements::default()),
+            naming,
+            metrics,
+        }
+    }
+
+    pub fn naming(&self) -> &Naming {
+        &self.naming
+    }
+
+    pub async fn prepare<C: GenericClient>(&self, client: &C, sql: &str) -> Result<Query, Error> {
+        let cached = self.statements.lock().unwrap().get(sql);
+        if let Some(query) = cached {
+            return Ok(query);
+        }
+        let named = self.naming.apply(sql);
+        let query = Query {
+            statement: client.prepare(&named).await?,
+            sql: named
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .into(),
+            metrics: Arc::clone(&self.metrics),
+        };
+        self.statements.lock().unwrap().insert(sql, query.clone());
+        Ok(query)
+    }
+}
+
+// A prepared statement that records how long it takes whenever it runs
+#[derive(Clone)]
+pub struct Query {
+    statement: Statement,
+    // on a single line, as it is reported
+    sql: Arc<str>,
+    metrics: Arc<QueryMetrics>,
+}
+
+impl Query {
+    async fn timed<T>(&self, params: usize, run: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let result = run.await;
+        self.metrics.record(&self.sql, params, started.elapsed());
+        result
+    }
+
+    pub async fn query<C: GenericClient>(
+        &self,
+        client: &C,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, Error> {
+        self.timed(params.len(), client.query(&self.statement, params))
+            .await
+    }
+
+    pub async fn query_one<C: GenericClient>(
+        &self,
+        client: &C,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Row, Error> {
+        self.timed(params.len(), client.query_one(&self.statement, params))
+            .await
+    }
+
+    pub async fn query_opt<C: GenericClient>(
+        &self,
+        client: &C,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<Row>, Error> {
+        self.timed(params.len(), client.query_opt(&self.statement, params))
+            .await
+    }
+
+    pub async fn execute<C: GenericClient>(
+        &self,
+        client: &C,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<u64, Error> {
+        self.timed(params.len(), client.execute(&self.statement, params))
+            .await
+    }
+
+    // Only the wait for the first rows is timed
+    pub async fn query_raw<C, P>(&self, client: &C, params: &[P]) -> Result<RowStream, Error>
+    where
+        C: GenericClient,
+        P: ToSql + Sync,
+    {
+        self.timed(params.len(), client.query_raw(&self.statement, params))
+            .await
+    }
+}
+
+pub struct CachedClient {
+    pub client: Client,
+    pub statements: StatementCache,
+}
+
+// Connection checked out of the pool, returned to it when dropped
+pub struct PooledClient {
+    client: Option<CachedClient>,
+    inner: Arc<Inner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledClient {
+    type Target = CachedClient;
+
+    fn deref(&self) -> &CachedClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut CachedClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl PooledClient {
+    // Close the connection instead of returning it to the pool, for one
+    // left in the middle of a transaction
+    pub fn discard(&mut self) {
+        self.client.take();
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            if client.client.is_closed() {
+                self.inner.breaker.record_failure();
+            } else {
+                self.inner.breaker.record_success();
+                self.inner.idle.lock().unwrap().push(client);
+            }
+        }
+    }
+}
+
+// Primary plus read replicas: writes go to the primary, reads are spread
+// round-robin over the healthy replicas and fall back to the primary
+pub struct Cluster {
+    primary: Database,
+    replicas: Vec<Database>,
+    next: AtomicUsize,
+}
+
+impl Cluster {
+    pub fn new(primary: Database, replicas: Vec<Database>) -> Cluster {
+        Cluster {
+            primary,
+            replicas,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn primary(&self) -> &Database {
+        &self.primary
+    }
+
+    pub fn replicas(&self) -> &[Database] {
+        &self.replicas
+    }
+
+    pub fn reader(&self) -> &Database {
+        let count = self.replicas.len();
+        if count == 0 {
+            return &self.primary;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..count)
+            .map(|offset| &self.replicas[(start + offset) % count])
+            .find(|replica| replica.is_available())
+            .unwrap_or(&self.primary)
+    }
+}
+
+impl Inner {
+    fn current(&self) -> Option<Arc<CachedClient>> {
+        self.client.read().unwrap().clone()
+    }
+
+    fn take_idle(&self) -> Option<CachedClient> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(client) = idle.pop() {
+            if !client.client.is_closed() {
+                return Some(client);
+            }
+        }
+        None
+    }
+
+    fn install(&self, client: CachedClient) {
+        *self.client.write().unwrap() = Some(Arc::new(client));
+        self.healthy.store(true, Ordering::SeqCst);
+    }
+}
+
+// `owner` is notified when the connection dies, for the shared client only
+async fn open(inner: &Inner, owner: Option<Weak<Inner>>) -> Result<CachedClient, Error> {
+    let url = inner.url.read().unwrap().clone();
+    let (client, connection) = tokio_postgres::connect(&url, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("Connection error: {}", e);
+            if let Some(inner) = owner.and_then(|owner| owner.upgrade()) {
+                connection_lost(&inner);
+            }
+        }
+    });
+    if let Some(timeout) = inner.statement_timeout {
+        client
+            .batch_execute(&format!("SET statement_timeout = {}", timeout.as_millis()))
+            .await?;
+    }
+    if let Some(schema) = &inner.naming.schema {
+        client
+            .batch_execute(&format!("SET search_path TO {}", schema))
+            .await?;
+    }
+    Ok(CachedClient {
+        client,
+        statements: StatementCache::new(inner.naming.clone(), Arc::clone(&inner.metrics)),
+    })
+}
+
+// Drop the dead client and reconnect with exponential backoff, once
+fn connection_lost(inner: &Arc<Inner>) {
+    inner.healthy.store(false, Ordering::SeqCst);
+    if inner.reconnecting.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    *inner.client.write().unwrap() = None;
+    let inner = Arc::clone(inner);
+    tokio::spawn(async move {
+        let mut backoff = inner.policy.initial_backoff;
+        loop {
+            match open(&inner, Some(Arc::downgrade(&inner))).await {
+                Ok(client) => {
+                    info!("Reconnected to database");
+                    inner.install(client);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Reconnection failed ({}), next attempt in {:?}", e, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff = inner.policy.next_backoff(backoff);
+                }
+            }
+        }
+        inner.reconnecting.store(false, Ordering::SeqCst);
+    });
+}
@@ -0,0 +1,148 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, FromRequest, HttpRequest};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::fallback;
+use crate::jsonapi::Format;
+use crate::routes;
+
+pub const DRY_RUN_HEADER: HeaderName = HeaderName::from_static("x-dry-run");
+
+#[derive(Deserialize)]
+struct DryRunQuery {
+    dry_run: Option<String>,
+}
+
+// Whether the query asks for a dry run, None when it can't be told
+fn requested(query: &str) -> Option<bool> {
+    let query = web::Query::<DryRunQuery>::from_query(query).ok()?;
+    match query.dry_run.as_deref() {
+        None | Some("false") | Some("0") => Some(false),
+        Some("true") | Some("1") | Some("") => Some(true),
+        Some(_) => None,
+    }
+}
+
+fn is_write(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    )
+}
+
+// `?dry_run=true` on a write: the handler does everything, validation and
+// constraints included, then rolls back. The response is the one a real
+// request would have got.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DryRun(bool);
+
+impl DryRun {
+    pub fn of(req: &HttpRequest) -> DryRun {
+        // DryRuns refused the requests whose query can't be told
+        let requested = requested(req.query_string()).unwrap_or(false);
+        DryRun(requested && is_write(req.method()))
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0
+    }
+}
+
+impl FromRequest for DryRun {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(DryRun::of(req)))
+    }
+}
+
+// Refuses `?dry_run=true` with a 400 on the writes that can't be rehearsed,
+// rather than carrying them out, and marks the responses of the others with
+// `X-Dry-Run: true`. The writes that can be rehearsed are the `dry_runs`
+// of routes::ROUTES. Backends other than Postgres can't rehearse anything.
+pub struct DryRuns {
+    enabled: bool,
+}
+
+impl DryRuns {
+    pub fn new(enabled: bool) -> DryRuns {
+        DryRuns { enabled }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for DryRuns
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = DryRunsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(DryRunsMiddleware {
+            service: Rc::new(service),
+            enabled: self.enabled,
+        }))
+    }
+}
+
+pub struct DryRunsMiddleware<S> {
+    service: Rc<S>,
+    enabled: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for DryRunsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let refusal = match requested(req.query_string()) {
+            _ if !is_write(req.method()) => None,
+            Some(false) => None,
+            Some(true) => {
+                let supported = self.enabled
+                    && routes::matching(req.path())
+                        .any(|route| route.dry_runs.contains(req.method()));
+                Some(format!(
+                    "dry_run is not supported on {} {}",
+                    req.method(),
+                    req.path()
+                ))
+                .filter(|_| !supported)
+            }
+            None => Some("dry_run must be true or false".to_string()),
+        };
+        if let Some(refusal) = refusal {
+            let response =
+                fallback::respond(Format::of(req.request()), StatusCode::BAD_REQUEST, &refusal);
+            return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+        }
+        if !DryRun::of(req.request()).is_set() {
+            return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) });
+        }
+        Box::pin(async move {
+            let mut response = service.call(req).await?;
+            response
+                .headers_mut()
+                .insert(DRY_RUN_HEADER, HeaderValue::from_static("true"));
+            Ok(response.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,144 @@
+use actix_web::{get, post, web, HttpResponse, Responder};
+use log::info;
+use serde_json::json;
+
+use crate::admin::Admin;
+use crate::db::{Cluster, DbError};
+use crate::events::Event;
+use crate::jsonapi::Format;
+use crate::links::Links;
+use crate::models::User;
+use crate::repository::Duplicate;
+use crate::tenant::Tenant;
+
+const DEFAULT_THRESHOLD: f32 = 0.6;
+const DEFAULT_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+struct DuplicatesQuery {
+    threshold: Option<f32>,
+    limit: Option<i64>,
+}
+
+// Pairs of users that are likely the same person: same address once
+// trimmed and lowercased, or names with a trigram similarity of at least
+// `threshold` when pg_trgm is installed (`fuzzy` in the response)
+#[get("/admin/users/duplicates")]
+async fn get_duplicates(
+    _admin: Admin,
+    query: web::Query<DuplicatesQuery>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    links: Links,
+) -> impl Responder {
+    let threshold = query.threshold.unwrap_or(DEFAULT_THRESHOLD);
+    if !(0.0..=1.0).contains(&threshold) {
+        return HttpResponse::BadRequest().body("threshold must be between 0 and 1");
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit < 1 {
+        return HttpResponse::BadRequest().body("limit must be positive");
+    }
+    let result: Result<(bool, Vec<Duplicate>, Vec<User>), DbError> = db
+        .reader()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let fuzzy = uow.users().has_trigrams().await?;
+                let duplicates = uow.users().duplicates(fuzzy, threshold, limit).await?;
+                let mut ids: Vec<i64> = duplicates
+                    .iter()
+                    .flat_map(|duplicate| [duplicate.ids.0, duplicate.ids.1])
+                    .collect();
+                ids.sort_unstable();
+                ids.dedup();
+                let users = uow.users().find_many(&ids).await?;
+                Ok((fuzzy, duplicates, users))
+            })
+        })
+        .await;
+    match result {
+        Ok((fuzzy, duplicates, users)) => {
+            let user = |id: i64| {
+                users
+                    .iter()
+                    .find(|user| user.id == Some(id))
+                    .map(|user| links.with_user(user))
+            };
+            let duplicates: Vec<_> = duplicates
+                .iter()
+                .map(|duplicate| {
+                    json!({
+                        "users": [user(duplicate.ids.0), user(duplicate.ids.1)],
+                        "reason": duplicate.reason,
+                        "similarity": duplicate.similarity,
+                    })
+                })
+                .collect();
+            HttpResponse::Ok().json(json!({ "duplicates": duplicates, "fuzzy": fuzzy }))
+        }
+        Err(e) => Format::Json.db_error(e, "Failed to look for duplicate users"),
+    }
+}
+
+enum Merge {
+    Done(User),
+    NotFound(i64),
+    // `remove` or `keep` was merged into another user already
+    Conflict,
+}
+
+// Fold user `remove` into user `keep` in one transaction: its identities
+// move to `keep`, its sessions and password resets are revoked, and it is
+// soft deleted, deactivated with `merged_into` set. Returns `keep`.
+#[post("/admin/users/{keep}/merge/{remove}")]
+async fn merge_users(
+    _admin: Admin,
+    path: web::Path<(String, String)>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    links: Links,
+) -> impl Responder {
+    let (keep, remove) = path.into_inner();
+    let (keep, remove) = match (keep.parse::<i64>(), remove.parse::<i64>()) {
+        (Ok(keep), Ok(remove)) => (keep, remove),
+        _ => return HttpResponse::BadRequest().body("User ids must be integers"),
+    };
+    if keep == remove {
+        return HttpResponse::BadRequest().body("Can't merge a user into itself");
+    }
+    let result: Result<Merge, DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let kept = match uow.users().find_for_update(keep).await? {
+                    Some(user) => user,
+                    None => return Ok(Merge::NotFound(keep)),
+                };
+                if uow.users().find_for_update(remove).await?.is_none() {
+                    return Ok(Merge::NotFound(remove));
+                }
+                if uow.users().merge(remove, keep).await?.is_none() {
+                    return Ok(Merge::Conflict);
+                }
+                uow.identities().move_user(remove, keep).await?;
+                uow.refresh_tokens().revoke_user(remove).await?;
+                uow.password_resets().revoke_all(remove).await?;
+                uow.publish(&Event::user_merged(remove, keep)).await?;
+                Ok(Merge::Done(kept))
+            })
+        })
+        .await;
+    match result {
+        Ok(Merge::Done(user)) => {
+            info!("Merged user {} into user {}", remove, keep);
+            HttpResponse::Ok().json(links.with_user(&user))
+        }
+        Ok(Merge::NotFound(id)) => HttpResponse::NotFound().body(format!("User {} not found", id)),
+        Ok(Merge::Conflict) => HttpResponse::Conflict().body("One of the users was merged already"),
+        Err(e) => Format::Json.db_error(e, "Failed to merge the users"),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_duplicates).service(merge_users);
+}
@@ -0,0 +1,194 @@
+use std::sync::OnceLock;
+
+use crate::pii;
+
+static RULES: OnceLock<Vec<Rule>> = OnceLock::new();
+
+// The domains of Gmail, whose addresses ignore the dots of their local part
+const GMAIL_DOMAINS: [&str; 2] = ["gmail.com", "googlemail.com"];
+
+// A step of the normalization addresses go through before they are stored
+// and compared: two addresses normalizing to the same one belong to the same
+// user
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rule {
+    // drop the surrounding whitespace
+    Trim,
+    // the whole address in lowercase, although the local part could be case
+    // sensitive no provider treats it so
+    Lowercase,
+    // drop the `+tag` of the local part, for every domain
+    Subaddress,
+    // on gmail.com and googlemail.com, drop the dots and the `+tag` of the
+    // local part, and write the domain gmail.com
+    Gmail,
+}
+
+impl Rule {
+    // `trim`, `lowercase`, `subaddress` or `gmail`
+    pub fn parse(name: &str) -> Option<Rule> {
+        match name {
+            "trim" => Some(Rule::Trim),
+            "lowercase" => Some(Rule::Lowercase),
+            "subaddress" => Some(Rule::Subaddress),
+            "gmail" => Some(Rule::Gmail),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, email: String) -> String {
+        match self {
+            Rule::Trim => email.trim().to_string(),
+            Rule::Lowercase => email.to_lowercase(),
+            Rule::Subaddress => match email.rsplit_once('@') {
+                Some((local, domain)) => format!("{}@{}", without_tag(local), domain),
+                None => email,
+            },
+            Rule::Gmail => match email.rsplit_once('@') {
+                Some((local, domain))
+                    if GMAIL_DOMAINS
+                        .iter()
+                        .any(|gmail| domain.eq_ignore_ascii_case(gmail)) =>
+                {
+                    format!("{}@gmail.com", without_tag(local).replace('.', ""))
+                }
+                _ => email,
+            },
+        }
+    }
+}
+
+fn without_tag(local: &str) -> &str {
+    match local.split_once('+') {
+        // `+tag` alone is a local part, not a tag
+        Some((base, _)) if !base.is_empty() => base,
+        _ => local,
+    }
+}
+
+// When EMAIL_NORMALIZATION isn't set
+pub fn default_rules() -> Vec<Rule> {
+    vec![Rule::Trim, Rule::Lowercase]
+}
+
+// Normalize the addresses with `rules` from now on. Set once at startup,
+// before the first user is written.
+pub fn install(rules: Vec<Rule>) {
+    if RULES.set(rules).is_err() {
+        panic!("The email normalization rules are already installed");
+    }
+}
+
+pub fn normalize(email: &str) -> String {
+    normalize_with(RULES.get_or_init(default_rules), email)
+}
+
+fn normalize_with(rules: &[Rule], email: &str) -> String {
+    rules
+        .iter()
+        .fold(email.to_string(), |email, rule| rule.apply(email))
+}
+
+// What the email_normalized column holds for `email`: the normalized
+// address, or its blind index when emails are encrypted, the normalized
+// address would give the encrypted one away
+pub fn key(email: &str) -> String {
+    let normalized = normalize(email);
+    pii::email_hash(&normalized).unwrap_or(normalized)
+}
+
+// The values the email_normalized column of a user with `email` may hold:
+// the normalized address, written before encryption, then its blind index
+// under every key
+pub fn keys(email: &str) -> Vec<String> {
+    let normalized = normalize(email);
+    let mut keys = pii::email_hashes(&normalized);
+    keys.insert(0, normalized);
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_parse_by_name() {
+        for (name, rule) in [
+            ("trim", Some(Rule::Trim)),
+            ("lowercase", Some(Rule::Lowercase)),
+            ("subaddress", Some(Rule::Subaddress)),
+            ("gmail", Some(Rule::Gmail)),
+            ("Gmail", None),
+            ("dots", None),
+        ] {
+            assert_eq!(Rule::parse(name), rule, "{}", name);
+        }
+    }
+
+    #[test]
+    fn default_rules_trim_and_lowercase() {
+        for (email, normalized) in [
+            ("  Jane.Doe@Example.COM \n", "jane.doe@example.com"),
+            ("jane+news@example.com", "jane+news@example.com"),
+            ("Jane.Doe@Gmail.com", "jane.doe@gmail.com"),
+        ] {
+            assert_eq!(normalize_with(&default_rules(), email), normalized);
+        }
+    }
+
+    #[test]
+    fn each_rule_applies_on_its_own() {
+        for (rule, email, normalized) in [
+            (Rule::Trim, " Jane@Example.com ", "Jane@Example.com"),
+            (Rule::Lowercase, "Jane@Example.com", "jane@example.com"),
+            (
+                Rule::Subaddress,
+                "jane+news@example.com",
+                "jane@example.com",
+            ),
+            (Rule::Subaddress, "jane+a+b@example.com", "jane@example.com"),
+            // `+tag` alone is a local part
+            (Rule::Subaddress, "+tag@example.com", "+tag@example.com"),
+            (Rule::Subaddress, "not an address", "not an address"),
+            (Rule::Gmail, "j.a.n.e+news@gmail.com", "jane@gmail.com"),
+            (Rule::Gmail, "jane.doe@googlemail.com", "janedoe@gmail.com"),
+            (Rule::Gmail, "jane.doe@GMAIL.COM", "janedoe@gmail.com"),
+            (
+                Rule::Gmail,
+                "jane.doe+news@example.com",
+                "jane.doe+news@example.com",
+            ),
+            (
+                Rule::Gmail,
+                "jane.doe@mail.gmail.com",
+                "jane.doe@mail.gmail.com",
+            ),
+        ] {
+            assert_eq!(rule.apply(email.to_string()), normalized, "{:?}", rule);
+        }
+    }
+
+    #[test]
+    fn rules_apply_in_turn() {
+        let rules = [Rule::Trim, Rule::Lowercase, Rule::Subaddress, Rule::Gmail];
+        for (email, normalized) in [
+            (" Jane.Doe+News@GoogleMail.com ", "janedoe@gmail.com"),
+            ("Jane.Doe+News@Example.com", "jane.doe@example.com"),
+        ] {
+            assert_eq!(normalize_with(&rules, email), normalized);
+        }
+        // the untrimmed local part keeps its leading space before gmail
+        assert_eq!(
+            normalize_with(&[Rule::Gmail, Rule::Trim], " j.ane@gmail.com"),
+            "jane@gmail.com"
+        );
+    }
+
+    #[test]
+    fn no_rules_keep_the_address() {
+        assert_eq!(
+            normalize_with(&[], " Jane@Example.com"),
+            " Jane@Example.com"
+        );
+    }
+}
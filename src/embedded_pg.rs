@@ -0,0 +1,42 @@
+use log::info;
+use postgresql_embedded::{PostgreSQL, Settings};
+use std::env;
+
+const DATABASE: &str = "crud";
+
+// A Postgres server downloaded and run by the application itself, so that
+// `cargo run` and `cargo test` work without Docker nor a Postgres install.
+// Started when EMBEDDED_PG=true, or when no DATABASE_URL was given at build
+// nor run time, and pointed to by DATABASE_URL for the configuration to
+// pick up. Its data is temporary: the server stops and its directory is
+// removed when the returned handle is dropped.
+pub async fn start(default_database_url: Option<&str>) -> Option<PostgreSQL> {
+    let forced = env::var("EMBEDDED_PG").is_ok_and(|value| value == "true");
+    if !forced && (env::var("DATABASE_URL").is_ok() || default_database_url.is_some()) {
+        return None;
+    }
+    info!("Starting an embedded Postgres, the first run downloads it");
+    let mut postgresql = PostgreSQL::new(Settings::default());
+    postgresql
+        .setup()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to install the embedded Postgres: {}", e));
+    postgresql
+        .start()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to start the embedded Postgres: {}", e));
+    let exists = postgresql
+        .database_exists(DATABASE)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to reach the embedded Postgres: {}", e));
+    if !exists {
+        postgresql
+            .create_database(DATABASE)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to create the {} database: {}", DATABASE, e));
+    }
+    let settings = postgresql.settings();
+    info!("Embedded Postgres listening on port {}", settings.port);
+    env::set_var("DATABASE_URL", settings.url(DATABASE));
+    Some(postgresql)
+}
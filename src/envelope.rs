@@ -0,0 +1,138 @@
+use actix_web::body::{self, BodySize, BodyStream, BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::web::Bytes;
+use futures_util::stream::{self, StreamExt};
+use serde_json::{json, Map, Value};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::config::Config;
+
+// `true` or `false`, overrides RESPONSE_ENVELOPE for one request
+pub const ENVELOPE_HEADER: &str = "X-Envelope";
+
+// Wraps the plain JSON responses in `{"data": ..., "meta": {...}}`, the
+// body as the handler wrote it in `data`, its status and X-Total-Count in
+// `meta`. Streamed lists stay streamed, the envelope is written around
+// them. JSON:API documents are left alone, they are enveloped already, as
+// are the other media types. Off unless RESPONSE_ENVELOPE, or per request
+// with X-Envelope.
+pub struct Envelopes {
+    enabled: bool,
+}
+
+impl Envelopes {
+    pub fn new(config: &Config) -> Envelopes {
+        Envelopes {
+            enabled: config.response_envelope,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Envelopes
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = EnvelopesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(EnvelopesMiddleware {
+            service: Rc::new(service),
+            enabled: self.enabled,
+        }))
+    }
+}
+
+pub struct EnvelopesMiddleware<S> {
+    service: Rc<S>,
+    enabled: bool,
+}
+
+// What X-Envelope asks for, None when absent or neither true nor false
+fn requested(headers: &HeaderMap) -> Option<bool> {
+    let value = headers.get(ENVELOPE_HEADER)?.to_str().ok()?;
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn is_plain_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media| media.trim().eq_ignore_ascii_case("application/json"))
+}
+
+fn meta(status: u16, headers: &HeaderMap) -> Value {
+    let mut meta = Map::new();
+    meta.insert("status".to_string(), Value::from(status));
+    let count = headers
+        .get(crate::TOTAL_COUNT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok());
+    if let Some(count) = count {
+        meta.insert("count".to_string(), Value::from(count));
+    }
+    Value::Object(meta)
+}
+
+impl<S, B> Service<ServiceRequest> for EnvelopesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let enveloped = requested(req.headers()).unwrap_or(self.enabled);
+        Box::pin(async move {
+            let response = service.call(req).await?;
+            if !enveloped || !is_plain_json(response.headers()) {
+                return Ok(response.map_into_boxed_body());
+            }
+            let meta = meta(response.status().as_u16(), response.headers());
+            let (request, response) = response.into_parts();
+            let (response, body) = response.into_parts();
+            let response = match body.size() {
+                BodySize::Sized(length) if length > 0 => {
+                    let bytes = body::to_bytes(body)
+                        .await
+                        .map_err(|e| ErrorInternalServerError(e.into().to_string()))?;
+                    let data: Value = serde_json::from_slice(&bytes)
+                        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+                    let enveloped = json!({ "data": data, "meta": meta }).to_string();
+                    response.set_body(BoxBody::new(enveloped))
+                }
+                BodySize::Stream => {
+                    let open = Bytes::from_static(b"{\"data\":");
+                    let close = Bytes::from(format!(",\"meta\":{}}}", meta));
+                    let mut body = Box::pin(body);
+                    let items = stream::poll_fn(move |cx| body.as_mut().poll_next(cx));
+                    let enveloped = stream::once(ready(Ok(open)))
+                        .chain(items)
+                        .chain(stream::once(ready(Ok(close))));
+                    response.set_body(BoxBody::new(BodyStream::new(enveloped)))
+                }
+                // nothing to wrap
+                _ => response.set_body(BoxBody::new(body)),
+            };
+            Ok(ServiceResponse::new(request, response))
+        })
+    }
+}
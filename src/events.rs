@@ -0,0 +1,115 @@
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ids;
+use crate::models::User;
+use crate::pii;
+
+// Mutations other systems can subscribe to, named after the resource so
+// events of other resources can join later
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    UserCreated,
+    UserUpdated,
+    UserDeleted,
+    UserMerged,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 4] = [
+        EventKind::UserCreated,
+        EventKind::UserUpdated,
+        EventKind::UserDeleted,
+        EventKind::UserMerged,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::UserCreated => "user.created",
+            EventKind::UserUpdated => "user.updated",
+            EventKind::UserDeleted => "user.deleted",
+            EventKind::UserMerged => "user.merged",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<EventKind> {
+        EventKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+// The user as events carry it: its address sealed as in the users table
+// with email encryption on, left out otherwise, so that the outbox, the
+// webhook queue, GET /changes and the backups never hold it in the clear
+fn user_data(user: &User) -> Value {
+    let mut data = json!(user);
+    match data.as_object_mut() {
+        Some(fields) if pii::is_enabled() => {
+            fields.insert("email".to_string(), Value::from(pii::seal(&user.email)));
+        }
+        Some(fields) => {
+            fields.remove("email");
+        }
+        None => (),
+    }
+    data
+}
+
+pub struct Event {
+    // generated with the event, the same in every delivery of it
+    pub id: String,
+    pub kind: EventKind,
+    pub data: Value,
+    // seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl Event {
+    fn new(kind: EventKind, data: Value) -> Event {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        Event {
+            id: ids::event_id(),
+            kind,
+            data,
+            timestamp,
+        }
+    }
+
+    pub fn user_created(user: &User) -> Event {
+        Event::new(EventKind::UserCreated, user_data(user))
+    }
+
+    pub fn user_updated(user: &User) -> Event {
+        Event::new(EventKind::UserUpdated, user_data(user))
+    }
+
+    pub fn user_deleted(id: i64) -> Event {
+        Event::new(EventKind::UserDeleted, json!({ "id": id }))
+    }
+
+    // `id` is soft deleted, `into` is the user that remains
+    pub fn user_merged(id: i64, into: i64) -> Event {
+        Event::new(
+            EventKind::UserMerged,
+            json!({ "id": id, "merged_into": into }),
+        )
+    }
+
+    // Identifier of the resource the event is about
+    pub fn key(&self) -> Option<String> {
+        self.data.get("id").map(|id| id.to_string())
+    }
+
+    // JSON document sent to subscribers
+    pub fn payload(&self) -> Value {
+        json!({
+            "id": self.id,
+            "event": self.kind.name(),
+            "timestamp": self.timestamp,
+            "data": self.data,
+        })
+    }
+}
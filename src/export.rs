@@ -0,0 +1,95 @@
+use serde_json::{Map, Value};
+
+use crate::models::UserField;
+
+// Columns of a CSV export of whole users, in order
+const USER_COLUMNS: [&str; 7] = [
+    "id",
+    "name",
+    "email",
+    "email_verified",
+    "status",
+    "tenant_id",
+    "merged_into",
+];
+
+// How GET /users/export writes users, one per line
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    // a JSON object per line
+    Ndjson,
+    // a heading line with the column names, then the values
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn parse(name: &str) -> Option<ExportFormat> {
+        match name {
+            "ndjson" => Some(ExportFormat::Ndjson),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn filename(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "users.ndjson",
+            ExportFormat::Csv => "users.csv",
+        }
+    }
+
+    // Written before the first user
+    pub fn preamble(&self, columns: &[&str]) -> Option<Vec<u8>> {
+        match self {
+            ExportFormat::Ndjson => None,
+            ExportFormat::Csv => Some(csv_line(columns.iter().map(|column| column.to_string()))),
+        }
+    }
+
+    pub fn line(&self, columns: &[&str], user: &Map<String, Value>) -> Vec<u8> {
+        match self {
+            ExportFormat::Ndjson => {
+                let mut line = serde_json::to_vec(user).unwrap_or_default();
+                line.push(b'\n');
+                line
+            }
+            ExportFormat::Csv => csv_line(columns.iter().map(|column| match user.get(*column) {
+                Some(Value::String(text)) => text.clone(),
+                Some(Value::Null) | None => String::new(),
+                Some(value) => value.to_string(),
+            })),
+        }
+    }
+}
+
+// The columns of an export of `fields`, all of them without
+pub fn columns(fields: Option<&[UserField]>) -> Vec<&'static str> {
+    match fields {
+        Some(fields) => fields.iter().map(UserField::column).collect(),
+        None => USER_COLUMNS.to_vec(),
+    }
+}
+
+// Values are quoted when they hold a separator, a quote or a line break,
+// and their quotes doubled (RFC 4180)
+fn csv_line(values: impl Iterator<Item = String>) -> Vec<u8> {
+    let mut line = values
+        .map(|value| {
+            if value.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line.into_bytes()
+}
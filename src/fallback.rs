@@ -0,0 +1,67 @@
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::json;
+
+use crate::jsonapi::{self, Format};
+use crate::routes;
+
+pub const PROBLEM_MEDIA_TYPE: &str = "application/problem+json";
+
+// RFC 7807 problem details
+pub fn problem(status: StatusCode, detail: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type(PROBLEM_MEDIA_TYPE)
+        .json(json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or_default(),
+            "status": status.as_u16(),
+            "detail": detail,
+        }))
+}
+
+// Problem details, or a JSON:API error document for JSON:API clients. Every
+// error of the API is answered so, `Format::error` included.
+pub fn respond(format: Format, status: StatusCode, detail: &str) -> HttpResponse {
+    match format {
+        Format::JsonApi => jsonapi::error_document(status, detail),
+        Format::Json => problem(status, detail),
+    }
+}
+
+// The methods of the routes matching `path`, to tell a wrong method from an
+// unknown path
+fn allowed_methods(path: &str) -> Vec<Method> {
+    let mut allowed = Vec::new();
+    for route in routes::matching(path) {
+        for method in route.methods {
+            if !allowed.contains(method) {
+                allowed.push(method.clone());
+            }
+        }
+    }
+    allowed
+}
+
+// Default service: 405 with the Allow header for known paths, 404 otherwise
+pub async fn not_found(req: HttpRequest, format: Format) -> HttpResponse {
+    let allowed = allowed_methods(req.path());
+    let (status, detail) = if allowed.is_empty() {
+        (
+            StatusCode::NOT_FOUND,
+            format!("No route for {}", req.path()),
+        )
+    } else {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("{} is not allowed on {}", req.method(), req.path()),
+        )
+    };
+    let mut response = respond(format, status, &detail);
+    if !allowed.is_empty() {
+        let allow: Vec<_> = allowed.iter().map(Method::as_str).collect();
+        if let Ok(value) = header::HeaderValue::from_str(&allow.join(", ")) {
+            response.headers_mut().insert(header::ALLOW, value);
+        }
+    }
+    response
+}
@@ -0,0 +1,190 @@
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde_json::{Map, Value};
+use std::future::ready;
+
+use crate::admin::Admin;
+use crate::auth::{Auth, SignedIn};
+use crate::filter::Kind;
+use crate::models::{Access, User};
+
+// What the restricted callers see of a field: a masked value, such as
+// `j***@example.com`, or nothing at all
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Visibility {
+    Masked,
+    Hidden,
+}
+
+impl Visibility {
+    pub fn parse(name: &str) -> Option<Visibility> {
+        match name {
+            "masked" => Some(Visibility::Masked),
+            "hidden" => Some(Visibility::Hidden),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FieldRule {
+    pub field: &'static str,
+    pub visibility: Visibility,
+}
+
+impl FieldRule {
+    // None for the fields never sent, for `id`, which the links and the
+    // routes need, and for masking a field that isn't text
+    pub fn parse(field: &str, visibility: &str) -> Option<FieldRule> {
+        let attribute = User::ATTRIBUTES
+            .iter()
+            .find(|attribute| attribute.name == field)
+            .filter(|attribute| attribute.access != Access::WriteOnly && attribute.name != "id")?;
+        let visibility = Visibility::parse(visibility).filter(|visibility| {
+            *visibility == Visibility::Hidden || attribute.kind == Kind::Text
+        })?;
+        Some(FieldRule {
+            field: attribute.name,
+            visibility,
+        })
+    }
+}
+
+// Only admins and the user themselves see the emails in full by default
+pub fn default_rules() -> Vec<FieldRule> {
+    vec![FieldRule {
+        field: "email",
+        visibility: Visibility::Masked,
+    }]
+}
+
+// The fields of the users restricted for the callers other than the admins
+// and the user themselves, applied to every user a response holds as it is
+// serialized, see Links::attach, and to the exports
+pub struct FieldPolicy {
+    rules: Vec<FieldRule>,
+}
+
+impl FieldPolicy {
+    pub fn new(rules: Vec<FieldRule>) -> FieldPolicy {
+        FieldPolicy { rules }
+    }
+
+    fn rule(&self, field: &str) -> Option<&FieldRule> {
+        self.rules.iter().find(|rule| rule.field == field)
+    }
+
+    // For the spec of the responses: a hidden field may be missing
+    pub fn is_hidden(&self, field: &str) -> bool {
+        self.rule(field)
+            .is_some_and(|rule| rule.visibility == Visibility::Hidden)
+    }
+}
+
+fn policy(req: &HttpRequest) -> Option<&FieldPolicy> {
+    req.app_data::<web::Data<FieldPolicy>>()
+        .map(|policy| policy.get_ref())
+}
+
+fn is_admin(req: &HttpRequest) -> bool {
+    Admin::from_request(req, &mut Payload::None)
+        .into_inner()
+        .is_ok()
+}
+
+// Who the users of a response are restricted for, resolved once per request
+// and kept in its extensions: an admin, the user of a valid access token, or
+// anyone else
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Caller {
+    admin: bool,
+    user_id: Option<i64>,
+}
+
+impl Caller {
+    // Whether the caller sees the fields of the user `id` in full
+    fn sees(&self, id: Option<i64>) -> bool {
+        self.admin || (self.user_id.is_some() && self.user_id == id)
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(caller) = req.extensions().get::<Caller>() {
+            return Box::pin(ready(Ok(*caller)));
+        }
+        let req = req.clone();
+        Box::pin(async move {
+            let admin = is_admin(&req);
+            let signed_in = req
+                .extensions()
+                .get::<SignedIn>()
+                .map(|signed_in| signed_in.0);
+            // the access token is only checked when no other extractor did
+            let user_id = match signed_in {
+                Some(user_id) => Some(user_id),
+                None if !admin => Auth::from_request(&req, &mut Payload::None)
+                    .await
+                    .ok()
+                    .map(|auth| auth.user_id),
+                None => None,
+            };
+            let caller = Caller { admin, user_id };
+            req.extensions_mut().insert(caller);
+            Ok(caller)
+        })
+    }
+}
+
+// `j***@example.com` for an email, the first character then `***` for the
+// other fields
+fn mask(field: &str, value: &Value) -> Value {
+    let text = match value.as_str() {
+        Some(text) => text,
+        None => return value.clone(),
+    };
+    let first: String = text.chars().take(1).collect();
+    match text.rsplit_once('@') {
+        Some((_, domain)) if field == "email" => Value::from(format!("{}***@{}", first, domain)),
+        _ => Value::from(format!("{}***", first)),
+    }
+}
+
+// Masks or removes the restricted fields of `user` unless `caller` may see
+// them, by the policy of the app of `req`
+pub fn restrict(req: &HttpRequest, caller: Caller, user: &mut Map<String, Value>) {
+    let policy = match policy(req) {
+        Some(policy) if !policy.rules.is_empty() => policy,
+        _ => return,
+    };
+    if caller.sees(user.get("id").and_then(Value::as_i64)) {
+        return;
+    }
+    for rule in &policy.rules {
+        match rule.visibility {
+            Visibility::Hidden => {
+                user.remove(rule.field);
+            }
+            Visibility::Masked => {
+                if let Some(value) = user.get_mut(rule.field) {
+                    *value = mask(rule.field, value);
+                }
+            }
+        }
+    }
+}
+
+// The fields restricted for the caller of `req`, who then can't filter on
+// them either: the matches would give their values away. None for admins.
+pub fn restricted_fields(req: &HttpRequest) -> Vec<&'static str> {
+    match policy(req) {
+        Some(policy) if !policy.rules.is_empty() && !is_admin(req) => {
+            policy.rules.iter().map(|rule| rule.field).collect()
+        }
+        _ => Vec::new(),
+    }
+}
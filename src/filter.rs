@@ -0,0 +1,446 @@
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use actix_web::{FromRequest, HttpRequest};
+use std::future::{ready, Ready};
+use std::marker::PhantomData;
+
+use crate::field_policy;
+use crate::jsonapi::Format;
+use crate::pii;
+use crate::timestamps::{self, Offset};
+
+// Most conditions a request may combine
+pub const MAX_CONDITIONS: usize = 8;
+
+// How a column is compared, and what its values must look like
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Text,
+    Integer,
+    Boolean,
+    Timestamp,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Text => "string",
+            Kind::Integer => "integer",
+            Kind::Boolean => "boolean",
+            Kind::Timestamp => "timestamp",
+        }
+    }
+
+    pub fn operators(&self) -> &'static [Operator] {
+        use Operator::*;
+        match self {
+            Kind::Text => &[Eq, Ne, Contains, StartsWith, EndsWith],
+            Kind::Integer | Kind::Timestamp => &[Eq, Ne, Lt, Lte, Gt, Gte],
+            Kind::Boolean => &[Eq, Ne],
+        }
+    }
+
+    // Every value is sent as text, cast back to the column type in the SQL
+    fn cast(&self) -> &'static str {
+        match self {
+            Kind::Text => "",
+            Kind::Integer => "::TEXT::BIGINT",
+            Kind::Boolean => "::TEXT::BOOLEAN",
+            Kind::Timestamp => "::TEXT::TIMESTAMPTZ",
+        }
+    }
+
+    fn check(&self, value: &str) -> bool {
+        match self {
+            Kind::Text => true,
+            Kind::Integer => value.parse::<i64>().is_ok(),
+            Kind::Boolean => value == "true" || value == "false",
+            Kind::Timestamp => is_timestamp(value),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+impl Operator {
+    const ALL: [Operator; 9] = [
+        Operator::Eq,
+        Operator::Ne,
+        Operator::Lt,
+        Operator::Lte,
+        Operator::Gt,
+        Operator::Gte,
+        Operator::Contains,
+        Operator::StartsWith,
+        Operator::EndsWith,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operator::Eq => "eq",
+            Operator::Ne => "ne",
+            Operator::Lt => "lt",
+            Operator::Lte => "lte",
+            Operator::Gt => "gt",
+            Operator::Gte => "gte",
+            Operator::Contains => "contains",
+            Operator::StartsWith => "startswith",
+            Operator::EndsWith => "endswith",
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            Operator::Eq => "=",
+            Operator::Ne => "<>",
+            Operator::Lt => "<",
+            Operator::Lte => "<=",
+            Operator::Gt => ">",
+            Operator::Gte => ">=",
+            Operator::Contains | Operator::StartsWith | Operator::EndsWith => "LIKE",
+        }
+    }
+
+    // The parameter bound for `value`, a LIKE pattern matching it literally
+    // for the text operators
+    fn param(&self, value: &str) -> String {
+        let escaped = || {
+            value
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        };
+        match self {
+            Operator::Contains => format!("%{}%", escaped()),
+            Operator::StartsWith => format!("{}%", escaped()),
+            Operator::EndsWith => format!("%{}", escaped()),
+            _ => value.to_string(),
+        }
+    }
+}
+
+// A column clients may filter on
+pub struct Field {
+    pub column: &'static str,
+    pub kind: Kind,
+}
+
+// Resources with filterable columns, the whitelist of the query DSL
+pub trait Filterable {
+    const FIELDS: &'static [Field];
+}
+
+struct Condition {
+    column: &'static str,
+    operator: Operator,
+    kind: Kind,
+    param: String,
+}
+
+// Conditions written `column[operator]=value` in the query string, such as
+// `?email[endswith]=@example.com&created_at[gte]=2024-01-01`, on the
+// columns `T` whitelists, turned into SQL with placeholders: the values are
+// only ever bound. Other parameters, `fields[users]` included, are left to
+// the route.
+pub struct Filter<T: Filterable> {
+    conditions: Vec<Condition>,
+    resource: PhantomData<T>,
+}
+
+impl<T: Filterable> Filter<T> {
+    pub fn parse(query: &str) -> Result<Filter<T>, String> {
+        let mut conditions = Vec::new();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            let (column, operator) = match key.strip_suffix(']').and_then(|key| key.split_once('['))
+            {
+                // the JSON:API sparse fieldsets, `fields[users]=`
+                Some(("fields", _)) | None => continue,
+                Some(parts) => parts,
+            };
+            let field = T::FIELDS
+                .iter()
+                .find(|field| field.column == column)
+                .ok_or_else(|| format!("Unknown filter field '{}'", column))?;
+            // the database only holds ciphertexts to compare with
+            if pii::is_encrypted(field.column) {
+                return Err(format!(
+                    "'{}' is encrypted and can't be filtered on",
+                    column
+                ));
+            }
+            let operator = Operator::ALL
+                .into_iter()
+                .find(|known| known.name() == operator)
+                .filter(|operator| field.kind.operators().contains(operator))
+                .ok_or_else(|| format!("Unsupported operator '{}' on '{}'", operator, column))?;
+            if !field.kind.check(&value) {
+                return Err(format!("Invalid value '{}' for '{}'", value, column));
+            }
+            conditions.push(Condition {
+                column: field.column,
+                operator,
+                kind: field.kind,
+                param: operator.param(&value),
+            });
+        }
+        if conditions.len() > MAX_CONDITIONS {
+            return Err(format!(
+                "At most {} filters can be combined",
+                MAX_CONDITIONS
+            ));
+        }
+        // the same conditions make the same SQL whatever their order, and
+        // share its prepared statement
+        conditions.sort_by_key(|condition| (condition.column, condition.operator.name()));
+        Ok(Filter {
+            conditions,
+            resource: PhantomData,
+        })
+    }
+
+    // ` AND ...` for every condition, their placeholders numbered from
+    // `first`. Empty without conditions.
+    pub fn sql(&self, first: usize) -> String {
+        self.conditions
+            .iter()
+            .enumerate()
+            .map(|(index, condition)| {
+                format!(
+                    " AND {} {} ${}{}",
+                    condition.column,
+                    condition.operator.sql(),
+                    first + index,
+                    condition.kind.cast()
+                )
+            })
+            .collect()
+    }
+
+    // Values of the placeholders of `sql`, in order
+    pub fn params(&self) -> impl Iterator<Item = &String> {
+        self.conditions.iter().map(|condition| &condition.param)
+    }
+}
+
+impl<T: Filterable> FromRequest for Filter<T> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let filter = Filter::parse(req.query_string()).and_then(|filter: Filter<T>| {
+            let restricted = field_policy::restricted_fields(req);
+            match filter
+                .conditions
+                .iter()
+                .find(|condition| restricted.contains(&condition.column))
+            {
+                Some(condition) => Err(format!("You can't filter on '{}'", condition.column)),
+                None => Ok(filter),
+            }
+        });
+        ready(filter.map_err(|e| {
+            let response = Format::of(req).error(StatusCode::BAD_REQUEST, &e);
+            InternalError::from_response(e, response).into()
+        }))
+    }
+}
+
+// `2024-01-01` or `2024-01-01T12:30:00`, with optional fractional seconds
+// and a `Z` or `+02:00` offset. Checked so that Postgres never gets a date
+// it would refuse.
+fn is_timestamp(value: &str) -> bool {
+    match value.split_once('T') {
+        None => timestamps::is_date(value),
+        Some((date, time)) => {
+            timestamps::is_date(date)
+                && timestamps::parse_time(time).is_some_and(|time| {
+                    // Postgres keeps microseconds and refuses the leap seconds
+                    time.second.is_none_or(|second| second <= 59)
+                        && time.fraction_digits <= 6
+                        && match time.offset {
+                            Offset::Hours(hours, _) => hours <= 14,
+                            Offset::Unspecified | Offset::Utc => true,
+                        }
+                })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Users;
+
+    impl Filterable for Users {
+        const FIELDS: &'static [Field] = &[
+            Field {
+                column: "name",
+                kind: Kind::Text,
+            },
+            Field {
+                column: "id",
+                kind: Kind::Integer,
+            },
+            Field {
+                column: "email_verified",
+                kind: Kind::Boolean,
+            },
+            Field {
+                column: "created_at",
+                kind: Kind::Timestamp,
+            },
+        ];
+    }
+
+    // The SQL of the conditions of `query`, placeholders numbered from 2,
+    // and their parameters
+    fn parse(query: &str) -> Result<(String, Vec<String>), String> {
+        Filter::<Users>::parse(query)
+            .map(|filter| (filter.sql(2), filter.params().cloned().collect()))
+    }
+
+    fn condition(sql: &str, param: &str) -> Result<(String, Vec<String>), String> {
+        Ok((sql.to_string(), vec![param.to_string()]))
+    }
+
+    #[test]
+    fn operators_become_sql_comparisons() {
+        for (query, sql, param) in [
+            ("id[eq]=7", " AND id = $2::TEXT::BIGINT", "7"),
+            ("id[ne]=7", " AND id <> $2::TEXT::BIGINT", "7"),
+            ("id[lt]=7", " AND id < $2::TEXT::BIGINT", "7"),
+            ("id[lte]=-7", " AND id <= $2::TEXT::BIGINT", "-7"),
+            ("id[gt]=7", " AND id > $2::TEXT::BIGINT", "7"),
+            ("id[gte]=7", " AND id >= $2::TEXT::BIGINT", "7"),
+            ("name[eq]=Jane", " AND name = $2", "Jane"),
+            ("name[contains]=an", " AND name LIKE $2", "%an%"),
+            ("name[startswith]=J", " AND name LIKE $2", "J%"),
+            ("name[endswith]=e", " AND name LIKE $2", "%e"),
+            (
+                "email_verified[ne]=true",
+                " AND email_verified <> $2::TEXT::BOOLEAN",
+                "true",
+            ),
+            (
+                "created_at[gte]=2024-01-01",
+                " AND created_at >= $2::TEXT::TIMESTAMPTZ",
+                "2024-01-01",
+            ),
+        ] {
+            assert_eq!(parse(query), condition(sql, param), "{}", query);
+        }
+    }
+
+    #[test]
+    fn values_are_decoded_and_matched_literally() {
+        for (query, param) in [
+            ("name[eq]=Jane%20Doe", "Jane Doe"),
+            ("name[eq]=a%26b%3Dc", "a&b=c"),
+            ("name[eq]=50%25_off", "50%_off"),
+            ("name[contains]=50%25_off", "%50\\%\\_off%"),
+            ("name[startswith]=a%5Cb", "a\\\\b%"),
+            ("name[endswith]=%25", "%\\%"),
+        ] {
+            let (_, params) = parse(query).unwrap();
+            assert_eq!(params, [param], "{}", query);
+        }
+    }
+
+    #[test]
+    fn the_same_conditions_make_the_same_sql() {
+        let (sql, params) = parse("name[eq]=Jane&id[gt]=1").unwrap();
+        assert_eq!(sql, " AND id > $2::TEXT::BIGINT AND name = $3");
+        assert_eq!(params, ["1", "Jane"]);
+        assert_eq!(parse("id[gt]=1&name[eq]=Jane"), Ok((sql, params)));
+    }
+
+    #[test]
+    fn other_parameters_are_left_to_the_route() {
+        assert_eq!(
+            parse("status=active&fields[users]=name&fields=id&limit=3"),
+            Ok((String::new(), Vec::new()))
+        );
+    }
+
+    #[test]
+    fn invalid_conditions_are_refused() {
+        for (query, error) in [
+            ("email[eq]=jane@example.com", "Unknown filter field 'email'"),
+            ("name[gt]=J", "Unsupported operator 'gt' on 'name'"),
+            (
+                "email_verified[lt]=true",
+                "Unsupported operator 'lt' on 'email_verified'",
+            ),
+            ("id[like]=1", "Unsupported operator 'like' on 'id'"),
+            ("id[eq]=seven", "Invalid value 'seven' for 'id'"),
+            ("id[eq]=", "Invalid value '' for 'id'"),
+            (
+                "email_verified[eq]=yes",
+                "Invalid value 'yes' for 'email_verified'",
+            ),
+            (
+                "created_at[gte]=2024-02-30",
+                "Invalid value '2024-02-30' for 'created_at'",
+            ),
+        ] {
+            assert_eq!(parse(query).err().as_deref(), Some(error), "{}", query);
+        }
+    }
+
+    #[test]
+    fn conditions_are_limited() {
+        let query = |count: usize| {
+            (0..count)
+                .map(|index| format!("id[ne]={}", index))
+                .collect::<Vec<_>>()
+                .join("&")
+        };
+        assert!(parse(&query(MAX_CONDITIONS)).is_ok());
+        assert_eq!(
+            parse(&query(MAX_CONDITIONS + 1)).err(),
+            Some(format!(
+                "At most {} filters can be combined",
+                MAX_CONDITIONS
+            ))
+        );
+    }
+
+    #[test]
+    fn timestamps_are_those_postgres_takes() {
+        for (value, valid) in [
+            ("2024-01-01", true),
+            ("2024-02-29", true),
+            ("2023-02-29", false),
+            ("0000-01-01", false),
+            ("2024-01-01T12:30", true),
+            ("2024-01-01T12:30:00", true),
+            ("2024-01-01T12:30:00Z", true),
+            ("2024-01-01T12:30:00.123456+02:00", true),
+            ("2024-01-01T12:30:00-14:00", true),
+            ("2024-01-01T12:30:00.1234567", false),
+            ("2024-01-01T12:30.5", false),
+            ("2024-01-01T12:30:60Z", false),
+            ("2024-01-01T24:00:00", false),
+            ("2024-01-01T12:30:00+15:00", false),
+            ("2024-01-01T12:30:00+02", false),
+            ("2024-01-01t12:30:00z", false),
+            ("2024-01-01 12:30:00", false),
+            ("yesterday", false),
+        ] {
+            assert_eq!(is_timestamp(value), valid, "{}", value);
+        }
+    }
+}
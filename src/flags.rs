@@ -0,0 +1,222 @@
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorInternalServerError, InternalError};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, put, web, FromRequest, HttpRequest, HttpResponse, Responder};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::admin::Admin;
+use crate::db::{Cluster, Database, DbError};
+use crate::fallback;
+use crate::jsonapi::Format;
+use crate::repository::FeatureFlag;
+use crate::tenant::Tenant;
+
+// A flag gating a route, see `Gate`
+pub trait Flag {
+    const NAME: &'static str;
+}
+
+// Keyset pagination of the users, GET /users/page
+pub struct CursorPagination;
+
+impl Flag for CursorPagination {
+    const NAME: &'static str = "cursor_pagination";
+}
+
+// The flags of the feature_flags table, reloaded every `ttl` so that
+// evaluating them costs no query. Unknown flags are off.
+pub struct FeatureFlags {
+    ttl: Duration,
+    flags: RwLock<Arc<HashMap<String, FeatureFlag>>>,
+}
+
+impl FeatureFlags {
+    pub fn new(ttl: Duration) -> FeatureFlags {
+        FeatureFlags {
+            ttl,
+            flags: RwLock::new(Arc::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str, tenant: &Tenant) -> bool {
+        self.flags
+            .read()
+            .unwrap()
+            .get(name)
+            .is_some_and(|flag| flag.is_enabled_for(tenant.as_str()))
+    }
+
+    pub async fn refresh(&self, db: &Database) -> Result<(), DbError> {
+        let flags = db
+            .run(|client| async move { client.feature_flags().list().await })
+            .await?;
+        let flags = flags
+            .into_iter()
+            .map(|flag| (flag.name.clone(), flag))
+            .collect();
+        *self.flags.write().unwrap() = Arc::new(flags);
+        Ok(())
+    }
+}
+
+// Background task reloading the flags, the previous ones stay in effect
+// while the database can't be read
+pub fn spawn_refresh(db: Database, flags: Arc<FeatureFlags>) {
+    actix_web::rt::spawn(async move {
+        loop {
+            if let Err(e) = flags.refresh(&db).await {
+                warn!("Failed to reload the feature flags: {}", e);
+            }
+            tokio::time::sleep(flags.ttl).await;
+        }
+    });
+}
+
+// Extracted when flag `F` is on for the tenant of the request. When it is
+// off the route answers as if it did not exist.
+pub struct Gate<F: Flag>(PhantomData<F>);
+
+impl<F: Flag> FromRequest for Gate<F> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let tenant = match Tenant::from_request(req, payload).into_inner() {
+            Ok(tenant) => tenant,
+            Err(e) => return ready(Err(e)),
+        };
+        let flags = match req.app_data::<web::Data<FeatureFlags>>() {
+            Some(flags) => flags,
+            None => return ready(Err(ErrorInternalServerError("Missing feature flags"))),
+        };
+        if flags.is_enabled(F::NAME, &tenant) {
+            return ready(Ok(Gate(PhantomData)));
+        }
+        let response = fallback::respond(
+            Format::of(req),
+            StatusCode::NOT_FOUND,
+            &format!("No route for {}", req.path()),
+        );
+        ready(Err(InternalError::from_response(F::NAME, response).into()))
+    }
+}
+
+#[derive(Deserialize)]
+struct FlagSettings {
+    enabled: bool,
+    #[serde(default)]
+    tenants: Vec<String>,
+    // kept as it was when left out
+    description: Option<String>,
+}
+
+// Lowercase letters, digits, `-` and `_`
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+#[get("/admin/flags")]
+async fn get_flags(_admin: Admin, db: web::Data<Cluster>) -> impl Responder {
+    let result = db
+        .primary()
+        .run(|client| async move { client.feature_flags().list().await })
+        .await;
+    match result {
+        Ok(flags) => HttpResponse::Ok().json(flags),
+        Err(e) => Format::Json.db_error(e, "Failed to retrieve feature flags"),
+    }
+}
+
+// Create or toggle a flag, in effect at once on this instance and within
+// FEATURE_FLAGS_REFRESH_SECS on the others
+#[put("/admin/flags/{name}")]
+async fn put_flag(
+    _admin: Admin,
+    path: web::Path<String>,
+    body: web::Json<FlagSettings>,
+    db: web::Data<Cluster>,
+    flags: web::Data<FeatureFlags>,
+) -> impl Responder {
+    let name = path.into_inner();
+    if !valid_name(&name) {
+        return HttpResponse::BadRequest().body(format!("Invalid flag name {}", name));
+    }
+    let settings = body.into_inner();
+    if let Some(tenant) = settings
+        .tenants
+        .iter()
+        .find(|id| Tenant::parse(id).is_none())
+    {
+        return HttpResponse::BadRequest().body(format!("Invalid tenant id {}", tenant));
+    }
+    let (name_ref, settings_ref) = (&name, &settings);
+    let result = db
+        .primary()
+        .run(move |client| async move {
+            client
+                .feature_flags()
+                .set(
+                    name_ref,
+                    settings_ref.enabled,
+                    &settings_ref.tenants,
+                    settings_ref.description.as_deref(),
+                )
+                .await
+        })
+        .await;
+    match result {
+        Ok(flag) => {
+            info!(
+                "Feature flag {} is {}",
+                flag.name,
+                if flag.enabled { "on" } else { "off" }
+            );
+            if let Err(e) = flags.refresh(db.primary()).await {
+                warn!("Failed to reload the feature flags: {}", e);
+            }
+            HttpResponse::Ok().json(flag)
+        }
+        Err(e) => Format::Json.db_error(e, &format!("Failed to set feature flag {}", name)),
+    }
+}
+
+#[delete("/admin/flags/{name}")]
+async fn delete_flag(
+    _admin: Admin,
+    path: web::Path<String>,
+    db: web::Data<Cluster>,
+    flags: web::Data<FeatureFlags>,
+) -> impl Responder {
+    let name = path.into_inner();
+    let name_ref = &name;
+    let result = db
+        .primary()
+        .run(|client| async move { client.feature_flags().delete(name_ref).await })
+        .await;
+    match result {
+        Ok(true) => {
+            info!("Deleted feature flag {}", name);
+            if let Err(e) = flags.refresh(db.primary()).await {
+                warn!("Failed to reload the feature flags: {}", e);
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().body(format!("Feature flag {} not found", name)),
+        Err(e) => Format::Json.db_error(e, &format!("Failed to delete feature flag {}", name)),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_flags)
+        .service(put_flag)
+        .service(delete_flag);
+}
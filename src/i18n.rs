@@ -0,0 +1,289 @@
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::HttpResponse;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::OnceLock;
+
+// Message catalogs, one `English = translation` per line, `{}` standing for
+// the values formatted in the message, in the same order. The messages of
+// the code are the English catalog.
+const CATALOGS: &[(&str, &str)] = &[
+    ("de", include_str!("../assets/locales/de.txt")),
+    ("fr", include_str!("../assets/locales/fr.txt")),
+];
+
+static LANGUAGES: OnceLock<Vec<Catalog>> = OnceLock::new();
+
+struct Template {
+    // the English message split around its `{}`
+    parts: Vec<String>,
+    translation: String,
+}
+
+struct Catalog {
+    language: &'static str,
+    exact: HashMap<String, String>,
+    templates: Vec<Template>,
+}
+
+impl Catalog {
+    fn parse(language: &'static str, source: &str) -> Catalog {
+        let mut catalog = Catalog {
+            language,
+            exact: HashMap::new(),
+            templates: Vec::new(),
+        };
+        let entries = source
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        for entry in entries {
+            let (message, translation) = entry
+                .split_once(" = ")
+                .unwrap_or_else(|| panic!("Invalid {} catalog entry: {}", language, entry));
+            if message.contains("{}") {
+                catalog.templates.push(Template {
+                    parts: message.split("{}").map(str::to_string).collect(),
+                    translation: translation.to_string(),
+                });
+            } else {
+                catalog
+                    .exact
+                    .insert(message.to_string(), translation.to_string());
+            }
+        }
+        catalog
+    }
+
+    fn translate(&self, message: &str) -> Option<String> {
+        if let Some(translation) = self.exact.get(message) {
+            return Some(translation.clone());
+        }
+        self.templates.iter().find_map(|template| {
+            let values = template.matches(message)?;
+            let mut translated = String::new();
+            let mut values = values.into_iter();
+            for (index, part) in template.translation.split("{}").enumerate() {
+                if index > 0 {
+                    // a value may be a message itself, e.g. a login refusal
+                    let value = values.next().unwrap_or_default();
+                    translated.push_str(self.exact.get(value).map_or(value, String::as_str));
+                }
+                translated.push_str(part);
+            }
+            Some(translated)
+        })
+    }
+}
+
+impl Template {
+    // The values of the `{}` when `message` is made from this template
+    fn matches<'a>(&self, message: &'a str) -> Option<Vec<&'a str>> {
+        let (first, rest) = self.parts.split_first()?;
+        let (last, middle) = rest.split_last()?;
+        let mut remaining = message.strip_prefix(first.as_str())?;
+        remaining = remaining.strip_suffix(last.as_str())?;
+        let mut values = Vec::with_capacity(self.parts.len() - 1);
+        for part in middle {
+            let (value, after) = remaining.split_once(part.as_str())?;
+            values.push(value);
+            remaining = after;
+        }
+        values.push(remaining);
+        Some(values)
+    }
+}
+
+fn catalogs() -> &'static [Catalog] {
+    LANGUAGES.get_or_init(|| {
+        CATALOGS
+            .iter()
+            .map(|(language, source)| Catalog::parse(language, source))
+            .collect()
+    })
+}
+
+// The catalog of the language the client prefers among those we have, None
+// for English: the first one of the highest quality in Accept-Language,
+// matched on its primary subtag so that `fr-CH` gets French
+fn negotiate(headers: &HeaderMap) -> Option<&'static Catalog> {
+    let mut ranges: Vec<(String, f32)> = headers
+        .get_all(header::ACCEPT_LANGUAGE)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let tag = params.next()?.trim().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let primary = tag.split('-').next().unwrap_or_default().to_string();
+            Some((primary, quality))
+                .filter(|(primary, quality)| !primary.is_empty() && *quality > 0.0)
+        })
+        .collect();
+    // stable, ties keep the order of the header
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges
+        .iter()
+        .find_map(|(language, _)| {
+            if language == "en" || language == "*" {
+                return Some(None);
+            }
+            catalogs()
+                .iter()
+                .find(|catalog| catalog.language == language)
+                .map(Some)
+        })
+        .flatten()
+}
+
+fn media_type(headers: &HeaderMap) -> String {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+// Translate the messages of an error body, None when it has none we know
+fn translate_body(catalog: &Catalog, media_type: &str, body: &[u8]) -> Option<Vec<u8>> {
+    match media_type {
+        "text/plain" => {
+            let message = std::str::from_utf8(body).ok()?;
+            catalog.translate(message).map(String::into_bytes)
+        }
+        "application/problem+json" | "application/vnd.api+json" => {
+            let mut document: Value = serde_json::from_slice(body).ok()?;
+            let mut translated = false;
+            let mut translate = |object: &mut Value| {
+                if let Some(Value::String(detail)) = object.get_mut("detail") {
+                    if let Some(translation) = catalog.translate(detail) {
+                        *detail = translation;
+                        translated = true;
+                    }
+                }
+            };
+            // a problem may list its errors under its own detail
+            translate(&mut document);
+            if let Some(Value::Array(errors)) = document.get_mut("errors") {
+                errors.iter_mut().for_each(&mut translate);
+            }
+            if translated {
+                serde_json::to_vec(&document).ok()
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+// The response with its messages in the language of `catalog`, marked
+// with Content-Language when something was translated
+async fn translate<B: MessageBody + 'static>(
+    catalog: &Catalog,
+    response: HttpResponse<B>,
+) -> HttpResponse<BoxBody> {
+    let media_type = media_type(response.headers());
+    let (mut response, body) = response.into_parts();
+    if !matches!(body.size(), BodySize::Sized(length) if length > 0) {
+        return response.set_body(BoxBody::new(body));
+    }
+    let bytes = match body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(_) => return response.set_body(BoxBody::new(())),
+    };
+    match translate_body(catalog, &media_type, &bytes) {
+        Some(translated) => {
+            if let Ok(language) = HeaderValue::from_str(catalog.language) {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_LANGUAGE, language);
+            }
+            response.set_body(BoxBody::new(translated))
+        }
+        None => response.set_body(BoxBody::new(bytes)),
+    }
+}
+
+// Error and validation messages in the language of Accept-Language when we
+// have a catalog for it, English otherwise. Only the messages change, in
+// plain text errors, problem details and JSON:API errors, successful
+// responses are left alone.
+pub struct Translations;
+
+impl<S, B> Transform<S, ServiceRequest> for Translations
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = TranslationsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TranslationsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct TranslationsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for TranslationsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let catalog = negotiate(req.headers());
+        Box::pin(async move {
+            let catalog = match catalog {
+                Some(catalog) => catalog,
+                None => {
+                    return service
+                        .call(req)
+                        .await
+                        .map(ServiceResponse::map_into_boxed_body)
+                }
+            };
+            match service.call(req).await {
+                Ok(response)
+                    if response.status().is_client_error()
+                        || response.status().is_server_error() =>
+                {
+                    let (request, response) = response.into_parts();
+                    let response = translate(catalog, response).await;
+                    Ok(ServiceResponse::new(request, response))
+                }
+                Ok(response) => Ok(response.map_into_boxed_body()),
+                // raised by extractors and middleware, answered further out
+                Err(e) => {
+                    let response = translate(catalog, e.error_response()).await;
+                    Err(InternalError::from_response(e, response).into())
+                }
+            }
+        })
+    }
+}
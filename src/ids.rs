@@ -0,0 +1,299 @@
+use rand::Rng;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+
+// 2024-01-01T00:00:00Z, where the timestamps of the snowflakes start
+const SNOWFLAKE_EPOCH: Duration = Duration::from_millis(1_704_067_200_000);
+const WORKER_BITS: u32 = 5;
+const SEQUENCE_BITS: u32 = 7;
+pub const MAX_WORKER: u64 = (1 << WORKER_BITS) - 1;
+
+// Crockford's base 32, without I, L, O and U
+const ULID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+static IDS: OnceLock<Ids> = OnceLock::new();
+
+// How the ids of new rows are chosen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdStrategy {
+    // the next value of the sequence of the table, chosen by the database
+    Serial,
+    // 53 bit integers, exact in JSON numbers: 41 bits of milliseconds since
+    // 2024, 5 bits of worker and 7 of sequence within the millisecond
+    Snowflake,
+    // 26 character strings: 48 bits of milliseconds since 1970 then 80
+    // random ones, in Crockford's base 32
+    Ulid,
+}
+
+impl IdStrategy {
+    pub fn parse(name: &str) -> Option<IdStrategy> {
+        match name {
+            "serial" => Some(IdStrategy::Serial),
+            "snowflake" => Some(IdStrategy::Snowflake),
+            "ulid" => Some(IdStrategy::Ulid),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IdStrategy::Serial => "serial",
+            IdStrategy::Snowflake => "snowflake",
+            IdStrategy::Ulid => "ulid",
+        }
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// Snowflake ids of one worker, increasing even when the clock goes back:
+// the milliseconds never go below the last ones used, and borrow from the
+// next millisecond once its sequence is exhausted
+pub struct Snowflake {
+    worker: u64,
+    // (milliseconds since the epoch, sequence) of the last id
+    last: Mutex<(u64, u64)>,
+}
+
+impl Snowflake {
+    pub fn new(worker: u64) -> Snowflake {
+        assert!(worker <= MAX_WORKER, "snowflake worker out of range");
+        Snowflake {
+            worker,
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn next(&self) -> i64 {
+        self.next_at(now().saturating_sub(SNOWFLAKE_EPOCH).as_millis() as u64)
+    }
+
+    // The id following the last one at `millis` since the epoch
+    fn next_at(&self, millis: u64) -> i64 {
+        let mut last = self.last.lock().unwrap();
+        *last = match *last {
+            (previous, sequence) if millis <= previous => {
+                if sequence < (1 << SEQUENCE_BITS) - 1 {
+                    (previous, sequence + 1)
+                } else {
+                    (previous + 1, 0)
+                }
+            }
+            _ => (millis, 0),
+        };
+        let (millis, sequence) = *last;
+        ((millis << (WORKER_BITS + SEQUENCE_BITS)) | (self.worker << SEQUENCE_BITS) | sequence)
+            as i64
+    }
+}
+
+// ULIDs, monotonic within the process: the ones of the same millisecond
+// follow the first one, its random part incremented
+#[derive(Default)]
+pub struct Ulid {
+    last: Mutex<u128>,
+}
+
+impl Ulid {
+    pub fn next(&self) -> String {
+        self.next_at(now().as_millis(), rand::thread_rng().gen())
+    }
+
+    // The ULID following the last one at `millis` since 1970, `random`
+    // giving its random part when it starts a millisecond
+    fn next_at(&self, millis: u128, random: u128) -> String {
+        let millis = millis & ((1 << 48) - 1);
+        let mut last = self.last.lock().unwrap();
+        *last = if millis <= *last >> 80 {
+            *last + 1
+        } else {
+            (millis << 80) | (random & ((1 << 80) - 1))
+        };
+        let value = *last;
+        (0..26)
+            .rev()
+            .map(|index| ULID_ALPHABET[(value >> (index * 5)) as usize & 31] as char)
+            .collect()
+    }
+}
+
+// The strategies of the users and the events, with their generators
+pub struct Ids {
+    users: IdStrategy,
+    events: IdStrategy,
+    snowflake: Snowflake,
+    ulid: Ulid,
+}
+
+impl Ids {
+    pub fn from_config(config: &Config) -> Ids {
+        Ids {
+            users: config.user_ids,
+            events: config.event_ids,
+            snowflake: Snowflake::new(config.id_worker),
+            ulid: Ulid::default(),
+        }
+    }
+
+    // For the startup log
+    pub fn describe(&self) -> String {
+        format!(
+            "{} user ids, {} event ids",
+            self.users.name(),
+            self.events.name()
+        )
+    }
+}
+
+impl Default for Ids {
+    fn default() -> Ids {
+        Ids {
+            users: IdStrategy::Serial,
+            events: IdStrategy::Ulid,
+            snowflake: Snowflake::new(0),
+            ulid: Ulid::default(),
+        }
+    }
+}
+
+// Generate the ids of `ids` from now on. Set once at startup.
+pub fn install(ids: Ids) {
+    if IDS.set(ids).is_err() {
+        panic!("The id generators are already installed");
+    }
+}
+
+fn ids() -> &'static Ids {
+    IDS.get_or_init(Ids::default)
+}
+
+// The id of a new user, known before it is inserted. None with serial ids,
+// the database chooses them.
+pub fn user_id() -> Option<i64> {
+    let ids = ids();
+    match ids.users {
+        IdStrategy::Snowflake => Some(ids.snowflake.next()),
+        IdStrategy::Serial | IdStrategy::Ulid => None,
+    }
+}
+
+// The id of a new event, which subscribers can deduplicate and order on
+pub fn event_id() -> String {
+    let ids = ids();
+    match ids.events {
+        IdStrategy::Snowflake => ids.snowflake.next().to_string(),
+        IdStrategy::Serial | IdStrategy::Ulid => ids.ulid.next(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(id: i64) -> (u64, u64, u64) {
+        let id = id as u64;
+        (
+            id >> (WORKER_BITS + SEQUENCE_BITS),
+            (id >> SEQUENCE_BITS) & MAX_WORKER,
+            id & ((1 << SEQUENCE_BITS) - 1),
+        )
+    }
+
+    #[test]
+    fn snowflakes_hold_the_millisecond_the_worker_and_a_sequence() {
+        let snowflake = Snowflake::new(3);
+        assert_eq!(parts(snowflake.next_at(1000)), (1000, 3, 0));
+        assert_eq!(parts(snowflake.next_at(1000)), (1000, 3, 1));
+        assert_eq!(parts(snowflake.next_at(1001)), (1001, 3, 0));
+    }
+
+    #[test]
+    fn snowflakes_keep_increasing_when_the_clock_goes_back() {
+        let snowflake = Snowflake::new(0);
+        let first = snowflake.next_at(5000);
+        let second = snowflake.next_at(4000);
+        assert!(second > first);
+        assert_eq!(parts(second), (5000, 0, 1));
+        // back on time once the clock passes the last id
+        assert_eq!(parts(snowflake.next_at(5001)), (5001, 0, 0));
+    }
+
+    #[test]
+    fn snowflakes_borrow_the_next_millisecond_once_the_sequence_is_exhausted() {
+        let snowflake = Snowflake::new(MAX_WORKER);
+        let ids: Vec<_> = (0..(1 << SEQUENCE_BITS) + 1)
+            .map(|_| snowflake.next_at(7))
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            parts(ids[ids.len() - 2]),
+            (7, MAX_WORKER, (1 << SEQUENCE_BITS) - 1)
+        );
+        assert_eq!(parts(ids[ids.len() - 1]), (8, MAX_WORKER, 0));
+        // the clock catching up doesn't repeat it
+        assert!(snowflake.next_at(8) > ids[ids.len() - 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "snowflake worker out of range")]
+    fn snowflake_workers_are_bounded() {
+        Snowflake::new(MAX_WORKER + 1);
+    }
+
+    #[test]
+    fn snowflakes_of_the_clock_increase() {
+        let snowflake = Snowflake::new(1);
+        let ids: Vec<_> = (0..1000).map(|_| snowflake.next()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids[0] > 0);
+    }
+
+    #[test]
+    fn ulids_are_the_millisecond_then_the_random_part_in_base_32() {
+        let ulid = Ulid::default();
+        assert_eq!(ulid.next_at(1, 0), "00000000010000000000000000");
+        let ulid = Ulid::default();
+        let id = ulid.next_at(1_700_000_000_000, u128::MAX);
+        assert_eq!(id.len(), 26);
+        assert_eq!(&id[..10], "01HF7YAT00");
+        assert_eq!(&id[10..], "ZZZZZZZZZZZZZZZZ");
+        assert!(id.bytes().all(|b| ULID_ALPHABET.contains(&b)));
+    }
+
+    #[test]
+    fn ulids_of_a_millisecond_follow_the_first_one() {
+        let ulid = Ulid::default();
+        let first = ulid.next_at(1_700_000_000_000, 41);
+        let second = ulid.next_at(1_700_000_000_000, 5);
+        assert_eq!(&first[..24], &second[..24]);
+        assert_eq!(&first[24..], "19");
+        assert_eq!(&second[24..], "1A");
+    }
+
+    #[test]
+    fn ulids_keep_increasing_when_the_clock_goes_back() {
+        let ulid = Ulid::default();
+        let ids = [
+            ulid.next_at(2000, 9),
+            ulid.next_at(1000, 0),
+            ulid.next_at(2000, 0),
+            ulid.next_at(2001, 0),
+        ];
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", ids);
+        assert_eq!(&ids[3][..10], &ulid.next_at(2001, 0)[..10]);
+    }
+
+    #[test]
+    fn ulids_of_the_clock_increase() {
+        let ulid = Ulid::default();
+        let ids: Vec<_> = (0..1000).map(|_| ulid.next()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,349 @@
+use log::warn;
+use serde_json::{json, Value};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::crypto;
+use crate::db::{Database, DbError};
+use crate::mailer::{Email, Mailer};
+use crate::models::User;
+use crate::repository::UnitOfWork;
+use crate::tenant::Tenant;
+use crate::verification;
+
+// Jobs claimed by the worker per poll
+const BATCH_SIZE: i64 = 20;
+
+// Side effects run after a request has committed, retried with backoff
+// until they succeed or run out of attempts
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobKind {
+    VerificationEmail,
+    WelcomeEmail,
+    PasswordResetEmail,
+}
+
+impl JobKind {
+    pub const ALL: [JobKind; 3] = [
+        JobKind::VerificationEmail,
+        JobKind::WelcomeEmail,
+        JobKind::PasswordResetEmail,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobKind::VerificationEmail => "verification_email",
+            JobKind::WelcomeEmail => "welcome_email",
+            JobKind::PasswordResetEmail => "password_reset_email",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<JobKind> {
+        JobKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+// Payload of the jobs emailing a user
+pub fn for_user(user: &User) -> Value {
+    json!({ "user_id": user.id, "tenant_id": user.tenant_id })
+}
+
+struct Worker {
+    db: Database,
+    emails: Emails,
+    max_attempts: i32,
+    poll_interval: Duration,
+    timeout: Duration,
+    password_reset_ttl: Duration,
+}
+
+pub fn spawn_worker(db: Database, mailer: Rc<dyn Mailer>, config: &Config) {
+    let worker = Worker {
+        db,
+        emails: Emails {
+            mailer,
+            secret_key: config.secret_key.clone(),
+            public_url: config.public_url.clone(),
+            verification_ttl: config.verification_ttl,
+        },
+        max_attempts: config.job_max_attempts,
+        poll_interval: config.job_poll_interval,
+        timeout: config.job_timeout,
+        password_reset_ttl: config.password_reset_ttl,
+    };
+    actix_web::rt::spawn(async move {
+        loop {
+            match worker.run_due().await {
+                // a full batch, more jobs may be due already
+                Ok(count) if count as i64 == BATCH_SIZE => {}
+                Ok(_) => tokio::time::sleep(worker.poll_interval).await,
+                Err(e) => {
+                    warn!("Job worker: {}", e);
+                    tokio::time::sleep(worker.poll_interval).await;
+                }
+            }
+        }
+    });
+}
+
+impl Worker {
+    async fn run_due(&self) -> Result<usize, DbError> {
+        // long enough for the whole batch to run before it is handed out again
+        let lease = self.timeout * 3;
+        let batch = self
+            .db
+            .run(|client| async move { client.jobs().claim_due(BATCH_SIZE, lease).await })
+            .await?;
+        for job in &batch {
+            let id = job.id;
+            let outcome = match JobKind::parse(&job.kind) {
+                Some(kind) => tokio::time::timeout(self.timeout, self.execute(kind, &job.payload))
+                    .await
+                    .unwrap_or_else(|_| Err("Timed out".to_string())),
+                None => Err(format!("Unknown job kind '{}'", job.kind)),
+            };
+            match outcome {
+                Ok(()) => {
+                    self.db
+                        .run(|client| async move { client.jobs().complete(id).await })
+                        .await?
+                }
+                Err(error) => {
+                    warn!(
+                        "Job {} ({}) failed (attempt {}): {}",
+                        id,
+                        job.kind,
+                        job.attempts + 1,
+                        error
+                    );
+                    let error = &error;
+                    let max_attempts = self.max_attempts;
+                    let backoff = retry_backoff(job.attempts + 1);
+                    self.db
+                        .run(move |client| async move {
+                            client.jobs().fail(id, error, max_attempts, backoff).await
+                        })
+                        .await?
+                }
+            }
+        }
+        Ok(batch.len())
+    }
+
+    async fn execute(&self, kind: JobKind, payload: &Value) -> Result<(), String> {
+        // the user may have been deleted before we got to it
+        let user = match self.user(payload).await? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        match kind {
+            JobKind::VerificationEmail => self.emails.send_verification(user).await,
+            JobKind::WelcomeEmail => self.emails.send_welcome(user).await,
+            JobKind::PasswordResetEmail => self.send_password_reset_email(user).await,
+        }
+    }
+
+    async fn user(&self, payload: &Value) -> Result<Option<User>, String> {
+        let id = payload["user_id"]
+            .as_i64()
+            .ok_or_else(|| format!("Invalid payload: {}", payload))?;
+        // jobs queued before tenants existed belong to the default one
+        let tenant = match payload["tenant_id"].as_str() {
+            Some(tenant) => {
+                Tenant::parse(tenant).ok_or_else(|| format!("Invalid payload: {}", payload))?
+            }
+            None => Tenant::default(),
+        };
+        let result: Result<Option<User>, DbError> = async {
+            let mut client = self.db.checkout().await?;
+            let uow = UnitOfWork::begin(&mut client, &tenant).await?;
+            Ok(uow.users().find(id).await?)
+        }
+        .await;
+        result.map_err(|e| e.to_string())
+    }
+
+    // The token is created here so that only its hash ever reaches the
+    // database
+    async fn send_password_reset_email(&self, user: User) -> Result<(), String> {
+        let id = user.id.unwrap_or_default();
+        let token = crypto::random_token(32);
+        let token_hash = &crypto::sha256_hex(token.as_bytes());
+        let ttl = self.password_reset_ttl;
+        self.db
+            .run(|client| async move { client.password_resets().create(id, token_hash, ttl).await })
+            .await
+            .map_err(|e| e.to_string())?;
+        self.emails.send_password_reset(user, &token, ttl).await
+    }
+}
+
+// The emails of the jobs, written and handed to the mailer
+struct Emails {
+    mailer: Rc<dyn Mailer>,
+    secret_key: String,
+    public_url: String,
+    verification_ttl: Duration,
+}
+
+impl Emails {
+    async fn send_verification(&self, user: User) -> Result<(), String> {
+        // verified in the meantime, through an earlier email
+        if user.email_verified {
+            return Ok(());
+        }
+        let tenant = Tenant::parse(&user.tenant_id).unwrap_or_default();
+        let token = verification::issue(
+            &self.secret_key,
+            &tenant,
+            user.id.unwrap_or_default(),
+            &user.email,
+            self.verification_ttl,
+        );
+        let email = Email {
+            to: user.email,
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Hello {}, please confirm your email address by following this link: {}/verify?token={}",
+                user.name, self.public_url, token
+            ),
+        };
+        self.mailer.send(&email).await
+    }
+
+    async fn send_welcome(&self, user: User) -> Result<(), String> {
+        let email = Email {
+            to: user.email,
+            subject: "Welcome!".to_string(),
+            body: format!(
+                "Hello {}, your email address is verified, welcome aboard!",
+                user.name
+            ),
+        };
+        self.mailer.send(&email).await
+    }
+
+    async fn send_password_reset(
+        &self,
+        user: User,
+        token: &str,
+        ttl: Duration,
+    ) -> Result<(), String> {
+        let email = Email {
+            to: user.email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hello {}, use this token with POST /auth/reset-password within {} minutes to choose a new password: {}\n\nIf you did not ask for it, you can ignore this email.",
+                user.name,
+                ttl.as_secs() / 60,
+                token
+            ),
+        };
+        self.mailer.send(&email).await
+    }
+}
+
+// 2, 4, 8... seconds, capped at an hour
+fn retry_backoff(attempts: i32) -> Duration {
+    Duration::from_secs(2u64.saturating_pow(attempts.max(1) as u32).min(3600))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::RecordingMailer;
+    use crate::models::AccountStatus;
+    use crate::verification::Claim;
+
+    const SECRET_KEY: &str = "secret";
+
+    fn emails(mailer: &Rc<RecordingMailer>) -> Emails {
+        Emails {
+            mailer: mailer.clone(),
+            secret_key: SECRET_KEY.to_string(),
+            public_url: "https://api.example.com".to_string(),
+            verification_ttl: Duration::from_secs(3600),
+        }
+    }
+
+    fn user(email_verified: bool) -> User {
+        User {
+            id: Some(42),
+            name: "Jane".to_string(),
+            email: "jane@example.com".to_string(),
+            email_verified,
+            password: None,
+            status: AccountStatus::Active,
+            tenant_id: "acme".to_string(),
+            merged_into: None,
+            updated_at: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn verification_email_links_to_a_token_of_the_user() {
+        let mailer = Rc::new(RecordingMailer::default());
+        emails(&mailer)
+            .send_verification(user(false))
+            .await
+            .unwrap();
+        let sent = mailer.sent.borrow();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "jane@example.com");
+        assert_eq!(sent[0].subject, "Verify your email address");
+        let (_, token) = sent[0]
+            .body
+            .split_once("https://api.example.com/verify?token=")
+            .expect("no verification link");
+        let claim = Claim::parse(token).expect("invalid token");
+        assert_eq!(claim.tenant.as_str(), "acme");
+        assert_eq!(claim.user_id, 42);
+        assert!(claim.verify(SECRET_KEY, "jane@example.com"));
+        assert!(!claim.verify(SECRET_KEY, "john@example.com"));
+    }
+
+    #[actix_web::test]
+    async fn verified_users_get_no_verification_email() {
+        let mailer = Rc::new(RecordingMailer::default());
+        emails(&mailer).send_verification(user(true)).await.unwrap();
+        assert!(mailer.sent.borrow().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn welcome_email_greets_the_user() {
+        let mailer = Rc::new(RecordingMailer::default());
+        emails(&mailer).send_welcome(user(true)).await.unwrap();
+        let sent = mailer.sent.borrow();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "jane@example.com");
+        assert_eq!(sent[0].subject, "Welcome!");
+        assert!(sent[0].body.starts_with("Hello Jane,"));
+    }
+
+    #[actix_web::test]
+    async fn password_reset_email_carries_the_token_and_its_lifetime() {
+        let mailer = Rc::new(RecordingMailer::default());
+        let ttl = Duration::from_secs(30 * 60);
+        emails(&mailer)
+            .send_password_reset(user(true), "reset-token", ttl)
+            .await
+            .unwrap();
+        let sent = mailer.sent.borrow();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "jane@example.com");
+        assert_eq!(sent[0].subject, "Reset your password");
+        assert!(sent[0].body.contains("within 30 minutes"));
+        assert!(sent[0].body.contains(": reset-token\n"));
+    }
+
+    #[test]
+    fn retries_back_off_exponentially_up_to_an_hour() {
+        assert_eq!(retry_backoff(0), Duration::from_secs(2));
+        assert_eq!(retry_backoff(1), Duration::from_secs(2));
+        assert_eq!(retry_backoff(3), Duration::from_secs(8));
+        assert_eq!(retry_backoff(12), Duration::from_secs(3600));
+        assert_eq!(retry_backoff(100), Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,897 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+use url::Url;
+
+use crate::timestamps::{self, Offset};
+
+// Keywords that only describe, ignored as well as those starting with `x-`
+const ANNOTATIONS: [&str; 11] = [
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "readOnly",
+    "writeOnly",
+    "deprecated",
+    "contentMediaType",
+];
+
+// What a request body breaks of its schema, `pointer` being the JSON Pointer
+// of the offending value in the body, the empty string for the whole body
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    pub pointer: String,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Null,
+    Boolean,
+    Object,
+    Array,
+    Number,
+    Integer,
+    String,
+}
+
+impl Type {
+    fn parse(name: &str) -> Option<Type> {
+        match name {
+            "null" => Some(Type::Null),
+            "boolean" => Some(Type::Boolean),
+            "object" => Some(Type::Object),
+            "array" => Some(Type::Array),
+            "number" => Some(Type::Number),
+            "integer" => Some(Type::Integer),
+            "string" => Some(Type::String),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Type::Null => "null",
+            Type::Boolean => "boolean",
+            Type::Object => "object",
+            Type::Array => "array",
+            Type::Number => "number",
+            Type::Integer => "integer",
+            Type::String => "string",
+        }
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Type::Null, Value::Null)
+            | (Type::Boolean, Value::Bool(_))
+            | (Type::Object, Value::Object(_))
+            | (Type::Array, Value::Array(_))
+            | (Type::Number, Value::Number(_))
+            | (Type::String, Value::String(_)) => true,
+            // 1.0 is an integer too
+            (Type::Integer, Value::Number(number)) => {
+                number.is_i64()
+                    || number.is_u64()
+                    || number.as_f64().is_some_and(|number| number.fract() == 0.0)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Date,
+    DateTime,
+    Email,
+    Uri,
+    Uuid,
+}
+
+impl Format {
+    fn parse(name: &str) -> Option<Format> {
+        match name {
+            "date" => Some(Format::Date),
+            "date-time" => Some(Format::DateTime),
+            "email" => Some(Format::Email),
+            "uri" => Some(Format::Uri),
+            "uuid" => Some(Format::Uuid),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Format::Date => "date",
+            Format::DateTime => "date-time",
+            Format::Email => "email",
+            Format::Uri => "uri",
+            Format::Uuid => "uuid",
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Format::Date => timestamps::is_date(value),
+            // RFC 3339, e.g. 2023-05-17T08:30:00Z or with an offset, which
+            // may be written in lowercase
+            Format::DateTime => {
+                value
+                    .to_ascii_uppercase()
+                    .split_once('T')
+                    .is_some_and(|(date, time)| {
+                        timestamps::is_date(date)
+                            && timestamps::parse_time(time).is_some_and(|time| {
+                                time.second.is_some() && time.offset != Offset::Unspecified
+                            })
+                    })
+            }
+            // the address a mail can be sent to, the mailer tells the rest
+            Format::Email => value.rsplit_once('@').is_some_and(|(local, domain)| {
+                !local.is_empty()
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+                    && !value.chars().any(char::is_whitespace)
+            }),
+            Format::Uri => Url::parse(value).is_ok(),
+            Format::Uuid => {
+                let groups: Vec<_> = value.split('-').collect();
+                groups.len() == 5
+                    && groups.iter().zip([8, 4, 4, 4, 12]).all(|(group, length)| {
+                        group.len() == length && group.chars().all(|c| c.is_ascii_hexdigit())
+                    })
+            }
+        }
+    }
+}
+
+// One check of a schema
+enum Rule {
+    // the `false` schema
+    Never,
+    Types(Vec<Type>),
+    Enum(Vec<Value>),
+    Const(Value),
+    MinLength(u64),
+    MaxLength(u64),
+    Pattern(Regex),
+    Format(Format),
+    Minimum(f64),
+    Maximum(f64),
+    ExclusiveMinimum(f64),
+    ExclusiveMaximum(f64),
+    MultipleOf(f64),
+    Properties(Vec<(String, Schema)>),
+    Required(Vec<String>),
+    // the schema of the members `properties` doesn't name
+    AdditionalProperties(Vec<String>, Schema),
+    MinProperties(u64),
+    MaxProperties(u64),
+    Items(Schema),
+    MinItems(u64),
+    MaxItems(u64),
+    UniqueItems,
+    AllOf(Vec<Schema>),
+    AnyOf(Vec<Schema>),
+    OneOf(Vec<Schema>),
+    Not(Schema),
+}
+
+// A JSON Schema compiled once to validate many documents. Covers the
+// validation keywords of draft 2020-12 that describe a request body, a
+// document using one of the others, `$ref` among them, is refused when
+// compiled rather than partly enforced.
+pub struct Schema {
+    rules: Vec<Rule>,
+}
+
+// The JSON Pointer of `key` under `location`, in the schema for its errors
+// and in the body for its violations
+fn child(location: &str, key: &str) -> String {
+    format!("{}/{}", location, key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn count(value: &Value, location: &str) -> Result<u64, String> {
+    value
+        .as_u64()
+        .ok_or_else(|| format!("{} must be a non-negative integer", location))
+}
+
+fn number(value: &Value, location: &str) -> Result<f64, String> {
+    value
+        .as_f64()
+        .ok_or_else(|| format!("{} must be a number", location))
+}
+
+fn schemas(value: &Value, location: &str) -> Result<Vec<Schema>, String> {
+    match value {
+        Value::Array(schemas) if !schemas.is_empty() => schemas
+            .iter()
+            .enumerate()
+            .map(|(index, schema)| Schema::compile_at(schema, &child(location, &index.to_string())))
+            .collect(),
+        _ => Err(format!("{} must be a non-empty array of schemas", location)),
+    }
+}
+
+fn strings(value: &Value, location: &str) -> Result<Vec<String>, String> {
+    value
+        .as_array()
+        .and_then(|values| {
+            values
+                .iter()
+                .map(|value| value.as_str().map(str::to_string))
+                .collect()
+        })
+        .ok_or_else(|| format!("{} must be an array of strings", location))
+}
+
+impl Schema {
+    // Errors name the location of the keyword that can't be compiled
+    pub fn compile(document: &Value) -> Result<Schema, String> {
+        Schema::compile_at(document, "#")
+    }
+
+    fn compile_at(document: &Value, location: &str) -> Result<Schema, String> {
+        let keywords = match document {
+            Value::Bool(true) => return Ok(Schema { rules: Vec::new() }),
+            Value::Bool(false) => {
+                return Ok(Schema {
+                    rules: vec![Rule::Never],
+                })
+            }
+            Value::Object(keywords) => keywords,
+            _ => return Err(format!("{} must be an object or a boolean", location)),
+        };
+        let mut rules = Vec::new();
+        for (keyword, value) in keywords {
+            let at = child(location, keyword);
+            let rule = match keyword.as_str() {
+                "type" => {
+                    let names = match value {
+                        Value::String(name) => vec![name.clone()],
+                        _ => strings(value, &at)?,
+                    };
+                    let mut types: Vec<_> = names
+                        .iter()
+                        .map(|name| {
+                            Type::parse(name)
+                                .ok_or_else(|| format!("{}: unknown type {}", at, name))
+                        })
+                        .collect::<Result<_, _>>()?;
+                    // OpenAPI 3.0 spells a null allowed besides the type so
+                    if keywords.get("nullable") == Some(&Value::Bool(true)) {
+                        types.push(Type::Null);
+                    }
+                    Rule::Types(types)
+                }
+                "nullable" => match value {
+                    Value::Bool(_) => continue,
+                    _ => return Err(format!("{} must be a boolean", at)),
+                },
+                "enum" => match value {
+                    Value::Array(values) => Rule::Enum(values.clone()),
+                    _ => return Err(format!("{} must be an array", at)),
+                },
+                "const" => Rule::Const(value.clone()),
+                "minLength" => Rule::MinLength(count(value, &at)?),
+                "maxLength" => Rule::MaxLength(count(value, &at)?),
+                "pattern" => {
+                    let pattern = value
+                        .as_str()
+                        .ok_or_else(|| format!("{} must be a string", at))?;
+                    Rule::Pattern(Regex::new(pattern).map_err(|e| format!("{}: {}", at, e))?)
+                }
+                "format" => {
+                    let name = value
+                        .as_str()
+                        .ok_or_else(|| format!("{} must be a string", at))?;
+                    Rule::Format(
+                        Format::parse(name)
+                            .ok_or_else(|| format!("{}: unsupported format {}", at, name))?,
+                    )
+                }
+                "minimum" => Rule::Minimum(number(value, &at)?),
+                "maximum" => Rule::Maximum(number(value, &at)?),
+                "exclusiveMinimum" => Rule::ExclusiveMinimum(number(value, &at)?),
+                "exclusiveMaximum" => Rule::ExclusiveMaximum(number(value, &at)?),
+                "multipleOf" => match number(value, &at)? {
+                    divisor if divisor > 0.0 => Rule::MultipleOf(divisor),
+                    _ => return Err(format!("{} must be greater than 0", at)),
+                },
+                "properties" => {
+                    let properties = value
+                        .as_object()
+                        .ok_or_else(|| format!("{} must be an object", at))?;
+                    Rule::Properties(
+                        properties
+                            .iter()
+                            .map(|(name, schema)| {
+                                Ok((name.clone(), Schema::compile_at(schema, &child(&at, name))?))
+                            })
+                            .collect::<Result<_, String>>()?,
+                    )
+                }
+                "required" => Rule::Required(strings(value, &at)?),
+                "additionalProperties" => {
+                    let known = keywords
+                        .get("properties")
+                        .and_then(Value::as_object)
+                        .map(|properties| properties.keys().cloned().collect())
+                        .unwrap_or_default();
+                    Rule::AdditionalProperties(known, Schema::compile_at(value, &at)?)
+                }
+                "minProperties" => Rule::MinProperties(count(value, &at)?),
+                "maxProperties" => Rule::MaxProperties(count(value, &at)?),
+                "items" => Rule::Items(Schema::compile_at(value, &at)?),
+                "minItems" => Rule::MinItems(count(value, &at)?),
+                "maxItems" => Rule::MaxItems(count(value, &at)?),
+                "uniqueItems" => match value {
+                    Value::Bool(true) => Rule::UniqueItems,
+                    Value::Bool(false) => continue,
+                    _ => return Err(format!("{} must be a boolean", at)),
+                },
+                "allOf" => Rule::AllOf(schemas(value, &at)?),
+                "anyOf" => Rule::AnyOf(schemas(value, &at)?),
+                "oneOf" => Rule::OneOf(schemas(value, &at)?),
+                "not" => Rule::Not(Schema::compile_at(value, &at)?),
+                keyword if ANNOTATIONS.contains(&keyword) || keyword.starts_with("x-") => continue,
+                keyword => return Err(format!("{}: unsupported keyword {}", location, keyword)),
+            };
+            rules.push(rule);
+        }
+        Ok(Schema { rules })
+    }
+
+    // Every violation of the schema in `value`, none when it matches
+    pub fn validate(&self, value: &Value) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        self.check(value, "", &mut violations);
+        violations
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        let mut violations = Vec::new();
+        self.check(value, "", &mut violations);
+        violations.is_empty()
+    }
+
+    fn check(&self, value: &Value, pointer: &str, violations: &mut Vec<Violation>) {
+        let mut nested = Vec::new();
+        for rule in &self.rules {
+            if let Some(message) = rule.check(value, pointer, &mut nested) {
+                violations.push(Violation {
+                    pointer: pointer.to_string(),
+                    message,
+                });
+            }
+        }
+        violations.append(&mut nested);
+    }
+}
+
+impl Rule {
+    // The message of the violation of `value` itself, those of its members
+    // and items are added to `nested`
+    fn check(&self, value: &Value, pointer: &str, nested: &mut Vec<Violation>) -> Option<String> {
+        let length = |string: &str| string.chars().count() as u64;
+        let number = |number: &serde_json::Number| number.as_f64().unwrap_or_default();
+        match (self, value) {
+            (Rule::Never, _) => Some("is not allowed".to_string()),
+            (Rule::Types(types), value) if !types.iter().any(|kind| kind.matches(value)) => {
+                let names: Vec<_> = types.iter().map(Type::name).collect();
+                Some(format!("must be of type {}", names.join(" or ")))
+            }
+            (Rule::Enum(values), value) if !values.iter().any(|allowed| equal(allowed, value)) => {
+                let values: Vec<_> = values.iter().map(Value::to_string).collect();
+                Some(format!("must be one of {}", values.join(", ")))
+            }
+            (Rule::Const(expected), value) if !equal(expected, value) => {
+                Some(format!("must equal {}", expected))
+            }
+            (Rule::MinLength(min), Value::String(string)) if length(string) < *min => {
+                Some(format!("must be at least {} characters long", min))
+            }
+            (Rule::MaxLength(max), Value::String(string)) if length(string) > *max => {
+                Some(format!("must be at most {} characters long", max))
+            }
+            (Rule::Pattern(pattern), Value::String(string)) if !pattern.is_match(string) => {
+                Some(format!("must match {}", pattern.as_str()))
+            }
+            (Rule::Format(format), Value::String(string)) if !format.matches(string) => {
+                Some(format!("must be a valid {}", format.name()))
+            }
+            (Rule::Minimum(min), Value::Number(value)) if number(value) < *min => {
+                Some(format!("must be at least {}", min))
+            }
+            (Rule::Maximum(max), Value::Number(value)) if number(value) > *max => {
+                Some(format!("must be at most {}", max))
+            }
+            (Rule::ExclusiveMinimum(min), Value::Number(value)) if number(value) <= *min => {
+                Some(format!("must be greater than {}", min))
+            }
+            (Rule::ExclusiveMaximum(max), Value::Number(value)) if number(value) >= *max => {
+                Some(format!("must be less than {}", max))
+            }
+            (Rule::MultipleOf(divisor), Value::Number(value)) => {
+                let quotient = number(value) / divisor;
+                // 0.3 is a multiple of 0.1 despite the float error
+                ((quotient - quotient.round()).abs() > 1e-9)
+                    .then(|| format!("must be a multiple of {}", divisor))
+            }
+            (Rule::Properties(properties), Value::Object(members)) => {
+                for (name, schema) in properties {
+                    if let Some(member) = members.get(name) {
+                        schema.check(member, &child(pointer, name), nested);
+                    }
+                }
+                None
+            }
+            (Rule::Required(names), Value::Object(members)) => {
+                for name in names.iter().filter(|name| !members.contains_key(*name)) {
+                    nested.push(Violation {
+                        pointer: child(pointer, name),
+                        message: "is required".to_string(),
+                    });
+                }
+                None
+            }
+            (Rule::AdditionalProperties(known, schema), Value::Object(members)) => {
+                for (name, member) in members.iter().filter(|(name, _)| !known.contains(name)) {
+                    schema.check(member, &child(pointer, name), nested);
+                }
+                None
+            }
+            (Rule::MinProperties(min), Value::Object(members)) if (members.len() as u64) < *min => {
+                Some(format!("must have at least {} properties", min))
+            }
+            (Rule::MaxProperties(max), Value::Object(members)) if members.len() as u64 > *max => {
+                Some(format!("must have at most {} properties", max))
+            }
+            (Rule::Items(schema), Value::Array(items)) => {
+                for (index, item) in items.iter().enumerate() {
+                    schema.check(item, &child(pointer, &index.to_string()), nested);
+                }
+                None
+            }
+            (Rule::MinItems(min), Value::Array(items)) if (items.len() as u64) < *min => {
+                Some(format!("must have at least {} items", min))
+            }
+            (Rule::MaxItems(max), Value::Array(items)) if items.len() as u64 > *max => {
+                Some(format!("must have at most {} items", max))
+            }
+            (Rule::UniqueItems, Value::Array(items)) => {
+                let duplicated = items
+                    .iter()
+                    .enumerate()
+                    .any(|(index, item)| items[..index].iter().any(|other| equal(item, other)));
+                duplicated.then(|| "must not contain duplicate items".to_string())
+            }
+            (Rule::AllOf(schemas), value) => {
+                for schema in schemas {
+                    schema.check(value, pointer, nested);
+                }
+                None
+            }
+            (Rule::AnyOf(schemas), value)
+                if !schemas.iter().any(|schema| schema.matches(value)) =>
+            {
+                Some("must match at least one of the schemas".to_string())
+            }
+            (Rule::OneOf(schemas), value)
+                if schemas
+                    .iter()
+                    .filter(|schema| schema.matches(value))
+                    .count()
+                    != 1 =>
+            {
+                Some("must match exactly one of the schemas".to_string())
+            }
+            (Rule::Not(schema), value) if schema.matches(value) => {
+                Some("must not match the schema".to_string())
+            }
+            // matched, or a keyword of another type leaving the value alone
+            _ => None,
+        }
+    }
+}
+
+// JSON equality, where 1 and 1.0 are the same number
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => equal_members(a, b),
+        _ => a == b,
+    }
+}
+
+fn equal_members(a: &Map<String, Value>, b: &Map<String, Value>) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .all(|(name, value)| b.get(name).is_some_and(|other| equal(value, other)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Whether `value` matches `schema`, for each `(schema, value, matches)`
+    fn assert_table(table: &[(Value, Value, bool)]) {
+        for (schema, value, matches) in table {
+            let compiled = Schema::compile(schema)
+                .unwrap_or_else(|e| panic!("{} doesn't compile: {}", schema, e));
+            let violations = compiled.validate(value);
+            assert_eq!(
+                violations.is_empty(),
+                *matches,
+                "{} against {}: {:?}",
+                value,
+                schema,
+                violations
+            );
+        }
+    }
+
+    // Whether `value` is of `format`, for each `(value, matches)`
+    fn assert_format(format: &str, table: &[(&str, bool)]) {
+        let table: Vec<_> = table
+            .iter()
+            .map(|(value, matches)| (json!({ "format": format }), json!(value), *matches))
+            .collect();
+        assert_table(&table);
+    }
+
+    #[test]
+    fn boolean_schemas_match_everything_or_nothing() {
+        assert_table(&[
+            (json!(true), json!({ "any": 1 }), true),
+            (json!(false), json!(null), false),
+            (json!({}), json!([1, 2]), true),
+        ]);
+    }
+
+    #[test]
+    fn type_takes_one_name_or_several() {
+        assert_table(&[
+            (json!({ "type": "string" }), json!("a"), true),
+            (json!({ "type": "string" }), json!(1), false),
+            (json!({ "type": "integer" }), json!(1), true),
+            (json!({ "type": "integer" }), json!(1.0), true),
+            (json!({ "type": "integer" }), json!(1.5), false),
+            (json!({ "type": "number" }), json!(1.5), true),
+            (json!({ "type": "boolean" }), json!(false), true),
+            (json!({ "type": "null" }), json!(null), true),
+            (json!({ "type": "object" }), json!([]), false),
+            (json!({ "type": "array" }), json!([]), true),
+            (json!({ "type": ["string", "null"] }), json!(null), true),
+            (json!({ "type": ["string", "null"] }), json!(0), false),
+        ]);
+    }
+
+    #[test]
+    fn nullable_adds_null_to_the_type() {
+        assert_table(&[
+            (
+                json!({ "type": "integer", "nullable": true }),
+                json!(null),
+                true,
+            ),
+            (
+                json!({ "type": "integer", "nullable": true }),
+                json!(7),
+                true,
+            ),
+            (
+                json!({ "type": "integer", "nullable": false }),
+                json!(null),
+                false,
+            ),
+            (json!({ "nullable": true }), json!("anything"), true),
+        ]);
+    }
+
+    #[test]
+    fn enum_and_const_compare_json_values() {
+        assert_table(&[
+            (json!({ "enum": ["a", 1] }), json!("a"), true),
+            (json!({ "enum": ["a", 1] }), json!(1.0), true),
+            (json!({ "enum": ["a", 1] }), json!("b"), false),
+            (
+                json!({ "const": { "a": [1] } }),
+                json!({ "a": [1.0] }),
+                true,
+            ),
+            (
+                json!({ "const": { "a": [1] } }),
+                json!({ "a": [1], "b": 2 }),
+                false,
+            ),
+        ]);
+    }
+
+    #[test]
+    fn string_keywords_count_characters() {
+        assert_table(&[
+            (json!({ "minLength": 2 }), json!("é"), false),
+            (json!({ "minLength": 2 }), json!("éé"), true),
+            (json!({ "maxLength": 2 }), json!("ééé"), false),
+            (json!({ "maxLength": 2 }), json!(12345), true),
+            (json!({ "pattern": "^[a-z]+$" }), json!("abc"), true),
+            (json!({ "pattern": "^[a-z]+$" }), json!("abc1"), false),
+            (json!({ "pattern": "b" }), json!("abc"), true),
+        ]);
+    }
+
+    #[test]
+    fn numeric_keywords_bound_numbers() {
+        assert_table(&[
+            (json!({ "minimum": 1 }), json!(1), true),
+            (json!({ "minimum": 1 }), json!(0.5), false),
+            (json!({ "maximum": 1 }), json!(1), true),
+            (json!({ "maximum": 1 }), json!(2), false),
+            (json!({ "exclusiveMinimum": 1 }), json!(1), false),
+            (json!({ "exclusiveMinimum": 1 }), json!(1.1), true),
+            (json!({ "exclusiveMaximum": 1 }), json!(1), false),
+            (json!({ "exclusiveMaximum": 1 }), json!(0.9), true),
+            (json!({ "multipleOf": 0.1 }), json!(0.3), true),
+            (json!({ "multipleOf": 2 }), json!(3), false),
+            (json!({ "minimum": 1 }), json!("0"), true),
+        ]);
+    }
+
+    #[test]
+    fn object_keywords_check_the_members() {
+        let person = json!({
+            "properties": { "name": { "type": "string" } },
+            "required": ["name"],
+            "additionalProperties": false,
+        });
+        assert_table(&[
+            (person.clone(), json!({ "name": "Jane" }), true),
+            (person.clone(), json!({ "name": 1 }), false),
+            (person.clone(), json!({}), false),
+            (person.clone(), json!({ "name": "Jane", "age": 3 }), false),
+            (
+                json!({ "additionalProperties": { "type": "integer" } }),
+                json!({ "a": 1 }),
+                true,
+            ),
+            (
+                json!({ "additionalProperties": { "type": "integer" } }),
+                json!({ "a": "1" }),
+                false,
+            ),
+            (json!({ "minProperties": 1 }), json!({}), false),
+            (
+                json!({ "maxProperties": 1 }),
+                json!({ "a": 1, "b": 2 }),
+                false,
+            ),
+            (json!({ "required": ["a"] }), json!("not an object"), true),
+        ]);
+    }
+
+    #[test]
+    fn array_keywords_check_the_items() {
+        assert_table(&[
+            (
+                json!({ "items": { "type": "integer" } }),
+                json!([1, 2]),
+                true,
+            ),
+            (
+                json!({ "items": { "type": "integer" } }),
+                json!([1, "2"]),
+                false,
+            ),
+            (json!({ "minItems": 1 }), json!([]), false),
+            (json!({ "maxItems": 1 }), json!([1, 2]), false),
+            (json!({ "uniqueItems": true }), json!([1, 1.0]), false),
+            (
+                json!({ "uniqueItems": true }),
+                json!([{ "a": 1 }, { "a": 2 }]),
+                true,
+            ),
+            (json!({ "uniqueItems": false }), json!([1, 1]), true),
+        ]);
+    }
+
+    #[test]
+    fn combinators_match_their_schemas() {
+        let small = json!({ "maximum": 10 });
+        let even = json!({ "multipleOf": 2 });
+        assert_table(&[
+            (json!({ "allOf": [small, even] }), json!(4), true),
+            (json!({ "allOf": [small, even] }), json!(5), false),
+            (json!({ "anyOf": [small, even] }), json!(12), true),
+            (json!({ "anyOf": [small, even] }), json!(13), false),
+            (json!({ "oneOf": [small, even] }), json!(12), true),
+            (json!({ "oneOf": [small, even] }), json!(4), false),
+            (json!({ "not": small }), json!(11), true),
+            (json!({ "not": small }), json!(9), false),
+        ]);
+    }
+
+    #[test]
+    fn annotations_are_ignored() {
+        assert_table(&[(
+            json!({
+                "$schema": "https://json-schema.org/draft/2020-12/schema",
+                "title": "A user",
+                "description": "Anyone",
+                "default": "jane",
+                "examples": ["jane"],
+                "readOnly": true,
+                "deprecated": false,
+                "x-routes": ["POST /users"],
+                "type": "string",
+            }),
+            json!("jane"),
+            true,
+        )]);
+    }
+
+    #[test]
+    fn date_is_a_day_that_exists() {
+        assert_format(
+            "date",
+            &[
+                ("2024-02-29", true),
+                ("2023-02-29", false),
+                ("2000-02-29", true),
+                ("1900-02-29", false),
+                ("2024-04-31", false),
+                ("2024-13-01", false),
+                ("2024-00-10", false),
+                ("2024-01-00", false),
+                ("0000-01-01", false),
+                ("2024-1-01", false),
+                ("2024-01-01T00:00:00Z", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn date_time_follows_rfc_3339() {
+        assert_format(
+            "date-time",
+            &[
+                ("2023-05-17T08:30:00Z", true),
+                ("2023-05-17t08:30:00z", true),
+                ("2023-05-17T08:30:00.123456789+02:00", true),
+                ("2023-05-17T08:30:00-05:30", true),
+                ("2016-12-31T23:59:60Z", true),
+                ("2023-05-17T08:30:00", false),
+                ("2023-05-17T08:30Z", false),
+                ("2023-05-17T24:00:00Z", false),
+                ("2023-05-17T08:30:00.Z", false),
+                ("2023-05-17T08:30:00+2:00", false),
+                ("2023-02-30T08:30:00Z", false),
+                ("2023-05-17 08:30:00Z", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn email_has_a_local_part_and_a_dotted_domain() {
+        assert_format(
+            "email",
+            &[
+                ("jane@example.com", true),
+                ("jane+news@mail.example.com", true),
+                ("@example.com", false),
+                ("jane@localhost", false),
+                ("jane@.example.com", false),
+                ("jane@example.com.", false),
+                ("jane doe@example.com", false),
+                ("jane", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn uri_is_absolute() {
+        assert_format(
+            "uri",
+            &[
+                ("https://example.com/a?b=c", true),
+                ("mailto:jane@example.com", true),
+                ("/relative/path", false),
+                ("example.com", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn uuid_is_five_groups_of_hex_digits() {
+        assert_format(
+            "uuid",
+            &[
+                ("123e4567-e89b-12d3-a456-426614174000", true),
+                ("123E4567-E89B-12D3-A456-426614174000", true),
+                ("123e4567e89b12d3a456426614174000", false),
+                ("123e4567-e89b-12d3-a456-42661417400g", false),
+                ("123e4567-e89b-12d3-a456-4266141740000", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn formats_leave_other_types_alone() {
+        assert_table(&[(json!({ "format": "email" }), json!(42), true)]);
+    }
+
+    #[test]
+    fn violations_point_to_the_offending_values() {
+        let schema = Schema::compile(&json!({
+            "properties": {
+                "a/b": { "items": { "type": "integer" } },
+                "name": { "minLength": 3 },
+            },
+            "required": ["name", "email"],
+        }))
+        .unwrap();
+        let violations: Vec<_> = schema
+            .validate(&json!({ "a/b": [1, "2"], "name": "J" }))
+            .into_iter()
+            .map(|violation| (violation.pointer, violation.message))
+            .collect();
+        assert_eq!(
+            violations,
+            [
+                ("/a~1b/1".to_string(), "must be of type integer".to_string()),
+                (
+                    "/name".to_string(),
+                    "must be at least 3 characters long".to_string()
+                ),
+                ("/email".to_string(), "is required".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_schemas_name_the_keyword() {
+        for (schema, error) in [
+            (json!([]), "# must be an object or a boolean"),
+            (json!({ "type": "text" }), "#/type: unknown type text"),
+            (json!({ "nullable": "yes" }), "#/nullable must be a boolean"),
+            (
+                json!({ "minLength": -1 }),
+                "#/minLength must be a non-negative integer",
+            ),
+            (
+                json!({ "multipleOf": 0 }),
+                "#/multipleOf must be greater than 0",
+            ),
+            (
+                json!({ "format": "ipv4" }),
+                "#/format: unsupported format ipv4",
+            ),
+            (json!({ "pattern": 1 }), "#/pattern must be a string"),
+            (
+                json!({ "anyOf": [] }),
+                "#/anyOf must be a non-empty array of schemas",
+            ),
+            (
+                json!({ "properties": { "a": { "$ref": "#/b" } } }),
+                "#/properties/a: unsupported keyword $ref",
+            ),
+        ] {
+            assert_eq!(
+                Schema::compile(&schema).err().as_deref(),
+                Some(error),
+                "{}",
+                schema
+            );
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{FromRequest, HttpRequest, HttpResponse};
+use futures_util::{future, stream, Stream, StreamExt};
+use log::error;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::future::{ready, Ready};
+
+use crate::db::DbError;
+use crate::fallback;
+
+pub const MEDIA_TYPE: &str = "application/vnd.api+json";
+
+// Response representation negotiated from the Accept header: plain JSON by
+// default, JSON:API documents when the client asks for them
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    JsonApi,
+}
+
+impl Format {
+    pub fn of(req: &HttpRequest) -> Format {
+        let accepts_jsonapi = req
+            .headers()
+            .get_all(header::ACCEPT)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media| media.trim().starts_with(MEDIA_TYPE));
+        if accepts_jsonapi {
+            Format::JsonApi
+        } else {
+            Format::Json
+        }
+    }
+
+    // `body` is a resource or a list of resources of `resource_type`
+    pub fn respond<T: Serialize>(
+        &self,
+        status: StatusCode,
+        resource_type: &str,
+        body: &T,
+    ) -> HttpResponse {
+        match self {
+            Format::Json => HttpResponse::build(status).json(body),
+            Format::JsonApi => {
+                let data = match serde_json::to_value(body) {
+                    Ok(Value::Array(items)) => Value::Array(
+                        items
+                            .into_iter()
+                            .map(|item| resource(resource_type, item))
+                            .collect(),
+                    ),
+                    Ok(item) => resource(resource_type, item),
+                    Err(e) => return self.error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+                };
+                HttpResponse::build(status)
+                    .content_type(MEDIA_TYPE)
+                    .json(json!({ "data": data }))
+            }
+        }
+    }
+
+    // A page of a list with links to the other pages: `_links` next to the
+    // items in plain JSON, top-level `links` in JSON:API
+    pub fn respond_page<T: Serialize>(
+        &self,
+        status: StatusCode,
+        resource_type: &str,
+        items: &[T],
+        links: Value,
+    ) -> HttpResponse {
+        let items = match serde_json::to_value(items) {
+            Ok(Value::Array(items)) => items,
+            Ok(_) => Vec::new(),
+            Err(e) => return self.error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        };
+        match self {
+            Format::Json => HttpResponse::build(status).json(json!({
+                resource_type: items,
+                "_links": links,
+            })),
+            Format::JsonApi => {
+                let data: Vec<_> = items
+                    .into_iter()
+                    .map(|item| resource(resource_type, item))
+                    .collect();
+                HttpResponse::build(status)
+                    .content_type(MEDIA_TYPE)
+                    .json(json!({ "data": data, "links": links }))
+            }
+        }
+    }
+
+    // Resources looked up by id: keyed by id in plain JSON, next to the ids
+    // that matched none, `missing`; a list with `missing` in the `meta` in
+    // JSON:API
+    pub fn respond_keyed(
+        &self,
+        status: StatusCode,
+        resource_type: &str,
+        items: Vec<(i64, Value)>,
+        missing: &[i64],
+    ) -> HttpResponse {
+        match self {
+            Format::Json => {
+                let items: Map<String, Value> = items
+                    .into_iter()
+                    .map(|(id, item)| (id.to_string(), item))
+                    .collect();
+                HttpResponse::build(status).json(json!({
+                    resource_type: items,
+                    "missing": missing,
+                }))
+            }
+            Format::JsonApi => {
+                let data: Vec<_> = items
+                    .into_iter()
+                    .map(|(id, item)| {
+                        let mut resource = resource(resource_type, item);
+                        // even when `?fields=` left the id out
+                        resource["id"] = Value::String(id.to_string());
+                        resource
+                    })
+                    .collect();
+                HttpResponse::build(status)
+                    .content_type(MEDIA_TYPE)
+                    .json(json!({ "data": data, "meta": { "missing": missing } }))
+            }
+        }
+    }
+
+    // Streamed counterpart of `respond` for lists, written item by item. An
+    // error once the response has started can only cut it short.
+    pub fn respond_stream<S, E>(
+        &self,
+        status: StatusCode,
+        resource_type: &'static str,
+        items: S,
+    ) -> HttpResponse
+    where
+        S: Stream<Item = Result<Value, E>> + 'static,
+        E: std::error::Error + 'static,
+    {
+        let format = *self;
+        let (open, close, content_type) = match format {
+            Format::Json => ("[", "]", "application/json"),
+            Format::JsonApi => ("{\"data\":[", "]}", MEDIA_TYPE),
+        };
+        let items = items.enumerate().map(move |(index, item)| {
+            let item = match item {
+                Ok(item) if format == Format::JsonApi => resource(resource_type, item),
+                Ok(item) => item,
+                Err(e) => {
+                    error!("Streamed response cut short: {}", e);
+                    return Err(Box::<dyn std::error::Error>::from(e.to_string()));
+                }
+            };
+            let mut chunk = if index == 0 {
+                Vec::new()
+            } else {
+                b",".to_vec()
+            };
+            chunk.extend(serde_json::to_vec(&item)?);
+            Ok(Bytes::from(chunk))
+        });
+        let body = stream::once(future::ready(Ok(Bytes::from_static(open.as_bytes()))))
+            .chain(items)
+            .chain(stream::once(future::ready(Ok(Bytes::from_static(
+                close.as_bytes(),
+            )))));
+        HttpResponse::build(status)
+            .content_type(content_type)
+            .streaming(body)
+    }
+
+    // Problem details in plain JSON, see fallback::respond
+    pub fn error(&self, status: StatusCode, message: &str) -> HttpResponse {
+        fallback::respond(*self, status, message)
+    }
+
+    // Map a database failure to a response, 503 while we are reconnecting,
+    // 504 when a statement ran out of time
+    pub fn db_error(&self, e: DbError, message: &str) -> HttpResponse {
+        match e {
+            DbError::Unavailable => {
+                self.error(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable")
+            }
+            DbError::Timeout => {
+                error!("{}: statement timeout", message);
+                self.error(StatusCode::GATEWAY_TIMEOUT, "Database query timed out")
+            }
+            DbError::Query(e) => {
+                error!("{}: {}", message, e);
+                self.error(StatusCode::INTERNAL_SERVER_ERROR, message)
+            }
+        }
+    }
+}
+
+// A JSON:API error document of one error
+pub fn error_document(status: StatusCode, detail: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type(MEDIA_TYPE)
+        .json(json!({
+            "errors": [{
+                "status": status.as_str(),
+                "title": status.canonical_reason().unwrap_or_default(),
+                "detail": detail,
+            }]
+        }))
+}
+
+impl FromRequest for Format {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(Format::of(req)))
+    }
+}
+
+// Members of a resource type that hold the id of another resource, as
+// `(resource type, member, type of the resource it points to)`
+const RELATIONSHIPS: [(&str, &str, &str); 1] = [("users", "merged_into", "users")];
+
+// Move the `id` out of the object, its `_links` to the resource `links` and
+// the ids of other resources to its `relationships`, everything else becomes
+// attributes
+fn resource(resource_type: &str, item: Value) -> Value {
+    match item {
+        Value::Object(mut attributes) => {
+            let mut resource = Map::new();
+            resource.insert("type".to_string(), Value::from(resource_type));
+            match attributes.remove("id") {
+                Some(Value::Null) | None => {}
+                Some(Value::String(id)) => {
+                    resource.insert("id".to_string(), Value::String(id));
+                }
+                // identifiers are always strings in JSON:API
+                Some(id) => {
+                    resource.insert("id".to_string(), Value::String(id.to_string()));
+                }
+            }
+            let links = attributes.remove("_links");
+            let relationships: Map<String, Value> = RELATIONSHIPS
+                .iter()
+                .filter(|(of, _, _)| *of == resource_type)
+                .filter_map(|(_, member, related_type)| {
+                    let data = match attributes.remove(*member)? {
+                        Value::Null => Value::Null,
+                        Value::String(id) => json!({ "type": related_type, "id": id }),
+                        id => json!({ "type": related_type, "id": id.to_string() }),
+                    };
+                    Some((member.to_string(), json!({ "data": data })))
+                })
+                .collect();
+            resource.insert("attributes".to_string(), Value::Object(attributes));
+            if !relationships.is_empty() {
+                resource.insert("relationships".to_string(), Value::Object(relationships));
+            }
+            if let Some(Value::Object(links)) = links {
+                let links = links
+                    .into_iter()
+                    .map(|(rel, link)| (rel, link_object(link)))
+                    .collect();
+                resource.insert("links".to_string(), Value::Object(links));
+            }
+            Value::Object(resource)
+        }
+        other => other,
+    }
+}
+
+// JSON:API link objects have no `method` member, it goes to their `meta`
+fn link_object(link: Value) -> Value {
+    match link {
+        Value::Object(mut link) => {
+            if let Some(method) = link.remove("method") {
+                link.insert("meta".to_string(), json!({ "method": method }));
+            }
+            Value::Object(link)
+        }
+        other => other,
+    }
+}
@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::outbox::Publisher;
+use crate::repository::OutboxMessage;
+
+// How long a message may wait in the producer queue
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Publishes outbox messages to a Kafka topic, keyed by resource id
+pub struct KafkaPublisher {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl KafkaPublisher {
+    pub fn new(brokers: &str, config: &Config) -> Result<KafkaPublisher, KafkaError> {
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", brokers)
+            .set("enable.idempotence", "true")
+            .set("message.timeout.ms", "30000");
+        // KAFKA_PROPERTIES overrides, e.g. security.protocol=SASL_SSL
+        for (key, value) in &config.kafka_properties {
+            client_config.set(key, value);
+        }
+        Ok(KafkaPublisher {
+            producer: client_config.create()?,
+            topic: config.kafka_topic.clone(),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Publisher for KafkaPublisher {
+    async fn publish(&self, message: &OutboxMessage) -> Result<(), String> {
+        let payload = message.payload.to_string();
+        let key = message.key.clone().unwrap_or_default();
+        let headers = OwnedHeaders::new().insert(Header {
+            key: "event",
+            value: Some(message.event.as_str()),
+        });
+        let record = FutureRecord::to(&self.topic)
+            .key(&key)
+            .payload(&payload)
+            .headers(headers);
+        self.producer
+            .send(record, QUEUE_TIMEOUT)
+            .await
+            .map(|_| ())
+            .map_err(|(e, _)| e.to_string())
+    }
+}
@@ -0,0 +1,104 @@
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::field_policy::{self, Caller};
+
+// Names given to the routes the links point to, in their route macros
+const USER: &str = "user";
+const USERS: &str = "users";
+const UPDATE_USER: &str = "update_user";
+const DELETE_USER: &str = "delete_user";
+const USERS_PAGE: &str = "users_page";
+
+// Hypermedia links of a response, resolved from the route names with
+// `url_for` so that they follow the route configuration. They are paths,
+// relative to the host the request was sent to. Every user sent goes through
+// them, for the field policy to apply to the caller.
+pub struct Links {
+    req: HttpRequest,
+    caller: Caller,
+}
+
+impl Links {
+    fn href(&self, name: &str, elements: &[String]) -> Option<String> {
+        self.req
+            .url_for(name, elements)
+            .ok()
+            .map(|url| url.path().to_string())
+    }
+
+    fn link(&self, name: &str, elements: &[String], method: Option<&str>) -> Option<Value> {
+        let href = self.href(name, elements)?;
+        Some(match method {
+            Some(method) => json!({ "href": href, "method": method }),
+            None => json!({ "href": href }),
+        })
+    }
+
+    pub fn user(&self, id: i64) -> Value {
+        let id = [id.to_string()];
+        let links = [
+            ("self", self.link(USER, &id, None)),
+            ("collection", self.link(USERS, &[], None)),
+            ("update", self.link(UPDATE_USER, &id, Some("PUT"))),
+            ("delete", self.link(DELETE_USER, &id, Some("DELETE"))),
+        ];
+        Value::Object(
+            links
+                .into_iter()
+                .filter_map(|(rel, link)| Some((rel.to_string(), link?)))
+                .collect(),
+        )
+    }
+
+    // The user as the caller may see it, see field_policy.rs, with its
+    // `_links` when its id is known
+    pub fn with_user<T: Serialize>(&self, user: &T) -> Value {
+        self.attach(json!(user))
+    }
+
+    pub fn attach(&self, mut user: Value) -> Value {
+        let id = user.get("id").and_then(Value::as_i64);
+        if let Value::Object(fields) = &mut user {
+            field_policy::restrict(&self.req, self.caller, fields);
+            if let Some(id) = id {
+                fields.insert("_links".to_string(), self.user(id));
+            }
+        }
+        user
+    }
+
+    // `self` and, when `next` is the query of the next page, `next`
+    pub fn page(&self, next: Option<String>) -> Value {
+        let mut links = Map::new();
+        let current = match self.req.query_string() {
+            "" => self.req.path().to_string(),
+            query => format!("{}?{}", self.req.path(), query),
+        };
+        links.insert("self".to_string(), json!({ "href": current }));
+        if let (Some(query), Some(path)) = (next, self.href(USERS_PAGE, &[])) {
+            let href = format!("{}?{}", path, query);
+            links.insert("next".to_string(), json!({ "href": href }));
+        }
+        Value::Object(links)
+    }
+}
+
+impl FromRequest for Links {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let caller = Caller::from_request(&req, payload);
+        Box::pin(async move {
+            Ok(Links {
+                caller: caller.await?,
+                req,
+            })
+        })
+    }
+}
@@ -0,0 +1,98 @@
+use log::info;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+// Where the server accepts connections, from LISTEN:
+// `host:port`, `unix:/path/to.sock` or `systemd`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Listen {
+    Tcp(String),
+    Unix(PathBuf),
+    // sockets passed by systemd socket activation
+    Systemd,
+}
+
+impl Listen {
+    pub fn parse(value: &str) -> Listen {
+        if value == "systemd" {
+            Listen::Systemd
+        } else if let Some(path) = value.strip_prefix("unix:") {
+            Listen::Unix(PathBuf::from(path))
+        } else {
+            Listen::Tcp(value.to_string())
+        }
+    }
+}
+
+// A socket inherited from systemd
+pub enum Inherited {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+// The first file descriptor systemd passes, SD_LISTEN_FDS_START
+const LISTEN_FDS_START: RawFd = 3;
+
+// Sockets listed by LISTEN_FDS, when LISTEN_PID is this process. The
+// variables are removed so that child processes don't take them as theirs.
+pub fn systemd_sockets() -> io::Result<Vec<Inherited>> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    if pid != Some(process::id()) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "LISTEN=systemd but no socket was passed to this process",
+        ));
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    if count == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "LISTEN_FDS passes no socket",
+        ));
+    }
+    let sockets = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // systemd hands over these descriptors, nothing else owns them
+            let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+            // only internet sockets have an address std understands
+            match tcp.local_addr() {
+                Ok(addr) => {
+                    info!("Listening on {} passed by systemd", addr);
+                    Inherited::Tcp(tcp)
+                }
+                Err(_) => {
+                    info!("Listening on a Unix socket passed by systemd");
+                    Inherited::Unix(unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) })
+                }
+            }
+        })
+        .collect();
+    Ok(sockets)
+}
+
+// Remove the socket left behind by a previous run, binding fails otherwise.
+// Anything that is not a socket is left alone.
+pub fn clear_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
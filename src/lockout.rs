@@ -0,0 +1,96 @@
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpResponse, Responder};
+use log::info;
+use serde_json::json;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::admin::Admin;
+use crate::db::{Cluster, DbError};
+use crate::jsonapi::Format;
+use crate::links::Links;
+use crate::models::User;
+use crate::tenant::Tenant;
+
+// Lockout events since the server started
+#[derive(Default)]
+pub struct LockoutMetrics {
+    accounts_locked: AtomicU64,
+    addresses_throttled: AtomicU64,
+    unlocks: AtomicU64,
+}
+
+impl LockoutMetrics {
+    pub fn account_locked(&self) {
+        self.accounts_locked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn address_throttled(&self) {
+        self.addresses_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn unlocked(&self) {
+        self.unlocks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// 429 telling the client when to come back
+pub fn too_many_attempts(format: Format, retry_after: u64) -> HttpResponse {
+    let mut response = format.error(
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many failed logins, try again later",
+    );
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
+
+// Lift a lockout before it ends and forget the failures of the account
+#[post("/users/{id}/unlock")]
+async fn unlock_user(
+    _admin: Admin,
+    path: web::Path<i64>,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    metrics: web::Data<LockoutMetrics>,
+    links: Links,
+) -> impl Responder {
+    let id = path.into_inner();
+    let result: Result<Option<User>, DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let user = match uow.users().unlock(id).await? {
+                    Some(user) => user,
+                    None => return Ok(None),
+                };
+                uow.login_failures().clear(&user.email).await?;
+                Ok(Some(user))
+            })
+        })
+        .await;
+    match result {
+        Ok(Some(user)) => {
+            info!("User {} unlocked", id);
+            metrics.unlocked();
+            format.respond(StatusCode::OK, crate::USERS, &links.with_user(&user))
+        }
+        Ok(None) => format.error(StatusCode::NOT_FOUND, &format!("User {} not found", id)),
+        Err(e) => format.db_error(e, &format!("Failed to unlock user {}", id)),
+    }
+}
+
+#[get("/admin/lockouts")]
+async fn get_lockout_metrics(_admin: Admin, metrics: web::Data<LockoutMetrics>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "accounts_locked": metrics.accounts_locked.load(Ordering::Relaxed),
+        "addresses_throttled": metrics.addresses_throttled.load(Ordering::Relaxed),
+        "unlocks": metrics.unlocks.load(Ordering::Relaxed),
+    }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(unlock_user).service(get_lockout_metrics);
+}
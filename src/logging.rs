@@ -0,0 +1,60 @@
+use env_logger::filter::{Builder as FilterBuilder, Filter};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::env;
+use std::sync::RwLock;
+
+use crate::redact::Redactor;
+
+// env_logger behind a filter that can be replaced while the server runs
+pub struct ReloadableLogger {
+    inner: env_logger::Logger,
+    filter: RwLock<Filter>,
+}
+
+impl ReloadableLogger {
+    // `spec` uses the RUST_LOG syntax, e.g. `info,rust_crud_api=debug`
+    pub fn set_filter(&self, spec: &str) {
+        let filter = FilterBuilder::new().parse(spec).build();
+        log::set_max_level(filter.filter());
+        *self.filter.write().unwrap() = filter;
+    }
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.read().unwrap().matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Filtered by RUST_LOG until told otherwise, `info` without it
+pub fn init(redactor: Option<Redactor>) -> &'static ReloadableLogger {
+    let mut builder = env_logger::Builder::new();
+    // everything goes through, the filter decides
+    builder
+        .filter_level(LevelFilter::Trace)
+        .format_timestamp(None)
+        .format_module_path(false);
+    if let Some(redactor) = redactor {
+        redactor.install(&mut builder);
+    }
+    let spec = env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
+    let filter = FilterBuilder::new().parse(&spec).build();
+    let max_level = filter.filter();
+    let logger: &'static ReloadableLogger = Box::leak(Box::new(ReloadableLogger {
+        inner: builder.build(),
+        filter: RwLock::new(filter),
+    }));
+    log::set_logger(logger).expect("Logger already initialized");
+    log::set_max_level(max_level);
+    logger
+}
@@ -0,0 +1,110 @@
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::info;
+#[cfg(test)]
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::config::Config;
+
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait(?Send)]
+pub trait Mailer {
+    async fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+// SMTP when SMTP_HOST is set, the log otherwise
+pub fn from_config(config: &Config) -> Result<Rc<dyn Mailer>, String> {
+    match &config.smtp_host {
+        Some(host) => {
+            info!("Sending emails through {}:{}", host, config.smtp_port);
+            Ok(Rc::new(SmtpMailer::new(host, config)?))
+        }
+        None => {
+            info!("SMTP_HOST is not set, emails are written to the log");
+            Ok(Rc::new(LogMailer))
+        }
+    }
+}
+
+// Stands in for SMTP in development: logs the emails instead of sending
+// them, but for their bodies, which hold the verification and reset tokens
+pub struct LogMailer;
+
+#[async_trait(?Send)]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        info!("Email to {}: {}", email.to, email.subject);
+        Ok(())
+    }
+}
+
+// Keeps the emails instead of sending them, for the tests to look at
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingMailer {
+    pub sent: RefCell<Vec<Email>>,
+}
+
+#[cfg(test)]
+#[async_trait(?Send)]
+impl Mailer for RecordingMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        self.sent.borrow_mut().push(email.clone());
+        Ok(())
+    }
+}
+
+// Submits emails to a relay with STARTTLS
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, config: &Config) -> Result<SmtpMailer, String> {
+        let from = config
+            .mail_from
+            .parse()
+            .map_err(|e| format!("Invalid MAIL_FROM {}: {}", config.mail_from, e))?;
+        let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(|e| e.to_string())?
+            .port(config.smtp_port);
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(SmtpMailer {
+            transport: transport.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        let to = email
+            .to
+            .parse()
+            .map_err(|e| format!("Invalid recipient {}: {}", email.to, e))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject.as_str())
+            .body(email.body.clone())
+            .map_err(|e| e.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
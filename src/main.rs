@@ -1,202 +1,1725 @@
-use actix_web::middleware::Logger;
-use actix_web::{delete, get, post, put, web, App, HttpResponse, HttpServer, Responder, Result};
-use env_logger::Env;
-use log::info;
-use std::sync::{Arc, Mutex};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::{Condition, Logger};
+use actix_web::{
+    delete, get, post, put, route, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+    Result,
+};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_postgres::Row;
 
-use tokio_postgres::{Client, NoTls};
+mod admin;
+mod admin_ui;
+mod api_keys;
+mod auth;
+mod avatars;
+mod backup;
+mod bench;
+mod body_log;
+mod body_schemas;
+mod breaker;
+mod build_info;
+mod cache;
+mod changes;
+mod chaos;
+mod concurrency;
+mod config;
+mod cors;
+mod crypto;
+mod db;
+mod dry_run;
+mod duplicates;
+mod email_rules;
+#[cfg(feature = "embedded-pg")]
+mod embedded_pg;
+mod envelope;
+mod events;
+mod export;
+mod fallback;
+mod field_policy;
+mod filter;
+mod flags;
+mod i18n;
+mod ids;
+mod jobs;
+mod json_schema;
+mod jsonapi;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod links;
+mod listener;
+mod lockout;
+mod logging;
+mod mailer;
+mod maintenance;
+mod me;
+mod method_override;
+mod metrics;
+mod models;
+#[cfg(feature = "mysql")]
+mod mysql;
+#[cfg(feature = "nats")]
+mod nats;
+mod normalize;
+mod oauth;
+mod openapi;
+#[cfg(feature = "outbox-relay")]
+mod outbox;
+mod payload;
+mod pii;
+mod preconditions;
+mod quotas;
+mod redact;
+mod repository;
+mod routes;
+mod schema;
+mod secrets;
+mod security_headers;
+mod self_check;
+mod session;
+mod settings;
+mod signatures;
+mod static_site;
+mod stats;
+mod status_page;
+mod storage;
+mod streaming;
+mod tenant;
+#[cfg(test)]
+mod test_app;
+mod timeouts;
+mod timestamps;
+mod tx;
+mod verification;
+mod webhooks;
+
+use admin::Admin;
+use api_keys::ApiKeys;
+use auth::Auth;
+use body_log::BodyLogger;
+use body_schemas::{BodySchemas, BodyValidation};
+use cache::CachePolicies;
+use concurrency::ConcurrencyLimit;
+use config::Config;
+use cors::Cors;
+use db::{Cluster, Database, DbError, FromRow, RetryPolicy};
+use dry_run::{DryRun, DryRuns};
+use envelope::Envelopes;
+use events::Event;
+use export::ExportFormat;
+use field_policy::{Caller, FieldPolicy};
+use filter::Filter;
+use flags::{CursorPagination, FeatureFlags, Gate};
+use jobs::JobKind;
+use jsonapi::Format;
+use links::Links;
+use listener::{Inherited, Listen};
+use logging::ReloadableLogger;
+use maintenance::{Maintenance, Maintenances};
+use method_override::MethodOverride;
+use metrics::{HttpMetrics, QueryMetrics, ResponseMetrics};
+use models::{AccountStatus, User, UserField};
+use normalize::NormalizePath;
+use payload::JsonBodies;
+use preconditions::Preconditions;
+use quotas::{QuotaCounts, Quotas};
+use redact::Redactor;
+use security_headers::SecurityHeaders;
+use session::Csrf;
+use settings::RuntimeSettings;
+use signatures::RequestSignatures;
+use static_site::StaticSite;
+use stats::StatsCache;
+use storage::BlobStore;
+use tenant::{NamedTenant, Tenant};
+use timeouts::Timeouts;
+use verification::Claim;
 
 #[macro_use]
 extern crate serde_derive;
 
-// Mode: User struct with id, name, email
-#[derive(Serialize, Deserialize)]
-struct User {
-    id: Option<i32>,
-    name: String,
-    email: String,
-}
+// DATABASE URL, when given at build time
+const DB_URL: Option<&str> = option_env!("DATABASE_URL");
 
-// DATABASE URL
-const DB_URL: &str = env!("DATABASE_URL");
+// JSON:API resource type of users
+const USERS: &str = "users";
+
+// Number of users a listing would return
+const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
 
 // CONTROLLERS
-#[get("/users")]
-async fn get_users(db: web::Data<Arc<Mutex<Client>>>) -> impl Responder {
+#[derive(Deserialize)]
+struct FieldsQuery {
+    fields: Option<String>,
+    #[serde(rename = "fields[users]")]
+    sparse_fields: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    fields: Option<String>,
+    #[serde(rename = "fields[users]")]
+    sparse_fields: Option<String>,
+    status: Option<String>,
+    ids: Option<String>,
+}
+
+// Most ids GET /users?ids= looks up at once
+const MAX_LOOKUP_IDS: usize = 100;
+
+// Page size of GET /users/page, by default and at most
+const PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(Deserialize)]
+struct PageQuery {
+    after: Option<i64>,
+    limit: Option<i64>,
+    status: Option<String>,
+}
+
+// Columns requested with `?fields=`, or with the JSON:API sparse fieldset
+// `?fields[users]=` which always keeps the id, None when the whole user is
+// wanted
+fn parse_fields(
+    format: Format,
+    fields: &Option<String>,
+    sparse_fields: &Option<String>,
+) -> Result<Option<Vec<UserField>>, HttpResponse> {
+    let bad_request = |e: String| format.error(StatusCode::BAD_REQUEST, &e);
+    match (fields, sparse_fields) {
+        (None, None) => Ok(None),
+        (Some(list), None) => UserField::parse_list(list).map(Some).map_err(bad_request),
+        (None, Some(list)) => {
+            let mut fields = UserField::parse_list(list).map_err(bad_request)?;
+            if !fields.contains(&UserField::Id) {
+                fields.insert(0, UserField::Id);
+            }
+            Ok(Some(fields))
+        }
+        (Some(_), Some(_)) => Err(bad_request(
+            "Give either fields or fields[users]".to_string(),
+        )),
+    }
+}
+
+// Comma separated ids, without duplicates
+fn parse_ids(format: Format, list: &str) -> Result<Vec<i64>, HttpResponse> {
+    let mut ids = Vec::new();
+    for id in list.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        let id = id
+            .parse::<i64>()
+            .map_err(|_| format.error(StatusCode::BAD_REQUEST, &format!("Invalid id '{}'", id)))?;
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    if ids.is_empty() || ids.len() > MAX_LOOKUP_IDS {
+        return Err(format.error(
+            StatusCode::BAD_REQUEST,
+            &format!("Give between 1 and {} ids", MAX_LOOKUP_IDS),
+        ));
+    }
+    Ok(ids)
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+    fields: Option<String>,
+    #[serde(rename = "fields[users]")]
+    sparse_fields: Option<String>,
+    status: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UpsertQuery {
+    key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StatusQuery {
+    status: Option<String>,
+}
+
+// Status filter of a listing, active users unless asked otherwise
+fn parse_status(format: Format, status: &Option<String>) -> Result<AccountStatus, HttpResponse> {
+    match status {
+        None => Ok(AccountStatus::Active),
+        Some(name) => AccountStatus::parse(name).ok_or_else(|| {
+            format.error(
+                StatusCode::BAD_REQUEST,
+                &format!("Unknown status '{}'", name),
+            )
+        }),
+    }
+}
+
+// The id of a /users/{id} path, which can't name a user unless it's a number
+fn parse_id(format: Format, path: &str) -> Result<i64, HttpResponse> {
+    path.parse::<i64>()
+        .map_err(|_| format.error(StatusCode::NOT_FOUND, &format!("User {} not found", path)))
+}
+
+// Hash of the password set with the user, if any
+async fn password_hash(
+    format: Format,
+    password: Option<&str>,
+) -> Result<Option<String>, HttpResponse> {
+    let password = match password {
+        Some(password) => password.to_string(),
+        None => return Ok(None),
+    };
+    auth::check_password(&password).map_err(|e| format.error(StatusCode::BAD_REQUEST, &e))?;
+    auth::hash_password(password).await.map(Some).map_err(|e| {
+        log::error!("Failed to hash password: {}", e);
+        format.error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password")
+    })
+}
+
+// Hash of the password of a /users body. POST /users is open to any caller
+// and PUT /users/{id} to the user, so only admins may set a password through
+// them: the users change theirs with PATCH /me or a password reset.
+async fn admin_password_hash(
+    format: Format,
+    admin: &Option<Admin>,
+    password: Option<&str>,
+) -> Result<Option<String>, HttpResponse> {
+    if password.is_some() && admin.is_none() {
+        return Err(format.error(
+            StatusCode::FORBIDDEN,
+            "Only admins can set a password here, use PATCH /me",
+        ));
+    }
+    password_hash(format, password).await
+}
+
+#[get("/users", name = "users")]
+async fn get_users(
+    query: web::Query<ListQuery>,
+    filter: Filter<User>,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+    links: Links,
+) -> impl Responder {
+    let fields = match parse_fields(format, &query.fields, &query.sparse_fields) {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    if let Some(ids) = &query.ids {
+        let ids = match parse_ids(format, ids) {
+            Ok(ids) => ids,
+            Err(response) => return response,
+        };
+        return lookup_users(format, &tenant, &db, &links, &ids, fields).await;
+    }
     info!("Retrieving list of users");
-    let client = db.lock().unwrap();
-    let mut users = Vec::new();
-    for row in client.query("SELECT * from users", &[]).await.unwrap() {
-        users.push(User {
-            id: row.get(0),
-            name: row.get(1),
-            email: row.get(2),
-        });
+    let status = match parse_status(format, &query.status) {
+        Ok(status) => status,
+        Err(response) => return response,
+    };
+    let result: Result<HttpResponse, DbError> = async {
+        let client = db.reader().checkout().await?;
+        let rows = client
+            .stream_users(&tenant, fields.as_deref(), status, &filter)
+            .await?;
+        let threshold = config.stream_threshold;
+        let response = match fields {
+            None => {
+                let to_value = move |row: Row| Ok(links.with_user(&User::from_row(&row)?));
+                streaming::respond_list(format, USERS, client, rows, threshold, to_value).await?
+            }
+            Some(fields) => {
+                let to_value = move |row: Row| {
+                    Ok(links.attach(Value::Object(UserField::partial_from_row(&fields, &row)?)))
+                };
+                streaming::respond_list(format, USERS, client, rows, threshold, to_value).await?
+            }
+        };
+        Ok(response)
+    }
+    .await;
+    result.unwrap_or_else(|e| format.db_error(e, "Failed to retrieve users"))
+}
+
+// GET /users?ids=: the users with these ids whatever their status, found
+// in one query, and the ids that matched none
+async fn lookup_users(
+    format: Format,
+    tenant: &Tenant,
+    db: &Cluster,
+    links: &Links,
+    ids: &[i64],
+    fields: Option<Vec<UserField>>,
+) -> HttpResponse {
+    info!("Retrieving {} users by id", ids.len());
+    let fields = &fields;
+    let result: Result<Vec<(i64, Value)>, DbError> = db
+        .reader()
+        .transaction(tenant, |uow| {
+            Box::pin(async move {
+                Ok(match &fields {
+                    None => uow
+                        .users()
+                        .find_many(ids)
+                        .await?
+                        .iter()
+                        .map(|user| (user.id.unwrap_or_default(), links.with_user(user)))
+                        .collect(),
+                    Some(fields) => uow
+                        .users()
+                        .find_many_fields(ids, fields)
+                        .await?
+                        .into_iter()
+                        .map(|(id, user)| (id, links.attach(Value::Object(user))))
+                        .collect(),
+                })
+            })
+        })
+        .await;
+    match result {
+        Ok(users) => {
+            let missing: Vec<i64> = ids
+                .iter()
+                .copied()
+                .filter(|id| !users.iter().any(|(found, _)| found == id))
+                .collect();
+            format.respond_keyed(StatusCode::OK, USERS, users, &missing)
+        }
+        Err(e) => format.db_error(e, "Failed to retrieve users"),
+    }
+}
+
+// Experimental keyset pagination: a page of users with an id above `after`.
+// The next page, when there may be one, is linked in the envelope and with
+// `Link: rel="next"`.
+#[get("/users/page", name = "users_page")]
+async fn get_users_page(
+    _gate: Gate<CursorPagination>,
+    query: web::Query<PageQuery>,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    links: Links,
+) -> impl Responder {
+    let status = match parse_status(format, &query.status) {
+        Ok(status) => status,
+        Err(response) => return response,
+    };
+    let limit = query.limit.unwrap_or(PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let after = query.after.unwrap_or(0);
+    let result: Result<Vec<User>, DbError> = db
+        .reader()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move { Ok(uow.users().page(status, after, limit).await?) })
+        })
+        .await;
+    let users = match result {
+        Ok(users) => users,
+        Err(e) => return format.db_error(e, "Failed to retrieve users"),
+    };
+    let last = users.last().and_then(|user| user.id);
+    let next = last
+        .filter(|_| users.len() as i64 == limit)
+        .map(|last| format!("after={}&limit={}&status={}", last, limit, status.name()));
+    let page = links.page(next);
+    let users: Vec<_> = users.iter().map(|user| links.with_user(user)).collect();
+    let mut response = format.respond_page(StatusCode::OK, USERS, &users, page.clone());
+    let next = page.pointer("/next/href").and_then(Value::as_str);
+    if let Some(next) = next {
+        if let Ok(value) = header::HeaderValue::from_str(&format!("<{}>; rel=\"next\"", next)) {
+            response.headers_mut().insert(header::LINK, value);
+        }
+    }
+    response
+}
+
+// The users of the listing, filters included, streamed one per line
+// however many there are. NDJSON by default, CSV with `?format=csv`.
+#[get("/users/export")]
+async fn export_users(
+    req: HttpRequest,
+    caller: Caller,
+    query: web::Query<ExportQuery>,
+    filter: Filter<User>,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let export = match query.format.as_deref() {
+        None => ExportFormat::Ndjson,
+        Some(name) => match ExportFormat::parse(name) {
+            Some(export) => export,
+            None => {
+                return format.error(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Unknown export format '{}', use ndjson or csv", name),
+                )
+            }
+        },
+    };
+    let fields = match parse_fields(format, &query.fields, &query.sparse_fields) {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    let status = match parse_status(format, &query.status) {
+        Ok(status) => status,
+        Err(response) => return response,
+    };
+    info!("Exporting users");
+    let result: Result<HttpResponse, DbError> = async {
+        let client = db.reader().checkout().await?;
+        let rows = client
+            .stream_users(&tenant, fields.as_deref(), status, &filter)
+            .await?;
+        let columns = export::columns(fields.as_deref());
+        let preamble = export.preamble(&columns);
+        let to_line = move |row: Row| {
+            let mut user = match &fields {
+                None => match serde_json::to_value(User::from_row(&row)?) {
+                    Ok(Value::Object(user)) => user,
+                    _ => Default::default(),
+                },
+                Some(fields) => UserField::partial_from_row(fields, &row)?,
+            };
+            field_policy::restrict(&req, caller, &mut user);
+            Ok(export.line(&columns, &user))
+        };
+        Ok(streaming::respond_lines(
+            export.content_type(),
+            export.filename(),
+            client,
+            rows,
+            preamble,
+            to_line,
+        ))
+    }
+    .await;
+    result.unwrap_or_else(|e| format.db_error(e, "Failed to export users"))
+}
+
+async fn count(
+    tenant: &Tenant,
+    db: &Cluster,
+    status: AccountStatus,
+    filter: &Filter<User>,
+) -> Result<i64, DbError> {
+    db.reader()
+        .transaction(tenant, |uow| {
+            Box::pin(async move { Ok(uow.users().count(status, filter).await?) })
+        })
+        .await
+}
+
+#[get("/users/count")]
+async fn count_users(
+    query: web::Query<StatusQuery>,
+    filter: Filter<User>,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let status = match parse_status(format, &query.status) {
+        Ok(status) => status,
+        Err(response) => return response,
+    };
+    match count(&tenant, &db, status, &filter).await {
+        Ok(count) => {
+            let body = match format {
+                Format::Json => json!({ "count": count }),
+                Format::JsonApi => json!({ "meta": { "count": count } }),
+            };
+            let content_type = match format {
+                Format::Json => "application/json",
+                Format::JsonApi => jsonapi::MEDIA_TYPE,
+            };
+            HttpResponse::Ok()
+                .content_type(content_type)
+                .insert_header((TOTAL_COUNT_HEADER, count))
+                .body(body.to_string())
+        }
+        Err(e) => format.db_error(e, "Failed to count users"),
     }
+}
 
-    HttpResponse::Ok().json(users)
+// The size of the listing without the listing itself
+#[route("/users", method = "HEAD")]
+async fn head_users(
+    query: web::Query<StatusQuery>,
+    filter: Filter<User>,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let status = match parse_status(format, &query.status) {
+        Ok(status) => status,
+        Err(response) => return response,
+    };
+    match count(&tenant, &db, status, &filter).await {
+        Ok(count) => HttpResponse::Ok()
+            .insert_header((TOTAL_COUNT_HEADER, count))
+            .finish(),
+        Err(e) => format.db_error(e, "Failed to count users"),
+    }
 }
 
 #[post("/users")]
-async fn create_user(body: web::Json<User>, db: web::Data<Arc<Mutex<Client>>>) -> impl Responder {
+async fn create_user(
+    admin: Option<Admin>,
+    body: web::Json<User>,
+    format: Format,
+    NamedTenant(tenant): NamedTenant,
+    dry_run: DryRun,
+    db: web::Data<Cluster>,
+    links: Links,
+) -> impl Responder {
     info!("Create an user");
-    let client = db.lock().unwrap();
     let user = body.into_inner();
-    let result = client
-        .query_one(
-            "INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id",
-            &[&user.name, &user.email],
-        )
+    let password_hash = match admin_password_hash(format, &admin, user.password.as_deref()).await {
+        Ok(hash) => hash,
+        Err(response) => return response,
+    };
+    let (user, password_hash) = (&user, &password_hash);
+    // None when another user has the address
+    let result: Result<Option<User>, DbError> = db
+        .primary()
+        .transaction_or_dry_run(&tenant, dry_run, |uow| {
+            Box::pin(async move {
+                if uow.users().email_taken(&user.email, None).await? {
+                    return Ok(None);
+                }
+                let user = uow.users().create(user, password_hash.as_deref()).await?;
+                uow.publish(&Event::user_created(&user)).await?;
+                uow.jobs()
+                    .enqueue(JobKind::VerificationEmail, &jobs::for_user(&user))
+                    .await?;
+                Ok(Some(user))
+            })
+        })
         .await;
-    if result.is_ok() {
-        let id: i32 = result.unwrap().get(0);
-        info!("New id: {}", id);
-        let user = User {
-            id: Some(id),
-            name: user.name,
-            email: user.email,
-        };
-        HttpResponse::Created().json(user)
-    } else {
-        HttpResponse::InternalServerError().body("Failed to insert into DB")
+    match result {
+        Ok(Some(user)) => {
+            info!("New id: {}", user.id.unwrap_or_default());
+            format.respond(StatusCode::CREATED, USERS, &links.with_user(&user))
+        }
+        Ok(None) => email_taken(format),
+        Err(e) => format.db_error(e, "Failed to insert into DB"),
     }
 }
 
-#[get("/users/{id}")]
-async fn get_user(path: web::Path<String>, db: web::Data<Arc<Mutex<Client>>>) -> impl Responder {
-    let client = db.lock().unwrap();
-    let path = path.into_inner();
-    let id = path.parse::<i32>();
-    if id.is_err() {
-        return HttpResponse::InternalServerError().body(format!("Can't parse {} as an id", path));
+// Create or update the user with the email of the body, for sync jobs that
+// may send the same user twice: 201 when it was created, 200 when it
+// existed. Sending it unchanged writes nothing.
+#[put("/users")]
+#[allow(clippy::too_many_arguments)]
+async fn upsert_user(
+    _admin: Admin,
+    query: web::Query<UpsertQuery>,
+    body: web::Json<User>,
+    format: Format,
+    tenant: Tenant,
+    dry_run: DryRun,
+    db: web::Data<Cluster>,
+    links: Links,
+) -> impl Responder {
+    if query.key.as_deref() != Some("email") {
+        return format.error(
+            StatusCode::BAD_REQUEST,
+            "Users are upserted by email, use ?key=email",
+        );
+    }
+    let user = body.into_inner();
+    let password_hash = match password_hash(format, user.password.as_deref()).await {
+        Ok(hash) => hash,
+        Err(response) => return response,
+    };
+    let (user, password_hash) = (&user, &password_hash);
+    let result: Result<(User, bool), DbError> = db
+        .primary()
+        .transaction_or_dry_run(&tenant, dry_run, |uow| {
+            Box::pin(async move {
+                let previous = uow.users().find_by_email_for_update(&user.email).await?;
+                let (user, created) = match previous {
+                    Some(previous) if previous.name == user.name && password_hash.is_none() => {
+                        return Ok((previous, false));
+                    }
+                    Some(previous) => {
+                        let id = previous.id.unwrap_or_default();
+                        let updated = uow
+                            .users()
+                            .update(id, user, password_hash.as_deref())
+                            .await?
+                            .unwrap_or(previous);
+                        uow.publish(&Event::user_updated(&updated)).await?;
+                        // a new password signs the user out
+                        if password_hash.is_some() {
+                            uow.refresh_tokens().revoke_user(id).await?;
+                        }
+                        (updated, false)
+                    }
+                    None => {
+                        let created = uow.users().create(user, password_hash.as_deref()).await?;
+                        uow.publish(&Event::user_created(&created)).await?;
+                        uow.jobs()
+                            .enqueue(JobKind::VerificationEmail, &jobs::for_user(&created))
+                            .await?;
+                        (created, true)
+                    }
+                };
+                Ok((user, created))
+            })
+        })
+        .await;
+    match result {
+        Ok((user, created)) => {
+            let status = if created {
+                info!("Upserted new user {}", user.id.unwrap_or_default());
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            };
+            preconditions::last_modified(
+                format.respond(status, USERS, &links.with_user(&user)),
+                user.updated_at,
+            )
+        }
+        Err(e) => format.db_error(e, "Failed to upsert user"),
     }
-    let id = id.unwrap();
+}
+
+// 304 when unchanged since If-Modified-Since
+#[get("/users/{id}", name = "user")]
+async fn get_user(
+    path: web::Path<String>,
+    query: web::Query<FieldsQuery>,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    links: Links,
+    preconditions: Preconditions,
+) -> impl Responder {
+    let id = match parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let fields = match parse_fields(format, &query.fields, &query.sparse_fields) {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
     info!("Retrieving user '{}'", id);
 
-    match client
-        .query_one("SELECT * FROM users WHERE id = $1", &[&id])
-        .await
-    {
-        Ok(row) => {
-            let user = User {
-                id: row.get(0),
-                name: row.get(1),
-                email: row.get(2),
-            };
-            HttpResponse::Ok().json(user)
+    let (fields, links) = (&fields, &links);
+    let user: Result<Option<(Value, Option<SystemTime>)>, DbError> = db
+        .reader()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                Ok(match &fields {
+                    None => uow
+                        .users()
+                        .find(id)
+                        .await?
+                        .map(|user| (links.with_user(&user), user.updated_at)),
+                    Some(fields) => {
+                        uow.users()
+                            .find_fields(id, fields)
+                            .await?
+                            .map(|(user, updated_at)| {
+                                (links.attach(Value::Object(user)), Some(updated_at))
+                            })
+                    }
+                })
+            })
+        })
+        .await;
+    match user {
+        Ok(Some((_, Some(updated_at)))) if preconditions.not_modified(updated_at) => {
+            preconditions::not_modified(updated_at)
         }
-        _ => {
+        Ok(Some((user, updated_at))) => {
+            preconditions::last_modified(format.respond(StatusCode::OK, USERS, &user), updated_at)
+        }
+        Ok(None) => {
             info!("User {} not found", id);
-            HttpResponse::NotFound().body(format!("User {} not found", id))
+            format.error(StatusCode::NOT_FOUND, &format!("User {} not found", id))
         }
+        Err(e) => format.db_error(e, &format!("Failed to retrieve user {}", id)),
     }
 }
 
-#[put("/users/{id}")]
+// Outcome of a write guarded by If-Unmodified-Since
+enum Guarded<T> {
+    Done(T),
+    NotFound,
+    // changed since, at this time
+    Modified(SystemTime),
+}
+
+// Emails are unique within a tenant once normalized
+fn email_taken(format: Format) -> HttpResponse {
+    format.error(
+        StatusCode::CONFLICT,
+        "Email address already used by another user",
+    )
+}
+
+fn modified_since(format: Format, id: i64, updated_at: SystemTime) -> HttpResponse {
+    preconditions::last_modified(
+        format.error(
+            StatusCode::PRECONDITION_FAILED,
+            &format!("User {} was modified since If-Unmodified-Since", id),
+        ),
+        Some(updated_at),
+    )
+}
+
+// 412 when changed since If-Unmodified-Since
+#[put("/users/{id}", name = "update_user")]
+#[allow(clippy::too_many_arguments)]
 async fn update_user(
+    admin: Option<Admin>,
+    auth: Option<Auth>,
     path: web::Path<String>,
     body: web::Json<User>,
-    db: web::Data<Arc<Mutex<Client>>>,
+    format: Format,
+    tenant: Tenant,
+    dry_run: DryRun,
+    db: web::Data<Cluster>,
+    links: Links,
+    preconditions: Preconditions,
 ) -> impl Responder {
-    let client = db.lock().unwrap();
-    let path = path.into_inner();
-    let mut user = body.into_inner();
-    let id = path.parse::<i32>();
-    if id.is_err() {
-        return HttpResponse::InternalServerError().body(format!("Can't parse {} as an id", path));
-    }
-    let id = id.unwrap();
-    let result = client
-        .execute(
-            "UPDATE users SET name = $1, email = $2 WHERE id = $3",
-            &[&user.name, &user.email, &id],
-        )
+    let user = body.into_inner();
+    let id = match parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if let Err(response) = auth::authorize_user(format, &admin, &auth, id, &tenant, "change it") {
+        return response;
+    }
+    let password_hash = match admin_password_hash(format, &admin, user.password.as_deref()).await {
+        Ok(hash) => hash,
+        Err(response) => return response,
+    };
+    let (user, password_hash, preconditions) = (&user, &password_hash, &preconditions);
+    // Done(None) when the new address is another user's
+    let result: Result<Guarded<Option<User>>, DbError> = db
+        .primary()
+        .transaction_or_dry_run(&tenant, dry_run, |uow| {
+            Box::pin(async move {
+                let previous = match uow.users().find_for_update(id).await? {
+                    Some(previous) => previous,
+                    None => return Ok(Guarded::NotFound),
+                };
+                if let Some(updated_at) = previous.updated_at.filter(|at| preconditions.failed(*at))
+                {
+                    return Ok(Guarded::Modified(updated_at));
+                }
+                if email_rules::normalize(&user.email) != email_rules::normalize(&previous.email)
+                    && uow.users().email_taken(&user.email, Some(id)).await?
+                {
+                    return Ok(Guarded::Done(None));
+                }
+                let user = match uow
+                    .users()
+                    .update(id, user, password_hash.as_deref())
+                    .await?
+                {
+                    Some(user) => user,
+                    None => return Ok(Guarded::NotFound),
+                };
+                uow.publish(&Event::user_updated(&user)).await?;
+                // a new password signs the user out
+                if password_hash.is_some() {
+                    uow.refresh_tokens().revoke_user(id).await?;
+                }
+                // the new address has to be verified again
+                if user.email != previous.email {
+                    uow.jobs()
+                        .enqueue(JobKind::VerificationEmail, &jobs::for_user(&user))
+                        .await?;
+                }
+                Ok(Guarded::Done(Some(user)))
+            })
+        })
         .await;
-    if result.is_ok() {
-        let result = result.unwrap();
-        if result != 0 {
-            user.id = Some(id);
-            HttpResponse::Ok().json(user)
-        } else {
-            HttpResponse::NotFound().finish()
+    match result {
+        Ok(Guarded::Done(Some(user))) => preconditions::last_modified(
+            format.respond(StatusCode::OK, USERS, &links.with_user(&user)),
+            user.updated_at,
+        ),
+        Ok(Guarded::Done(None)) => email_taken(format),
+        Ok(Guarded::NotFound) => {
+            format.error(StatusCode::NOT_FOUND, &format!("User {} not found", id))
         }
-    } else {
-        return HttpResponse::InternalServerError().body(format!("Failed to update user {}", id));
+        Ok(Guarded::Modified(updated_at)) => modified_since(format, id, updated_at),
+        Err(e) => format.db_error(e, &format!("Failed to update user {}", id)),
     }
 }
 
-#[delete("/users/{id}")]
-async fn delete_user(path: web::Path<String>, db: web::Data<Arc<Mutex<Client>>>) -> impl Responder {
-    let client = db.lock().unwrap();
-    let path = path.into_inner();
-    let id = path.parse::<i32>();
-    if id.is_err() {
-        return HttpResponse::InternalServerError().body(format!("Can't parse {} as an id", path));
+// 412 when changed since If-Unmodified-Since
+#[delete("/users/{id}", name = "delete_user")]
+#[allow(clippy::too_many_arguments)]
+async fn delete_user(
+    admin: Option<Admin>,
+    auth: Option<Auth>,
+    path: web::Path<String>,
+    format: Format,
+    tenant: Tenant,
+    dry_run: DryRun,
+    db: web::Data<Cluster>,
+    store: web::Data<dyn BlobStore>,
+    preconditions: Preconditions,
+) -> impl Responder {
+    let id = match parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if let Err(response) = auth::authorize_user(format, &admin, &auth, id, &tenant, "delete it") {
+        return response;
     }
-    let id = id.unwrap();
     info!("Deleting user '{}'", id);
-    let rows_affected = client
-        .execute("DELETE FROM users WHERE id = $1", &[&id])
+    let preconditions = &preconditions;
+    let result: Result<Guarded<()>, DbError> = db
+        .primary()
+        .transaction_or_dry_run(&tenant, dry_run, |uow| {
+            Box::pin(async move {
+                // only locked when there is a precondition to check
+                if preconditions.has_unmodified_since() {
+                    let updated_at = match uow.users().find_for_update(id).await? {
+                        Some(user) => user.updated_at,
+                        None => return Ok(Guarded::NotFound),
+                    };
+                    if let Some(updated_at) = updated_at.filter(|at| preconditions.failed(*at)) {
+                        return Ok(Guarded::Modified(updated_at));
+                    }
+                }
+                if !uow.users().delete(id).await? {
+                    return Ok(Guarded::NotFound);
+                }
+                uow.publish(&Event::user_deleted(id)).await?;
+                Ok(Guarded::Done(()))
+            })
+        })
         .await;
-    if rows_affected.is_ok() {
-        let rows_affected = rows_affected.unwrap();
-        if rows_affected == 0 {
-            HttpResponse::NotFound().body(format!("User {} not found", id))
-        } else {
+    match result {
+        Ok(Guarded::NotFound) => {
+            format.error(StatusCode::NOT_FOUND, &format!("User {} not found", id))
+        }
+        Ok(Guarded::Modified(updated_at)) => modified_since(format, id, updated_at),
+        Ok(Guarded::Done(())) if dry_run.is_set() => HttpResponse::NoContent().finish(),
+        Ok(Guarded::Done(())) => {
+            // the user is gone either way, a leftover file is only logged
+            if let Err(e) = store.delete(&avatars::key(&tenant, id)).await {
+                log::warn!("Failed to delete the avatar of user {}: {}", id, e);
+            }
             HttpResponse::NoContent().finish()
         }
+        Err(e) => format.db_error(e, "SQL query failed"),
+    }
+}
+
+#[post("/users/{id}/resend-verification")]
+async fn resend_verification(
+    admin: Option<Admin>,
+    auth: Option<Auth>,
+    path: web::Path<String>,
+    format: Format,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let id = match parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if let Err(response) = auth::authorize_user(
+        format,
+        &admin,
+        &auth,
+        id,
+        &tenant,
+        "resend its verification",
+    ) {
+        return response;
+    }
+    let result: Result<Option<bool>, DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let user = match uow.users().find(id).await? {
+                    Some(user) => user,
+                    None => return Ok(None),
+                };
+                if !user.email_verified {
+                    uow.jobs()
+                        .enqueue(JobKind::VerificationEmail, &jobs::for_user(&user))
+                        .await?;
+                }
+                Ok(Some(user.email_verified))
+            })
+        })
+        .await;
+    match result {
+        Ok(Some(false)) => HttpResponse::Accepted().finish(),
+        Ok(Some(true)) => format.error(StatusCode::CONFLICT, "Email address already verified"),
+        Ok(None) => format.error(StatusCode::NOT_FOUND, &format!("User {} not found", id)),
+        Err(e) => format.db_error(e, "Failed to queue verification email"),
+    }
+}
+
+// Move the user to `status`, published as an update
+async fn change_status(
+    path: &str,
+    status: AccountStatus,
+    format: Format,
+    tenant: Tenant,
+    dry_run: DryRun,
+    db: &Cluster,
+    links: Links,
+) -> HttpResponse {
+    let id = match parse_id(format, path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let result: Result<Option<User>, DbError> = db
+        .primary()
+        .transaction_or_dry_run(&tenant, dry_run, |uow| {
+            Box::pin(async move {
+                let user = match uow.users().set_status(id, status).await? {
+                    Some(user) => user,
+                    None => return Ok(None),
+                };
+                // leaving the active status also ends the logins
+                if status != AccountStatus::Active {
+                    uow.refresh_tokens().revoke_user(id).await?;
+                }
+                uow.publish(&Event::user_updated(&user)).await?;
+                Ok(Some(user))
+            })
+        })
+        .await;
+    match result {
+        Ok(Some(user)) => {
+            info!("User {} is now {}", id, status.name());
+            preconditions::last_modified(
+                format.respond(StatusCode::OK, USERS, &links.with_user(&user)),
+                user.updated_at,
+            )
+        }
+        Ok(None) => format.error(StatusCode::NOT_FOUND, &format!("User {} not found", id)),
+        Err(e) => format.db_error(e, &format!("Failed to update user {}", id)),
+    }
+}
+
+#[post("/users/{id}/suspend")]
+async fn suspend_user(
+    _admin: Admin,
+    path: web::Path<String>,
+    format: Format,
+    tenant: Tenant,
+    dry_run: DryRun,
+    db: web::Data<Cluster>,
+    links: Links,
+) -> impl Responder {
+    change_status(
+        &path,
+        AccountStatus::Suspended,
+        format,
+        tenant,
+        dry_run,
+        &db,
+        links,
+    )
+    .await
+}
+
+#[post("/users/{id}/activate")]
+async fn activate_user(
+    _admin: Admin,
+    path: web::Path<String>,
+    format: Format,
+    tenant: Tenant,
+    dry_run: DryRun,
+    db: web::Data<Cluster>,
+    links: Links,
+) -> impl Responder {
+    change_status(
+        &path,
+        AccountStatus::Active,
+        format,
+        tenant,
+        dry_run,
+        &db,
+        links,
+    )
+    .await
+}
+
+#[post("/users/{id}/deactivate")]
+async fn deactivate_user(
+    _admin: Admin,
+    path: web::Path<String>,
+    format: Format,
+    tenant: Tenant,
+    dry_run: DryRun,
+    db: web::Data<Cluster>,
+    links: Links,
+) -> impl Responder {
+    change_status(
+        &path,
+        AccountStatus::Deactivated,
+        format,
+        tenant,
+        dry_run,
+        &db,
+        links,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct VerifyQuery {
+    token: String,
+}
+
+#[get("/verify")]
+async fn verify_email(
+    query: web::Query<VerifyQuery>,
+    format: Format,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+    links: Links,
+) -> impl Responder {
+    let invalid = || format.error(StatusCode::BAD_REQUEST, "Invalid or expired token");
+    let claim = match Claim::parse(&query.token) {
+        Some(claim) => claim,
+        None => return invalid(),
+    };
+    let (claim, config) = (&claim, &config);
+    let result: Result<Option<User>, DbError> = db
+        .primary()
+        .transaction(&claim.tenant, |uow| {
+            Box::pin(async move {
+                let user = match uow.users().find_for_update(claim.user_id).await? {
+                    Some(user) if claim.verify(&config.secret_key, &user.email) => user,
+                    _ => return Ok(None),
+                };
+                if user.email_verified {
+                    return Ok(Some(user));
+                }
+                let user = uow.users().mark_email_verified(claim.user_id).await?;
+                if let Some(user) = &user {
+                    uow.publish(&Event::user_updated(user)).await?;
+                    uow.jobs()
+                        .enqueue(JobKind::WelcomeEmail, &jobs::for_user(user))
+                        .await?;
+                }
+                Ok(user)
+            })
+        })
+        .await;
+    match result {
+        Ok(Some(user)) => format.respond(StatusCode::OK, USERS, &links.with_user(&user)),
+        Ok(None) => invalid(),
+        Err(e) => format.db_error(e, "Failed to verify email address"),
+    }
+}
+
+// Still ok in maintenance, the instance serves the reads
+#[get("/healthz")]
+async fn healthz(db: web::Data<Cluster>, maintenance: web::Data<Maintenance>) -> impl Responder {
+    if !db.primary().is_healthy() {
+        HttpResponse::ServiceUnavailable().body("database unavailable")
+    } else if maintenance.is_enabled() {
+        HttpResponse::Ok().body("maintenance")
+    } else {
+        HttpResponse::Ok().body("ok")
+    }
+}
+
+fn readiness(db: &Database) -> Value {
+    let breaker = db.breaker();
+    json!({
+        "healthy": db.is_healthy(),
+        "breaker": breaker.state().name(),
+        "breaker_trips": breaker.trips.load(Ordering::Relaxed),
+        "breaker_rejected": breaker.rejected.load(Ordering::Relaxed),
+    })
+}
+
+// Ready while the primary can be reached, with the state of every database
+#[get("/readyz")]
+async fn readyz(db: web::Data<Cluster>, maintenance: web::Data<Maintenance>) -> impl Responder {
+    let body = json!({
+        "primary": readiness(db.primary()),
+        "replicas": db.replicas().iter().map(readiness).collect::<Vec<_>>(),
+        "maintenance": maintenance.is_enabled(),
+    });
+    if db.primary().is_available() {
+        HttpResponse::Ok().json(body)
     } else {
-        HttpResponse::InternalServerError().body("SQL query failed")
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+// Where the user routes get their data from
+#[derive(Clone)]
+enum Backend {
+    Postgres(web::Data<Cluster>),
+    // --bench-mode
+    Memory(web::Data<bench::Dataset>),
+    // a mysql:// or mariadb:// DATABASE_URL
+    #[cfg(feature = "mysql")]
+    MySql(web::Data<mysql::Store>),
+}
+
+fn routes(cfg: &mut web::ServiceConfig, backend: &Backend) {
+    match backend {
+        Backend::Postgres(db) => {
+            cfg.app_data(db.clone())
+                .service(get_users)
+                .service(head_users)
+                .service(count_users)
+                .service(export_users)
+                .service(get_users_page)
+                .service(create_user)
+                .service(upsert_user)
+                .service(get_user)
+                .service(update_user)
+                .service(delete_user)
+                .service(resend_verification)
+                .service(suspend_user)
+                .service(activate_user)
+                .service(deactivate_user)
+                .service(verify_email)
+                .service(healthz)
+                .service(readyz)
+                .configure(admin_ui::configure)
+                .configure(api_keys::configure)
+                .configure(auth::configure)
+                .configure(avatars::configure)
+                .configure(backup::configure)
+                .configure(changes::configure)
+                .configure(duplicates::configure)
+                .configure(flags::configure)
+                .configure(lockout::configure)
+                .configure(maintenance::configure)
+                .configure(me::configure)
+                .configure(metrics::configure)
+                .configure(oauth::configure)
+                .configure(openapi::configure)
+                .configure(pii::configure)
+                .configure(quotas::configure)
+                .configure(schema::configure)
+                .configure(settings::configure)
+                .configure(signatures::configure)
+                .configure(stats::configure)
+                .configure(status_page::configure)
+                .configure(webhooks::configure);
+        }
+        Backend::Memory(dataset) => {
+            cfg.app_data(dataset.clone());
+            bench::configure(cfg);
+        }
+        #[cfg(feature = "mysql")]
+        Backend::MySql(store) => {
+            cfg.app_data(store.clone());
+            mysql::configure(cfg);
+        }
+    }
+    build_info::configure(cfg);
+}
+
+// What the workers of the server are built from, each gets a copy
+#[derive(Clone)]
+struct Shared {
+    config: web::Data<Config>,
+    backend: Backend,
+    body_schemas: Arc<BodySchemas>,
+    lockout_metrics: web::Data<lockout::LockoutMetrics>,
+    limiter: web::Data<concurrency::Limiter>,
+    http_metrics: web::Data<HttpMetrics>,
+    maintenance: web::Data<Maintenance>,
+    field_policy: web::Data<FieldPolicy>,
+    store: web::Data<dyn BlobStore>,
+    query_metrics: web::Data<QueryMetrics>,
+    stats_cache: web::Data<StatsCache>,
+    static_site: web::Data<StaticSite>,
+    settings: web::Data<RuntimeSettings>,
+    feature_flags: web::Data<FeatureFlags>,
+    quota_counts: web::Data<QuotaCounts>,
+}
+
+impl Shared {
+    fn new(
+        config: Config,
+        backend: Backend,
+        query_metrics: Arc<QueryMetrics>,
+        logger: &'static ReloadableLogger,
+    ) -> Shared {
+        Shared {
+            lockout_metrics: web::Data::new(lockout::LockoutMetrics::default()),
+            limiter: web::Data::new(concurrency::Limiter::new(&config)),
+            http_metrics: web::Data::new(HttpMetrics::new()),
+            maintenance: web::Data::new(Maintenance::new(&config)),
+            field_policy: web::Data::new(FieldPolicy::new(config.field_policy.clone())),
+            body_schemas: Arc::new(BodySchemas::from_config(&config)),
+            store: web::Data::from(storage::from_config(&config)),
+            query_metrics: web::Data::from(query_metrics),
+            stats_cache: web::Data::new(StatsCache::new(config.stats_cache_ttl)),
+            static_site: web::Data::new(StaticSite::from_config(&config)),
+            feature_flags: web::Data::new(FeatureFlags::new(config.feature_flags_refresh)),
+            quota_counts: web::Data::new(QuotaCounts::default()),
+            settings: web::Data::new(RuntimeSettings::new(&config, logger)),
+            config: web::Data::new(config),
+            backend,
+        }
     }
 }
 
+// The application each worker serves, the contract tests of openapi too
+fn app(
+    shared: &Shared,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let config = &shared.config;
+    // API keys and partners are stored in Postgres, and dry runs roll back
+    // its transactions
+    let postgres = matches!(shared.backend, Backend::Postgres(_));
+    App::new()
+        .wrap(tx::Transactions)
+        .wrap(DryRuns::new(postgres))
+        .wrap(Envelopes::new(config))
+        .wrap(BodyValidation::new(shared.body_schemas.clone(), config))
+        .wrap(JsonBodies::new(config))
+        .wrap(Csrf::new(config))
+        .wrap(Condition::new(postgres, Quotas))
+        .wrap(Condition::new(postgres, ApiKeys))
+        .wrap(Condition::new(postgres, RequestSignatures::new(config)))
+        .wrap(
+            routes::ROUTES
+                .iter()
+                .filter(|route| route.upload)
+                .fold(Timeouts::new(config.request_timeout), |timeouts, route| {
+                    timeouts.route(route.pattern, config.upload_timeout)
+                }),
+        )
+        .wrap(Maintenances::new(shared.maintenance.clone()))
+        .wrap(MethodOverride::new(config))
+        .wrap(ConcurrencyLimit::new(shared.limiter.clone()))
+        .wrap(i18n::Translations)
+        .wrap(CachePolicies::new(config))
+        .wrap(Cors)
+        .wrap(NormalizePath::new(config))
+        .wrap(Condition::new(chaos::is_enabled(), chaos::Faults))
+        .wrap(SecurityHeaders::new(config))
+        .wrap(BodyLogger::new(config))
+        .wrap(ResponseMetrics::new(shared.http_metrics.clone()))
+        .wrap(Logger::default())
+        .app_data(config.clone())
+        .app_data(shared.lockout_metrics.clone())
+        .app_data(shared.limiter.clone())
+        .app_data(shared.http_metrics.clone())
+        .app_data(shared.maintenance.clone())
+        .app_data(shared.field_policy.clone())
+        .app_data(shared.store.clone())
+        .app_data(shared.query_metrics.clone())
+        .app_data(shared.stats_cache.clone())
+        .app_data(shared.static_site.clone())
+        .app_data(shared.settings.clone())
+        .app_data(shared.feature_flags.clone())
+        .app_data(shared.quota_counts.clone())
+        .app_data(payload::json_config(config))
+        .configure(|cfg| routes(cfg, &shared.backend))
+        .default_service(web::to(static_site::fallback))
+}
+
 // main function
 #[actix_web::main]
 async fn main() -> Result<(), std::io::Error> {
     // Initialize the logger
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-        .format_timestamp(None)
-        .format_module_path(false)
-        .init();
+    let logger = logging::init(Redactor::from_env());
+    info!(
+        "rust-crud-api {} ({}, built {})",
+        build_info::VERSION,
+        build_info::GIT_COMMIT,
+        build_info::TIMESTAMP
+    );
+
+    // settings naming a secret are replaced by its value first
+    let secrets = secrets::Secrets::from_env();
+    secrets.resolve().await;
+    // kept until the server stops, which stops Postgres too
+    #[cfg(feature = "embedded-pg")]
+    let _embedded_pg = embedded_pg::start(DB_URL).await;
+    let config = Config::from_env(DB_URL);
+    if let Some(keyring) = pii::Keyring::from_config(&config) {
+        info!("Encrypting emails at rest");
+        pii::install(keyring);
+    }
+    email_rules::install(config.email_normalization.clone());
+    let ids = ids::Ids::from_config(&config);
+    info!("Generating {}", ids.describe());
+    ids::install(ids);
+    if let Some(chaos) = chaos::Chaos::from_config(&config) {
+        warn!("Chaos testing: {}", chaos.describe());
+        chaos::install(chaos);
+    }
+    let mut report = self_check::Report::new();
+    self_check::check_config(&mut report, &config);
+    let query_metrics = Arc::new(QueryMetrics::new(config.slow_query_threshold));
+    let backend = if config.bench_mode {
+        info!(
+            "Bench mode: serving {} generated users, without database nor authentication",
+            config.bench_users
+        );
+        Backend::Memory(web::Data::new(bench::Dataset::generate(config.bench_users)))
+    } else if is_mysql_url(&config.database_url) {
+        connect_mysql(&config).await
+    } else {
+        Backend::Postgres(web::Data::new(
+            connect(&config, Arc::clone(&query_metrics), &mut report).await,
+        ))
+    };
+    // nothing runs in the background until every check passed
+    report.finish();
+    let shared = Shared::new(config, backend, query_metrics, logger);
+    if shared.maintenance.is_enabled() {
+        info!("Starting in maintenance mode, writes are refused");
+    }
+    if let Some(dir) = &shared.config.request_schema_dir {
+        info!(
+            "Validating the bodies of {} routes against the schemas in {}",
+            shared.body_schemas.len(),
+            dir
+        );
+    }
+    if let Some(dir) = &shared.config.static_dir {
+        info!("Serving the frontend in {}", dir);
+    }
+    if let Backend::Postgres(db) = &shared.backend {
+        quotas::spawn_flush(
+            db.primary().clone(),
+            shared.quota_counts.clone().into_inner(),
+            &shared.config,
+        );
+        spawn_workers(db.primary(), &shared.config).await;
+        flags::spawn_refresh(
+            db.primary().clone(),
+            shared.feature_flags.clone().into_inner(),
+        );
+        secrets::spawn_refresh(secrets, db.primary().clone(), shared.config.secrets_refresh);
+    }
+    // kept until the server stops
+    let _watcher = settings::watch(shared.settings.clone().into_inner());
+    let server_config = shared.config.clone();
+    let server = HttpServer::new(move || app(&shared))
+        .keep_alive(server_config.keep_alive)
+        .client_request_timeout(server_config.client_request_timeout)
+        .max_connections(server_config.max_connections)
+        .backlog(server_config.backlog);
+    let server = match server_config.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match server_config.listen.clone() {
+        Listen::Tcp(address) => {
+            info!("Listening on {}", address);
+            server.bind(address)?
+        }
+        Listen::Unix(path) => {
+            listener::clear_stale_socket(&path)?;
+            info!("Listening on {}", path.display());
+            server.bind_uds(path)?
+        }
+        Listen::Systemd => listener::systemd_sockets()?.into_iter().try_fold(
+            server,
+            |server, socket| match socket {
+                Inherited::Tcp(socket) => server.listen(socket),
+                Inherited::Unix(socket) => server.listen_uds(socket),
+            },
+        )?,
+    };
+    server.run().await
+}
 
+// The primary and the replicas, with the workers relying on them started
+async fn connect(
+    config: &Config,
+    query_metrics: Arc<QueryMetrics>,
+    report: &mut self_check::Report,
+) -> Cluster {
     info!("Setup database");
     // set database
-    let db_client = Arc::new(Mutex::new(
-        setup_database().await.expect("Failed to connect to DB"),
-    ));
-    HttpServer::new(move || {
-        App::new()
-            .wrap(Logger::default())
-            .app_data(web::Data::new(db_client.clone()))
-            .service(get_users)
-            .service(create_user)
-            .service(get_user)
-            .service(update_user)
-            .service(delete_user)
+    let policy = RetryPolicy {
+        breaker_threshold: config.breaker_threshold,
+        breaker_cooldown: config.breaker_cooldown,
+        ..RetryPolicy::default()
+    };
+    let primary = Database::connect(
+        &config.database_url,
+        policy.clone(),
+        config.pool_size,
+        config.statement_timeout,
+        config.naming.clone(),
+        Arc::clone(&query_metrics),
+    )
+    .await
+    .unwrap_or_else(|e| {
+        report.fail(
+            "database",
+            format!(
+                "{}: check DATABASE_URL and that Postgres accepts connections from here",
+                e
+            ),
+        );
+        report.abort()
+    });
+    self_check::check_connection(report, &primary).await;
+    self_check::check_schema_version(report, &primary).await;
+    if let Err(e) = setup_database(&primary, config.tenant_rls).await {
+        report.fail(
+            "migrations",
+            format!(
+                "{}: the database user needs to create tables and policies",
+                e
+            ),
+        );
+        report.abort();
+    }
+    self_check::check_tables(report, &primary).await;
+    self_check::check_trigrams(report, &primary).await;
+    let replicas = config
+        .replica_urls
+        .iter()
+        .map(|url| {
+            Database::connect_lazy(
+                url,
+                policy.clone(),
+                config.pool_size,
+                config.statement_timeout,
+                config.naming.clone(),
+                Arc::clone(&query_metrics),
+            )
+        })
+        .collect();
+    report.ok(
+        "replicas",
+        format!(
+            "{} configured, connected in the background",
+            config.replica_urls.len()
+        ),
+    );
+    Cluster::new(primary, replicas)
+}
+
+async fn spawn_workers(db: &Database, config: &Config) {
+    webhooks::spawn_worker(db.clone(), config);
+    let mailer = mailer::from_config(config).expect("Failed to set up the mailer");
+    jobs::spawn_worker(db.clone(), mailer, config);
+    spawn_outbox_relay(db, config).await;
+}
+
+// mysql:// and mariadb:// database URLs select the MySQL backend
+fn is_mysql_url(url: &str) -> bool {
+    url.starts_with("mysql://") || url.starts_with("mariadb://")
+}
+
+#[cfg(feature = "mysql")]
+async fn connect_mysql(config: &Config) -> Backend {
+    info!("Setup MySQL database, serving the user CRUD routes only");
+    let store = mysql::Store::connect(config)
+        .await
+        .expect("Failed to connect to MySQL");
+    Backend::MySql(web::Data::new(store))
+}
+
+#[cfg(not(feature = "mysql"))]
+async fn connect_mysql(_config: &Config) -> Backend {
+    panic!("DATABASE_URL is a MySQL URL, build with --features mysql to use it");
+}
+
+// Relay the outbox to the brokers that are both configured and compiled in
+#[cfg_attr(not(feature = "outbox-relay"), allow(unused_variables))]
+async fn spawn_outbox_relay(db: &Database, config: &Config) {
+    #[cfg(feature = "outbox-relay")]
+    #[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(unused_mut))]
+    let mut publishers: Vec<Box<dyn outbox::Publisher>> = Vec::new();
+
+    #[cfg(feature = "kafka")]
+    if let Some(brokers) = &config.kafka_brokers {
+        let publisher =
+            kafka::KafkaPublisher::new(brokers, config).expect("Failed to create Kafka producer");
+        info!(
+            "Relaying outbox events to Kafka topic {}",
+            config.kafka_topic
+        );
+        publishers.push(Box::new(publisher));
+    }
+    #[cfg(not(feature = "kafka"))]
+    if config.kafka_brokers.is_some() {
+        log::warn!("KAFKA_BROKERS is set but the server was built without the kafka feature");
+    }
+
+    #[cfg(feature = "nats")]
+    if let Some(url) = &config.nats_url {
+        let publisher = nats::NatsPublisher::connect(url, &config.nats_subject_prefix)
+            .await
+            .expect("Failed to connect to NATS");
+        info!(
+            "Relaying outbox events to NATS subjects {}.*",
+            config.nats_subject_prefix
+        );
+        publishers.push(Box::new(publisher));
+    }
+    #[cfg(not(feature = "nats"))]
+    if config.nats_url.is_some() {
+        log::warn!("NATS_URL is set but the server was built without the nats feature");
+    }
+
+    #[cfg(feature = "outbox-relay")]
+    if !publishers.is_empty() {
+        outbox::spawn_relay(db.clone(), publishers, config.outbox_poll_interval);
+    }
+}
+
+async fn setup_database(db: &Database, row_level_security: bool) -> Result<(), DbError> {
+    // Create tables, users first as the others reference it
+    db.run(|client| async move {
+        let naming = client.statements.naming();
+        if let Some(schema) = &naming.schema {
+            client
+                .client
+                .batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema))
+                .await?;
+        }
+        for schema in repository::SCHEMAS {
+            client.client.batch_execute(&naming.apply(schema)).await?;
+        }
+        let policies = if row_level_security {
+            repository::ENABLE_ROW_LEVEL_SECURITY
+        } else {
+            repository::DISABLE_ROW_LEVEL_SECURITY
+        };
+        client.client.batch_execute(&naming.apply(policies)).await?;
+        client
+            .client
+            .batch_execute(&naming.apply(&format!(
+                "INSERT INTO {{prefix}}schema_version (version) VALUES ({}) ON CONFLICT DO NOTHING",
+                repository::SCHEMA_VERSION
+            )))
+            .await?;
+        Ok(())
     })
-    .bind(("0.0.0.0", 8080))?
-    .run()
     .await
 }
 
-async fn setup_database() -> Result<tokio_postgres::Client, tokio_postgres::Error> {
-    // connect to database
-    let (client, connection) = tokio_postgres::connect(DB_URL, NoTls).await?;
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("Connection error: {}", e);
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{self, TestRequest};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_app::{self, admin, create_user, email, login, request, shared, signed_in};
+
+    const PASSWORD: &str = "correct horse battery";
+
+    #[actix_web::test]
+    async fn only_the_user_or_an_admin_change_a_user() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let victim = email("victim");
+        let id = create_user(send, &victim, PASSWORD).await;
+        let path = format!("/users/{}", id);
+        let takeover = json!({ "name": "Jane Doe", "email": email("attacker") });
+
+        for request in [
+            request("PUT", &path).set_json(&takeover),
+            request("DELETE", &path),
+            request("POST", &format!("{}/resend-verification", path)),
+            request("PUT", "/users?key=email").set_json(&takeover),
+        ] {
+            let response = send(request).await;
+            assert_eq!(
+                response.status(),
+                401,
+                "{} anonymously",
+                response.request().path()
+            );
         }
-    });
 
-    // Create table
-    client
-        .batch_execute(
-            "CREATE TABLE IF NOT EXISTS users (
-            id SERIAL PRIMARY KEY,
-            name VARCHAR NOT NULL,
-            email VARCHAR NOT NULL
-        )",
-        )
-        .await?;
-    Ok(client)
+        let other = email("other");
+        create_user(send, &other, PASSWORD).await;
+        let (_, tokens) = login(send, &other, PASSWORD).await;
+        for request in [
+            signed_in("PUT", &path, &tokens).set_json(&takeover),
+            signed_in("DELETE", &path, &tokens),
+            signed_in("POST", &format!("{}/resend-verification", path), &tokens),
+        ] {
+            let response = send(request).await;
+            assert_eq!(
+                response.status(),
+                403,
+                "{} as another user",
+                response.request().path()
+            );
+        }
+        let response =
+            send(signed_in("PUT", "/users?key=email", &tokens).set_json(&takeover)).await;
+        assert_eq!(response.status(), 401, "PUT /users as a user");
+
+        let (_, user) = test_app::json(send(admin("GET", &path)).await).await;
+        assert_eq!(user["email"], json!(victim), "the address was changed");
+
+        let (status, tokens) = login(send, &victim, PASSWORD).await;
+        assert_eq!(status, 200, "login of the victim: {}", tokens);
+        let own = json!({ "name": "Jane Roe", "email": email("victim") });
+        let response = send(signed_in("PUT", &path, &tokens).set_json(&own)).await;
+        assert_eq!(response.status(), 200, "PUT of the user itself");
+        let response = send(signed_in("DELETE", &path, &tokens)).await;
+        assert_eq!(response.status(), 204, "DELETE of the user itself");
+    }
+
+    #[actix_web::test]
+    async fn users_never_share_an_address_once_normalized() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let jane = email("jane");
+        let id = create_user(send, &jane, PASSWORD).await;
+        let respelled = jane.to_uppercase();
+        let user = |email: &str| json!({ "name": "Jane Doe", "email": email });
+
+        let response = send(admin("POST", "/users").set_json(user(&respelled))).await;
+        assert_eq!(response.status(), 409, "POST of {:?}", respelled);
+
+        let other = email("other");
+        let other_id = create_user(send, &other, PASSWORD).await;
+        let other_path = format!("/users/{}", other_id);
+        let response = send(admin("PUT", &other_path).set_json(user(&respelled))).await;
+        assert_eq!(response.status(), 409, "PUT of {:?}", respelled);
+        let (_, tokens) = login(send, &other, PASSWORD).await;
+        let changes = json!({ "email": respelled });
+        let response = send(signed_in("PATCH", "/me", &tokens).set_json(&changes)).await;
+        assert_eq!(response.status(), 409, "PATCH /me of {:?}", respelled);
+        let (_, unchanged) = test_app::json(send(admin("GET", &other_path)).await).await;
+        assert_eq!(unchanged["email"], json!(other));
+
+        // the user holding it may respell it
+        let path = format!("/users/{}", id);
+        let (status, body) =
+            test_app::json(send(admin("PUT", &path).set_json(user(&respelled))).await).await;
+        assert_eq!(status, 200, "PUT of its own address: {}", body);
+    }
 }
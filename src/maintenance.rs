@@ -0,0 +1,162 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{get, put, web, HttpResponse, Responder};
+use log::info;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::RwLock;
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::fallback;
+use crate::jsonapi::Format;
+
+pub const DEFAULT_MESSAGE: &str = "The API is under maintenance, only reads are served";
+
+// Still writable in maintenance, to leave it, and to restore a snapshot
+// while nothing else writes
+const EXEMPT: [&str; 2] = ["/admin/maintenance", "/admin/restore"];
+
+#[derive(Clone, Serialize)]
+struct State {
+    enabled: bool,
+    message: String,
+}
+
+// Whether writes are refused, shared by all the workers. Toggled on this
+// instance only, MAINTENANCE_MODE puts every instance in it from the start.
+pub struct Maintenance {
+    state: RwLock<State>,
+}
+
+impl Maintenance {
+    pub fn new(config: &Config) -> Maintenance {
+        Maintenance {
+            state: RwLock::new(State {
+                enabled: config.maintenance_mode,
+                message: config.maintenance_message.clone(),
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.read().unwrap().enabled
+    }
+
+    // The message writes are refused with, None out of maintenance
+    fn refusal(&self) -> Option<String> {
+        let state = self.state.read().unwrap();
+        state.enabled.then(|| state.message.clone())
+    }
+}
+
+fn is_read(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+// In maintenance, answers the writes 503 with MAINTENANCE_MESSAGE before
+// they reach a handler, the reads go through as usual. For migrations and
+// backfills that must not see the data change under them.
+pub struct Maintenances {
+    maintenance: web::Data<Maintenance>,
+}
+
+impl Maintenances {
+    pub fn new(maintenance: web::Data<Maintenance>) -> Maintenances {
+        Maintenances { maintenance }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Maintenances
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = MaintenancesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MaintenancesMiddleware {
+            service: Rc::new(service),
+            maintenance: self.maintenance.clone(),
+        }))
+    }
+}
+
+pub struct MaintenancesMiddleware<S> {
+    service: Rc<S>,
+    maintenance: web::Data<Maintenance>,
+}
+
+impl<S, B> Service<ServiceRequest> for MaintenancesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let refusal = match self.maintenance.refusal() {
+            Some(message) if !is_read(req.method()) && !EXEMPT.contains(&req.path()) => message,
+            _ => {
+                let service = self.service.clone();
+                return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) });
+            }
+        };
+        let response = fallback::respond(
+            Format::of(req.request()),
+            StatusCode::SERVICE_UNAVAILABLE,
+            &refusal,
+        );
+        Box::pin(async move { Ok(req.into_response(response).map_into_right_body()) })
+    }
+}
+
+#[derive(Deserialize)]
+struct MaintenanceSettings {
+    enabled: bool,
+    // kept as it was when left out
+    message: Option<String>,
+}
+
+#[get("/admin/maintenance")]
+async fn get_maintenance(_admin: Admin, maintenance: web::Data<Maintenance>) -> impl Responder {
+    let state = maintenance.state.read().unwrap().clone();
+    HttpResponse::Ok().json(state)
+}
+
+#[put("/admin/maintenance")]
+async fn put_maintenance(
+    _admin: Admin,
+    body: web::Json<MaintenanceSettings>,
+    maintenance: web::Data<Maintenance>,
+) -> impl Responder {
+    let settings = body.into_inner();
+    let state = {
+        let mut state = maintenance.state.write().unwrap();
+        state.enabled = settings.enabled;
+        if let Some(message) = settings.message {
+            state.message = message;
+        }
+        state.clone()
+    };
+    if state.enabled {
+        info!("Entered maintenance mode, writes are refused");
+    } else {
+        info!("Left maintenance mode");
+    }
+    HttpResponse::Ok().json(state)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_maintenance).service(put_maintenance);
+}
@@ -0,0 +1,156 @@
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, patch, web, HttpResponse, Responder};
+use log::{info, warn};
+
+use crate::auth::Auth;
+use crate::db::{Cluster, DbError};
+use crate::dry_run::DryRun;
+use crate::events::Event;
+use crate::jobs::{self, JobKind};
+use crate::jsonapi::Format;
+use crate::links::Links;
+use crate::models::User;
+use crate::preconditions;
+use crate::storage::BlobStore;
+use crate::tx::Tx;
+use crate::{avatars, email_rules, USERS};
+
+// What a user may change of their own account, the fields left out keep
+// their value. The status and the verification stay out of reach.
+#[derive(Deserialize)]
+struct AccountChanges {
+    name: Option<String>,
+    email: Option<String>,
+    password: Option<String>,
+}
+
+// The routes below act on the user of the access token, never on an id
+// taken from the request, so that the API can back the app of the users
+// themselves and not only admin tooling
+#[get("/me")]
+async fn get_me(
+    auth: Auth,
+    format: Format,
+    db: web::Data<Cluster>,
+    links: Links,
+) -> impl Responder {
+    let result: Result<Option<User>, DbError> = db
+        .reader()
+        .transaction(&auth.tenant, |uow| {
+            Box::pin(async move { Ok(uow.account(auth.user_id).find().await?) })
+        })
+        .await;
+    match result {
+        Ok(Some(user)) => preconditions::last_modified(
+            format.respond(StatusCode::OK, USERS, &links.with_user(&user)),
+            user.updated_at,
+        ),
+        Ok(None) => format.error(StatusCode::NOT_FOUND, "Account not found"),
+        Err(e) => format.db_error(e, "Failed to retrieve the account"),
+    }
+}
+
+// A new password signs the user out everywhere, this token included. The
+// request transaction is only committed once the handler answered 2xx.
+#[patch("/me")]
+async fn update_me(
+    auth: Auth,
+    body: web::Json<AccountChanges>,
+    format: Format,
+    tx: Tx,
+    links: Links,
+) -> impl Responder {
+    let changes = body.into_inner();
+    let password_hash = match crate::password_hash(format, changes.password.as_deref()).await {
+        Ok(hash) => hash,
+        Err(response) => return response,
+    };
+    let (changes, password_hash, tx) = (&changes, &password_hash, &tx);
+    // Some(None) when the new address is another user's
+    let result: Result<Option<Option<User>>, DbError> = tx
+        .retry(|| async move {
+            let account = tx.account(auth.user_id);
+            let previous = match account.find_for_update().await? {
+                Some(previous) => previous,
+                None => return Ok(None),
+            };
+            let previous_email = previous.email.clone();
+            let user = User {
+                name: changes.name.clone().unwrap_or(previous.name),
+                email: changes.email.clone().unwrap_or(previous.email),
+                ..previous
+            };
+            if email_rules::normalize(&user.email) != email_rules::normalize(&previous_email)
+                && account.email_taken(&user.email).await?
+            {
+                return Ok(Some(None));
+            }
+            let user = match account.update(&user, password_hash.as_deref()).await? {
+                Some(user) => user,
+                None => return Ok(None),
+            };
+            tx.publish(&Event::user_updated(&user)).await?;
+            if password_hash.is_some() {
+                tx.refresh_tokens().revoke_user(account.id()).await?;
+            }
+            if user.email != previous_email {
+                tx.jobs()
+                    .enqueue(JobKind::VerificationEmail, &jobs::for_user(&user))
+                    .await?;
+            }
+            Ok(Some(Some(user)))
+        })
+        .await;
+    match result {
+        Ok(Some(Some(user))) => preconditions::last_modified(
+            format.respond(StatusCode::OK, USERS, &links.with_user(&user)),
+            user.updated_at,
+        ),
+        Ok(Some(None)) => crate::email_taken(format),
+        Ok(None) => format.error(StatusCode::NOT_FOUND, "Account not found"),
+        Err(e) => format.db_error(e, "Failed to update the account"),
+    }
+}
+
+#[delete("/me")]
+async fn delete_me(
+    auth: Auth,
+    format: Format,
+    dry_run: DryRun,
+    db: web::Data<Cluster>,
+    store: web::Data<dyn BlobStore>,
+) -> impl Responder {
+    info!("User '{}' is deleting their account", auth.user_id);
+    let result: Result<bool, DbError> = db
+        .primary()
+        .transaction_or_dry_run(&auth.tenant, dry_run, |uow| {
+            Box::pin(async move {
+                let account = uow.account(auth.user_id);
+                if !account.delete().await? {
+                    return Ok(false);
+                }
+                uow.publish(&Event::user_deleted(account.id())).await?;
+                Ok(true)
+            })
+        })
+        .await;
+    match result {
+        Ok(true) if dry_run.is_set() => HttpResponse::NoContent().finish(),
+        Ok(true) => {
+            let key = avatars::key(&auth.tenant, auth.user_id);
+            if let Err(e) = store.delete(&key).await {
+                warn!(
+                    "Failed to delete the avatar of user {}: {}",
+                    auth.user_id, e
+                );
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => format.error(StatusCode::NOT_FOUND, "Account not found"),
+        Err(e) => format.db_error(e, "Failed to delete the account"),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_me).service(update_me).service(delete_me);
+}
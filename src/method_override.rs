@@ -0,0 +1,107 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{Method, StatusCode};
+use log::info;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::config::Config;
+use crate::fallback;
+use crate::jsonapi::Format;
+
+pub const OVERRIDE_HEADER: &str = "X-HTTP-Method-Override";
+
+// The methods a POST may stand for, those proxies tend to block
+const OVERRIDABLE: [Method; 3] = [Method::PUT, Method::PATCH, Method::DELETE];
+
+// Routes a POST carrying `X-HTTP-Method-Override: PUT`, `PATCH` or `DELETE`
+// as a request of that method, for clients behind proxies that only let GET
+// and POST through. Every override is logged with the client address. Any
+// other value is refused with a 400. Off unless METHOD_OVERRIDE.
+pub struct MethodOverride {
+    enabled: bool,
+}
+
+impl MethodOverride {
+    pub fn new(config: &Config) -> MethodOverride {
+        MethodOverride {
+            enabled: config.method_override,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MethodOverride
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = MethodOverrideMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MethodOverrideMiddleware {
+            service: Rc::new(service),
+            enabled: self.enabled,
+        }))
+    }
+}
+
+pub struct MethodOverrideMiddleware<S> {
+    service: Rc<S>,
+    enabled: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for MethodOverrideMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let requested = match req.headers().get(OVERRIDE_HEADER) {
+            Some(value) if self.enabled && req.method() == Method::POST => Some(
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.trim().to_ascii_uppercase().parse::<Method>().ok())
+                    .filter(|method| OVERRIDABLE.contains(method)),
+            ),
+            _ => None,
+        };
+        match requested {
+            Some(Some(method)) => {
+                let client = req
+                    .peer_addr()
+                    .map(|addr| addr.ip().to_string())
+                    .unwrap_or_default();
+                info!(
+                    "Method override: POST {} from {} dispatched as {}",
+                    req.path(),
+                    client,
+                    method
+                );
+                req.head_mut().method = method;
+            }
+            Some(None) => {
+                let response = fallback::respond(
+                    Format::of(req.request()),
+                    StatusCode::BAD_REQUEST,
+                    &format!("{} must be PUT, PATCH or DELETE", OVERRIDE_HEADER),
+                );
+                return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+            }
+            None => (),
+        }
+        let service = Rc::clone(&self.service);
+        Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) })
+    }
+}
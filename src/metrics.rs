@@ -0,0 +1,378 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{get, web, HttpResponse, Responder};
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::breaker::BreakerState;
+use crate::concurrency::Limiter;
+use crate::db::{Cluster, Database};
+
+// Upper bounds of the latency buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // observations up to each bound, not cumulated
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+// Latency of every statement the repositories run, by SQL, shared by all
+// the connections. Statements slower than `slow_threshold` are logged.
+pub struct QueryMetrics {
+    slow_threshold: Option<Duration>,
+    histograms: Mutex<HashMap<Arc<str>, Histogram>>,
+}
+
+impl QueryMetrics {
+    pub fn new(slow_threshold: Option<Duration>) -> QueryMetrics {
+        QueryMetrics {
+            slow_threshold,
+            histograms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Parameters can hold personal data, only their number is logged
+    pub fn record(&self, sql: &Arc<str>, params: usize, elapsed: Duration) {
+        if self
+            .slow_threshold
+            .is_some_and(|threshold| elapsed >= threshold)
+        {
+            warn!(
+                "Slow query ({:?}): {} [{} parameter(s) redacted]",
+                elapsed, sql, params
+            );
+        }
+        self.histograms
+            .lock()
+            .unwrap()
+            .entry(sql.clone())
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    fn render(&self, out: &mut String) {
+        let histograms = self.histograms.lock().unwrap();
+        out.push_str("# HELP db_query_duration_seconds Latency of database statements\n");
+        out.push_str("# TYPE db_query_duration_seconds histogram\n");
+        for (sql, histogram) in histograms.iter() {
+            let query = label(sql);
+            let mut cumulated = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulated += count;
+                let _ = writeln!(
+                    out,
+                    "db_query_duration_seconds_bucket{{query=\"{}\",le=\"{}\"}} {}",
+                    query, bound, cumulated
+                );
+            }
+            let _ = writeln!(
+                out,
+                "db_query_duration_seconds_bucket{{query=\"{}\",le=\"+Inf\"}} {}",
+                query, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "db_query_duration_seconds_sum{{query=\"{}\"}} {}",
+                query, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "db_query_duration_seconds_count{{query=\"{}\"}} {}",
+                query, histogram.count
+            );
+        }
+    }
+
+    // Statements run and their mean latency, over all of them
+    pub fn summary(&self) -> (u64, Option<Duration>) {
+        let histograms = self.histograms.lock().unwrap();
+        let (count, sum) = histograms
+            .values()
+            .fold((0, 0.0), |(count, sum), histogram| {
+                (count + histogram.count, sum + histogram.sum)
+            });
+        let mean = (count > 0).then(|| Duration::from_secs_f64(sum / count as f64));
+        (count, mean)
+    }
+}
+
+// The minutes the recent error rate is computed over
+pub const RECENT_MINUTES: u64 = 5;
+
+// Responses of a minute since the epoch
+struct Minute {
+    minute: u64,
+    responses: u64,
+    errors: u64,
+}
+
+// Responses by status class since the start, and those of the last
+// RECENT_MINUTES for the recent error rate, 5xx being the errors
+pub struct HttpMetrics {
+    started: Instant,
+    // 1xx to 5xx
+    responses: [AtomicU64; 5],
+    recent: Mutex<VecDeque<Minute>>,
+}
+
+impl HttpMetrics {
+    pub fn new() -> HttpMetrics {
+        HttpMetrics {
+            started: Instant::now(),
+            responses: Default::default(),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() / 60)
+            .unwrap_or_default()
+    }
+
+    fn record(&self, status: u16) {
+        let class = usize::from(status / 100).clamp(1, 5);
+        self.responses[class - 1].fetch_add(1, Ordering::Relaxed);
+        let now = HttpMetrics::now();
+        let mut recent = self.recent.lock().unwrap();
+        if recent.back().is_none_or(|last| last.minute != now) {
+            recent.push_back(Minute {
+                minute: now,
+                responses: 0,
+                errors: 0,
+            });
+        }
+        while recent
+            .front()
+            .is_some_and(|first| first.minute + RECENT_MINUTES <= now)
+        {
+            recent.pop_front();
+        }
+        let minute = recent.back_mut().expect("current minute pushed above");
+        minute.responses += 1;
+        minute.errors += u64::from(class == 5);
+    }
+
+    // Responses and errors of the last RECENT_MINUTES
+    pub fn recent(&self) -> (u64, u64) {
+        let now = HttpMetrics::now();
+        self.recent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|minute| minute.minute + RECENT_MINUTES > now)
+            .fold((0, 0), |(responses, errors), minute| {
+                (responses + minute.responses, errors + minute.errors)
+            })
+    }
+
+    fn render(&self, out: &mut String) {
+        out.push_str("# HELP http_responses_total Responses sent, by status class\n");
+        out.push_str("# TYPE http_responses_total counter\n");
+        for (index, count) in self.responses.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "http_responses_total{{class=\"{}xx\"}} {}",
+                index + 1,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        out.push_str("# HELP process_uptime_seconds Time since the server started\n");
+        out.push_str("# TYPE process_uptime_seconds gauge\n");
+        let _ = writeln!(
+            out,
+            "process_uptime_seconds {}",
+            self.uptime().as_secs_f64()
+        );
+    }
+}
+
+// Counts every response in HttpMetrics by its status
+pub struct ResponseMetrics {
+    metrics: web::Data<HttpMetrics>,
+}
+
+impl ResponseMetrics {
+    pub fn new(metrics: web::Data<HttpMetrics>) -> ResponseMetrics {
+        ResponseMetrics { metrics }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ResponseMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = ResponseMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ResponseMetricsMiddleware {
+            service: Rc::new(service),
+            metrics: self.metrics.clone(),
+        }))
+    }
+}
+
+pub struct ResponseMetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: web::Data<HttpMetrics>,
+}
+
+impl<S, B> Service<ServiceRequest> for ResponseMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            let result = service.call(req).await;
+            // errors become their response further out
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            metrics.record(status.as_u16());
+            result
+        })
+    }
+}
+
+// Escaped for a Prometheus label value
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_breakers(db: &Cluster, out: &mut String) {
+    let databases = std::iter::once(("primary".to_string(), db.primary())).chain(
+        db.replicas()
+            .iter()
+            .enumerate()
+            .map(|(index, replica)| (format!("replica{}", index), replica)),
+    );
+    let databases: Vec<(String, &Database)> = databases.collect();
+    out.push_str("# HELP db_circuit_breaker_state Current state of the circuit breaker\n");
+    out.push_str("# TYPE db_circuit_breaker_state gauge\n");
+    for (name, database) in &databases {
+        let current = database.breaker().state();
+        for state in [
+            BreakerState::Closed,
+            BreakerState::Open,
+            BreakerState::HalfOpen,
+        ] {
+            let _ = writeln!(
+                out,
+                "db_circuit_breaker_state{{database=\"{}\",state=\"{}\"}} {}",
+                name,
+                state.name(),
+                u8::from(state == current)
+            );
+        }
+    }
+    out.push_str("# HELP db_circuit_breaker_trips_total Times the circuit breaker opened\n");
+    out.push_str("# TYPE db_circuit_breaker_trips_total counter\n");
+    for (name, database) in &databases {
+        let _ = writeln!(
+            out,
+            "db_circuit_breaker_trips_total{{database=\"{}\"}} {}",
+            name,
+            database.breaker().trips.load(Ordering::Relaxed)
+        );
+    }
+    out.push_str(
+        "# HELP db_circuit_breaker_rejected_total Calls turned away by the open circuit breaker\n",
+    );
+    out.push_str("# TYPE db_circuit_breaker_rejected_total counter\n");
+    for (name, database) in &databases {
+        let _ = writeln!(
+            out,
+            "db_circuit_breaker_rejected_total{{database=\"{}\"}} {}",
+            name,
+            database.breaker().rejected.load(Ordering::Relaxed)
+        );
+    }
+}
+
+fn render_limiter(limiter: &Limiter, out: &mut String) {
+    out.push_str(
+        "# HELP http_requests_in_flight Requests being handled under the concurrency limit\n",
+    );
+    out.push_str("# TYPE http_requests_in_flight gauge\n");
+    let _ = writeln!(out, "http_requests_in_flight {}", limiter.in_flight());
+    out.push_str("# HELP http_requests_queued Requests waiting for their turn\n");
+    out.push_str("# TYPE http_requests_queued gauge\n");
+    let _ = writeln!(out, "http_requests_queued {}", limiter.queued());
+    out.push_str(
+        "# HELP http_requests_rejected_total Requests answered 503 as the queue was full\n",
+    );
+    out.push_str("# TYPE http_requests_rejected_total counter\n");
+    let _ = writeln!(
+        out,
+        "http_requests_rejected_total {}",
+        limiter.rejected.load(Ordering::Relaxed)
+    );
+}
+
+// Prometheus text exposition format
+#[get("/metrics")]
+async fn get_metrics(
+    db: web::Data<Cluster>,
+    queries: web::Data<QueryMetrics>,
+    limiter: web::Data<Limiter>,
+    responses: web::Data<HttpMetrics>,
+) -> impl Responder {
+    let mut out = String::new();
+    queries.render(&mut out);
+    responses.render(&mut out);
+    render_breakers(&db, &mut out);
+    if limiter.is_enabled() {
+        render_limiter(&limiter, &mut out);
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(out)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_metrics);
+}
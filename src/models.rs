@@ -0,0 +1,256 @@
+use serde_json::{Map, Value};
+use std::time::SystemTime;
+use tokio_postgres::{Error, Row};
+
+use crate::db::FromRow;
+use crate::filter::{Field, Filterable, Kind};
+use crate::pii;
+
+// Mode: User struct with id, name, email
+#[derive(Serialize, Deserialize)]
+pub struct User {
+    pub id: Option<i64>,
+    pub name: String,
+    pub email: String,
+    // set by following the link of the verification email, ignored on input
+    #[serde(default)]
+    pub email_verified: bool,
+    // only ever read from requests, the database holds its hash
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    // changed through the suspend and activate routes, ignored on input
+    #[serde(default, skip_deserializing)]
+    pub status: AccountStatus,
+    // taken from the request, never from its body
+    #[serde(default, skip_deserializing)]
+    pub tenant_id: String,
+    // the user this one was merged into by an admin, ignored on input
+    #[serde(default, skip_deserializing)]
+    pub merged_into: Option<i64>,
+    // sent as Last-Modified, None until stored
+    #[serde(skip)]
+    pub updated_at: Option<SystemTime>,
+}
+
+impl FromRow for User {
+    fn from_row(row: &Row) -> Result<User, Error> {
+        Ok(User {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            email: pii::reveal(row.try_get("email")?),
+            email_verified: row.try_get("email_verified")?,
+            password: None,
+            status: AccountStatus::parse(row.try_get("status")?).unwrap_or_default(),
+            tenant_id: row.try_get("tenant_id")?,
+            merged_into: row.try_get("merged_into")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+// What a client may do with a field of a body
+#[derive(Clone, Copy, PartialEq)]
+pub enum Access {
+    ReadWrite,
+    // sent, ignored on input
+    ReadOnly,
+    // taken, never sent
+    WriteOnly,
+}
+
+// A field of a body as the API sends and takes it, for GET /api/v1/schema
+pub struct Attribute {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub access: Access,
+    pub required: bool,
+}
+
+impl User {
+    // Follows the serde attributes of the struct
+    pub const ATTRIBUTES: &'static [Attribute] = &[
+        Attribute {
+            name: "id",
+            kind: Kind::Integer,
+            access: Access::ReadOnly,
+            required: false,
+        },
+        Attribute {
+            name: "name",
+            kind: Kind::Text,
+            access: Access::ReadWrite,
+            required: true,
+        },
+        Attribute {
+            name: "email",
+            kind: Kind::Text,
+            access: Access::ReadWrite,
+            required: true,
+        },
+        Attribute {
+            name: "email_verified",
+            kind: Kind::Boolean,
+            access: Access::ReadOnly,
+            required: false,
+        },
+        Attribute {
+            name: "password",
+            kind: Kind::Text,
+            access: Access::WriteOnly,
+            required: false,
+        },
+        Attribute {
+            name: "status",
+            kind: Kind::Text,
+            access: Access::ReadOnly,
+            required: false,
+        },
+        Attribute {
+            name: "tenant_id",
+            kind: Kind::Text,
+            access: Access::ReadOnly,
+            required: false,
+        },
+        Attribute {
+            name: "merged_into",
+            kind: Kind::Integer,
+            access: Access::ReadOnly,
+            required: false,
+        },
+    ];
+}
+
+// Columns of `?column[operator]=value` filters on users
+impl Filterable for User {
+    const FIELDS: &'static [Field] = &[
+        Field {
+            column: "id",
+            kind: Kind::Integer,
+        },
+        Field {
+            column: "name",
+            kind: Kind::Text,
+        },
+        Field {
+            column: "email",
+            kind: Kind::Text,
+        },
+        Field {
+            column: "email_verified",
+            kind: Kind::Boolean,
+        },
+        Field {
+            column: "created_at",
+            kind: Kind::Timestamp,
+        },
+        Field {
+            column: "updated_at",
+            kind: Kind::Timestamp,
+        },
+    ];
+}
+
+// Only active users may log in and show up in the default listing.
+// Suspended accounts are locked by an admin, deactivated ones closed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Suspended,
+    Deactivated,
+}
+
+impl AccountStatus {
+    pub const ALL: [AccountStatus; 3] = [
+        AccountStatus::Active,
+        AccountStatus::Suspended,
+        AccountStatus::Deactivated,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Suspended => "suspended",
+            AccountStatus::Deactivated => "deactivated",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<AccountStatus> {
+        AccountStatus::ALL
+            .into_iter()
+            .find(|status| status.name() == name)
+    }
+}
+
+// Columns a client may select with `?fields=`
+#[derive(Clone, Copy, PartialEq)]
+pub enum UserField {
+    Id,
+    Name,
+    Email,
+    EmailVerified,
+    Status,
+    MergedInto,
+}
+
+impl UserField {
+    pub const ALL: [UserField; 6] = [
+        UserField::Id,
+        UserField::Name,
+        UserField::Email,
+        UserField::EmailVerified,
+        UserField::Status,
+        UserField::MergedInto,
+    ];
+
+    pub fn column(&self) -> &'static str {
+        match self {
+            UserField::Id => "id",
+            UserField::Name => "name",
+            UserField::Email => "email",
+            UserField::EmailVerified => "email_verified",
+            UserField::Status => "status",
+            UserField::MergedInto => "merged_into",
+        }
+    }
+
+    // Parse a comma separated field list, rejecting anything not whitelisted
+    pub fn parse_list(list: &str) -> Result<Vec<UserField>, String> {
+        let mut fields = Vec::new();
+        for name in list.split(',').map(str::trim) {
+            let field = match name {
+                "id" => UserField::Id,
+                "name" => UserField::Name,
+                "email" => UserField::Email,
+                "email_verified" => UserField::EmailVerified,
+                "status" => UserField::Status,
+                "merged_into" => UserField::MergedInto,
+                _ => return Err(format!("Unknown field '{}'", name)),
+            };
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        Ok(fields)
+    }
+
+    // Partial user holding only the selected columns, in `fields` order
+    pub fn partial_from_row(fields: &[UserField], row: &Row) -> Result<Map<String, Value>, Error> {
+        let mut user = Map::new();
+        for field in fields {
+            let column = field.column();
+            let value = match field {
+                UserField::Id => Value::from(row.try_get::<_, i64>(column)?),
+                UserField::Name | UserField::Status => {
+                    Value::from(row.try_get::<_, String>(column)?)
+                }
+                UserField::Email => Value::from(pii::reveal(row.try_get(column)?)),
+                UserField::EmailVerified => Value::from(row.try_get::<_, bool>(column)?),
+                UserField::MergedInto => Value::from(row.try_get::<_, Option<i64>>(column)?),
+            };
+            user.insert(column.to_string(), value);
+        }
+        Ok(user)
+    }
+}
@@ -0,0 +1,497 @@
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use log::{error, info};
+use mysql_async::prelude::*;
+use mysql_async::{Error, Opts, Pool, TxOpts};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime};
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::db::Naming;
+use crate::email_rules;
+use crate::ids;
+use crate::jsonapi::{self, Format};
+use crate::links::Links;
+use crate::models::{AccountStatus, User};
+use crate::pii;
+use crate::preconditions;
+use crate::tenant::Tenant;
+use crate::{StatusQuery, TOTAL_COUNT_HEADER, USERS};
+
+// The users table of the Postgres schema, in the MySQL and MariaDB dialect.
+// Created whole: MySQL has no `ADD COLUMN IF NOT EXISTS` to grow it with,
+// the tables created before are migrated by `migrate` instead.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}users (
+        id BIGINT AUTO_INCREMENT PRIMARY KEY,
+        name VARCHAR(255) NOT NULL,
+        email VARCHAR(512) NOT NULL,
+        email_hash VARCHAR(64),
+        email_verified BOOLEAN NOT NULL DEFAULT false,
+        password_hash VARCHAR(255),
+        session_version INT NOT NULL DEFAULT 0,
+        tenant_id VARCHAR(255) NOT NULL DEFAULT 'default',
+        status VARCHAR(16) NOT NULL DEFAULT 'active'
+            CHECK (status IN ('active', 'suspended', 'deactivated')),
+        locked_until TIMESTAMP NULL,
+        avatar_type VARCHAR(255),
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        merged_into BIGINT,
+        email_normalized VARCHAR(512),
+        INDEX {prefix}users_tenant_email (tenant_id, email),
+        INDEX {prefix}users_tenant_email_hash (tenant_id, email_hash),
+        INDEX {prefix}users_tenant_email_normalized (tenant_id, email_normalized),
+        FOREIGN KEY (merged_into) REFERENCES {prefix}users (id) ON DELETE SET NULL
+    )
+";
+
+// The columns added to the table since the first release of the backend,
+// with the statement adding each of them to the tables created before
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    (
+        "locked_until",
+        "ALTER TABLE {prefix}users ADD COLUMN locked_until TIMESTAMP NULL",
+    ),
+    (
+        "avatar_type",
+        "ALTER TABLE {prefix}users ADD COLUMN avatar_type VARCHAR(255)",
+    ),
+    (
+        "merged_into",
+        "ALTER TABLE {prefix}users ADD COLUMN merged_into BIGINT,
+             ADD FOREIGN KEY (merged_into) REFERENCES {prefix}users (id) ON DELETE SET NULL",
+    ),
+    (
+        "email_normalized",
+        "ALTER TABLE {prefix}users ADD COLUMN email_normalized VARCHAR(512),
+             ADD INDEX {prefix}users_tenant_email_normalized (tenant_id, email_normalized)",
+    ),
+];
+
+// What every query selects, decoded by `from_row`
+const COLUMNS: &str =
+    "id, name, email, email_verified, status, tenant_id, merged_into, UNIX_TIMESTAMP(updated_at)";
+
+type UserRow = (i64, String, String, bool, String, String, Option<i64>, i64);
+
+fn from_row(
+    (id, name, email, email_verified, status, tenant_id, merged_into, updated_at): UserRow,
+) -> User {
+    User {
+        id: Some(id),
+        name,
+        email: pii::reveal(email),
+        email_verified,
+        password: None,
+        status: AccountStatus::parse(&status).unwrap_or_default(),
+        tenant_id,
+        merged_into,
+        updated_at: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(updated_at as u64)),
+    }
+}
+
+// The users of a MySQL or MariaDB database, behind the CRUD routes. The
+// other features (logins, webhooks, jobs, the outbox...) need Postgres.
+pub struct Store {
+    pool: Pool,
+    naming: Naming,
+}
+
+impl Store {
+    pub async fn connect(config: &Config) -> Result<Store, Error> {
+        // the driver only knows the mysql scheme, MariaDB speaks the same protocol
+        let url = match config.database_url.strip_prefix("mariadb://") {
+            Some(rest) => format!("mysql://{}", rest),
+            None => config.database_url.clone(),
+        };
+        let opts = Opts::from_url(&url).map_err(Error::Url)?;
+        let store = Store {
+            pool: Pool::new(opts),
+            naming: config.naming.clone(),
+        };
+        let mut conn = store.pool.get_conn().await?;
+        conn.query_drop(store.sql(SCHEMA)).await?;
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    // Brings a table created by an earlier release to SCHEMA: 64 bit ids,
+    // which the snowflakes need, and the columns added since
+    async fn migrate(&self) -> Result<(), Error> {
+        let mut conn = self.pool.get_conn().await?;
+        let columns: Vec<(String, String)> = conn
+            .exec(
+                "SELECT COLUMN_NAME, DATA_TYPE FROM information_schema.COLUMNS
+                 WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+                (self.sql("{prefix}users"),),
+            )
+            .await?;
+        let is_int = |name: &str| {
+            columns
+                .iter()
+                .any(|(column, kind)| column == name && kind.eq_ignore_ascii_case("int"))
+        };
+        if is_int("id") {
+            info!("Migrating the ids of the users to BIGINT");
+            conn.query_drop(
+                self.sql("ALTER TABLE {prefix}users MODIFY id BIGINT NOT NULL AUTO_INCREMENT"),
+            )
+            .await?;
+        }
+        for (name, sql) in ADDED_COLUMNS {
+            if !columns.iter().any(|(column, _)| column == name) {
+                info!("Adding the {} column to the users", name);
+                conn.query_drop(self.sql(sql)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sql(&self, sql: &str) -> String {
+        self.naming.apply(&sql.replace("{columns}", COLUMNS))
+    }
+
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.pool.get_conn().await?.ping().await
+    }
+
+    pub async fn list(&self, tenant: &Tenant, status: AccountStatus) -> Result<Vec<User>, Error> {
+        let mut conn = self.pool.get_conn().await?;
+        let rows: Vec<UserRow> = conn
+            .exec(
+                self.sql(
+                    "SELECT {columns} FROM {prefix}users
+                     WHERE tenant_id = ? AND status = ? ORDER BY id",
+                ),
+                (tenant.as_str(), status.name()),
+            )
+            .await?;
+        Ok(rows.into_iter().map(from_row).collect())
+    }
+
+    pub async fn count(&self, tenant: &Tenant, status: AccountStatus) -> Result<i64, Error> {
+        let mut conn = self.pool.get_conn().await?;
+        let count: Option<(i64,)> = conn
+            .exec_first(
+                self.sql("SELECT COUNT(*) FROM {prefix}users WHERE tenant_id = ? AND status = ?"),
+                (tenant.as_str(), status.name()),
+            )
+            .await?;
+        Ok(count.map_or(0, |(count,)| count))
+    }
+
+    pub async fn find(&self, tenant: &Tenant, id: i64) -> Result<Option<User>, Error> {
+        let mut conn = self.pool.get_conn().await?;
+        let row: Option<UserRow> = conn
+            .exec_first(
+                self.sql("SELECT {columns} FROM {prefix}users WHERE id = ? AND tenant_id = ?"),
+                (id, tenant.as_str()),
+            )
+            .await?;
+        Ok(row.map(from_row))
+    }
+
+    pub async fn create(
+        &self,
+        tenant: &Tenant,
+        user: &User,
+        password_hash: Option<&str>,
+    ) -> Result<User, Error> {
+        let mut conn = self.pool.get_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        // a NULL id is numbered by AUTO_INCREMENT
+        let id = ids::user_id();
+        tx.exec_drop(
+            self.sql(
+                "INSERT INTO {prefix}users
+                     (id, name, email, email_hash, password_hash, tenant_id, email_normalized)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+            ),
+            (
+                id,
+                &user.name,
+                pii::seal(&user.email),
+                pii::email_hash(&user.email),
+                password_hash.map(str::to_string),
+                tenant.as_str(),
+                email_rules::key(&user.email),
+            ),
+        )
+        .await?;
+        let id = id.unwrap_or_else(|| tx.last_insert_id().unwrap_or_default() as i64);
+        // no RETURNING in MySQL, the defaults are read back
+        let row: Option<UserRow> = tx
+            .exec_first(
+                self.sql("SELECT {columns} FROM {prefix}users WHERE id = ?"),
+                (id,),
+            )
+            .await?;
+        tx.commit().await?;
+        Ok(row
+            .map(from_row)
+            .expect("the inserted row is visible to its transaction"))
+    }
+
+    // None when there is no user with this id. Changing the email address
+    // for one normalized differently clears its verification, changing the
+    // password signs the user out.
+    pub async fn update(
+        &self,
+        tenant: &Tenant,
+        id: i64,
+        user: &User,
+        password_hash: Option<&str>,
+    ) -> Result<Option<User>, Error> {
+        let mut conn = self.pool.get_conn().await?;
+        let mut tx = conn.start_transaction(TxOpts::default()).await?;
+        let previous: Option<UserRow> = tx
+            .exec_first(
+                self.sql(
+                    "SELECT {columns} FROM {prefix}users
+                     WHERE id = ? AND tenant_id = ? FOR UPDATE",
+                ),
+                (id, tenant.as_str()),
+            )
+            .await?;
+        let previous = match previous {
+            Some(previous) => from_row(previous),
+            None => return Ok(None),
+        };
+        // the stored addresses are compared decrypted, each encryption differs
+        let email_verified = previous.email_verified
+            && email_rules::normalize(&previous.email) == email_rules::normalize(&user.email);
+        tx.exec_drop(
+            self.sql(
+                "UPDATE {prefix}users
+                 SET name = ?,
+                     email = ?,
+                     email_hash = ?,
+                     email_verified = ?,
+                     email_normalized = ?,
+                     password_hash = COALESCE(?, password_hash),
+                     session_version = session_version + (? IS NOT NULL),
+                     updated_at = CURRENT_TIMESTAMP
+                 WHERE id = ? AND tenant_id = ?",
+            ),
+            (
+                &user.name,
+                pii::seal(&user.email),
+                pii::email_hash(&user.email),
+                email_verified,
+                email_rules::key(&user.email),
+                password_hash.map(str::to_string),
+                password_hash.map(str::to_string),
+                id,
+                tenant.as_str(),
+            ),
+        )
+        .await?;
+        let row: Option<UserRow> = tx
+            .exec_first(
+                self.sql("SELECT {columns} FROM {prefix}users WHERE id = ?"),
+                (id,),
+            )
+            .await?;
+        tx.commit().await?;
+        Ok(row.map(from_row))
+    }
+
+    // false when there is no user with this id
+    pub async fn delete(&self, tenant: &Tenant, id: i64) -> Result<bool, Error> {
+        let mut conn = self.pool.get_conn().await?;
+        conn.exec_drop(
+            self.sql("DELETE FROM {prefix}users WHERE id = ? AND tenant_id = ?"),
+            (id, tenant.as_str()),
+        )
+        .await?;
+        Ok(conn.affected_rows() != 0)
+    }
+}
+
+fn db_error(format: Format, e: Error, message: &str) -> HttpResponse {
+    error!("{}: {}", message, e);
+    format.error(StatusCode::INTERNAL_SERVER_ERROR, message)
+}
+
+fn not_found(format: Format, id: i64) -> HttpResponse {
+    format.error(StatusCode::NOT_FOUND, &format!("User {} not found", id))
+}
+
+#[get("/users", name = "users")]
+async fn get_users(
+    query: web::Query<StatusQuery>,
+    format: Format,
+    tenant: Tenant,
+    store: web::Data<Store>,
+    links: Links,
+) -> impl Responder {
+    let status = match crate::parse_status(format, &query.status) {
+        Ok(status) => status,
+        Err(response) => return response,
+    };
+    match store.list(&tenant, status).await {
+        Ok(users) => {
+            let users: Vec<Value> = users.iter().map(|user| links.with_user(user)).collect();
+            format.respond(StatusCode::OK, USERS, &users)
+        }
+        Err(e) => db_error(format, e, "Failed to retrieve users"),
+    }
+}
+
+#[get("/users/count")]
+async fn count_users(
+    query: web::Query<StatusQuery>,
+    format: Format,
+    tenant: Tenant,
+    store: web::Data<Store>,
+) -> impl Responder {
+    let status = match crate::parse_status(format, &query.status) {
+        Ok(status) => status,
+        Err(response) => return response,
+    };
+    let count = match store.count(&tenant, status).await {
+        Ok(count) => count,
+        Err(e) => return db_error(format, e, "Failed to count users"),
+    };
+    let body = match format {
+        Format::Json => json!({ "count": count }),
+        Format::JsonApi => json!({ "meta": { "count": count } }),
+    };
+    let content_type = match format {
+        Format::Json => "application/json",
+        Format::JsonApi => jsonapi::MEDIA_TYPE,
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((TOTAL_COUNT_HEADER, count))
+        .body(body.to_string())
+}
+
+#[post("/users")]
+async fn create_user(
+    admin: Option<Admin>,
+    body: web::Json<User>,
+    format: Format,
+    tenant: Tenant,
+    store: web::Data<Store>,
+    links: Links,
+) -> impl Responder {
+    let user = body.into_inner();
+    let password_hash =
+        match crate::admin_password_hash(format, &admin, user.password.as_deref()).await {
+            Ok(hash) => hash,
+            Err(response) => return response,
+        };
+    match store.create(&tenant, &user, password_hash.as_deref()).await {
+        Ok(user) => {
+            info!("New id: {}", user.id.unwrap_or_default());
+            format.respond(StatusCode::CREATED, USERS, &links.with_user(&user))
+        }
+        Err(e) => db_error(format, e, "Failed to insert into DB"),
+    }
+}
+
+#[get("/users/{id}", name = "user")]
+async fn get_user(
+    path: web::Path<String>,
+    format: Format,
+    tenant: Tenant,
+    store: web::Data<Store>,
+    links: Links,
+) -> impl Responder {
+    let id = match crate::parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    match store.find(&tenant, id).await {
+        Ok(Some(user)) => preconditions::last_modified(
+            format.respond(StatusCode::OK, USERS, &links.with_user(&user)),
+            user.updated_at,
+        ),
+        Ok(None) => not_found(format, id),
+        Err(e) => db_error(format, e, &format!("Failed to retrieve user {}", id)),
+    }
+}
+
+#[put("/users/{id}", name = "update_user")]
+async fn update_user(
+    admin: Option<Admin>,
+    path: web::Path<String>,
+    body: web::Json<User>,
+    format: Format,
+    tenant: Tenant,
+    store: web::Data<Store>,
+    links: Links,
+) -> impl Responder {
+    let user = body.into_inner();
+    let id = match crate::parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let password_hash =
+        match crate::admin_password_hash(format, &admin, user.password.as_deref()).await {
+            Ok(hash) => hash,
+            Err(response) => return response,
+        };
+    match store
+        .update(&tenant, id, &user, password_hash.as_deref())
+        .await
+    {
+        Ok(Some(user)) => preconditions::last_modified(
+            format.respond(StatusCode::OK, USERS, &links.with_user(&user)),
+            user.updated_at,
+        ),
+        Ok(None) => not_found(format, id),
+        Err(e) => db_error(format, e, &format!("Failed to update user {}", id)),
+    }
+}
+
+#[delete("/users/{id}", name = "delete_user")]
+async fn delete_user(
+    path: web::Path<String>,
+    format: Format,
+    tenant: Tenant,
+    store: web::Data<Store>,
+) -> impl Responder {
+    let id = match crate::parse_id(format, &path) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    info!("Deleting user '{}'", id);
+    match store.delete(&tenant, id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => not_found(format, id),
+        Err(e) => db_error(format, e, &format!("Failed to delete user {}", id)),
+    }
+}
+
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+// Ready while the database answers a ping
+#[get("/readyz")]
+async fn readyz(store: web::Data<Store>) -> impl Responder {
+    match store.ping().await {
+        Ok(()) => HttpResponse::Ok().json(json!({ "primary": { "healthy": true } })),
+        Err(e) => {
+            error!("MySQL is unreachable: {}", e);
+            HttpResponse::ServiceUnavailable().json(json!({ "primary": { "healthy": false } }))
+        }
+    }
+}
+
+// The user CRUD routes, all that a MySQL database serves
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_users)
+        .service(count_users)
+        .service(create_user)
+        .service(get_user)
+        .service(update_user)
+        .service(delete_user)
+        .service(healthz)
+        .service(readyz);
+}
@@ -0,0 +1,36 @@
+use async_trait::async_trait;
+
+use crate::outbox::Publisher;
+use crate::repository::OutboxMessage;
+
+// Publishes outbox messages as JSON on `<prefix>.<event>`, e.g.
+// `events.user.created`
+pub struct NatsPublisher {
+    client: async_nats::Client,
+    subject_prefix: String,
+}
+
+impl NatsPublisher {
+    pub async fn connect(
+        url: &str,
+        subject_prefix: &str,
+    ) -> Result<NatsPublisher, async_nats::ConnectError> {
+        Ok(NatsPublisher {
+            client: async_nats::connect(url).await?,
+            subject_prefix: subject_prefix.to_string(),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Publisher for NatsPublisher {
+    async fn publish(&self, message: &OutboxMessage) -> Result<(), String> {
+        let subject = format!("{}.{}", self.subject_prefix, message.event);
+        self.client
+            .publish(subject, message.payload.to_string().into())
+            .await
+            .map_err(|e| e.to_string())?;
+        // only report success once the server has the message
+        self.client.flush().await.map_err(|e| e.to_string())
+    }
+}
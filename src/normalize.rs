@@ -0,0 +1,146 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::http::uri::{PathAndQuery, Uri};
+use actix_web::{HttpMessage, HttpResponse};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::config::Config;
+use crate::static_site;
+
+// What to do with a request whose path is not in its canonical form
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathMode {
+    // route the path as it is
+    Off,
+    // route the request as if it had been sent to the canonical path
+    Merge,
+    // answer 308 Permanent Redirect to the canonical path
+    Redirect,
+}
+
+impl PathMode {
+    pub fn parse(name: &str) -> Option<PathMode> {
+        match name {
+            "off" => Some(PathMode::Off),
+            "merge" => Some(PathMode::Merge),
+            "redirect" => Some(PathMode::Redirect),
+            _ => None,
+        }
+    }
+}
+
+// Canonical paths have no repeated and no trailing `/`, so `/users/` and
+// `//users` both lead to `/users`. With `lowercase`, the API paths are also
+// lowercased, the files of the frontend keep their case.
+pub struct NormalizePath {
+    mode: PathMode,
+    lowercase: bool,
+}
+
+impl NormalizePath {
+    pub fn new(config: &Config) -> NormalizePath {
+        NormalizePath {
+            mode: config.path_normalization,
+            lowercase: config.case_insensitive_paths,
+        }
+    }
+}
+
+// The URI as the client sent it, in the extensions of the requests routed
+// as their canonical path
+pub struct SentUri(pub Uri);
+
+// None when `path` is canonical already
+fn canonical(path: &str, lowercase: bool) -> Option<String> {
+    let mut canonical = String::with_capacity(path.len());
+    for c in path.chars() {
+        if !(c == '/' && canonical.ends_with('/')) {
+            canonical.push(c);
+        }
+    }
+    if canonical.len() > 1 && canonical.ends_with('/') {
+        canonical.pop();
+    }
+    if lowercase {
+        let lower = canonical.to_ascii_lowercase();
+        if static_site::is_api_path(&lower) {
+            canonical = lower;
+        }
+    }
+    if canonical == path {
+        None
+    } else {
+        Some(canonical)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for NormalizePath
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = NormalizePathMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(NormalizePathMiddleware {
+            service: Rc::new(service),
+            mode: self.mode,
+            lowercase: self.lowercase,
+        }))
+    }
+}
+
+pub struct NormalizePathMiddleware<S> {
+    service: Rc<S>,
+    mode: PathMode,
+    lowercase: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for NormalizePathMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let path = match self.mode {
+            PathMode::Off => None,
+            _ => canonical(req.path(), self.lowercase),
+        };
+        if let Some(path) = path {
+            let target = match req.query_string() {
+                "" => path,
+                query => format!("{}?{}", path, query),
+            };
+            if self.mode == PathMode::Redirect {
+                let response = HttpResponse::PermanentRedirect()
+                    .insert_header((header::LOCATION, target))
+                    .finish();
+                return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+            }
+            let mut parts = req.head().uri.clone().into_parts();
+            if let Ok(path_and_query) = PathAndQuery::from_maybe_shared(target) {
+                parts.path_and_query = Some(path_and_query);
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    req.match_info_mut().get_mut().update(&uri);
+                    let sent = std::mem::replace(&mut req.head_mut().uri, uri);
+                    req.extensions_mut().insert(SentUri(sent));
+                }
+            }
+        }
+        let service = Rc::clone(&self.service);
+        Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) })
+    }
+}
@@ -0,0 +1,458 @@
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::{header, StatusCode};
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use log::{error, info, warn};
+use serde_json::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+use crate::auth;
+use crate::config::Config;
+use crate::crypto;
+use crate::db::{Cluster, DbError};
+use crate::events::Event;
+use crate::jobs::{self, JobKind};
+use crate::jsonapi::Format;
+use crate::models::{AccountStatus, User};
+use crate::repository::UnitOfWork;
+use crate::tenant::Tenant;
+
+// Holds the nonce of the login in progress, the `state` parameter must match
+const STATE_COOKIE: &str = "oauth_state";
+// Time the user has to complete the login at the provider
+const STATE_TTL: Duration = Duration::from_secs(600);
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProviderKind {
+    Google,
+    GitHub,
+}
+
+impl ProviderKind {
+    pub const ALL: [ProviderKind; 2] = [ProviderKind::Google, ProviderKind::GitHub];
+
+    // As it appears in the routes and in the OAUTH_<NAME>_* variables
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProviderKind::Google => "google",
+            ProviderKind::GitHub => "github",
+        }
+    }
+
+    fn authorize_url(&self) -> &'static str {
+        match self {
+            ProviderKind::Google => "https://accounts.google.com/o/oauth2/v2/auth",
+            ProviderKind::GitHub => "https://github.com/login/oauth/authorize",
+        }
+    }
+
+    fn token_url(&self) -> &'static str {
+        match self {
+            ProviderKind::Google => "https://oauth2.googleapis.com/token",
+            ProviderKind::GitHub => "https://github.com/login/oauth/access_token",
+        }
+    }
+
+    fn scope(&self) -> &'static str {
+        match self {
+            ProviderKind::Google => "openid email profile",
+            ProviderKind::GitHub => "read:user user:email",
+        }
+    }
+}
+
+pub struct OAuthProvider {
+    pub kind: ProviderKind,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+// What we learn about the user from the provider
+struct Profile {
+    subject: String,
+    name: String,
+    email: String,
+    email_verified: bool,
+}
+
+fn provider<'a>(config: &'a Config, name: &str) -> Option<&'a OAuthProvider> {
+    config
+        .oauth_providers
+        .iter()
+        .find(|provider| provider.kind.name() == name)
+}
+
+fn redirect_uri(config: &Config, provider: &OAuthProvider) -> String {
+    format!(
+        "{}/auth/{}/callback",
+        config.public_url,
+        provider.kind.name()
+    )
+}
+
+// `<tenant>.<nonce>.<expiry>.<signature>`, the nonce is also kept in a
+// cookie so the callback can only complete a login started from the same
+// browser
+fn state(secret: &str, provider: &str, tenant: &Tenant, nonce: &str, expires: u64) -> String {
+    let data = format!("oauth-state:{}:{}:{}:{}", provider, tenant, nonce, expires);
+    let signature = crypto::hex(&crypto::hmac_sha256(secret.as_bytes(), data.as_bytes()));
+    format!("{}.{}.{}.{}", tenant, nonce, expires, signature)
+}
+
+// The tenant the login was started for, None when the state is not valid
+fn check_state(secret: &str, provider: &str, state_param: &str, cookie: &str) -> Option<Tenant> {
+    let mut parts = state_param.splitn(4, '.');
+    let tenant = Tenant::parse(parts.next()?)?;
+    let nonce = parts.next()?;
+    let expires = parts.next()?.parse().ok()?;
+    let expected = state(secret, provider, &tenant, nonce, expires);
+    let valid = expires >= now()
+        && bool::from(expected.as_bytes().ct_eq(state_param.as_bytes()))
+        && bool::from(nonce.as_bytes().ct_eq(cookie.as_bytes()));
+    if valid {
+        Some(tenant)
+    } else {
+        None
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    tenant: Option<String>,
+}
+
+// Send the user to the provider's consent page
+#[get("/auth/{provider}/login")]
+async fn login(
+    path: web::Path<String>,
+    query: web::Query<LoginQuery>,
+    format: Format,
+    config: web::Data<Config>,
+) -> impl Responder {
+    // a link can't set X-Tenant-Id, the tenant is a query parameter here
+    let tenant = match &query.tenant {
+        Some(tenant) => match Tenant::parse(tenant) {
+            Some(tenant) => tenant,
+            None => return format.error(StatusCode::BAD_REQUEST, "Invalid tenant id"),
+        },
+        None => Tenant::default(),
+    };
+    let provider = match provider(&config, &path) {
+        Some(provider) => provider,
+        None => {
+            return format.error(
+                StatusCode::NOT_FOUND,
+                &format!("Unknown login provider {}", path),
+            )
+        }
+    };
+    let nonce = crypto::random_token(16);
+    let state = state(
+        &config.secret_key,
+        provider.kind.name(),
+        &tenant,
+        &nonce,
+        now() + STATE_TTL.as_secs(),
+    );
+    let location = url::Url::parse_with_params(
+        provider.kind.authorize_url(),
+        &[
+            ("response_type", "code"),
+            ("client_id", provider.client_id.as_str()),
+            ("redirect_uri", redirect_uri(&config, provider).as_str()),
+            ("scope", provider.kind.scope()),
+            ("state", state.as_str()),
+        ],
+    )
+    .expect("Provider URLs are valid");
+    let cookie = Cookie::build(STATE_COOKIE, nonce)
+        .path("/auth")
+        .http_only(true)
+        .secure(config.public_url.starts_with("https://"))
+        .same_site(SameSite::Lax)
+        .max_age(actix_web::cookie::time::Duration::seconds(
+            STATE_TTL.as_secs() as i64,
+        ))
+        .finish();
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location.as_str()))
+        .cookie(cookie)
+        .finish()
+}
+
+#[derive(Deserialize)]
+struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+// The provider sends the user back here: trade the code for the profile,
+// find or create the matching user and log them in
+#[get("/auth/{provider}/callback")]
+async fn callback(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<Callback>,
+    format: Format,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let provider = match provider(&config, &path) {
+        Some(provider) => provider,
+        None => {
+            return format.error(
+                StatusCode::NOT_FOUND,
+                &format!("Unknown login provider {}", path),
+            )
+        }
+    };
+    if let Some(e) = &query.error {
+        return format.error(StatusCode::BAD_REQUEST, &format!("Login refused: {}", e));
+    }
+    let (code, state_param) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return format.error(StatusCode::BAD_REQUEST, "Missing code or state"),
+    };
+    let cookie = req
+        .cookie(STATE_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .unwrap_or_default();
+    let tenant = match check_state(
+        &config.secret_key,
+        provider.kind.name(),
+        state_param,
+        &cookie,
+    ) {
+        Some(tenant) => tenant,
+        None => return format.error(StatusCode::BAD_REQUEST, "Invalid or expired login state"),
+    };
+    let profile = match fetch_profile(&config, provider, code).await {
+        Ok(profile) => profile,
+        Err(e) => {
+            warn!("{} login failed: {}", provider.kind.name(), e);
+            return format.error(StatusCode::BAD_GATEWAY, "Login provider unavailable");
+        }
+    };
+    if profile.email.is_empty() {
+        return format.error(
+            StatusCode::BAD_REQUEST,
+            "The login provider did not share an email address",
+        );
+    }
+    let kind = provider.kind;
+    let (profile, config_ref, tenant_ref) = (&profile, &config, &tenant);
+    let result: Result<Result<auth::SessionTokens, Refused>, DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let user = match provision(uow, kind, profile).await? {
+                    Some(user) => user,
+                    None => return Ok(Err(Refused::EmailTaken)),
+                };
+                let user_id = user.id.unwrap_or_default();
+                // None for accounts that may not log in
+                let version = match uow.users().session_version(user_id).await? {
+                    Some(version) => version,
+                    None => {
+                        uow.discard();
+                        return Ok(Err(Refused::Status(user.status)));
+                    }
+                };
+                let family = crypto::random_token(16);
+                let tokens = auth::issue_tokens(
+                    uow.refresh_tokens(),
+                    config_ref,
+                    tenant_ref,
+                    user_id,
+                    version,
+                    &family,
+                )
+                .await?;
+                info!("User {} logged in with {}", user_id, kind.name());
+                Ok(Ok(tokens))
+            })
+        })
+        .await;
+    match result {
+        Ok(Ok(tokens)) => {
+            let mut response = auth::token_pair(&config, &tokens);
+            let cookie = Cookie::build(STATE_COOKIE, "").path("/auth").finish();
+            if let Err(e) = response.add_removal_cookie(&cookie) {
+                error!("Failed to clear the login state cookie: {}", e);
+            }
+            response
+        }
+        Ok(Err(Refused::Status(status))) => format.error(
+            StatusCode::FORBIDDEN,
+            &format!("Account is {}", status.name()),
+        ),
+        Ok(Err(Refused::EmailTaken)) => format.error(
+            StatusCode::CONFLICT,
+            "Another account has this email address, log in with its password",
+        ),
+        Err(e) => format.db_error(e, "Failed to log in"),
+    }
+}
+
+// Why a provider login gets no tokens
+enum Refused {
+    Status(AccountStatus),
+    // held by an account that can't be linked
+    EmailTaken,
+}
+
+// The user linked to the provider account, created on first login. An
+// existing account is only linked when both sides verified the address,
+// None when there is one that can't be.
+async fn provision(
+    uow: &UnitOfWork<'_>,
+    kind: ProviderKind,
+    profile: &Profile,
+) -> Result<Option<User>, tokio_postgres::Error> {
+    let identities = uow.identities();
+    if let Some(user) = identities.find_user(kind.name(), &profile.subject).await? {
+        return Ok(Some(user));
+    }
+    let existing = if profile.email_verified {
+        uow.users()
+            .find_by_email(&profile.email)
+            .await?
+            .filter(|user| user.email_verified)
+    } else {
+        None
+    };
+    let user = match existing {
+        Some(user) => user,
+        None if uow.users().email_taken(&profile.email, None).await? => return Ok(None),
+        None => {
+            let new_user = User {
+                id: None,
+                name: profile.name.clone(),
+                email: profile.email.clone(),
+                email_verified: false,
+                password: None,
+                status: AccountStatus::Active,
+                tenant_id: String::new(),
+                merged_into: None,
+                updated_at: None,
+            };
+            let mut user = uow.users().create(&new_user, None).await?;
+            if profile.email_verified {
+                if let Some(verified) = uow
+                    .users()
+                    .mark_email_verified(user.id.unwrap_or_default())
+                    .await?
+                {
+                    user = verified;
+                }
+            }
+            uow.publish(&Event::user_created(&user)).await?;
+            let job = if user.email_verified {
+                JobKind::WelcomeEmail
+            } else {
+                JobKind::VerificationEmail
+            };
+            uow.jobs().enqueue(job, &jobs::for_user(&user)).await?;
+            user
+        }
+    };
+    identities
+        .link(kind.name(), &profile.subject, user.id.unwrap_or_default())
+        .await?;
+    Ok(Some(user))
+}
+
+async fn fetch_profile(
+    config: &Config,
+    provider: &OAuthProvider,
+    code: &str,
+) -> Result<Profile, String> {
+    let http = awc::Client::builder()
+        .timeout(PROVIDER_TIMEOUT)
+        .add_default_header((header::USER_AGENT, "rust-crud-api"))
+        .finish();
+    let redirect_uri = redirect_uri(config, provider);
+    let token: Value = http
+        .post(provider.kind.token_url())
+        .insert_header((header::ACCEPT, "application/json"))
+        .send_form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("client_secret", provider.client_secret.as_str()),
+        ])
+        .await
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    let access_token = token["access_token"]
+        .as_str()
+        .ok_or_else(|| format!("No access token in {}", token))?;
+    let get = |url: &'static str| {
+        let request = http.get(url).bearer_auth(access_token);
+        async move {
+            let mut response = request.send().await.map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("{} answered HTTP {}", url, response.status()));
+            }
+            response.json::<Value>().await.map_err(|e| e.to_string())
+        }
+    };
+    match provider.kind {
+        ProviderKind::Google => {
+            let info = get("https://openidconnect.googleapis.com/v1/userinfo").await?;
+            Ok(Profile {
+                subject: string(&info["sub"]),
+                name: string(&info["name"]),
+                email: string(&info["email"]),
+                email_verified: info["email_verified"].as_bool().unwrap_or(false),
+            })
+        }
+        ProviderKind::GitHub => {
+            let info = get("https://api.github.com/user").await?;
+            // the profile only shows the public address, if any
+            let emails = get("https://api.github.com/user/emails").await?;
+            let primary = emails
+                .as_array()
+                .and_then(|emails| emails.iter().find(|email| email["primary"] == true));
+            let name = match info["name"].as_str() {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => string(&info["login"]),
+            };
+            Ok(Profile {
+                subject: string(&info["id"]),
+                name,
+                email: primary
+                    .map(|email| string(&email["email"]))
+                    .unwrap_or_default(),
+                email_verified: primary
+                    .and_then(|email| email["verified"].as_bool())
+                    .unwrap_or(false),
+            })
+        }
+    }
+}
+
+// Strings as they are, numbers (GitHub ids) in decimal
+fn string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(login).service(callback);
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
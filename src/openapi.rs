@@ -0,0 +1,515 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde_json::{json, Map, Value};
+
+use crate::auth::MIN_PASSWORD_LENGTH;
+use crate::build_info;
+use crate::field_policy::FieldPolicy;
+use crate::filter::Kind;
+use crate::models::{Access, AccountStatus, Attribute, User};
+
+// The JSON Schema of a field, with the constraints the handlers check
+fn property(attribute: &Attribute) -> Value {
+    let mut property = Map::new();
+    let kind = match attribute.kind {
+        Kind::Integer => "integer",
+        Kind::Boolean => "boolean",
+        Kind::Text | Kind::Timestamp => "string",
+    };
+    property.insert("type".to_string(), json!(kind));
+    if attribute.kind == Kind::Timestamp {
+        property.insert("format".to_string(), json!("date-time"));
+    }
+    match attribute.name {
+        "email" => {
+            property.insert("format".to_string(), json!("email"));
+        }
+        "password" => {
+            property.insert("minLength".to_string(), json!(MIN_PASSWORD_LENGTH));
+        }
+        "status" => {
+            let statuses: Vec<_> = AccountStatus::ALL.iter().map(AccountStatus::name).collect();
+            property.insert("enum".to_string(), json!(statuses));
+        }
+        "merged_into" => {
+            property.insert("nullable".to_string(), json!(true));
+        }
+        _ => (),
+    }
+    match attribute.access {
+        Access::ReadOnly => property.insert("readOnly".to_string(), json!(true)),
+        Access::WriteOnly => property.insert("writeOnly".to_string(), json!(true)),
+        Access::ReadWrite => None,
+    };
+    Value::Object(property)
+}
+
+// A user as sent: every field but the write only ones, all of them but
+// those the field policy hides, and its links
+fn user(policy: &FieldPolicy) -> Value {
+    let mut properties: Map<String, Value> = User::ATTRIBUTES
+        .iter()
+        .filter(|attribute| attribute.access != Access::WriteOnly)
+        .map(|attribute| (attribute.name.to_string(), property(attribute)))
+        .collect();
+    let required: Vec<_> = properties
+        .keys()
+        .filter(|field| !policy.is_hidden(field))
+        .cloned()
+        .collect();
+    properties.insert("_links".to_string(), json!({ "type": "object" }));
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+// A user as taken by POST and PUT, the read only fields being ignored
+fn new_user() -> Value {
+    let writable = || {
+        User::ATTRIBUTES
+            .iter()
+            .filter(|attribute| attribute.access != Access::ReadOnly)
+    };
+    let properties: Map<String, Value> = writable()
+        .map(|attribute| (attribute.name.to_string(), property(attribute)))
+        .collect();
+    let required: Vec<_> = writable()
+        .filter(|attribute| attribute.required)
+        .map(|attribute| attribute.name)
+        .collect();
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+// A value `schema` accepts, for the examples of the request bodies: its
+// `example` or first `enum` value, else one built from its type, format and
+// bounds. Objects get every property that isn't read only.
+fn example(schema: &Value) -> Value {
+    if let Some(example) = schema.get("example") {
+        return example.clone();
+    }
+    if let Some(first) = schema.get("enum").and_then(|values| values.get(0)) {
+        return first.clone();
+    }
+    match schema["type"].as_str() {
+        Some("object") => {
+            let properties = schema["properties"].as_object().into_iter().flatten();
+            Value::Object(
+                properties
+                    .filter(|(_, property)| property["readOnly"] != json!(true))
+                    .map(|(name, property)| (name.clone(), example(property)))
+                    .collect(),
+            )
+        }
+        Some("array") => json!([example(&schema["items"])]),
+        Some("integer") | Some("number") => {
+            schema.get("minimum").cloned().unwrap_or_else(|| json!(1))
+        }
+        Some("boolean") => json!(true),
+        Some("string") => match schema["format"].as_str() {
+            Some("email") => json!("jane.doe@example.com"),
+            Some("date-time") => json!("2024-01-01T00:00:00Z"),
+            Some("date") => json!("2024-01-01"),
+            Some("uri") => json!("https://example.com"),
+            _ => {
+                let length = schema["minLength"].as_u64().unwrap_or_default() as usize;
+                json!(format!("{:x<1$}", "example", length))
+            }
+        },
+        _ => Value::Null,
+    }
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+// The errors are problem details, see fallback::problem
+fn error(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/problem+json": { "schema": reference("Problem") } },
+    })
+}
+
+// `schema` as a JSON response
+fn content(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+// A request body of `schema`, with an example generated from it
+fn body(name: &str, schema: &Value) -> Value {
+    json!({
+        "required": true,
+        "content": {
+            "application/json": {
+                "schema": reference(name),
+                "example": example(schema),
+            },
+        },
+    })
+}
+
+// The errors every operation may get from the middlewares, and those of the
+// bodies for the operations taking one
+fn add_shared_errors(paths: &mut Value) {
+    let operations = paths
+        .as_object_mut()
+        .into_iter()
+        .flat_map(|paths| paths.values_mut())
+        .filter_map(Value::as_object_mut)
+        .flat_map(|operations| operations.values_mut());
+    for operation in operations {
+        let takes_body = operation.get("requestBody").is_some();
+        if let Some(responses) = operation
+            .get_mut("responses")
+            .and_then(Value::as_object_mut)
+        {
+            responses
+                .entry("429")
+                .or_insert_with(|| error("Over the quota of the caller, see Retry-After"));
+            if takes_body {
+                responses
+                    .entry("400")
+                    .or_insert_with(|| error("Invalid JSON"));
+                responses
+                    .entry("413")
+                    .or_insert_with(|| error("Body larger than MAX_JSON_BYTES"));
+                responses
+                    .entry("415")
+                    .or_insert_with(|| error("Body that isn't JSON"));
+            }
+        }
+    }
+}
+
+// OpenAPI 3.0 description of the user CRUD routes, derived from the same
+// definitions as GET /api/v1/schema. The contract tests below replay its
+// examples against the application and check the responses against it.
+pub fn spec(policy: &FieldPolicy) -> Value {
+    let new_user = new_user();
+    let id = json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "integer" },
+    });
+    let statuses: Vec<_> = AccountStatus::ALL.iter().map(AccountStatus::name).collect();
+    let mut spec = json!({
+        "openapi": "3.0.3",
+        "info": { "title": "rust-crud-api", "version": build_info::VERSION },
+        "paths": {
+            "/users": {
+                "get": {
+                    "operationId": "listUsers",
+                    "parameters": [{
+                        "name": "status",
+                        "in": "query",
+                        "schema": { "type": "string", "enum": statuses },
+                    }],
+                    "responses": {
+                        "200": content("The users", json!({ "type": "array", "items": reference("User") })),
+                        "400": error("Invalid status"),
+                    },
+                },
+                "post": {
+                    "operationId": "createUser",
+                    "requestBody": body("NewUser", &new_user),
+                    "responses": {
+                        "201": content("The user created", reference("User")),
+                        "400": error("Invalid user"),
+                        "409": error("Email address of another user"),
+                    },
+                },
+            },
+            "/users/count": {
+                "get": {
+                    "operationId": "countUsers",
+                    "responses": {
+                        "200": content("The number of users", reference("Count")),
+                    },
+                },
+            },
+            "/users/{id}": {
+                "get": {
+                    "operationId": "getUser",
+                    "parameters": [id],
+                    "responses": {
+                        "200": content("The user", reference("User")),
+                        "404": error("No such user"),
+                    },
+                },
+                "put": {
+                    "operationId": "updateUser",
+                    "parameters": [id],
+                    "requestBody": body("NewUser", &new_user),
+                    "responses": {
+                        "200": content("The user updated", reference("User")),
+                        "400": error("Invalid user"),
+                        "401": error("Not signed in as the user or an admin"),
+                        "403": error("Signed in as another user"),
+                        "404": error("No such user"),
+                        "409": error("Email address of another user"),
+                        "412": error("Modified since If-Unmodified-Since"),
+                    },
+                },
+                "delete": {
+                    "operationId": "deleteUser",
+                    "parameters": [id],
+                    "responses": {
+                        "204": { "description": "The user was deleted" },
+                        "401": error("Not signed in as the user or an admin"),
+                        "403": error("Signed in as another user"),
+                        "404": error("No such user"),
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": {
+                "User": user(policy),
+                "NewUser": new_user,
+                // RFC 7807, some list their violations in `errors` too
+                "Problem": {
+                    "type": "object",
+                    "properties": {
+                        "type": { "type": "string" },
+                        "title": { "type": "string" },
+                        "status": { "type": "integer" },
+                        "detail": { "type": "string" },
+                    },
+                    "required": ["type", "title", "status", "detail"],
+                },
+                "Count": {
+                    "type": "object",
+                    "properties": { "count": { "type": "integer", "minimum": 0 } },
+                    "required": ["count"],
+                    "additionalProperties": false,
+                },
+            },
+        },
+    });
+    add_shared_errors(&mut spec["paths"]);
+    spec
+}
+
+#[get("/api/v1/openapi.json")]
+async fn get_openapi(policy: web::Data<FieldPolicy>) -> impl Responder {
+    HttpResponse::Ok().json(spec(&policy))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_openapi);
+}
+
+// Contract tests: replay the examples of the spec against the application,
+// served in-process as test_app.rs does, and check every response against
+// the schema the spec gives for its status, so that the spec and the
+// handlers can't drift apart
+#[cfg(test)]
+mod tests {
+    use actix_web::body::MessageBody;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::header;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::{json, Map, Value};
+
+    use crate::config::Config;
+    use crate::field_policy::FieldPolicy;
+    use crate::json_schema::Schema;
+    use crate::test_app::{admin, email, shared};
+    use crate::{app, DB_URL};
+
+    // `schema` with its references to the components of `spec` replaced by
+    // them
+    fn resolve(spec: &Value, schema: &Value) -> Value {
+        match schema {
+            Value::Object(keywords) => match keywords.get("$ref").and_then(Value::as_str) {
+                Some(reference) => {
+                    let pointer = reference.strip_prefix('#').expect("external reference");
+                    let target = spec
+                        .pointer(pointer)
+                        .unwrap_or_else(|| panic!("dangling reference {}", reference));
+                    resolve(spec, target)
+                }
+                None => Value::Object(
+                    keywords
+                        .iter()
+                        .map(|(keyword, value)| (keyword.clone(), resolve(spec, value)))
+                        .collect::<Map<_, _>>(),
+                ),
+            },
+            Value::Array(schemas) => schemas.iter().map(|schema| resolve(spec, schema)).collect(),
+            _ => schema.clone(),
+        }
+    }
+
+    // The violations of `value` against `schema`, as `pointer: message` lines
+    fn violations(spec: &Value, schema: &Value, value: &Value) -> String {
+        let schema = Schema::compile(&resolve(spec, schema))
+            .unwrap_or_else(|e| panic!("invalid schema in the spec: {}", e));
+        schema
+            .validate(value)
+            .iter()
+            .map(|violation| format!("{}: {}", violation.pointer, violation.message))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn operation<'a>(spec: &'a Value, method: &str, path: &str) -> &'a Value {
+        let operation = &spec["paths"][path][method.to_ascii_lowercase()];
+        assert!(
+            operation.is_object(),
+            "{} {} isn't in the spec",
+            method,
+            path
+        );
+        operation
+    }
+
+    // The example of the request body of the operation
+    fn example(spec: &Value, method: &str, path: &str) -> Value {
+        operation(spec, method, path)["requestBody"]["content"]["application/json"]["example"]
+            .clone()
+    }
+
+    // Checks the response to `method path`, `path` being the one of the
+    // spec, against the one the spec documents for its status. Returns the
+    // JSON body, Null for the others.
+    async fn check<B: MessageBody>(
+        spec: &Value,
+        method: &str,
+        path: &str,
+        response: ServiceResponse<B>,
+    ) -> (u16, Value) {
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = test::read_body(response).await;
+        let name = format!("{} {} answered {}", method, path, status);
+        let documented = &operation(spec, method, path)["responses"][status.to_string().as_str()];
+        assert!(documented.is_object(), "{}, which isn't documented", name);
+        let content = documented["content"]
+            .as_object()
+            .cloned()
+            .unwrap_or_default();
+        if content.is_empty() {
+            assert!(body.is_empty(), "{} with an undocumented body", name);
+            return (status, Value::Null);
+        }
+        let media_type = content_type.split(';').next().unwrap_or_default();
+        let schema = &content
+            .get(media_type)
+            .unwrap_or_else(|| panic!("{} with an undocumented {}", name, media_type))["schema"];
+        // problem details included
+        let value = if media_type == "application/json" || media_type.ends_with("+json") {
+            serde_json::from_slice(&body)
+                .unwrap_or_else(|e| panic!("{} with invalid JSON: {}", name, e))
+        } else {
+            Value::String(String::from_utf8_lossy(&body).into_owned())
+        };
+        let violations = violations(spec, schema, &value);
+        assert!(
+            violations.is_empty(),
+            "{}, not as documented:\n{}",
+            name,
+            violations
+        );
+        (status, value)
+    }
+
+    #[test]
+    fn examples_match_their_schemas() {
+        let spec = super::spec(&FieldPolicy::new(Config::from_env(DB_URL).field_policy));
+        let paths = spec["paths"].as_object().expect("the spec has no paths");
+        let mut examples = 0;
+        for (path, operations) in paths {
+            for (method, operation) in operations.as_object().into_iter().flatten() {
+                let content = &operation["requestBody"]["content"]["application/json"];
+                if content.is_null() {
+                    continue;
+                }
+                let example = content
+                    .get("example")
+                    .unwrap_or_else(|| panic!("{} {} has no example", method, path));
+                let violations = violations(&spec, &content["schema"], example);
+                assert!(
+                    violations.is_empty(),
+                    "the example of {} {} doesn't match its schema:\n{}",
+                    method,
+                    path,
+                    violations
+                );
+                examples += 1;
+            }
+        }
+        assert!(examples > 0, "the spec has no example");
+    }
+
+    #[actix_web::test]
+    async fn users_behave_as_documented() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let response = send(admin("GET", "/api/v1/openapi.json")).await;
+        assert_eq!(response.status(), 200, "GET /api/v1/openapi.json");
+        let spec: Value = test::read_body_json(response).await;
+
+        // addresses are unique, the runs sharing the tables
+        let mut created = example(&spec, "POST", "/users");
+        created["email"] = json!(email("example"));
+        let response = send(admin("POST", "/users").set_json(&created)).await;
+        let (status, user) = check(&spec, "POST", "/users", response).await;
+        assert_eq!(status, 201, "POST /users with its example");
+        let id = user["id"].as_i64().expect("the user created has no id");
+        let user_path = format!("/users/{}", id);
+
+        let response = send(admin("GET", &user_path)).await;
+        let (status, _) = check(&spec, "GET", "/users/{id}", response).await;
+        assert_eq!(status, 200, "GET /users/{{id}} of the user created");
+        let mut update = example(&spec, "PUT", "/users/{id}");
+        update["email"] = json!(email("example"));
+        let response = send(admin("PUT", &user_path).set_json(&update)).await;
+        let (status, _) = check(&spec, "PUT", "/users/{id}", response).await;
+        assert_eq!(status, 200, "PUT /users/{{id}} with its example");
+        for path in ["/users", "/users/count"] {
+            check(&spec, "GET", path, send(admin("GET", path)).await).await;
+        }
+
+        // the errors of the bodies
+        let text = admin("PUT", &user_path)
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .set_payload("Jane Doe");
+        let (status, _) = check(&spec, "PUT", "/users/{id}", send(text).await).await;
+        assert_eq!(status, 415, "PUT /users/{{id}} of text");
+        let invalid = admin("PUT", &user_path)
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload("{\"name\":");
+        let (status, _) = check(&spec, "PUT", "/users/{id}", send(invalid).await).await;
+        assert_eq!(status, 400, "PUT /users/{{id}} of invalid JSON");
+        let huge = admin("POST", "/users").set_json(json!({ "name": "x".repeat(1 << 20) }));
+        let (status, _) = check(&spec, "POST", "/users", send(huge).await).await;
+        assert_eq!(status, 413, "POST /users of a huge body");
+
+        let response = send(admin("DELETE", &user_path)).await;
+        let (status, _) = check(&spec, "DELETE", "/users/{id}", response).await;
+        assert_eq!(status, 204, "DELETE /users/{{id}} of the user created");
+        let response = send(admin("GET", &user_path)).await;
+        let (status, _) = check(&spec, "GET", "/users/{id}", response).await;
+        assert_eq!(status, 404, "GET /users/{{id}} of the user deleted");
+        let response = send(admin("DELETE", &user_path)).await;
+        let (status, _) = check(&spec, "DELETE", "/users/{id}", response).await;
+        assert_eq!(status, 404, "DELETE /users/{{id}} of the user deleted");
+    }
+}
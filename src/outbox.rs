@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use log::warn;
+use std::time::Duration;
+
+use crate::db::{Database, DbError};
+use crate::repository::{OutboxMessage, UnitOfWork};
+use crate::tenant::Tenant;
+
+// Messages relayed per transaction
+const BATCH_SIZE: i64 = 100;
+
+// Destination of the outbox messages, e.g. a message broker
+#[async_trait(?Send)]
+pub trait Publisher {
+    async fn publish(&self, message: &OutboxMessage) -> Result<(), String>;
+}
+
+// Background task moving committed events from the outbox table to every
+// publisher. A message is marked published only once all publishers have
+// acknowledged it, so delivery is at-least-once, in outbox order.
+pub fn spawn_relay(db: Database, publishers: Vec<Box<dyn Publisher>>, poll_interval: Duration) {
+    actix_web::rt::spawn(async move {
+        loop {
+            match relay_batch(&db, &publishers).await {
+                // a full batch, more messages may be waiting
+                Ok(count) if count as i64 == BATCH_SIZE => {}
+                Ok(_) => tokio::time::sleep(poll_interval).await,
+                Err(e) => {
+                    warn!("Outbox relay: {}", e);
+                    tokio::time::sleep(poll_interval).await;
+                }
+            }
+        }
+    });
+}
+
+async fn relay_batch(db: &Database, publishers: &[Box<dyn Publisher>]) -> Result<usize, DbError> {
+    let mut client = db.checkout().await?;
+    // the outbox is shared by all tenants
+    let tenant = Tenant::default();
+    let uow = UnitOfWork::begin(&mut client, &tenant).await?;
+    let messages = uow.outbox().claim_unpublished(BATCH_SIZE).await?;
+    let mut published = Vec::new();
+    'messages: for message in &messages {
+        for publisher in publishers {
+            if let Err(e) = publisher.publish(message).await {
+                // stop here, later messages must not overtake this one; it
+                // is sent again to all publishers on the next attempt
+                warn!("Failed to publish outbox message {}: {}", message.id, e);
+                break 'messages;
+            }
+        }
+        published.push(message.id);
+    }
+    uow.outbox().mark_published(&published).await?;
+    uow.commit().await?;
+    Ok(published.len())
+}
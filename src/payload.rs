@@ -0,0 +1,141 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::web;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::config::Config;
+use crate::fallback;
+use crate::jsonapi::Format;
+use crate::routes;
+
+// Limit and errors of the `web::Json` extractor
+pub fn json_config(config: &Config) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(config.max_json_bytes)
+        .error_handler(|err, req| {
+            let status = match &err {
+                JsonPayloadError::Overflow { .. }
+                | JsonPayloadError::OverflowKnownLength { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                JsonPayloadError::ContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            let response = fallback::respond(Format::of(req), status, &err.to_string());
+            InternalError::from_response(err, response).into()
+        })
+}
+
+pub fn is_json(content_type: &str) -> bool {
+    let media = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media == "application/json" || (media.starts_with("application/") && media.ends_with("+json"))
+}
+
+// Refuses bodies of write requests that aren't JSON with a 415 and those
+// announcing more than MAX_JSON_BYTES with a 413, before they are read.
+// Bodies without a Content-Length are cut off by the JSON extractor.
+pub struct JsonBodies {
+    max_bytes: usize,
+}
+
+impl JsonBodies {
+    pub fn new(config: &Config) -> JsonBodies {
+        JsonBodies {
+            max_bytes: config.max_json_bytes,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for JsonBodies
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = JsonBodiesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(JsonBodiesMiddleware {
+            service: Rc::new(service),
+            max_bytes: self.max_bytes,
+        }))
+    }
+}
+
+pub struct JsonBodiesMiddleware<S> {
+    service: Rc<S>,
+    max_bytes: usize,
+}
+
+impl<S> JsonBodiesMiddleware<S> {
+    fn check(&self, req: &ServiceRequest) -> Result<(), (StatusCode, String)> {
+        if !matches!(
+            *req.method(),
+            Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+        ) || routes::matching(req.path()).any(|route| route.upload)
+        {
+            return Ok(());
+        }
+        let headers = req.headers();
+        let length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        let has_body = length.map_or(headers.contains_key(header::TRANSFER_ENCODING), |length| {
+            length > 0
+        });
+        if !has_body {
+            return Ok(());
+        }
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        if !content_type.is_some_and(is_json) {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!(
+                    "Expected an application/json body, got {}",
+                    content_type.unwrap_or("no Content-Type")
+                ),
+            ));
+        }
+        match length {
+            Some(length) if length > self.max_bytes => Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Bodies are limited to {} bytes", self.max_bytes),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for JsonBodiesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Err((status, detail)) = self.check(&req) {
+            let response = fallback::respond(Format::of(req.request()), status, &detail);
+            return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+        }
+        let service = self.service.clone();
+        Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) })
+    }
+}
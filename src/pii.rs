@@ -0,0 +1,228 @@
+use actix_web::{post, web, HttpResponse, Responder};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{error, info};
+use rand::RngCore;
+use serde_json::json;
+use std::sync::OnceLock;
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::crypto;
+use crate::db::{Cluster, DbError};
+use crate::jsonapi::Format;
+use crate::tenant::Tenant;
+
+// Encrypted emails are stored as `enc:<key id>:<base64 nonce and
+// ciphertext>`, anything else is an address written before encryption was
+// turned on and is read as is
+const PREFIX: &str = "enc:";
+const NONCE_BYTES: usize = 12;
+const DEFAULT_ROTATE_LIMIT: i64 = 1000;
+
+static KEYRING: OnceLock<Keyring> = OnceLock::new();
+
+struct Key {
+    // first bytes of the key hash, names the key in the stored values
+    id: String,
+    cipher: Aes256Gcm,
+    // keys the blind index, derived so that it changes with the key
+    index_key: Vec<u8>,
+}
+
+impl Key {
+    // A base64 encoded 256 bit key, as `openssl rand -base64 32` prints
+    fn parse(encoded: &str) -> Result<Key, String> {
+        let bytes = STANDARD.decode(encoded.trim()).map_err(|e| e.to_string())?;
+        let cipher = Aes256Gcm::new_from_slice(&bytes)
+            .map_err(|_| format!("expected 32 bytes, got {}", bytes.len()))?;
+        Ok(Key {
+            id: crypto::sha256_hex(&bytes)[..8].to_string(),
+            cipher,
+            index_key: crypto::hmac_sha256(&bytes, b"email index"),
+        })
+    }
+
+    fn hash(&self, email: &str) -> String {
+        crypto::hex(&crypto::hmac_sha256(&self.index_key, email.as_bytes()))
+    }
+}
+
+// AES-256-GCM keys of the email column. The first one encrypts, the others
+// are former keys, still needed to read the rows not rotated yet.
+pub struct Keyring {
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    // None unless EMAIL_ENCRYPTION_KEY is set
+    pub fn from_config(config: &Config) -> Option<Keyring> {
+        let current = config.email_encryption_key.as_ref()?;
+        let keys = std::iter::once(current)
+            .chain(&config.email_encryption_old_keys)
+            .map(|encoded| {
+                Key::parse(encoded)
+                    .unwrap_or_else(|e| panic!("Invalid email encryption key: {}", e))
+            })
+            .collect();
+        Some(Keyring { keys })
+    }
+
+    fn current(&self) -> &Key {
+        &self.keys[0]
+    }
+
+    fn seal(&self, email: &str) -> String {
+        let key = self.current();
+        let mut nonce = [0u8; NONCE_BYTES];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = key
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), email.as_bytes())
+            .expect("AES-GCM encrypts messages of any reasonable size");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        format!("{}{}:{}", PREFIX, key.id, STANDARD.encode(sealed))
+    }
+
+    fn open(&self, stored: &str) -> Result<String, String> {
+        let (id, sealed) = stored[PREFIX.len()..]
+            .split_once(':')
+            .ok_or("malformed value")?;
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == id)
+            .ok_or_else(|| format!("unknown key {}", id))?;
+        let sealed = STANDARD.decode(sealed).map_err(|e| e.to_string())?;
+        if sealed.len() < NONCE_BYTES {
+            return Err("malformed value".to_string());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+        let email = key
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "authentication failed".to_string())?;
+        String::from_utf8(email).map_err(|e| e.to_string())
+    }
+}
+
+// Encrypt the emails written from now on, with `keyring`. Set once at
+// startup, before the first user is read.
+pub fn install(keyring: Keyring) {
+    if KEYRING.set(keyring).is_err() {
+        panic!("The email encryption keys are already installed");
+    }
+}
+
+pub fn is_enabled() -> bool {
+    KEYRING.get().is_some()
+}
+
+// Columns whose values are only known to the application
+pub fn is_encrypted(column: &str) -> bool {
+    is_enabled() && column == "email"
+}
+
+// What the email column holds for `email`
+pub fn seal(email: &str) -> String {
+    match KEYRING.get() {
+        Some(keyring) => keyring.seal(email),
+        None => email.to_string(),
+    }
+}
+
+// The address behind the value of the email column
+pub fn open(stored: String) -> Result<String, String> {
+    if !stored.starts_with(PREFIX) {
+        return Ok(stored);
+    }
+    match KEYRING.get() {
+        Some(keyring) => keyring.open(&stored),
+        None => Err("EMAIL_ENCRYPTION_KEY is not set".to_string()),
+    }
+}
+
+// Like `open`, a value that can't be decrypted is returned as stored after
+// logging why
+pub fn reveal(stored: String) -> String {
+    open(stored.clone()).unwrap_or_else(|e| {
+        error!("Failed to decrypt an email: {}", e);
+        stored
+    })
+}
+
+// The blind index of `email` under the current key, stored in the
+// email_hash column: lookups by address can't compare ciphertexts, each
+// encryption of the same address differs
+pub fn email_hash(email: &str) -> Option<String> {
+    KEYRING.get().map(|keyring| keyring.current().hash(email))
+}
+
+// The blind indexes `email` may be stored under, one per key. Empty when
+// emails aren't encrypted.
+pub fn email_hashes(email: &str) -> Vec<String> {
+    KEYRING
+        .get()
+        .map(|keyring| keyring.keys.iter().map(|key| key.hash(email)).collect())
+        .unwrap_or_default()
+}
+
+// `LIKE` pattern of the values encrypted with the current key
+pub fn current_pattern() -> Option<String> {
+    KEYRING
+        .get()
+        .map(|keyring| format!("{}{}:%", PREFIX, keyring.current().id))
+}
+
+#[derive(Deserialize)]
+struct RotateQuery {
+    limit: Option<i64>,
+}
+
+// Re-encrypt up to `limit` emails of the tenant with the current key, plain
+// ones included. Call it again until nothing `remaining`, then the former
+// keys can be dropped from EMAIL_ENCRYPTION_OLD_KEYS.
+#[post("/admin/email-encryption/rotate")]
+async fn rotate(
+    _admin: Admin,
+    query: web::Query<RotateQuery>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let pattern = match current_pattern() {
+        Some(pattern) => pattern,
+        None => return HttpResponse::BadRequest().body("EMAIL_ENCRYPTION_KEY is not set"),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_ROTATE_LIMIT);
+    if limit < 1 {
+        return HttpResponse::BadRequest().body("limit must be positive");
+    }
+    let pattern = &pattern;
+    let result: Result<(u64, i64), DbError> = db
+        .primary()
+        .transaction(&tenant, |uow| {
+            Box::pin(async move {
+                let rotated = uow.users().rotate_emails(pattern, limit).await?;
+                let remaining = uow.users().count_unrotated_emails(pattern).await?;
+                Ok((rotated, remaining))
+            })
+        })
+        .await;
+    match result {
+        Ok((rotated, remaining)) => {
+            info!(
+                "Re-encrypted {} email(s) of tenant {}, {} remaining",
+                rotated, tenant, remaining
+            );
+            HttpResponse::Ok().json(json!({ "rotated": rotated, "remaining": remaining }))
+        }
+        Err(e) => Format::Json.db_error(e, "Failed to rotate the email encryption key"),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(rotate);
+}
@@ -0,0 +1,149 @@
+use actix_web::dev::Payload;
+use actix_web::http::header::{
+    Header, HttpDate, IfModifiedSince, IfUnmodifiedSince, LastModified, TryIntoHeaderValue,
+};
+use actix_web::{FromRequest, HttpRequest, HttpResponse};
+use std::future::{ready, Ready};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// If-Modified-Since and If-Unmodified-Since of a request, compared with the
+// last change of a resource to the second, the precision of HTTP dates.
+// An invalid date counts as none.
+pub struct Preconditions {
+    modified_since: Option<SystemTime>,
+    unmodified_since: Option<SystemTime>,
+}
+
+fn seconds(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+impl Preconditions {
+    // For GET: the copy of the client is still current, answer 304
+    pub fn not_modified(&self, last_modified: SystemTime) -> bool {
+        self.modified_since
+            .is_some_and(|since| seconds(last_modified) <= seconds(since))
+    }
+
+    // For writes: the resource changed since the copy of the client, answer
+    // 412 rather than overwrite the change
+    pub fn failed(&self, last_modified: SystemTime) -> bool {
+        self.unmodified_since
+            .is_some_and(|since| seconds(last_modified) > seconds(since))
+    }
+
+    pub fn has_unmodified_since(&self) -> bool {
+        self.unmodified_since.is_some()
+    }
+}
+
+impl FromRequest for Preconditions {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(Preconditions {
+            modified_since: IfModifiedSince::parse(req).ok().map(|since| since.0.into()),
+            unmodified_since: IfUnmodifiedSince::parse(req)
+                .ok()
+                .map(|since| since.0.into()),
+        }))
+    }
+}
+
+pub fn last_modified(mut response: HttpResponse, at: Option<SystemTime>) -> HttpResponse {
+    if let Some(at) = at {
+        if let Ok(value) = LastModified(HttpDate::from(at)).try_into_value() {
+            response.headers_mut().insert(LastModified::name(), value);
+        }
+    }
+    response
+}
+
+pub fn not_modified(at: SystemTime) -> HttpResponse {
+    last_modified(HttpResponse::NotModified().finish(), Some(at))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::json;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::app;
+    use crate::test_app::{self, admin, email, shared};
+
+    #[test]
+    fn dates_are_compared_to_the_second() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let since = |date: Option<SystemTime>| Preconditions {
+            modified_since: date,
+            unmodified_since: date,
+        };
+        let same_second = since(Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        assert!(same_second.not_modified(at));
+        assert!(!same_second.failed(at));
+        let before = since(Some(UNIX_EPOCH + Duration::from_secs(1_699_999_999)));
+        assert!(!before.not_modified(at));
+        assert!(before.failed(at));
+        let none = since(None);
+        assert!(!none.not_modified(at) && !none.failed(at) && !none.has_unmodified_since());
+    }
+
+    #[actix_web::test]
+    async fn users_honor_the_preconditions() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let user = json!({ "name": "Jane Doe", "email": email("preconditions") });
+        let (status, user) =
+            test_app::json(send(admin("POST", "/users").set_json(&user)).await).await;
+        assert_eq!(status, 201);
+        let path = format!("/users/{}", user["id"]);
+
+        let response = send(admin("GET", &path)).await;
+        let last_modified = response
+            .headers()
+            .get(header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .expect("no Last-Modified")
+            .to_string();
+        let date: SystemTime = last_modified.parse::<HttpDate>().unwrap().into();
+        let earlier = HttpDate::from(date - Duration::from_secs(3600)).to_string();
+
+        let response = send(
+            admin("GET", &path).insert_header((header::IF_MODIFIED_SINCE, last_modified.as_str())),
+        )
+        .await;
+        assert_eq!(response.status(), 304, "GET unchanged since");
+        let response =
+            send(admin("GET", &path).insert_header((header::IF_MODIFIED_SINCE, earlier.as_str())))
+                .await;
+        assert_eq!(response.status(), 200, "GET changed since");
+
+        let update = json!({ "name": "Jane Roe", "email": user["email"] });
+        let stale = admin("PUT", &path)
+            .insert_header((header::IF_UNMODIFIED_SINCE, earlier.as_str()))
+            .set_json(&update);
+        let response = send(stale).await;
+        assert_eq!(response.status(), 412, "PUT changed since");
+        assert!(response.headers().contains_key(header::LAST_MODIFIED));
+        let (_, current) = test_app::json(send(admin("GET", &path)).await).await;
+        assert_eq!(current["name"], "Jane Doe", "overwritten despite the 412");
+        let fresh = admin("PUT", &path)
+            .insert_header((header::IF_UNMODIFIED_SINCE, last_modified.as_str()))
+            .set_json(&update);
+        assert_eq!(send(fresh).await.status(), 200, "PUT unchanged since");
+
+        let stale =
+            admin("DELETE", &path).insert_header((header::IF_UNMODIFIED_SINCE, earlier.as_str()));
+        assert_eq!(send(stale).await.status(), 412, "DELETE changed since");
+        let later = HttpDate::from(SystemTime::now() + Duration::from_secs(60)).to_string();
+        let fresh =
+            admin("DELETE", &path).insert_header((header::IF_UNMODIFIED_SINCE, later.as_str()));
+        assert_eq!(send(fresh).await.status(), 204, "DELETE unchanged since");
+    }
+}
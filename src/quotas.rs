@@ -0,0 +1,559 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, web, FromRequest, HttpMessage, HttpResponse, Responder};
+use log::{error, info};
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::admin::Admin;
+use crate::api_keys::Authenticated;
+use crate::auth;
+use crate::config::Config;
+use crate::db::{Cluster, Database, DbError};
+use crate::fallback;
+use crate::jsonapi::Format;
+use crate::signatures::Signed;
+use crate::static_site;
+use crate::tenant::Tenant;
+
+pub const LIMIT_HEADER: HeaderName = HeaderName::from_static("x-quota-limit");
+pub const REMAINING_HEADER: HeaderName = HeaderName::from_static("x-quota-remaining");
+// seconds until the period ends and the quota is whole again
+pub const RESET_HEADER: HeaderName = HeaderName::from_static("x-quota-reset");
+
+// Never counted: the probes and scrapes; the admin API is left out below
+const EXEMPT: [&str; 4] = ["/healthz", "/readyz", "/metrics", "/version"];
+
+// How long a subject without requests is remembered
+const IDLE: Duration = Duration::from_secs(60);
+
+// How long a quota lasts before it starts over
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+impl QuotaPeriod {
+    pub fn parse(name: &str) -> Option<QuotaPeriod> {
+        match name {
+            "day" => Some(QuotaPeriod::Day),
+            "month" => Some(QuotaPeriod::Month),
+            _ => None,
+        }
+    }
+
+    // as date_trunc takes it
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuotaPeriod::Day => "day",
+            QuotaPeriod::Month => "month",
+        }
+    }
+}
+
+// The requests per period of `tenant`, 0 for no quota
+fn tenant_limit(config: &Config, tenant: &Tenant) -> i64 {
+    config
+        .tenant_quotas
+        .iter()
+        .find(|(id, _)| id == tenant.as_str())
+        .map_or(config.tenant_quota, |(_, quota)| *quota)
+}
+
+// The requests per period of `key`, 0 for no quota
+fn key_limit(config: &Config, key: &Authenticated) -> i64 {
+    key.0.quota.unwrap_or(config.api_key_quota)
+}
+
+// The requests per period of each IP address of the anonymous requests
+fn ip_limit(config: &Config) -> i64 {
+    config.ip_quota.unwrap_or(config.tenant_quota)
+}
+
+fn is_counted(path: &str) -> bool {
+    static_site::is_api_path(path)
+        && !EXEMPT.contains(&path)
+        && path != "/admin"
+        && !path.starts_with("/admin/")
+}
+
+// The quota a request was checked against, the one with the fewest
+// requests left when both its tenant and its API key have one
+struct Checked {
+    subject: String,
+    limit: i64,
+    // None once exhausted
+    requests: Option<i64>,
+    reset_in: i64,
+}
+
+impl Checked {
+    fn remaining(&self) -> i64 {
+        self.requests.map_or(0, |requests| self.limit - requests)
+    }
+
+    fn insert_headers(&self, headers: &mut header::HeaderMap) {
+        for (name, value) in [
+            (LIMIT_HEADER, self.limit),
+            (REMAINING_HEADER, self.remaining()),
+            (RESET_HEADER, self.reset_in.max(0)),
+        ] {
+            headers.insert(name, HeaderValue::from(value));
+        }
+    }
+}
+
+// What this instance knows of the requests of a subject in the period
+struct Count {
+    // in the database at the last flush, those of the other instances
+    // included
+    stored: i64,
+    // let through since, not flushed yet. Negative when the first count
+    // went over the quota, to be taken back.
+    pending: i64,
+    resets_at: Instant,
+    used_at: Instant,
+}
+
+impl Count {
+    fn requests(&self) -> i64 {
+        self.stored + self.pending
+    }
+}
+
+// The requests of the subjects, counted in memory and added to the
+// quota_usage table every QUOTA_FLUSH_SECS in one statement, so that the
+// requests don't all write the same rows of the primary. Only the first
+// request of a subject waits for the database, to learn what the other
+// instances counted. The instances see each other's requests at every
+// flush: together they may let a few requests more than the quota through.
+#[derive(Default)]
+pub struct QuotaCounts {
+    counts: Mutex<HashMap<String, Count>>,
+}
+
+impl QuotaCounts {
+    // Counts a request of `subject` unless it made `limit` already
+    async fn count(
+        &self,
+        db: &Database,
+        period: &str,
+        subject: &str,
+        limit: i64,
+    ) -> Result<Checked, DbError> {
+        let now = Instant::now();
+        let checked = |count: &Count, requests: Option<i64>| Checked {
+            subject: subject.to_string(),
+            limit,
+            requests,
+            reset_in: count.resets_at.saturating_duration_since(now).as_secs() as i64,
+        };
+        {
+            let mut counts = self.counts.lock().unwrap();
+            match counts.get_mut(subject) {
+                Some(count) if count.resets_at > now => {
+                    count.used_at = now;
+                    if count.requests() >= limit {
+                        return Ok(checked(count, None));
+                    }
+                    count.pending += 1;
+                    return Ok(checked(count, Some(count.requests())));
+                }
+                Some(_) => {
+                    counts.remove(subject);
+                }
+                None => (),
+            }
+        }
+        let subjects = [subject.to_string()];
+        let added = db
+            .run(|client| {
+                let subjects = &subjects;
+                async move { client.quotas().add(subjects, &[1], period).await }
+            })
+            .await?;
+        let (requests, reset_in) = added
+            .first()
+            .map(|(_, requests, reset_in)| (*requests, *reset_in))
+            .unwrap_or_default();
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(subject.to_string()).or_insert(Count {
+            stored: 0,
+            pending: 0,
+            resets_at: now + Duration::from_secs(reset_in.max(0) as u64),
+            used_at: now,
+        });
+        // another request may have counted it meanwhile
+        count.stored = count.stored.max(requests);
+        if requests > limit {
+            count.pending -= 1;
+            return Ok(checked(count, None));
+        }
+        Ok(checked(count, Some(count.requests())))
+    }
+
+    // Takes back a request `count` let through, another quota refusing it
+    fn uncount(&self, subject: &str) {
+        if let Some(count) = self.counts.lock().unwrap().get_mut(subject) {
+            count.pending -= 1;
+        }
+    }
+
+    // After an admin reset, the next request reads the count again
+    fn forget(&self, subject: &str) {
+        self.counts.lock().unwrap().remove(subject);
+    }
+
+    // Adds the pending requests to the database and learns those of the
+    // other instances. The subjects idle for a while, and the counts of a
+    // period that ended, are dropped.
+    async fn flush(&self, db: &Database, period: &str) -> Result<(), DbError> {
+        let now = Instant::now();
+        let (subjects, pending): (Vec<String>, Vec<i64>) = {
+            let mut counts = self.counts.lock().unwrap();
+            counts.retain(|_, count| {
+                count.resets_at > now && (count.pending != 0 || now - count.used_at < IDLE)
+            });
+            counts
+                .iter_mut()
+                .map(|(subject, count)| {
+                    let pending = std::mem::take(&mut count.pending);
+                    count.stored += pending;
+                    (subject.clone(), pending)
+                })
+                .unzip()
+        };
+        if subjects.is_empty() {
+            return Ok(());
+        }
+        let added = db
+            .run(|client| {
+                let (subjects, pending) = (&subjects, &pending);
+                async move { client.quotas().add(subjects, pending, period).await }
+            })
+            .await;
+        let mut counts = self.counts.lock().unwrap();
+        match added {
+            Ok(added) => {
+                for (subject, requests, _) in added {
+                    if let Some(count) = counts.get_mut(&subject) {
+                        count.stored = requests;
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // added again at the next flush
+                for (subject, pending) in subjects.iter().zip(pending) {
+                    if let Some(count) = counts.get_mut(subject) {
+                        count.stored -= pending;
+                        count.pending += pending;
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+pub fn spawn_flush(db: Database, counts: Arc<QuotaCounts>, config: &Config) {
+    let (period, interval) = (config.quota_period.name(), config.quota_flush);
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = counts.flush(&db, period).await {
+                error!("Failed to store the request counts: {}", e);
+            }
+        }
+    });
+}
+
+// Counts the API requests of every tenant, API key and anonymous IP address
+// against their quota of requests per QUOTA_PERIOD, stored in Postgres so
+// that every instance shares them, see QuotaCounts. Requests over the quota get a 429 with Retry-After until the
+// period ends, and aren't counted; the others carry X-Quota-Limit,
+// X-Quota-Remaining and X-Quota-Reset. The probes, /metrics and the admin
+// API are never counted. Off unless TENANT_QUOTA, TENANT_QUOTAS or
+// API_KEY_QUOTA, IP_QUOTA, or a key created with a quota.
+pub struct Quotas;
+
+impl<S, B> Transform<S, ServiceRequest> for Quotas
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = QuotasMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(QuotasMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct QuotasMiddleware<S> {
+    service: Rc<S>,
+}
+
+// The quotas that apply to the request, by subject. A tenant is only
+// charged for the requests proven to come from it: with one of its access
+// tokens, API keys or partners. X-Tenant-Id alone would let anyone use up
+// the quota of another tenant, those requests count against their IP
+// address instead.
+fn subjects(req: &ServiceRequest, config: &Config) -> Vec<(String, i64)> {
+    let mut subjects = Vec::new();
+    let key = req
+        .extensions()
+        .get::<Authenticated>()
+        .map(|key| (format!("api_key:{}", key.0.id), key_limit(config, key)));
+    let trusted = key.is_some() || req.extensions().get::<Signed>().is_some();
+    let tenant = match auth::access_token(req.request(), config) {
+        Some(token) => auth::token_tenant(config, &token),
+        // the tenant of the key or the partner, a mismatch is refused further on
+        None if trusted => Tenant::extract(req.request()).into_inner().ok(),
+        None => None,
+    };
+    match tenant {
+        Some(tenant) => {
+            subjects.push((format!("tenant:{}", tenant), tenant_limit(config, &tenant)));
+        }
+        None => {
+            let ip = req
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default();
+            subjects.push((format!("ip:{}", ip), ip_limit(config)));
+        }
+    }
+    subjects.extend(key);
+    subjects.retain(|(_, limit)| *limit > 0);
+    subjects
+}
+
+impl<S, B> Service<ServiceRequest> for QuotasMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let context = req
+            .app_data::<web::Data<Config>>()
+            .zip(req.app_data::<web::Data<Cluster>>())
+            .zip(req.app_data::<web::Data<QuotaCounts>>());
+        let subjects = match context {
+            Some(((config, _), _)) if is_counted(req.path()) => subjects(&req, config),
+            _ => Vec::new(),
+        };
+        if subjects.is_empty() {
+            return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) });
+        }
+        let ((config, db), counts) = context.expect("quota context checked above");
+        let (period, db, counts) = (config.quota_period.name(), db.clone(), counts.clone());
+        Box::pin(async move {
+            let mut checked: Option<Checked> = None;
+            let mut counted_for = Vec::new();
+            for (subject, limit) in subjects {
+                // the request isn't held up by a failing count
+                let quota = match counts.count(db.primary(), period, &subject, limit).await {
+                    Ok(quota) => quota,
+                    Err(e) => {
+                        error!("Failed to count the request of {}: {}", subject, e);
+                        continue;
+                    }
+                };
+                let exhausted = quota.requests.is_none();
+                if checked
+                    .as_ref()
+                    .is_none_or(|checked| quota.remaining() < checked.remaining())
+                {
+                    checked = Some(quota);
+                }
+                if exhausted {
+                    break;
+                }
+                counted_for.push(subject);
+            }
+            let checked = match checked {
+                Some(checked) => checked,
+                None => return Ok(service.call(req).await?.map_into_left_body()),
+            };
+            if checked.requests.is_none() {
+                for subject in &counted_for {
+                    counts.uncount(subject);
+                }
+                let message = match checked.subject.split(':').next() {
+                    Some("api_key") => "Request quota of the API key exhausted",
+                    Some("ip") => "Request quota of the IP address exhausted",
+                    _ => "Request quota of the tenant exhausted",
+                };
+                let mut response = fallback::respond(
+                    Format::of(req.request()),
+                    StatusCode::TOO_MANY_REQUESTS,
+                    message,
+                );
+                checked.insert_headers(response.headers_mut());
+                response.headers_mut().insert(
+                    header::RETRY_AFTER,
+                    HeaderValue::from(checked.reset_in.max(1)),
+                );
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            let mut response = service.call(req).await?;
+            checked.insert_headers(response.headers_mut());
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+// The usage of the current period, with the quota of each subject, as of
+// the last flush of every instance
+#[get("/admin/quotas")]
+async fn get_quotas(
+    _admin: Admin,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let period = config.quota_period.name();
+    let result = db
+        .primary()
+        .run(|client| async move {
+            let usage = client.quotas().list(period).await?;
+            let keys = client.api_keys().list_all().await?;
+            Ok((usage, keys))
+        })
+        .await;
+    let (usage, keys) = match result {
+        Ok(found) => found,
+        Err(e) => return Format::Json.db_error(e, "Failed to retrieve quotas"),
+    };
+    let key_quotas: HashMap<_, _> = keys
+        .iter()
+        .map(|key| {
+            (
+                format!("api_key:{}", key.id),
+                key.quota.unwrap_or(config.api_key_quota),
+            )
+        })
+        .collect();
+    let usage: Vec<_> = usage
+        .iter()
+        .map(|usage| {
+            let limit = match usage
+                .subject
+                .strip_prefix("tenant:")
+                .and_then(Tenant::parse)
+            {
+                Some(tenant) => tenant_limit(&config, &tenant),
+                None if usage.subject.starts_with("ip:") => ip_limit(&config),
+                None => key_quotas.get(&usage.subject).copied().unwrap_or_default(),
+            };
+            let remaining = (limit > 0).then(|| (limit - usage.requests).max(0));
+            json!({
+                "subject": usage.subject,
+                "requests": usage.requests,
+                "limit": (limit > 0).then_some(limit),
+                "remaining": remaining,
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({ "period": period, "usage": usage }))
+}
+
+// Gives `subject` its whole quota back for the current period
+#[delete("/admin/quotas/{subject}")]
+async fn reset_quota(
+    _admin: Admin,
+    path: web::Path<String>,
+    db: web::Data<Cluster>,
+    counts: web::Data<QuotaCounts>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let subject = &path.into_inner();
+    // the other instances learn it at their next flush
+    counts.forget(subject);
+    let period = config.quota_period.name();
+    let result = db
+        .primary()
+        .run(|client| async move { client.quotas().reset(subject, period).await })
+        .await;
+    match result {
+        Ok(true) => {
+            info!("Reset the quota of {}", subject);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => {
+            HttpResponse::NotFound().body(format!("No requests of {} this {}", subject, period))
+        }
+        Err(e) => Format::Json.db_error(e, &format!("Failed to reset the quota of {}", subject)),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_quotas).service(reset_quota);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{self, TestRequest};
+    use rand::Rng;
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::app;
+    use crate::test_app::{admin, request, shared_with};
+
+    #[actix_web::test]
+    async fn anonymous_requests_are_refused_over_the_quota_of_their_address() {
+        let shared = shared_with(|config| config.ip_quota = Some(3)).await;
+        let app = test::init_service(app(&shared)).await;
+        let mut rng = rand::thread_rng();
+        let client = SocketAddr::from(([10, rng.gen(), rng.gen(), rng.gen()], 40000));
+        let send =
+            |request: TestRequest| test::call_service(&app, request.peer_addr(client).to_request());
+        let remaining = |response: &ServiceResponse<_>| {
+            response
+                .headers()
+                .get(REMAINING_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+
+        for left in ["2", "1", "0"] {
+            let response = send(request("GET", "/users/count")).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(remaining(&response).as_deref(), Some(left));
+        }
+        let response = send(request("GET", "/users/count")).await;
+        assert_eq!(response.status(), 429, "over the quota");
+        assert_eq!(remaining(&response).as_deref(), Some("0"));
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        // never counted
+        let response = send(request("GET", "/healthz")).await;
+        assert_eq!(response.status(), 200, "a probe over the quota");
+
+        let subject = format!("/admin/quotas/ip:{}", client.ip());
+        assert_eq!(send(admin("DELETE", &subject)).await.status(), 204, "reset");
+        let response = send(request("GET", "/users/count")).await;
+        assert_eq!(response.status(), 200, "once reset");
+        assert_eq!(remaining(&response).as_deref(), Some("2"));
+    }
+}
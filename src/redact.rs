@@ -0,0 +1,164 @@
+use env_logger::Builder;
+use std::env;
+use std::io::Write;
+
+const DEFAULT_FIELDS: &str = "email,password,token,secret,name";
+
+// Masks personal data in log messages: email addresses wherever they
+// appear, and the values of the configured fields, written `field=value`,
+// `field: value` or `"field":"value"`
+pub struct Redactor {
+    fields: Vec<String>,
+}
+
+impl Redactor {
+    // Read before the configuration, which already logs. None unless
+    // LOG_REDACT_PII is set.
+    pub fn from_env() -> Option<Redactor> {
+        let enabled = env::var("LOG_REDACT_PII")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(false);
+        if !enabled {
+            return None;
+        }
+        let fields = env::var("LOG_REDACT_FIELDS").unwrap_or_else(|_| DEFAULT_FIELDS.to_string());
+        Some(Redactor {
+            fields: fields
+                .split(',')
+                .map(|field| field.trim().to_ascii_lowercase())
+                .filter(|field| !field.is_empty())
+                .collect(),
+        })
+    }
+
+    // Have `builder` write redacted messages
+    pub fn install(self, builder: &mut Builder) {
+        builder.format(move |buf, record| {
+            let message = self.redact(&record.args().to_string());
+            writeln!(
+                buf,
+                "[{:<5} {}] {}",
+                buf.default_styled_level(record.level()),
+                record.target(),
+                message
+            )
+        });
+    }
+
+    pub fn redact(&self, message: &str) -> String {
+        mask_fields(&mask_emails(message), &self.fields)
+    }
+}
+
+fn is_local_part(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "._%+-".contains(c)
+}
+
+fn is_domain(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '.' || c == '-'
+}
+
+// `jane.doe@example.com` becomes `j***@example.com`
+fn mask_emails(message: &str) -> String {
+    let chars: Vec<char> = message.chars().collect();
+    let mut out = String::with_capacity(message.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '@' {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let local = chars[..i]
+            .iter()
+            .rev()
+            .take_while(|c| is_local_part(**c))
+            .count();
+        let domain: String = chars[i + 1..]
+            .iter()
+            .take_while(|c| is_domain(**c))
+            .collect();
+        let domain = domain.trim_end_matches('.');
+        if local == 0 || !domain.contains('.') {
+            out.push('@');
+            i += 1;
+            continue;
+        }
+        // the local part was already copied, keep only its first character
+        let keep = out.chars().count() - local + 1;
+        out = out.chars().take(keep).collect();
+        out.push_str("***@");
+        out.push_str(domain);
+        i += 1 + domain.chars().count();
+    }
+    out
+}
+
+// Replace the values following `field=`, `field: ` or `"field":` with `***`
+fn mask_fields(message: &str, fields: &[String]) -> String {
+    let lower = message.to_ascii_lowercase();
+    let mut out = String::with_capacity(message.len());
+    let mut copied = 0;
+    let mut search = 0;
+    while let Some((start, field)) = next_field(&lower, search, fields) {
+        let mut value = start + field.len();
+        if lower[value..].starts_with('"') {
+            value += 1;
+        }
+        let separator = lower[value..]
+            .chars()
+            .take_while(|c| *c == '=' || *c == ':' || *c == ' ')
+            .count();
+        search = value;
+        if separator == 0 || !lower[value..value + separator].contains(['=', ':']) {
+            continue;
+        }
+        value += separator;
+        let quoted = lower[value..].starts_with('"');
+        if quoted {
+            value += 1;
+        }
+        let end = value
+            + message[value..]
+                .find(|c: char| {
+                    if quoted {
+                        c == '"'
+                    } else {
+                        c.is_whitespace() || c == ',' || c == '}' || c == ')'
+                    }
+                })
+                .unwrap_or(message.len() - value);
+        if end == value {
+            continue;
+        }
+        out.push_str(&message[copied..value]);
+        out.push_str("***");
+        copied = end;
+        search = end;
+    }
+    out.push_str(&message[copied..]);
+    out
+}
+
+// The first of `fields` found from `from` on as a whole word
+fn next_field<'a>(lower: &str, from: usize, fields: &'a [String]) -> Option<(usize, &'a str)> {
+    fields
+        .iter()
+        .filter_map(|field| {
+            let mut at = from;
+            while let Some(found) = lower[at..].find(field.as_str()) {
+                let start = at + found;
+                let before = lower[..start].chars().next_back();
+                let after = lower[start + field.len()..].chars().next();
+                let word =
+                    |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !word(before) && !word(after) {
+                    return Some((start, field.as_str()));
+                }
+                at = start + field.len();
+            }
+            None
+        })
+        .min_by_key(|(start, _)| *start)
+}
@@ -0,0 +1,47 @@
+use tokio_postgres::{Error, GenericClient};
+
+use super::UserRepository;
+use crate::models::User;
+
+// The user an access token belongs to, for the self-service routes: the id
+// is fixed when the repository is made, so no query can reach another row
+pub struct AccountRepository<'a, C: GenericClient> {
+    users: UserRepository<'a, C>,
+    id: i64,
+}
+
+impl<'a, C: GenericClient> AccountRepository<'a, C> {
+    pub fn new(users: UserRepository<'a, C>, id: i64) -> Self {
+        AccountRepository { users, id }
+    }
+
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub async fn find(&self) -> Result<Option<User>, Error> {
+        self.users.find(self.id).await
+    }
+
+    // Locked until the end of the transaction
+    pub async fn find_for_update(&self) -> Result<Option<User>, Error> {
+        self.users.find_for_update(self.id).await
+    }
+
+    pub async fn update(
+        &self,
+        user: &User,
+        password_hash: Option<&str>,
+    ) -> Result<Option<User>, Error> {
+        self.users.update(self.id, user, password_hash).await
+    }
+
+    // Whether another user has `email`, see UserRepository::email_taken
+    pub async fn email_taken(&self, email: &str) -> Result<bool, Error> {
+        self.users.email_taken(email, Some(self.id)).await
+    }
+
+    pub async fn delete(&self) -> Result<bool, Error> {
+        self.users.delete(self.id).await
+    }
+}
@@ -0,0 +1,145 @@
+use tokio_postgres::{Error, GenericClient, Row};
+
+use crate::db::{FromRow, Query, StatementCache};
+use crate::tenant::Tenant;
+
+#[derive(Serialize)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    // first characters of the key, to tell keys apart
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    // the only tenant the key acts on
+    pub tenant_id: String,
+    // requests per QUOTA_PERIOD, API_KEY_QUOTA when None
+    pub quota: Option<i64>,
+    pub last_used_at: Option<String>,
+}
+
+impl FromRow for ApiKey {
+    fn from_row(row: &Row) -> Result<ApiKey, Error> {
+        Ok(ApiKey {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            prefix: row.try_get("prefix")?,
+            scopes: row.try_get("scopes")?,
+            tenant_id: row.try_get("tenant_id")?,
+            quota: row.try_get("quota")?,
+            last_used_at: row.try_get("last_used_at")?,
+        })
+    }
+}
+
+// Keys are only stored hashed, revoked keys are kept for the record. The
+// keys of the releases before tenants were tied to keys belong to the
+// default tenant.
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}api_keys (
+        id SERIAL PRIMARY KEY,
+        name VARCHAR NOT NULL,
+        prefix VARCHAR NOT NULL,
+        key_hash VARCHAR NOT NULL UNIQUE,
+        scopes VARCHAR[] NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        last_used_at TIMESTAMPTZ,
+        revoked_at TIMESTAMPTZ
+    );
+    ALTER TABLE {prefix}api_keys ADD COLUMN IF NOT EXISTS quota BIGINT;
+    ALTER TABLE {prefix}api_keys ADD COLUMN IF NOT EXISTS tenant_id VARCHAR NOT NULL DEFAULT 'default';
+";
+
+// Columns of ApiKey, timestamps as RFC 3339 text
+const COLUMNS: &str =
+    "id, name, prefix, scopes, tenant_id, quota, to_char(last_used_at AT TIME ZONE 'UTC', \
+                       'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS last_used_at";
+
+pub struct ApiKeyRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+}
+
+impl<'a, C: GenericClient> ApiKeyRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache) -> Self {
+        ApiKeyRepository { client, statements }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    pub async fn create(
+        &self,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[String],
+        tenant: &str,
+        quota: Option<i64>,
+    ) -> Result<ApiKey, Error> {
+        let sql = format!(
+            "INSERT INTO {{prefix}}api_keys (name, prefix, key_hash, scopes, tenant_id, quota)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             RETURNING {}",
+            COLUMNS
+        );
+        let statement = self.prepare(&sql).await?;
+        let row = statement
+            .query_one(
+                self.client,
+                &[&name, &prefix, &key_hash, &scopes, &tenant, &quota],
+            )
+            .await?;
+        ApiKey::from_row(&row)
+    }
+
+    // Keys of every tenant that have not been revoked
+    pub async fn list_all(&self) -> Result<Vec<ApiKey>, Error> {
+        let sql = format!(
+            "SELECT {} FROM {{prefix}}api_keys WHERE revoked_at IS NULL ORDER BY id",
+            COLUMNS
+        );
+        let statement = self.prepare(&sql).await?;
+        let rows = statement.query(self.client, &[]).await?;
+        ApiKey::from_rows(&rows)
+    }
+
+    // Keys of the tenant that have not been revoked
+    pub async fn list(&self, tenant: &Tenant) -> Result<Vec<ApiKey>, Error> {
+        let sql = format!(
+            "SELECT {} FROM {{prefix}}api_keys
+             WHERE tenant_id = $1 AND revoked_at IS NULL ORDER BY id",
+            COLUMNS
+        );
+        let statement = self.prepare(&sql).await?;
+        let rows = statement.query(self.client, &[&tenant.as_str()]).await?;
+        ApiKey::from_rows(&rows)
+    }
+
+    // false when the tenant has no live key with this id
+    pub async fn revoke(&self, tenant: &Tenant, id: i32) -> Result<bool, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}api_keys SET revoked_at = now()
+                 WHERE id = $1 AND tenant_id = $2 AND revoked_at IS NULL",
+            )
+            .await?;
+        Ok(statement
+            .execute(self.client, &[&id, &tenant.as_str()])
+            .await?
+            != 0)
+    }
+
+    // The live key with this hash, recording that it was used
+    pub async fn authenticate(&self, key_hash: &str) -> Result<Option<ApiKey>, Error> {
+        let sql = format!(
+            "UPDATE {{prefix}}api_keys SET last_used_at = now()
+             WHERE key_hash = $1 AND revoked_at IS NULL
+             RETURNING {}",
+            COLUMNS
+        );
+        let statement = self.prepare(&sql).await?;
+        let row = statement.query_opt(self.client, &[&key_hash]).await?;
+        ApiKey::from_opt(row.as_ref())
+    }
+}
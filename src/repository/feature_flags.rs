@@ -0,0 +1,103 @@
+use tokio_postgres::{Error, GenericClient, Row};
+
+use crate::db::{FromRow, Query, StatementCache};
+
+#[derive(Clone, Serialize)]
+pub struct FeatureFlag {
+    pub name: String,
+    pub enabled: bool,
+    // tenants the flag is on for, even while it is off for everyone else
+    pub tenants: Vec<String>,
+    pub description: Option<String>,
+    pub updated_at: String,
+}
+
+impl FromRow for FeatureFlag {
+    fn from_row(row: &Row) -> Result<FeatureFlag, Error> {
+        Ok(FeatureFlag {
+            name: row.try_get("name")?,
+            enabled: row.try_get("enabled")?,
+            tenants: row.try_get("tenants")?,
+            description: row.try_get("description")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+impl FeatureFlag {
+    pub fn is_enabled_for(&self, tenant: &str) -> bool {
+        self.enabled || self.tenants.iter().any(|id| id == tenant)
+    }
+}
+
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}feature_flags (
+        name VARCHAR PRIMARY KEY,
+        enabled BOOLEAN NOT NULL DEFAULT false,
+        tenants VARCHAR[] NOT NULL DEFAULT '{}',
+        description VARCHAR,
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+";
+
+// Columns of FeatureFlag, timestamps as RFC 3339 text
+const COLUMNS: &str = "name, enabled, tenants, description, to_char(updated_at AT TIME ZONE \
+                       'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS updated_at";
+
+// Flags are global, each lists the tenants it is on for
+pub struct FeatureFlagRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+}
+
+impl<'a, C: GenericClient> FeatureFlagRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache) -> Self {
+        FeatureFlagRepository { client, statements }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    pub async fn list(&self) -> Result<Vec<FeatureFlag>, Error> {
+        let sql = format!(
+            "SELECT {} FROM {{prefix}}feature_flags ORDER BY name",
+            COLUMNS
+        );
+        let statement = self.prepare(&sql).await?;
+        let rows = statement.query(self.client, &[]).await?;
+        FeatureFlag::from_rows(&rows)
+    }
+
+    // Create the flag or replace its settings
+    pub async fn set(
+        &self,
+        name: &str,
+        enabled: bool,
+        tenants: &[String],
+        description: Option<&str>,
+    ) -> Result<FeatureFlag, Error> {
+        let sql = format!(
+            "INSERT INTO {{prefix}}feature_flags (name, enabled, tenants, description)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (name) DO UPDATE SET enabled = $2, tenants = $3,
+                 description = COALESCE($4, {{prefix}}feature_flags.description),
+                 updated_at = now()
+             RETURNING {}",
+            COLUMNS
+        );
+        let statement = self.prepare(&sql).await?;
+        let row = statement
+            .query_one(self.client, &[&name, &enabled, &tenants, &description])
+            .await?;
+        FeatureFlag::from_row(&row)
+    }
+
+    // false when there is no flag by this name
+    pub async fn delete(&self, name: &str) -> Result<bool, Error> {
+        let statement = self
+            .prepare("DELETE FROM {prefix}feature_flags WHERE name = $1")
+            .await?;
+        Ok(statement.execute(self.client, &[&name]).await? != 0)
+    }
+}
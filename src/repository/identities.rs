@@ -0,0 +1,100 @@
+use tokio_postgres::{Error, GenericClient};
+
+use crate::db::{FromRow, Query, StatementCache};
+use crate::models::User;
+
+// Accounts at external login providers, keyed on the provider's subject:
+// the same account may sign in to several tenants
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}user_identities (
+        provider VARCHAR NOT NULL,
+        subject VARCHAR NOT NULL,
+        user_id BIGINT NOT NULL REFERENCES {prefix}users (id) ON DELETE CASCADE,
+        PRIMARY KEY (provider, subject)
+    );
+    ALTER TABLE {prefix}user_identities ADD COLUMN IF NOT EXISTS tenant_id VARCHAR NOT NULL DEFAULT 'default';
+    DO $$
+    BEGIN
+        IF NOT EXISTS (
+            SELECT 1 FROM information_schema.key_column_usage
+            WHERE table_schema = current_schema()
+              AND constraint_name = '{prefix}user_identities_pkey' AND column_name = 'tenant_id'
+        ) THEN
+            ALTER TABLE {prefix}user_identities DROP CONSTRAINT {prefix}user_identities_pkey;
+            ALTER TABLE {prefix}user_identities ADD PRIMARY KEY (tenant_id, provider, subject);
+        END IF;
+    END
+    $$;
+    DO $$
+    BEGIN
+        IF EXISTS (
+            SELECT 1 FROM information_schema.columns
+            WHERE table_schema = current_schema()
+              AND table_name = '{prefix}user_identities' AND column_name = 'user_id' AND data_type = 'integer'
+        ) THEN
+            ALTER TABLE {prefix}user_identities ALTER COLUMN user_id TYPE BIGINT;
+        END IF;
+    END
+    $$;
+";
+
+pub struct IdentityRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+    tenant: &'a str,
+}
+
+impl<'a, C: GenericClient> IdentityRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache, tenant: &'a str) -> Self {
+        IdentityRepository {
+            client,
+            statements,
+            tenant,
+        }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    pub async fn find_user(&self, provider: &str, subject: &str) -> Result<Option<User>, Error> {
+        let statement = self
+            .prepare(
+                "SELECT u.* FROM {prefix}users u
+                 JOIN {prefix}user_identities i ON i.user_id = u.id
+                 WHERE i.tenant_id = $3 AND i.provider = $1 AND i.subject = $2
+                   AND u.tenant_id = $3",
+            )
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&provider, &subject, &self.tenant])
+            .await?;
+        User::from_opt(row.as_ref())
+    }
+
+    // Move the identities of user `from` to user `to`, returns how many
+    pub async fn move_user(&self, from: i64, to: i64) -> Result<u64, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}user_identities SET user_id = $2
+                 WHERE user_id = $1 AND tenant_id = $3",
+            )
+            .await?;
+        statement
+            .execute(self.client, &[&from, &to, &self.tenant])
+            .await
+    }
+
+    pub async fn link(&self, provider: &str, subject: &str, user_id: i64) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "INSERT INTO {prefix}user_identities (tenant_id, provider, subject, user_id)
+                 VALUES ($4, $1, $2, $3)",
+            )
+            .await?;
+        statement
+            .execute(self.client, &[&provider, &subject, &user_id, &self.tenant])
+            .await?;
+        Ok(())
+    }
+}
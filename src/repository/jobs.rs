@@ -0,0 +1,123 @@
+use serde_json::Value;
+use std::time::Duration;
+use tokio_postgres::{Error, GenericClient};
+
+use crate::db::{Query, StatementCache};
+use crate::jobs::JobKind;
+
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}jobs (
+        id BIGSERIAL PRIMARY KEY,
+        kind VARCHAR NOT NULL,
+        payload JSONB NOT NULL,
+        status VARCHAR NOT NULL DEFAULT 'pending',
+        attempts INTEGER NOT NULL DEFAULT 0,
+        run_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        last_error VARCHAR,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX IF NOT EXISTS {prefix}jobs_due ON {prefix}jobs (run_at) WHERE status = 'pending';
+";
+
+// A job claimed by the worker
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    pub payload: Value,
+    pub attempts: i32,
+}
+
+pub struct JobRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+}
+
+impl<'a, C: GenericClient> JobRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache) -> Self {
+        JobRepository { client, statements }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    pub async fn enqueue(&self, kind: JobKind, payload: &Value) -> Result<(), Error> {
+        let statement = self
+            .prepare("INSERT INTO {prefix}jobs (kind, payload) VALUES ($1, $2)")
+            .await?;
+        statement
+            .execute(self.client, &[&kind.name(), payload])
+            .await?;
+        Ok(())
+    }
+
+    // Lease up to `limit` due jobs: they won't be handed out again before
+    // `lease` elapses, so several workers can share the queue
+    pub async fn claim_due(&self, limit: i64, lease: Duration) -> Result<Vec<Job>, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}jobs SET run_at = now() + make_interval(secs => $2)
+                 WHERE id IN (
+                     SELECT id FROM {prefix}jobs
+                     WHERE status = 'pending' AND run_at <= now()
+                     ORDER BY run_at
+                     LIMIT $1
+                     FOR UPDATE SKIP LOCKED
+                 )
+                 RETURNING id, kind, payload, attempts",
+            )
+            .await?;
+        let rows = statement
+            .query(self.client, &[&limit, &lease.as_secs_f64()])
+            .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Job {
+                    id: row.try_get("id")?,
+                    kind: row.try_get("kind")?,
+                    payload: row.try_get("payload")?,
+                    attempts: row.try_get("attempts")?,
+                })
+            })
+            .collect()
+    }
+
+    pub async fn complete(&self, id: i64) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}jobs SET status = 'done', attempts = attempts + 1, last_error = NULL
+                 WHERE id = $1",
+            )
+            .await?;
+        statement.execute(self.client, &[&id]).await?;
+        Ok(())
+    }
+
+    // Run the job again after `backoff`, or give up once `max_attempts` is
+    // reached
+    pub async fn fail(
+        &self,
+        id: i64,
+        error: &str,
+        max_attempts: i32,
+        backoff: Duration,
+    ) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}jobs
+                 SET attempts = attempts + 1,
+                     last_error = $2,
+                     status = CASE WHEN attempts + 1 >= $3 THEN 'failed' ELSE 'pending' END,
+                     run_at = now() + make_interval(secs => $4)
+                 WHERE id = $1",
+            )
+            .await?;
+        statement
+            .execute(
+                self.client,
+                &[&id, &error, &max_attempts, &backoff.as_secs_f64()],
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+use tokio_postgres::{Error, GenericClient};
+
+use crate::db::{Query, StatementCache};
+
+// Failed logins, counted per account and per client address over a sliding
+// window. Rows older than the window are pruned as new ones come in.
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}login_failures (
+        id BIGSERIAL PRIMARY KEY,
+        tenant_id VARCHAR NOT NULL,
+        email VARCHAR NOT NULL,
+        ip VARCHAR NOT NULL,
+        failed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX IF NOT EXISTS {prefix}login_failures_email ON {prefix}login_failures (tenant_id, email, failed_at);
+    CREATE INDEX IF NOT EXISTS {prefix}login_failures_ip ON {prefix}login_failures (ip, failed_at);
+";
+
+pub struct LoginFailureRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+    tenant: &'a str,
+}
+
+impl<'a, C: GenericClient> LoginFailureRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache, tenant: &'a str) -> Self {
+        LoginFailureRepository {
+            client,
+            statements,
+            tenant,
+        }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    // Record a failure, returns the failures of the account within `window`
+    pub async fn record(&self, email: &str, ip: &str, window: Duration) -> Result<i64, Error> {
+        let prune = self
+            .prepare(
+                "DELETE FROM {prefix}login_failures WHERE failed_at < now() - make_interval(secs => $1)",
+            )
+            .await?;
+        prune.execute(self.client, &[&window.as_secs_f64()]).await?;
+        let insert = self
+            .prepare(
+                "INSERT INTO {prefix}login_failures (tenant_id, email, ip) VALUES ($1, $2, $3)",
+            )
+            .await?;
+        insert
+            .execute(self.client, &[&self.tenant, &email, &ip])
+            .await?;
+        let count = self
+            .prepare(
+                "SELECT count(*) AS failures FROM {prefix}login_failures
+                 WHERE tenant_id = $1 AND email = $2
+                   AND failed_at >= now() - make_interval(secs => $3)",
+            )
+            .await?;
+        let row = count
+            .query_one(self.client, &[&self.tenant, &email, &window.as_secs_f64()])
+            .await?;
+        row.try_get("failures")
+    }
+
+    // Failures of the account within `window`
+    pub async fn count_for_email(&self, email: &str, window: Duration) -> Result<i64, Error> {
+        let statement = self
+            .prepare(
+                "SELECT count(*) AS failures FROM {prefix}login_failures
+                 WHERE tenant_id = $1 AND email = $2
+                   AND failed_at >= now() - make_interval(secs => $3)",
+            )
+            .await?;
+        let row = statement
+            .query_one(self.client, &[&self.tenant, &email, &window.as_secs_f64()])
+            .await?;
+        row.try_get("failures")
+    }
+
+    // Failures from `ip` within `window`, whatever the account or tenant
+    pub async fn count_for_ip(&self, ip: &str, window: Duration) -> Result<i64, Error> {
+        let statement = self
+            .prepare(
+                "SELECT count(*) AS failures FROM {prefix}login_failures
+                 WHERE ip = $1 AND failed_at >= now() - make_interval(secs => $2)",
+            )
+            .await?;
+        let row = statement
+            .query_one(self.client, &[&ip, &window.as_secs_f64()])
+            .await?;
+        row.try_get("failures")
+    }
+
+    // Forget the failures of an account, on a successful login or unlock
+    pub async fn clear(&self, email: &str) -> Result<(), Error> {
+        let statement = self
+            .prepare("DELETE FROM {prefix}login_failures WHERE tenant_id = $1 AND email = $2")
+            .await?;
+        statement
+            .execute(self.client, &[&self.tenant, &email])
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use tokio_postgres::{Client, Error, RowStream, Transaction};
+
+use crate::db::{CachedClient, StatementCache};
+use crate::dry_run::DryRun;
+use crate::events::Event;
+use crate::filter::Filter;
+use crate::models::{AccountStatus, User, UserField};
+use crate::tenant::Tenant;
+
+mod account;
+mod api_keys;
+mod feature_flags;
+mod identities;
+mod jobs;
+mod login_failures;
+mod outbox;
+mod partners;
+mod password_resets;
+mod quotas;
+mod refresh_tokens;
+mod users;
+mod webhooks;
+
+pub use account::AccountRepository;
+pub use api_keys::{ApiKey, ApiKeyRepository};
+pub use feature_flags::{FeatureFlag, FeatureFlagRepository};
+pub use identities::IdentityRepository;
+pub use jobs::JobRepository;
+pub use login_failures::LoginFailureRepository;
+#[cfg(feature = "outbox-relay")]
+pub use outbox::OutboxMessage;
+pub use outbox::OutboxRepository;
+pub use partners::{Partner, PartnerRepository};
+pub use password_resets::PasswordResetRepository;
+pub use quotas::QuotaRepository;
+pub use refresh_tokens::RefreshTokenRepository;
+pub use users::{
+    Duplicate, UserRepository, UserStats, DISABLE_ROW_LEVEL_SECURITY, ENABLE_ROW_LEVEL_SECURITY,
+};
+pub use webhooks::{DeliveryQueue, PendingDelivery, WebhookRepository};
+
+// Table definitions, in creation order
+pub const SCHEMAS: [&str; 12] = [
+    users::SCHEMA,
+    webhooks::SCHEMA,
+    api_keys::SCHEMA,
+    identities::SCHEMA,
+    password_resets::SCHEMA,
+    refresh_tokens::SCHEMA,
+    jobs::SCHEMA,
+    outbox::SCHEMA,
+    login_failures::SCHEMA,
+    feature_flags::SCHEMA,
+    partners::SCHEMA,
+    quotas::SCHEMA,
+];
+
+// Bumped with every change to the SCHEMAS and recorded once they are
+// applied: a build finding a higher version runs against a database that a
+// newer release migrated, and doesn't start
+pub const SCHEMA_VERSION: i32 = 8;
+
+pub const SCHEMA_VERSION_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}schema_version (
+        version INTEGER PRIMARY KEY,
+        applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+";
+
+// The tables the SCHEMAS create, without their prefix
+pub fn tables() -> Vec<&'static str> {
+    const CREATE: &str = "CREATE TABLE IF NOT EXISTS {prefix}";
+    SCHEMAS
+        .iter()
+        .flat_map(|schema| schema.split(CREATE).skip(1))
+        .filter_map(|rest| rest.split(|c: char| c.is_whitespace() || c == '(').next())
+        .collect()
+}
+
+// Users are only reachable through a unit of work, which knows their tenant,
+// or streamed with `stream_users`
+impl CachedClient {
+    // A stream can't borrow a transaction, so `app.tenant_id` is set for the
+    // session instead: use a dedicated connection, the units of work running
+    // on it later set their own
+    pub async fn stream_users(
+        &self,
+        tenant: &Tenant,
+        fields: Option<&[UserField]>,
+        status: AccountStatus,
+        filter: &Filter<User>,
+    ) -> Result<RowStream, Error> {
+        self.client
+            .execute(
+                "SELECT set_config('app.tenant_id', $1, false)",
+                &[&tenant.as_str()],
+            )
+            .await?;
+        UserRepository::new(&self.client, &self.statements, tenant.as_str())
+            .stream(fields, status, filter)
+            .await
+    }
+
+    pub fn api_keys(&self) -> ApiKeyRepository<'_, Client> {
+        ApiKeyRepository::new(&self.client, &self.statements)
+    }
+
+    pub fn webhooks<'a>(&'a self, tenant: &'a Tenant) -> WebhookRepository<'a, Client> {
+        WebhookRepository::new(&self.client, &self.statements, tenant.as_str())
+    }
+
+    pub fn webhook_deliveries(&self) -> DeliveryQueue<'_, Client> {
+        DeliveryQueue::new(&self.client, &self.statements)
+    }
+
+    pub fn partners(&self) -> PartnerRepository<'_, Client> {
+        PartnerRepository::new(&self.client, &self.statements)
+    }
+
+    pub fn jobs(&self) -> JobRepository<'_, Client> {
+        JobRepository::new(&self.client, &self.statements)
+    }
+
+    pub fn password_resets(&self) -> PasswordResetRepository<'_, Client> {
+        PasswordResetRepository::new(&self.client, &self.statements)
+    }
+
+    pub fn refresh_tokens(&self) -> RefreshTokenRepository<'_, Client> {
+        RefreshTokenRepository::new(&self.client, &self.statements)
+    }
+
+    pub fn feature_flags(&self) -> FeatureFlagRepository<'_, Client> {
+        FeatureFlagRepository::new(&self.client, &self.statements)
+    }
+
+    pub fn quotas(&self) -> QuotaRepository<'_, Client> {
+        QuotaRepository::new(&self.client, &self.statements)
+    }
+}
+
+// A transaction shared by several repositories: nothing is persisted until
+// `commit`, dropping the unit of work rolls everything back. The tenant is
+// also set as `app.tenant_id` for the row level security policies.
+pub struct UnitOfWork<'a> {
+    tx: Transaction<'a>,
+    statements: &'a StatementCache,
+    tenant: &'a Tenant,
+}
+
+impl<'a> UnitOfWork<'a> {
+    pub async fn begin(
+        client: &'a mut CachedClient,
+        tenant: &'a Tenant,
+    ) -> Result<UnitOfWork<'a>, Error> {
+        let tx = client.client.transaction().await?;
+        tx.execute(
+            "SELECT set_config('app.tenant_id', $1, true)",
+            &[&tenant.as_str()],
+        )
+        .await?;
+        Ok(UnitOfWork {
+            tx,
+            statements: &client.statements,
+            tenant,
+        })
+    }
+
+    pub fn users(&self) -> UserRepository<'_, Transaction<'a>> {
+        UserRepository::new(&self.tx, self.statements, self.tenant.as_str())
+    }
+
+    // The users repository narrowed to the one with `user_id`
+    pub fn account(&self, user_id: i64) -> AccountRepository<'_, Transaction<'a>> {
+        AccountRepository::new(self.users(), user_id)
+    }
+
+    pub fn webhooks(&self) -> WebhookRepository<'_, Transaction<'a>> {
+        WebhookRepository::new(&self.tx, self.statements, self.tenant.as_str())
+    }
+
+    pub fn jobs(&self) -> JobRepository<'_, Transaction<'a>> {
+        JobRepository::new(&self.tx, self.statements)
+    }
+
+    pub fn password_resets(&self) -> PasswordResetRepository<'_, Transaction<'a>> {
+        PasswordResetRepository::new(&self.tx, self.statements)
+    }
+
+    pub fn refresh_tokens(&self) -> RefreshTokenRepository<'_, Transaction<'a>> {
+        RefreshTokenRepository::new(&self.tx, self.statements)
+    }
+
+    pub fn identities(&self) -> IdentityRepository<'_, Transaction<'a>> {
+        IdentityRepository::new(&self.tx, self.statements, self.tenant.as_str())
+    }
+
+    pub fn login_failures(&self) -> LoginFailureRepository<'_, Transaction<'a>> {
+        LoginFailureRepository::new(&self.tx, self.statements, self.tenant.as_str())
+    }
+
+    pub fn outbox(&self) -> OutboxRepository<'_, Transaction<'a>> {
+        OutboxRepository::new(&self.tx, self.statements)
+    }
+
+    // Record the event along with the change: it reaches the outbox and the
+    // webhook queue only if the transaction commits
+    pub async fn publish(&self, event: &Event) -> Result<(), Error> {
+        self.outbox().append(event).await?;
+        self.webhooks().enqueue(event).await?;
+        Ok(())
+    }
+
+    pub async fn commit(self) -> Result<(), Error> {
+        self.tx.commit().await
+    }
+
+    // Commit, or for a dry run roll back once the deferred constraints are
+    // checked, so that the request fails as it would have on commit
+    pub async fn finish(self, dry_run: DryRun) -> Result<(), Error> {
+        if !dry_run.is_set() {
+            return self.commit().await;
+        }
+        self.tx
+            .batch_execute("SET CONSTRAINTS ALL IMMEDIATE")
+            .await?;
+        self.tx.rollback().await
+    }
+}
+
+// The unit of work of one attempt of `Database::transaction`. Its closure
+// may borrow what the caller holds for `'env` as well, since `'env`
+// outlives the connection the unit of work borrows for `'c`.
+pub struct Attempt<'c, 'env> {
+    uow: UnitOfWork<'c>,
+    discarded: Cell<bool>,
+    _env: PhantomData<&'c &'env ()>,
+}
+
+impl<'c, 'env> Attempt<'c, 'env> {
+    pub fn new(uow: UnitOfWork<'c>) -> Attempt<'c, 'env> {
+        Attempt {
+            uow,
+            discarded: Cell::new(false),
+            _env: PhantomData,
+        }
+    }
+
+    // Roll the changes back once the closure returns instead of committing
+    // them, for the outcomes that must leave nothing behind
+    pub fn discard(&self) {
+        self.discarded.set(true);
+    }
+
+    pub async fn finish(self, dry_run: DryRun) -> Result<(), Error> {
+        if self.discarded.get() {
+            return self.uow.tx.rollback().await;
+        }
+        self.uow.finish(dry_run).await
+    }
+}
+
+impl<'c> Deref for Attempt<'c, '_> {
+    type Target = UnitOfWork<'c>;
+
+    fn deref(&self) -> &UnitOfWork<'c> {
+        &self.uow
+    }
+}
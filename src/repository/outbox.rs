@@ -0,0 +1,167 @@
+use serde_json::Value;
+use tokio_postgres::{Error, GenericClient};
+
+use crate::db::{Query, StatementCache};
+use crate::events::Event;
+
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}outbox (
+        id BIGSERIAL PRIMARY KEY,
+        event VARCHAR NOT NULL,
+        key VARCHAR,
+        payload JSONB NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        published_at TIMESTAMPTZ
+    );
+    CREATE INDEX IF NOT EXISTS {prefix}outbox_unpublished ON {prefix}outbox (id) WHERE published_at IS NULL;
+    ALTER TABLE {prefix}outbox ADD COLUMN IF NOT EXISTS change_seq BIGSERIAL;
+    CREATE UNIQUE INDEX IF NOT EXISTS {prefix}outbox_change_seq ON {prefix}outbox (change_seq);
+    ALTER TABLE {prefix}outbox ALTER COLUMN change_seq DROP DEFAULT;
+    ALTER TABLE {prefix}outbox ALTER COLUMN change_seq DROP NOT NULL;
+    CREATE INDEX IF NOT EXISTS {prefix}outbox_unsequenced ON {prefix}outbox (id) WHERE change_seq IS NULL;
+";
+
+// An event as read from GET /changes, `seq` orders them
+#[derive(Serialize)]
+pub struct Change {
+    pub seq: i64,
+    pub event: String,
+    pub key: Option<String>,
+    pub data: Value,
+    pub created_at: String,
+}
+
+// Only the publishers read the event, compiled in with their broker feature
+#[cfg(feature = "outbox-relay")]
+pub struct OutboxMessage {
+    pub id: i64,
+    #[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(dead_code))]
+    pub event: String,
+    // id of the resource, keeps the events of one user in order downstream
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    pub key: Option<String>,
+    #[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(dead_code))]
+    pub payload: serde_json::Value,
+}
+
+pub struct OutboxRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+}
+
+impl<'a, C: GenericClient> OutboxRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache) -> Self {
+        OutboxRepository { client, statements }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    // Without its `change_seq`, which `sequence` gives it once committed
+    pub async fn append(&self, event: &Event) -> Result<(), Error> {
+        let statement = self
+            .prepare("INSERT INTO {prefix}outbox (event, key, payload) VALUES ($1, $2, $3)")
+            .await?;
+        statement
+            .execute(
+                self.client,
+                &[&event.kind.name(), &event.key(), &event.payload()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Give up to `limit` committed events their `change_seq`, in the order
+    // they were written. Values handed out at insert time would follow the
+    // inserts, not the commits, and a reader polling past the highest one it
+    // saw could skip a change still uncommitted then. Only the sequencing
+    // transactions wait for each other, until they commit, so the values
+    // they give are always higher than the ones readers already saw.
+    pub async fn sequence(&self, limit: i64) -> Result<u64, Error> {
+        let lock = self
+            .prepare("SELECT pg_advisory_xact_lock(hashtext('{prefix}outbox_change_seq'))")
+            .await?;
+        lock.execute(self.client, &[]).await?;
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}outbox o SET change_seq = pending.seq
+                 FROM (
+                     SELECT id, nextval(pg_get_serial_sequence('{prefix}outbox', 'change_seq')) AS seq
+                     FROM (
+                         SELECT id FROM {prefix}outbox
+                         WHERE change_seq IS NULL
+                         ORDER BY id
+                         LIMIT $1
+                     ) unsequenced
+                 ) pending
+                 WHERE o.id = pending.id",
+            )
+            .await?;
+        statement.execute(self.client, &[&limit]).await
+    }
+
+    // Up to `limit` changes after the `since` sequence number, oldest first
+    pub async fn changes(&self, since: i64, limit: i64) -> Result<Vec<Change>, Error> {
+        let statement = self
+            .prepare(
+                "SELECT change_seq, event, key, payload->'data' AS data,
+                        to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS created_at
+                 FROM {prefix}outbox
+                 WHERE change_seq > $1
+                 ORDER BY change_seq
+                 LIMIT $2",
+            )
+            .await?;
+        let rows = statement.query(self.client, &[&since, &limit]).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Change {
+                    seq: row.try_get("change_seq")?,
+                    event: row.try_get("event")?,
+                    key: row.try_get("key")?,
+                    data: row.try_get("data")?,
+                    created_at: row.try_get("created_at")?,
+                })
+            })
+            .collect()
+    }
+
+    // Oldest unpublished messages, locked until the surrounding transaction
+    // ends so concurrent relays skip them
+    #[cfg(feature = "outbox-relay")]
+    pub async fn claim_unpublished(&self, limit: i64) -> Result<Vec<OutboxMessage>, Error> {
+        let statement = self
+            .prepare(
+                "SELECT id, event, key, payload FROM {prefix}outbox
+                 WHERE published_at IS NULL
+                 ORDER BY id
+                 LIMIT $1
+                 FOR UPDATE SKIP LOCKED",
+            )
+            .await?;
+        let rows = statement.query(self.client, &[&limit]).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(OutboxMessage {
+                    id: row.try_get("id")?,
+                    event: row.try_get("event")?,
+                    key: row.try_get("key")?,
+                    payload: row.try_get("payload")?,
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "outbox-relay")]
+    pub async fn mark_published(&self, ids: &[i64]) -> Result<(), Error> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let statement = self
+            .prepare("UPDATE {prefix}outbox SET published_at = now() WHERE id = ANY($1)")
+            .await?;
+        statement.execute(self.client, &[&ids]).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use std::time::Duration;
+use tokio_postgres::{Error, GenericClient, Row};
+
+use crate::db::{FromRow, Query, StatementCache};
+use crate::tenant::Tenant;
+
+#[derive(Serialize)]
+pub struct Partner {
+    pub id: i32,
+    pub name: String,
+    // sent in X-Partner-Id, public
+    pub key_id: String,
+    // verifies the signatures, only disclosed when the partner is registered
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub scopes: Vec<String>,
+    // the only tenant the partner acts on
+    pub tenant_id: String,
+    pub last_used_at: Option<String>,
+}
+
+impl FromRow for Partner {
+    fn from_row(row: &Row) -> Result<Partner, Error> {
+        Ok(Partner {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            key_id: row.try_get("key_id")?,
+            secret: row.try_get("secret")?,
+            scopes: row.try_get("scopes")?,
+            tenant_id: row.try_get("tenant_id")?,
+            last_used_at: row.try_get("last_used_at")?,
+        })
+    }
+}
+
+// Secrets are stored as they are, verifying an HMAC takes the key. The
+// signatures seen within the replay window are kept to refuse them twice,
+// with the attempt that recorded them.
+// The partners registered before tenants were tied to them belong to the
+// default tenant.
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}partners (
+        id SERIAL PRIMARY KEY,
+        name VARCHAR NOT NULL,
+        key_id VARCHAR NOT NULL UNIQUE,
+        secret VARCHAR NOT NULL,
+        scopes VARCHAR[] NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        last_used_at TIMESTAMPTZ,
+        revoked_at TIMESTAMPTZ
+    );
+    CREATE TABLE IF NOT EXISTS {prefix}partner_signatures (
+        partner_id INTEGER NOT NULL REFERENCES {prefix}partners (id) ON DELETE CASCADE,
+        signature VARCHAR NOT NULL,
+        seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        PRIMARY KEY (partner_id, signature)
+    );
+    ALTER TABLE {prefix}partners ADD COLUMN IF NOT EXISTS tenant_id VARCHAR NOT NULL DEFAULT 'default';
+    ALTER TABLE {prefix}partner_signatures ADD COLUMN IF NOT EXISTS attempt VARCHAR;
+";
+
+// Columns of Partner, timestamps as RFC 3339 text
+const COLUMNS: &str =
+    "id, name, key_id, secret, scopes, tenant_id, to_char(last_used_at AT TIME ZONE 'UTC', \
+                       'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') AS last_used_at";
+
+pub struct PartnerRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+}
+
+impl<'a, C: GenericClient> PartnerRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache) -> Self {
+        PartnerRepository { client, statements }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    pub async fn create(
+        &self,
+        name: &str,
+        key_id: &str,
+        secret: &str,
+        scopes: &[String],
+        tenant: &str,
+    ) -> Result<Partner, Error> {
+        let sql = format!(
+            "INSERT INTO {{prefix}}partners (name, key_id, secret, scopes, tenant_id)
+             VALUES ($1, $2, $3, $4, $5)
+             RETURNING {}",
+            COLUMNS
+        );
+        let statement = self.prepare(&sql).await?;
+        let row = statement
+            .query_one(self.client, &[&name, &key_id, &secret, &scopes, &tenant])
+            .await?;
+        Partner::from_row(&row)
+    }
+
+    // Partners of the tenant that have not been revoked
+    pub async fn list(&self, tenant: &Tenant) -> Result<Vec<Partner>, Error> {
+        let sql = format!(
+            "SELECT {} FROM {{prefix}}partners
+             WHERE tenant_id = $1 AND revoked_at IS NULL ORDER BY id",
+            COLUMNS
+        );
+        let statement = self.prepare(&sql).await?;
+        let rows = statement.query(self.client, &[&tenant.as_str()]).await?;
+        Partner::from_rows(&rows)
+    }
+
+    // false when the tenant has no live partner with this id
+    pub async fn revoke(&self, tenant: &Tenant, id: i32) -> Result<bool, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}partners SET revoked_at = now()
+                 WHERE id = $1 AND tenant_id = $2 AND revoked_at IS NULL",
+            )
+            .await?;
+        Ok(statement
+            .execute(self.client, &[&id, &tenant.as_str()])
+            .await?
+            != 0)
+    }
+
+    // The live partner with this key id
+    pub async fn find(&self, key_id: &str) -> Result<Option<Partner>, Error> {
+        let sql = format!(
+            "SELECT {} FROM {{prefix}}partners WHERE key_id = $1 AND revoked_at IS NULL",
+            COLUMNS
+        );
+        let statement = self.prepare(&sql).await?;
+        let row = statement.query_opt(self.client, &[&key_id]).await?;
+        Partner::from_opt(row.as_ref())
+    }
+
+    // Records a verified signature of the partner, and that it was used.
+    // false when it was seen within `window` already, a replay, unless by
+    // the same `attempt`: `Database::run` replays a call that failed after
+    // its insert went through, the replay finds the signature it recorded.
+    // Those older than the window are forgotten, their timestamp refuses
+    // them.
+    pub async fn record_signature(
+        &self,
+        partner_id: i32,
+        signature: &str,
+        attempt: &str,
+        window: Duration,
+    ) -> Result<bool, Error> {
+        let window = window.as_secs_f64();
+        let statement = self
+            .prepare(
+                "DELETE FROM {prefix}partner_signatures
+                 WHERE partner_id = $1 AND seen_at < now() - make_interval(secs => $2)",
+            )
+            .await?;
+        statement
+            .execute(self.client, &[&partner_id, &window])
+            .await?;
+        // the table is read as of before the insert, one of the two answers
+        let statement = self
+            .prepare(
+                "WITH recorded AS (
+                     INSERT INTO {prefix}partner_signatures (partner_id, signature, attempt)
+                     VALUES ($1, $2, $3)
+                     ON CONFLICT DO NOTHING
+                     RETURNING attempt
+                 )
+                 SELECT attempt FROM recorded
+                 UNION ALL
+                 SELECT attempt FROM {prefix}partner_signatures
+                 WHERE partner_id = $1 AND signature = $2",
+            )
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&partner_id, &signature, &attempt])
+            .await?;
+        let recorded = match row {
+            Some(row) => row.try_get::<_, Option<String>>("attempt")?.as_deref() == Some(attempt),
+            None => false,
+        };
+        if recorded {
+            let statement = self
+                .prepare("UPDATE {prefix}partners SET last_used_at = now() WHERE id = $1")
+                .await?;
+            statement.execute(self.client, &[&partner_id]).await?;
+        }
+        Ok(recorded)
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+use tokio_postgres::{Error, GenericClient};
+
+use crate::db::{Query, StatementCache};
+
+// Only the SHA-256 of the tokens is stored, the tokens themselves are
+// emailed
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}password_resets (
+        id SERIAL PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES {prefix}users (id) ON DELETE CASCADE,
+        token_hash VARCHAR NOT NULL UNIQUE,
+        expires_at TIMESTAMPTZ NOT NULL,
+        used_at TIMESTAMPTZ
+    );
+    DO $$
+    BEGIN
+        IF EXISTS (
+            SELECT 1 FROM information_schema.columns
+            WHERE table_schema = current_schema()
+              AND table_name = '{prefix}password_resets' AND column_name = 'user_id' AND data_type = 'integer'
+        ) THEN
+            ALTER TABLE {prefix}password_resets ALTER COLUMN user_id TYPE BIGINT;
+        END IF;
+    END
+    $$;
+";
+
+pub struct PasswordResetRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+}
+
+impl<'a, C: GenericClient> PasswordResetRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache) -> Self {
+        PasswordResetRepository { client, statements }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    pub async fn create(&self, user_id: i64, token_hash: &str, ttl: Duration) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "INSERT INTO {prefix}password_resets (user_id, token_hash, expires_at)
+                 VALUES ($1, $2, now() + make_interval(secs => $3))",
+            )
+            .await?;
+        statement
+            .execute(self.client, &[&user_id, &token_hash, &ttl.as_secs_f64()])
+            .await?;
+        Ok(())
+    }
+
+    // Use up an unexpired token, returns the user it was issued for
+    pub async fn consume(&self, token_hash: &str) -> Result<Option<i64>, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}password_resets SET used_at = now()
+                 WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+                 RETURNING user_id",
+            )
+            .await?;
+        let row = statement.query_opt(self.client, &[&token_hash]).await?;
+        row.map(|row| row.try_get("user_id")).transpose()
+    }
+
+    // Void the other tokens still out there for this user
+    pub async fn revoke_all(&self, user_id: i64) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}password_resets SET used_at = now()
+                 WHERE user_id = $1 AND used_at IS NULL",
+            )
+            .await?;
+        statement.execute(self.client, &[&user_id]).await?;
+        Ok(())
+    }
+}
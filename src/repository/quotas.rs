@@ -0,0 +1,111 @@
+use tokio_postgres::{Error, GenericClient, Row};
+
+use crate::db::{FromRow, Query, StatementCache};
+
+// The requests a tenant or an API key made in a period
+#[derive(Serialize)]
+pub struct Usage {
+    // `tenant:<id>` or `api_key:<id>`
+    pub subject: String,
+    pub requests: i64,
+}
+
+impl FromRow for Usage {
+    fn from_row(row: &Row) -> Result<Usage, Error> {
+        Ok(Usage {
+            subject: row.try_get("subject")?,
+            requests: row.try_get("requests")?,
+        })
+    }
+}
+
+// One row per subject and period, the past periods are kept for the record.
+// Periods start at UTC midnight, of the first day of the month for monthly
+// quotas.
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}quota_usage (
+        subject VARCHAR NOT NULL,
+        period_start DATE NOT NULL,
+        requests BIGINT NOT NULL,
+        PRIMARY KEY (subject, period_start)
+    );
+";
+
+// The start of the current `day` or `month`, $1 being the period
+const PERIOD_START: &str = "date_trunc($1, now() AT TIME ZONE 'UTC')::DATE";
+
+pub struct QuotaRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+}
+
+impl<'a, C: GenericClient> QuotaRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache) -> Self {
+        QuotaRepository { client, statements }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    // Adds the `requests` of each of `subjects` to the current `period`, in
+    // one statement. Returns the requests each subject made so far, every
+    // instance included, and the seconds left until the period ends.
+    pub async fn add(
+        &self,
+        subjects: &[String],
+        requests: &[i64],
+        period: &str,
+    ) -> Result<Vec<(String, i64, i64)>, Error> {
+        let statement = self
+            .prepare(
+                "WITH period AS (SELECT date_trunc($1, now() AT TIME ZONE 'UTC') AS start)
+                 INSERT INTO {prefix}quota_usage AS q (subject, period_start, requests)
+                 SELECT counted.subject, (SELECT start FROM period)::DATE, counted.requests
+                 FROM unnest($2::VARCHAR[], $3::BIGINT[]) AS counted (subject, requests)
+                 ON CONFLICT (subject, period_start)
+                     DO UPDATE SET requests = GREATEST(q.requests + EXCLUDED.requests, 0)
+                 RETURNING subject, requests,
+                     EXTRACT(EPOCH FROM (SELECT start FROM period) + ('1 ' || $1)::INTERVAL
+                         - now() AT TIME ZONE 'UTC')::BIGINT AS reset_in",
+            )
+            .await?;
+        let rows = statement
+            .query(self.client, &[&period, &subjects, &requests])
+            .await?;
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("subject")?,
+                    row.try_get("requests")?,
+                    row.try_get("reset_in")?,
+                ))
+            })
+            .collect()
+    }
+
+    // Every subject that made requests in the current `period`, the busiest
+    // first
+    pub async fn list(&self, period: &str) -> Result<Vec<Usage>, Error> {
+        let sql = format!(
+            "SELECT subject, requests FROM {{prefix}}quota_usage
+             WHERE period_start = {}
+             ORDER BY requests DESC, subject",
+            PERIOD_START
+        );
+        let statement = self.prepare(&sql).await?;
+        let rows = statement.query(self.client, &[&period]).await?;
+        Usage::from_rows(&rows)
+    }
+
+    // Forgets the requests of `subject` in the current `period`, false when
+    // it made none
+    pub async fn reset(&self, subject: &str, period: &str) -> Result<bool, Error> {
+        let sql = format!(
+            "DELETE FROM {{prefix}}quota_usage WHERE period_start = {} AND subject = $2",
+            PERIOD_START
+        );
+        let statement = self.prepare(&sql).await?;
+        Ok(statement.execute(self.client, &[&period, &subject]).await? != 0)
+    }
+}
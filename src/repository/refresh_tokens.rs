@@ -0,0 +1,131 @@
+use std::time::Duration;
+use tokio_postgres::{Error, GenericClient};
+
+use crate::db::{Query, StatementCache};
+
+// Refresh tokens are stored hashed. Every token of a login belongs to the
+// same family: refreshing revokes the token presented and issues the next
+// one of the family, logging out revokes the whole family.
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}refresh_tokens (
+        id SERIAL PRIMARY KEY,
+        user_id BIGINT NOT NULL REFERENCES {prefix}users (id) ON DELETE CASCADE,
+        family VARCHAR NOT NULL,
+        token_hash VARCHAR NOT NULL UNIQUE,
+        expires_at TIMESTAMPTZ NOT NULL,
+        revoked_at TIMESTAMPTZ
+    );
+    CREATE INDEX IF NOT EXISTS {prefix}refresh_tokens_family ON {prefix}refresh_tokens (family);
+    DO $$
+    BEGIN
+        IF EXISTS (
+            SELECT 1 FROM information_schema.columns
+            WHERE table_schema = current_schema()
+              AND table_name = '{prefix}refresh_tokens' AND column_name = 'user_id' AND data_type = 'integer'
+        ) THEN
+            ALTER TABLE {prefix}refresh_tokens ALTER COLUMN user_id TYPE BIGINT;
+        END IF;
+    END
+    $$;
+";
+
+pub struct RefreshTokenRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+}
+
+impl<'a, C: GenericClient> RefreshTokenRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache) -> Self {
+        RefreshTokenRepository { client, statements }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    pub async fn create(
+        &self,
+        user_id: i64,
+        family: &str,
+        token_hash: &str,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "INSERT INTO {prefix}refresh_tokens (user_id, family, token_hash, expires_at)
+                 VALUES ($1, $2, $3, now() + make_interval(secs => $4))",
+            )
+            .await?;
+        statement
+            .execute(
+                self.client,
+                &[&user_id, &family, &token_hash, &ttl.as_secs_f64()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Revoke a live token, returns the user and family it belongs to
+    pub async fn consume(&self, token_hash: &str) -> Result<Option<(i64, String)>, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}refresh_tokens SET revoked_at = now()
+                 WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > now()
+                 RETURNING user_id, family",
+            )
+            .await?;
+        let row = statement.query_opt(self.client, &[&token_hash]).await?;
+        row.map(|row| Ok((row.try_get("user_id")?, row.try_get("family")?)))
+            .transpose()
+    }
+
+    // A revoked token presented again was stolen from one of the parties:
+    // revoke its whole family. Returns whether the token was known.
+    pub async fn revoke_family_of(&self, token_hash: &str) -> Result<bool, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}refresh_tokens SET revoked_at = now()
+                 WHERE revoked_at IS NULL AND family IN (
+                     SELECT family FROM {prefix}refresh_tokens WHERE token_hash = $1
+                 )",
+            )
+            .await?;
+        Ok(statement.execute(self.client, &[&token_hash]).await? != 0)
+    }
+
+    pub async fn revoke_family(&self, family: &str) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}refresh_tokens SET revoked_at = now()
+                 WHERE family = $1 AND revoked_at IS NULL",
+            )
+            .await?;
+        statement.execute(self.client, &[&family]).await?;
+        Ok(())
+    }
+
+    pub async fn revoke_user(&self, user_id: i64) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}refresh_tokens SET revoked_at = now()
+                 WHERE user_id = $1 AND revoked_at IS NULL",
+            )
+            .await?;
+        statement.execute(self.client, &[&user_id]).await?;
+        Ok(())
+    }
+
+    // Whether the login has not been logged out or revoked
+    pub async fn is_active(&self, family: &str) -> Result<bool, Error> {
+        let statement = self
+            .prepare(
+                "SELECT EXISTS (
+                     SELECT 1 FROM {prefix}refresh_tokens
+                     WHERE family = $1 AND revoked_at IS NULL AND expires_at > now()
+                 ) AS active",
+            )
+            .await?;
+        let row = statement.query_one(self.client, &[&family]).await?;
+        row.try_get("active")
+    }
+}
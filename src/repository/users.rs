@@ -0,0 +1,753 @@
+use log::error;
+use serde_json::{Map, Value};
+use std::time::{Duration, SystemTime};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Error, GenericClient, RowStream};
+
+use crate::db::{FromRow, Query, StatementCache};
+use crate::email_rules;
+use crate::filter::Filter;
+use crate::ids;
+use crate::models::{AccountStatus, User, UserField};
+use crate::pii;
+
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}users (
+        id BIGSERIAL PRIMARY KEY,
+        name VARCHAR NOT NULL,
+        email VARCHAR NOT NULL
+    );
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT false;
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS password_hash VARCHAR;
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS session_version INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS tenant_id VARCHAR NOT NULL DEFAULT 'default';
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS status VARCHAR NOT NULL DEFAULT 'active'
+        CHECK (status IN ('active', 'suspended', 'deactivated'));
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS locked_until TIMESTAMPTZ;
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS avatar_type VARCHAR;
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+    CREATE INDEX IF NOT EXISTS {prefix}users_tenant_email ON {prefix}users (tenant_id, email);
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS email_hash VARCHAR;
+    CREATE INDEX IF NOT EXISTS {prefix}users_tenant_email_hash ON {prefix}users (tenant_id, email_hash);
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS merged_into BIGINT REFERENCES {prefix}users (id) ON DELETE SET NULL;
+    ALTER TABLE {prefix}users ADD COLUMN IF NOT EXISTS email_normalized VARCHAR;
+    CREATE INDEX IF NOT EXISTS {prefix}users_tenant_email_normalized ON {prefix}users (tenant_id, email_normalized);
+    DO $$
+    BEGIN
+        IF EXISTS (
+            SELECT 1 FROM information_schema.columns
+            WHERE table_schema = current_schema()
+              AND table_name = '{prefix}users' AND column_name = 'id' AND data_type = 'integer'
+        ) THEN
+            ALTER TABLE {prefix}users ALTER COLUMN id TYPE BIGINT, ALTER COLUMN merged_into TYPE BIGINT;
+            EXECUTE format('ALTER SEQUENCE %s AS BIGINT', pg_get_serial_sequence('{prefix}users', 'id'));
+        END IF;
+    END
+    $$;
+";
+
+// Row level security on top of the tenant filter of every query: rows of
+// other tenants than `app.tenant_id`, set by the unit of work, are invisible
+pub const ENABLE_ROW_LEVEL_SECURITY: &str = "
+    ALTER TABLE {prefix}users ENABLE ROW LEVEL SECURITY;
+    ALTER TABLE {prefix}users FORCE ROW LEVEL SECURITY;
+    DROP POLICY IF EXISTS tenant_isolation ON {prefix}users;
+    CREATE POLICY tenant_isolation ON {prefix}users
+        USING (tenant_id = current_setting('app.tenant_id', true))
+        WITH CHECK (tenant_id = current_setting('app.tenant_id', true));
+";
+
+pub const DISABLE_ROW_LEVEL_SECURITY: &str = "
+    DROP POLICY IF EXISTS tenant_isolation ON {prefix}users;
+    ALTER TABLE {prefix}users NO FORCE ROW LEVEL SECURITY;
+    ALTER TABLE {prefix}users DISABLE ROW LEVEL SECURITY;
+";
+
+// What a login is checked against
+pub struct Credentials {
+    pub user: User,
+    pub password_hash: Option<String>,
+    pub session_version: i32,
+    // seconds left until a lockout ends, None when not locked
+    pub locked_for: Option<i64>,
+}
+
+// Two users that are likely the same person
+pub struct Duplicate {
+    pub ids: (i64, i64),
+    // "email" when their addresses match, "name" when only their names are alike
+    pub reason: &'static str,
+    // of the names, 1 for matching addresses
+    pub similarity: f32,
+}
+
+// Aggregates over the users of a tenant
+pub struct UserStats {
+    pub total: i64,
+    pub deactivated: i64,
+    // (day, users created that day), oldest first, days without any included
+    pub signups: Vec<(String, i64)>,
+}
+
+// Users of one tenant: every query is restricted to its rows
+pub struct UserRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+    tenant: &'a str,
+}
+
+impl<'a, C: GenericClient> UserRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache, tenant: &'a str) -> Self {
+        UserRepository {
+            client,
+            statements,
+            tenant,
+        }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    pub async fn count(&self, status: AccountStatus, filter: &Filter<User>) -> Result<i64, Error> {
+        let sql = format!(
+            "SELECT count(*) AS users FROM {{prefix}}users WHERE tenant_id = $1 AND status = $2{}",
+            filter.sql(3)
+        );
+        let statement = self.prepare(&sql).await?;
+        let status = status.name();
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&self.tenant, &status];
+        params.extend(filter.params().map(|param| param as &(dyn ToSql + Sync)));
+        let row = statement.query_one(self.client, &params).await?;
+        row.try_get("users")
+    }
+
+    // Signups cover the last `days` days, today included
+    pub async fn stats(&self, days: i32) -> Result<UserStats, Error> {
+        let statement = self
+            .prepare(
+                "SELECT count(*) AS total,
+                     count(*) FILTER (WHERE status = 'deactivated') AS deactivated
+                 FROM {prefix}users WHERE tenant_id = $1",
+            )
+            .await?;
+        let totals = statement.query_one(self.client, &[&self.tenant]).await?;
+        let statement = self
+            .prepare(
+                "SELECT day::DATE::TEXT AS day, count(u.id) AS signups
+                 FROM generate_series(
+                     (current_date - ($2::INTEGER - 1))::TIMESTAMPTZ,
+                     current_date::TIMESTAMPTZ,
+                     '1 day'
+                 ) AS day
+                 LEFT JOIN {prefix}users u ON u.tenant_id = $1
+                     AND u.created_at >= day
+                     AND u.created_at < day + INTERVAL '1 day'
+                 GROUP BY day ORDER BY day",
+            )
+            .await?;
+        let signups = statement
+            .query(self.client, &[&self.tenant, &days])
+            .await?
+            .iter()
+            .map(|row| Ok((row.try_get("day")?, row.try_get("signups")?)))
+            .collect::<Result<_, Error>>()?;
+        Ok(UserStats {
+            total: totals.try_get("total")?,
+            deactivated: totals.try_get("deactivated")?,
+            signups,
+        })
+    }
+
+    pub async fn find(&self, id: i64) -> Result<Option<User>, Error> {
+        let statement = self
+            .prepare("SELECT * FROM {prefix}users WHERE id = $1 AND tenant_id = $2")
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&id, &self.tenant])
+            .await?;
+        User::from_opt(row.as_ref())
+    }
+
+    // Users with `status` and an id above `after`, in id order
+    pub async fn page(
+        &self,
+        status: AccountStatus,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<User>, Error> {
+        let statement = self
+            .prepare(
+                "SELECT * FROM {prefix}users
+                 WHERE tenant_id = $1 AND status = $2 AND id > $3
+                 ORDER BY id LIMIT $4",
+            )
+            .await?;
+        let rows = statement
+            .query(self.client, &[&self.tenant, &status.name(), &after, &limit])
+            .await?;
+        User::from_rows(&rows)
+    }
+
+    // Like `find`, locking the row until the end of the transaction
+    pub async fn find_for_update(&self, id: i64) -> Result<Option<User>, Error> {
+        let statement = self
+            .prepare("SELECT * FROM {prefix}users WHERE id = $1 AND tenant_id = $2 FOR UPDATE")
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&id, &self.tenant])
+            .await?;
+        User::from_opt(row.as_ref())
+    }
+
+    pub async fn find_by_email(&self, email: &str) -> Result<Option<User>, Error> {
+        Ok(self.credentials(email).await?.map(|found| found.user))
+    }
+
+    // Until the end of the transaction, the calls for the same address once
+    // normalized wait for each other even when there is no such user yet.
+    // Emails aren't unique in the table, the duplicates from before the
+    // normalization stay until merged, so that is what keeps two writes from
+    // both taking an address.
+    async fn lock_email(&self, email: &str) -> Result<(), Error> {
+        let statement = self
+            .prepare("SELECT pg_advisory_xact_lock(hashtext('{prefix}users'), hashtext($1 || '/' || $2))")
+            .await?;
+        statement
+            .execute(self.client, &[&self.tenant, &email_rules::normalize(email)])
+            .await?;
+        Ok(())
+    }
+
+    // Whether a user other than `except`, not merged, has `email` once
+    // normalized. Locks the address until the end of the transaction, for
+    // the create or update checking it.
+    pub async fn email_taken(&self, email: &str, except: Option<i64>) -> Result<bool, Error> {
+        self.lock_email(email).await?;
+        let statement = self
+            .prepare(
+                "SELECT EXISTS (
+                     SELECT 1 FROM {prefix}users
+                     WHERE (email_normalized = ANY($4) OR email = $1 OR email_hash = ANY($3))
+                       AND tenant_id = $2 AND merged_into IS NULL
+                       AND id IS DISTINCT FROM $5
+                 ) AS taken",
+            )
+            .await?;
+        let row = statement
+            .query_one(
+                self.client,
+                &[
+                    &email,
+                    &self.tenant,
+                    &pii::email_hashes(email),
+                    &email_rules::keys(email),
+                    &except,
+                ],
+            )
+            .await?;
+        row.try_get("taken")
+    }
+
+    // The user with `email`, the oldest not merged when there are several,
+    // locked until the end of the transaction along with the address
+    pub async fn find_by_email_for_update(&self, email: &str) -> Result<Option<User>, Error> {
+        self.lock_email(email).await?;
+        let statement = self
+            .prepare(
+                "SELECT * FROM {prefix}users
+                 WHERE (email_normalized = ANY($4) OR email = $1 OR email_hash = ANY($3))
+                   AND tenant_id = $2
+                 ORDER BY merged_into IS NOT NULL, id LIMIT 1 FOR UPDATE",
+            )
+            .await?;
+        let row = statement
+            .query_opt(
+                self.client,
+                &[
+                    &email,
+                    &self.tenant,
+                    &pii::email_hashes(email),
+                    &email_rules::keys(email),
+                ],
+            )
+            .await?;
+        User::from_opt(row.as_ref())
+    }
+
+    // Addresses are compared once normalized, those stored before the
+    // normalization by their blind index when encrypted, by their address
+    // when left from before encryption. A user merged into another one only
+    // comes when no other user has the address.
+    pub async fn credentials(&self, email: &str) -> Result<Option<Credentials>, Error> {
+        let statement = self
+            .prepare(
+                "SELECT *,
+                     CASE WHEN locked_until > now()
+                         THEN CEIL(EXTRACT(EPOCH FROM locked_until - now()))::BIGINT
+                     END AS locked_for
+                 FROM {prefix}users
+                 WHERE (email_normalized = ANY($4) OR email = $1 OR email_hash = ANY($3))
+                   AND tenant_id = $2
+                 ORDER BY merged_into IS NOT NULL, id LIMIT 1",
+            )
+            .await?;
+        let row = statement
+            .query_opt(
+                self.client,
+                &[
+                    &email,
+                    &self.tenant,
+                    &pii::email_hashes(email),
+                    &email_rules::keys(email),
+                ],
+            )
+            .await?;
+        row.map(|row| {
+            Ok(Credentials {
+                user: User::from_row(&row)?,
+                password_hash: row.try_get("password_hash")?,
+                session_version: row.try_get("session_version")?,
+                locked_for: row.try_get("locked_for")?,
+            })
+        })
+        .transpose()
+    }
+
+    // Bumped whenever the tokens issued so far must stop working, None when
+    // there is no active user with this id
+    pub async fn session_version(&self, id: i64) -> Result<Option<i32>, Error> {
+        let statement = self
+            .prepare(
+                "SELECT session_version FROM {prefix}users
+                 WHERE id = $1 AND tenant_id = $2 AND status = 'active'",
+            )
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&id, &self.tenant])
+            .await?;
+        row.map(|row| row.try_get("session_version")).transpose()
+    }
+
+    // Users with `status`, all columns or only `fields`, yielding the rows as
+    // they arrive
+    pub async fn stream(
+        &self,
+        fields: Option<&[UserField]>,
+        status: AccountStatus,
+        filter: &Filter<User>,
+    ) -> Result<RowStream, Error> {
+        let sql = format!(
+            "SELECT {} FROM {{prefix}}users WHERE tenant_id = $1 AND status = $2{}",
+            fields.map(columns).unwrap_or_else(|| "*".to_string()),
+            filter.sql(3)
+        );
+        let statement = self.prepare(&sql).await?;
+        let mut params = vec![self.tenant, status.name()];
+        params.extend(filter.params().map(String::as_str));
+        statement.query_raw(self.client, &params).await
+    }
+
+    // Sparse variant of `find`, selecting only `fields`, along with the time
+    // of the last change
+    pub async fn find_fields(
+        &self,
+        id: i64,
+        fields: &[UserField],
+    ) -> Result<Option<(Map<String, Value>, SystemTime)>, Error> {
+        let sql = format!(
+            "SELECT {}, updated_at FROM {{prefix}}users WHERE id = $1 AND tenant_id = $2",
+            columns(fields)
+        );
+        let statement = self.prepare(&sql).await?;
+        let row = statement
+            .query_opt(self.client, &[&id, &self.tenant])
+            .await?;
+        row.as_ref()
+            .map(|row| {
+                Ok((
+                    UserField::partial_from_row(fields, row)?,
+                    row.try_get("updated_at")?,
+                ))
+            })
+            .transpose()
+    }
+
+    // The users among `ids`, in id order, in a single query
+    pub async fn find_many(&self, ids: &[i64]) -> Result<Vec<User>, Error> {
+        let statement = self
+            .prepare(
+                "SELECT * FROM {prefix}users WHERE id = ANY($1) AND tenant_id = $2 ORDER BY id",
+            )
+            .await?;
+        let rows = statement.query(self.client, &[&ids, &self.tenant]).await?;
+        User::from_rows(&rows)
+    }
+
+    // Sparse variant of `find_many`, each user along with its id
+    pub async fn find_many_fields(
+        &self,
+        ids: &[i64],
+        fields: &[UserField],
+    ) -> Result<Vec<(i64, Map<String, Value>)>, Error> {
+        let sql = format!(
+            "SELECT {}, id FROM {{prefix}}users WHERE id = ANY($1) AND tenant_id = $2 ORDER BY id",
+            columns(fields)
+        );
+        let statement = self.prepare(&sql).await?;
+        let rows = statement.query(self.client, &[&ids, &self.tenant]).await?;
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("id")?,
+                    UserField::partial_from_row(fields, row)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub async fn create(&self, user: &User, password_hash: Option<&str>) -> Result<User, Error> {
+        // the database fills the id in when none is generated
+        let statement = self
+            .prepare(
+                "INSERT INTO {prefix}users
+                     (id, name, email, email_hash, password_hash, tenant_id, email_normalized)
+                 VALUES (COALESCE($7, nextval(pg_get_serial_sequence('{prefix}users', 'id'))),
+                         $1, $2, $5, $3, $4, $6)
+                 RETURNING *",
+            )
+            .await?;
+        let row = statement
+            .query_one(
+                self.client,
+                &[
+                    &user.name,
+                    &pii::seal(&user.email),
+                    &password_hash,
+                    &self.tenant,
+                    &pii::email_hash(&user.email),
+                    &email_rules::key(&user.email),
+                    &ids::user_id(),
+                ],
+            )
+            .await?;
+        User::from_row(&row)
+    }
+
+    // None when there is no user with this id. Changing the email address
+    // clears its verification, unless it normalizes to the same one, changing
+    // the password signs the user out.
+    pub async fn update(
+        &self,
+        id: i64,
+        user: &User,
+        password_hash: Option<&str>,
+    ) -> Result<Option<User>, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}users
+                 SET name = $1,
+                     email = $2,
+                     email_hash = $6,
+                     email_normalized = $9,
+                     email_verified = email_verified
+                         AND (email = $7 OR email_hash = ANY($8) OR email_normalized = ANY($10)),
+                     password_hash = COALESCE($4, password_hash),
+                     session_version = session_version + ($4::VARCHAR IS NOT NULL)::INTEGER,
+                     updated_at = now()
+                 WHERE id = $3 AND tenant_id = $5
+                 RETURNING *",
+            )
+            .await?;
+        let row = statement
+            .query_opt(
+                self.client,
+                &[
+                    &user.name,
+                    &pii::seal(&user.email),
+                    &id,
+                    &password_hash,
+                    &self.tenant,
+                    &pii::email_hash(&user.email),
+                    &user.email,
+                    &pii::email_hashes(&user.email),
+                    &email_rules::key(&user.email),
+                    &email_rules::keys(&user.email),
+                ],
+            )
+            .await?;
+        User::from_opt(row.as_ref())
+    }
+
+    // Also signs the user out everywhere, false when there is no user with
+    // this id
+    pub async fn set_password(&self, id: i64, password_hash: &str) -> Result<bool, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}users
+                 SET password_hash = $2, session_version = session_version + 1
+                 WHERE id = $1 AND tenant_id = $3",
+            )
+            .await?;
+        Ok(statement
+            .execute(self.client, &[&id, &password_hash, &self.tenant])
+            .await?
+            != 0)
+    }
+
+    pub async fn mark_email_verified(&self, id: i64) -> Result<Option<User>, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}users SET email_verified = true, updated_at = now()
+                 WHERE id = $1 AND tenant_id = $2
+                 RETURNING *",
+            )
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&id, &self.tenant])
+            .await?;
+        User::from_opt(row.as_ref())
+    }
+
+    // Leaving the active status signs the user out everywhere
+    pub async fn set_status(&self, id: i64, status: AccountStatus) -> Result<Option<User>, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}users
+                 SET status = $2::VARCHAR,
+                     session_version = session_version + ($2::VARCHAR <> 'active')::INTEGER,
+                     updated_at = now()
+                 WHERE id = $1 AND tenant_id = $3
+                 RETURNING *",
+            )
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&id, &status.name(), &self.tenant])
+            .await?;
+        User::from_opt(row.as_ref())
+    }
+
+    // Content type of the avatar, None when the user has none or there is no
+    // user with this id
+    pub async fn avatar_type(&self, id: i64) -> Result<Option<String>, Error> {
+        let statement = self
+            .prepare("SELECT avatar_type FROM {prefix}users WHERE id = $1 AND tenant_id = $2")
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&id, &self.tenant])
+            .await?;
+        Ok(row
+            .map(|row| row.try_get("avatar_type"))
+            .transpose()?
+            .flatten())
+    }
+
+    pub async fn set_avatar(&self, id: i64, content_type: Option<&str>) -> Result<bool, Error> {
+        let statement = self
+            .prepare("UPDATE {prefix}users SET avatar_type = $2 WHERE id = $1 AND tenant_id = $3")
+            .await?;
+        Ok(statement
+            .execute(self.client, &[&id, &content_type, &self.tenant])
+            .await?
+            != 0)
+    }
+
+    // Refuse logins to the account for `duration`
+    pub async fn lock(&self, id: i64, duration: Duration) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}users SET locked_until = now() + make_interval(secs => $2)
+                 WHERE id = $1 AND tenant_id = $3",
+            )
+            .await?;
+        statement
+            .execute(self.client, &[&id, &duration.as_secs_f64(), &self.tenant])
+            .await?;
+        Ok(())
+    }
+
+    // None when there is no user with this id
+    pub async fn unlock(&self, id: i64) -> Result<Option<User>, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}users SET locked_until = NULL
+                 WHERE id = $1 AND tenant_id = $2
+                 RETURNING *",
+            )
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&id, &self.tenant])
+            .await?;
+        User::from_opt(row.as_ref())
+    }
+
+    // Encrypt up to `limit` emails not matching `pattern`, those of the
+    // current key, with it. Returns how many were: the ones no key decrypts
+    // are left as they are.
+    pub async fn rotate_emails(&self, pattern: &str, limit: i64) -> Result<u64, Error> {
+        let statement = self
+            .prepare(
+                "SELECT id, email FROM {prefix}users
+                 WHERE tenant_id = $1 AND email NOT LIKE $2
+                 ORDER BY id LIMIT $3 FOR UPDATE",
+            )
+            .await?;
+        let rows = statement
+            .query(self.client, &[&self.tenant, &pattern, &limit])
+            .await?;
+        let update = self
+            .prepare(
+                "UPDATE {prefix}users SET email = $2, email_hash = $3, email_normalized = $5
+                 WHERE id = $1 AND tenant_id = $4",
+            )
+            .await?;
+        let mut rotated = 0;
+        for row in &rows {
+            let id: i64 = row.try_get("id")?;
+            let email = match pii::open(row.try_get("email")?) {
+                Ok(email) => email,
+                Err(e) => {
+                    error!("Can't re-encrypt the email of user {}: {}", id, e);
+                    continue;
+                }
+            };
+            update
+                .execute(
+                    self.client,
+                    &[
+                        &id,
+                        &pii::seal(&email),
+                        &pii::email_hash(&email),
+                        &self.tenant,
+                        &email_rules::key(&email),
+                    ],
+                )
+                .await?;
+            rotated += 1;
+        }
+        Ok(rotated)
+    }
+
+    pub async fn count_unrotated_emails(&self, pattern: &str) -> Result<i64, Error> {
+        let statement = self
+            .prepare(
+                "SELECT count(*) AS users FROM {prefix}users WHERE tenant_id = $1 AND email NOT LIKE $2",
+            )
+            .await?;
+        let row = statement
+            .query_one(self.client, &[&self.tenant, &pattern])
+            .await?;
+        row.try_get("users")
+    }
+
+    // Whether pg_trgm is installed, to compare names in `duplicates`
+    pub async fn has_trigrams(&self) -> Result<bool, Error> {
+        let statement = self
+            .prepare(
+                "SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'pg_trgm') AS installed",
+            )
+            .await?;
+        let row = statement.query_one(self.client, &[]).await?;
+        row.try_get("installed")
+    }
+
+    // Pairs of users not merged yet whose addresses match once normalized,
+    // or with `fuzzy` (pg_trgm installed) whose names have a trigram
+    // similarity of at least `threshold`, the most similar first. Compares
+    // every pair of the tenant, meant for an occasional cleanup.
+    pub async fn duplicates(
+        &self,
+        fuzzy: bool,
+        threshold: f32,
+        limit: i64,
+    ) -> Result<Vec<Duplicate>, Error> {
+        // encrypted addresses only compare through their blind index, those
+        // stored before the normalization after trimming and lowercasing
+        let same_email = "(COALESCE(a.email_normalized = b.email_normalized, false)
+            OR lower(trim(a.email)) = lower(trim(b.email))
+            OR COALESCE(a.email_hash = b.email_hash, false))";
+        let (similarity, condition) = if fuzzy {
+            (
+                "similarity(lower(a.name), lower(b.name))",
+                "similarity(lower(a.name), lower(b.name)) >= $3",
+            )
+        } else {
+            ("0", "false")
+        };
+        let sql = format!(
+            "SELECT a.id AS a_id, b.id AS b_id, {same_email} AS same_email,
+                    CASE WHEN {same_email} THEN 1 ELSE {similarity} END::REAL AS similarity
+             FROM {{prefix}}users a
+             JOIN {{prefix}}users b ON b.tenant_id = a.tenant_id AND b.id > a.id
+             WHERE a.tenant_id = $1 AND a.merged_into IS NULL AND b.merged_into IS NULL
+               AND ({same_email} OR {condition})
+             ORDER BY similarity DESC, a.id, b.id
+             LIMIT $2",
+        );
+        let statement = self.prepare(&sql).await?;
+        let rows = if fuzzy {
+            statement
+                .query(self.client, &[&self.tenant, &limit, &threshold])
+                .await?
+        } else {
+            statement
+                .query(self.client, &[&self.tenant, &limit])
+                .await?
+        };
+        rows.iter()
+            .map(|row| {
+                Ok(Duplicate {
+                    ids: (row.try_get("a_id")?, row.try_get("b_id")?),
+                    reason: if row.try_get("same_email")? {
+                        "email"
+                    } else {
+                        "name"
+                    },
+                    similarity: row.try_get("similarity")?,
+                })
+            })
+            .collect()
+    }
+
+    // Soft delete `id` in favour of `into`: marked merged and deactivated,
+    // which also signs it out. None when either was merged already.
+    pub async fn merge(&self, id: i64, into: i64) -> Result<Option<User>, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}users
+                 SET merged_into = $2,
+                     status = 'deactivated',
+                     session_version = session_version + 1,
+                     updated_at = now()
+                 WHERE id = $1 AND tenant_id = $3 AND merged_into IS NULL
+                   AND EXISTS (
+                       SELECT 1 FROM {prefix}users
+                       WHERE id = $2 AND tenant_id = $3 AND merged_into IS NULL
+                   )
+                 RETURNING *",
+            )
+            .await?;
+        let row = statement
+            .query_opt(self.client, &[&id, &into, &self.tenant])
+            .await?;
+        User::from_opt(row.as_ref())
+    }
+
+    // false when there is no user with this id
+    pub async fn delete(&self, id: i64) -> Result<bool, Error> {
+        let statement = self
+            .prepare("DELETE FROM {prefix}users WHERE id = $1 AND tenant_id = $2")
+            .await?;
+        let rows_affected = statement.execute(self.client, &[&id, &self.tenant]).await?;
+        Ok(rows_affected != 0)
+    }
+}
+
+fn columns(fields: &[UserField]) -> String {
+    fields
+        .iter()
+        .map(UserField::column)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
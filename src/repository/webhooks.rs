@@ -0,0 +1,287 @@
+use serde_json::Value;
+use std::time::Duration;
+use tokio_postgres::{Error, GenericClient, Row};
+
+use crate::db::{FromRow, Query, StatementCache};
+use crate::events::Event;
+
+#[derive(Serialize)]
+pub struct Webhook {
+    pub id: i32,
+    // only called back for the events of its tenant
+    pub tenant_id: String,
+    pub url: String,
+    pub event: String,
+    // only disclosed when the webhook is registered
+    #[serde(skip_serializing)]
+    pub secret: String,
+}
+
+impl FromRow for Webhook {
+    fn from_row(row: &Row) -> Result<Webhook, Error> {
+        Ok(Webhook {
+            id: row.try_get("id")?,
+            tenant_id: row.try_get("tenant_id")?,
+            url: row.try_get("url")?,
+            event: row.try_get("event")?,
+            secret: row.try_get("secret")?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct Delivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+}
+
+impl FromRow for Delivery {
+    fn from_row(row: &Row) -> Result<Delivery, Error> {
+        Ok(Delivery {
+            id: row.try_get("id")?,
+            webhook_id: row.try_get("webhook_id")?,
+            event: row.try_get("event")?,
+            status: row.try_get("status")?,
+            attempts: row.try_get("attempts")?,
+            last_error: row.try_get("last_error")?,
+        })
+    }
+}
+
+// A delivery claimed by the worker, with everything needed to send it
+pub struct PendingDelivery {
+    pub id: i32,
+    pub event: String,
+    pub payload: Value,
+    pub attempts: i32,
+    pub url: String,
+    pub secret: String,
+}
+
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {prefix}webhooks (
+        id SERIAL PRIMARY KEY,
+        url VARCHAR NOT NULL,
+        event VARCHAR NOT NULL,
+        secret VARCHAR NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS {prefix}webhook_deliveries (
+        id SERIAL PRIMARY KEY,
+        webhook_id INTEGER NOT NULL REFERENCES {prefix}webhooks (id) ON DELETE CASCADE,
+        event VARCHAR NOT NULL,
+        payload JSONB NOT NULL,
+        status VARCHAR NOT NULL DEFAULT 'pending',
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        last_error VARCHAR
+    );
+    CREATE INDEX IF NOT EXISTS {prefix}webhook_deliveries_due
+        ON {prefix}webhook_deliveries (next_attempt_at) WHERE status = 'pending';
+    ALTER TABLE {prefix}webhooks
+        ADD COLUMN IF NOT EXISTS tenant_id VARCHAR NOT NULL DEFAULT 'default';
+";
+
+// The webhooks of one tenant
+pub struct WebhookRepository<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+    tenant: &'a str,
+}
+
+impl<'a, C: GenericClient> WebhookRepository<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache, tenant: &'a str) -> Self {
+        WebhookRepository {
+            client,
+            statements,
+            tenant,
+        }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    pub async fn create(&self, url: &str, event: &str, secret: &str) -> Result<Webhook, Error> {
+        let statement = self
+            .prepare(
+                "INSERT INTO {prefix}webhooks (url, event, secret, tenant_id)
+                 VALUES ($1, $2, $3, $4) RETURNING *",
+            )
+            .await?;
+        let row = statement
+            .query_one(self.client, &[&url, &event, &secret, &self.tenant])
+            .await?;
+        Webhook::from_row(&row)
+    }
+
+    pub async fn list(&self) -> Result<Vec<Webhook>, Error> {
+        let statement = self
+            .prepare("SELECT * FROM {prefix}webhooks WHERE tenant_id = $1 ORDER BY id")
+            .await?;
+        let rows = statement.query(self.client, &[&self.tenant]).await?;
+        Webhook::from_rows(&rows)
+    }
+
+    // false when there is no webhook with this id
+    pub async fn delete(&self, id: i32) -> Result<bool, Error> {
+        let statement = self
+            .prepare("DELETE FROM {prefix}webhooks WHERE id = $1 AND tenant_id = $2")
+            .await?;
+        Ok(statement.execute(self.client, &[&id, &self.tenant]).await? != 0)
+    }
+
+    // Queue one delivery per webhook of the tenant subscribed to the event,
+    // returns how many
+    pub async fn enqueue(&self, event: &Event) -> Result<u64, Error> {
+        let statement = self
+            .prepare(
+                "INSERT INTO {prefix}webhook_deliveries (webhook_id, event, payload)
+                 SELECT id, event, $2::JSONB FROM {prefix}webhooks
+                 WHERE event = $1 AND tenant_id = $3",
+            )
+            .await?;
+        statement
+            .execute(
+                self.client,
+                &[&event.kind.name(), &event.payload(), &self.tenant],
+            )
+            .await
+    }
+
+    // `status` filters on pending, delivered or dead
+    pub async fn deliveries(
+        &self,
+        webhook_id: i32,
+        status: Option<&str>,
+    ) -> Result<Vec<Delivery>, Error> {
+        let statement = self
+            .prepare(
+                "SELECT d.* FROM {prefix}webhook_deliveries d
+                 JOIN {prefix}webhooks w ON w.id = d.webhook_id
+                 WHERE d.webhook_id = $1 AND w.tenant_id = $3
+                     AND ($2::VARCHAR IS NULL OR d.status = $2)
+                 ORDER BY d.id DESC",
+            )
+            .await?;
+        let rows = statement
+            .query(self.client, &[&webhook_id, &status, &self.tenant])
+            .await?;
+        Delivery::from_rows(&rows)
+    }
+
+    // Put a dead delivery back in the queue, false if it is not dead
+    pub async fn retry(&self, webhook_id: i32, delivery_id: i32) -> Result<bool, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}webhook_deliveries d
+                 SET status = 'pending', attempts = 0, next_attempt_at = now()
+                 FROM {prefix}webhooks w
+                 WHERE d.id = $1 AND d.webhook_id = $2 AND d.status = 'dead'
+                     AND w.id = d.webhook_id AND w.tenant_id = $3",
+            )
+            .await?;
+        Ok(statement
+            .execute(self.client, &[&delivery_id, &webhook_id, &self.tenant])
+            .await?
+            != 0)
+    }
+}
+
+// The deliveries of every tenant, as the worker sends them
+pub struct DeliveryQueue<'a, C: GenericClient> {
+    client: &'a C,
+    statements: &'a StatementCache,
+}
+
+impl<'a, C: GenericClient> DeliveryQueue<'a, C> {
+    pub fn new(client: &'a C, statements: &'a StatementCache) -> Self {
+        DeliveryQueue { client, statements }
+    }
+
+    async fn prepare(&self, sql: &str) -> Result<Query, Error> {
+        self.statements.prepare(self.client, sql).await
+    }
+
+    // Lease up to `limit` due deliveries: they won't be handed out again
+    // before `lease` elapses, so several workers can share the queue
+    pub async fn claim_due(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<PendingDelivery>, Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}webhook_deliveries d
+                 SET next_attempt_at = now() + make_interval(secs => $2)
+                 FROM {prefix}webhooks w
+                 WHERE w.id = d.webhook_id AND d.id IN (
+                     SELECT id FROM {prefix}webhook_deliveries
+                     WHERE status = 'pending' AND next_attempt_at <= now()
+                     ORDER BY next_attempt_at
+                     LIMIT $1
+                     FOR UPDATE SKIP LOCKED
+                 )
+                 RETURNING d.id, d.event, d.payload, d.attempts, w.url, w.secret",
+            )
+            .await?;
+        let rows = statement
+            .query(self.client, &[&limit, &lease.as_secs_f64()])
+            .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(PendingDelivery {
+                    id: row.try_get("id")?,
+                    event: row.try_get("event")?,
+                    payload: row.try_get("payload")?,
+                    attempts: row.try_get("attempts")?,
+                    url: row.try_get("url")?,
+                    secret: row.try_get("secret")?,
+                })
+            })
+            .collect()
+    }
+
+    pub async fn mark_delivered(&self, id: i32) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}webhook_deliveries
+                 SET status = 'delivered', attempts = attempts + 1, last_error = NULL
+                 WHERE id = $1",
+            )
+            .await?;
+        statement.execute(self.client, &[&id]).await?;
+        Ok(())
+    }
+
+    // Schedule another attempt after `backoff`, or move the delivery to the
+    // dead letters once `max_attempts` is reached
+    pub async fn mark_failed(
+        &self,
+        id: i32,
+        error: &str,
+        max_attempts: i32,
+        backoff: Duration,
+    ) -> Result<(), Error> {
+        let statement = self
+            .prepare(
+                "UPDATE {prefix}webhook_deliveries
+                 SET attempts = attempts + 1,
+                     last_error = $2,
+                     status = CASE WHEN attempts + 1 >= $3 THEN 'dead' ELSE 'pending' END,
+                     next_attempt_at = now() + make_interval(secs => $4)
+                 WHERE id = $1",
+            )
+            .await?;
+        statement
+            .execute(
+                self.client,
+                &[&id, &error, &max_attempts, &backoff.as_secs_f64()],
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use actix_web::dev::ResourceDef;
+use actix_web::http::Method;
+use std::sync::OnceLock;
+
+use crate::cache::Caching;
+
+// A route of the API and what the middlewares need to know of it, before
+// the request is routed
+pub struct Route {
+    pub pattern: &'static str,
+    pub methods: &'static [Method],
+    // the writes that can be rehearsed, their handlers take a `DryRun` and
+    // finish their transaction with it, and have no side effect outside of it
+    pub dry_runs: &'static [Method],
+    // takes something else than JSON, the handler checks its own bodies
+    pub upload: bool,
+    // unless CACHE_POLICIES says otherwise
+    pub caching: Caching,
+}
+
+const fn route(pattern: &'static str, methods: &'static [Method]) -> Route {
+    Route {
+        pattern,
+        methods,
+        dry_runs: &[],
+        upload: false,
+        caching: Caching::Unset,
+    }
+}
+
+impl Route {
+    const fn dry_runs(self, dry_runs: &'static [Method]) -> Route {
+        Route { dry_runs, ..self }
+    }
+
+    const fn upload(self) -> Route {
+        Route {
+            upload: true,
+            ..self
+        }
+    }
+
+    const fn caching(self, caching: Caching) -> Route {
+        Route { caching, ..self }
+    }
+}
+
+// Every route and the methods it answers, as registered in main: the tests
+// fail on a handler or a method missing here. Where several patterns match
+// a path, such as `/users/count` and `/users/{id}`, the first one listed
+// wins.
+pub const ROUTES: &[Route] = &[
+    route(
+        "/users",
+        &[Method::GET, Method::HEAD, Method::POST, Method::PUT],
+    )
+    .dry_runs(&[Method::POST, Method::PUT])
+    .caching(Caching::Public),
+    route("/users/count", &[Method::GET]).caching(Caching::Public),
+    route("/users/export", &[Method::GET]).caching(Caching::NoStore),
+    route("/users/page", &[Method::GET]).caching(Caching::Public),
+    route("/users/{id}", &[Method::GET, Method::PUT, Method::DELETE])
+        .dry_runs(&[Method::PUT, Method::DELETE])
+        // revalidated with If-Modified-Since
+        .caching(Caching::NoCache),
+    route("/users/{id}/avatar", &[Method::GET, Method::PUT])
+        .upload()
+        .caching(Caching::Private),
+    route("/users/{id}/resend-verification", &[Method::POST]),
+    route("/users/{id}/suspend", &[Method::POST]).dry_runs(&[Method::POST]),
+    route("/users/{id}/activate", &[Method::POST]).dry_runs(&[Method::POST]),
+    route("/users/{id}/deactivate", &[Method::POST]).dry_runs(&[Method::POST]),
+    route("/users/{id}/unlock", &[Method::POST]),
+    route("/verify", &[Method::GET]),
+    route("/healthz", &[Method::GET]).caching(Caching::NoStore),
+    route("/readyz", &[Method::GET]).caching(Caching::NoStore),
+    route("/metrics", &[Method::GET]).caching(Caching::NoStore),
+    route("/version", &[Method::GET]).caching(Caching::NoStore),
+    route("/api/v1/schema", &[Method::GET]),
+    route("/api/v1/openapi.json", &[Method::GET]),
+    route("/auth/login", &[Method::POST]).caching(Caching::NoStore),
+    route("/auth/refresh", &[Method::POST]).caching(Caching::NoStore),
+    route("/auth/logout", &[Method::POST]).caching(Caching::NoStore),
+    route("/auth/session", &[Method::GET]).caching(Caching::NoStore),
+    route("/auth/forgot-password", &[Method::POST]).caching(Caching::NoStore),
+    route("/auth/reset-password", &[Method::POST]).caching(Caching::NoStore),
+    route("/auth/{provider}/login", &[Method::GET]).caching(Caching::NoStore),
+    route("/auth/{provider}/callback", &[Method::GET]).caching(Caching::NoStore),
+    route("/me", &[Method::GET, Method::PATCH, Method::DELETE])
+        .dry_runs(&[Method::PATCH, Method::DELETE])
+        .caching(Caching::NoStore),
+    route("/admin", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/assets/{file}", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/api-keys", &[Method::GET, Method::POST]).caching(Caching::NoStore),
+    route("/admin/api-keys/{id}", &[Method::DELETE]).caching(Caching::NoStore),
+    route("/admin/backup", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/restore", &[Method::POST])
+        .upload()
+        .caching(Caching::NoStore),
+    route("/admin/partners", &[Method::GET, Method::POST]).caching(Caching::NoStore),
+    route("/admin/partners/{id}", &[Method::DELETE]).caching(Caching::NoStore),
+    route("/admin/config", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/email-encryption/rotate", &[Method::POST]).caching(Caching::NoStore),
+    route("/admin/flags", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/flags/{name}", &[Method::PUT, Method::DELETE]).caching(Caching::NoStore),
+    route("/admin/lockouts", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/quotas", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/quotas/{subject}", &[Method::DELETE]).caching(Caching::NoStore),
+    route("/admin/maintenance", &[Method::GET, Method::PUT]).caching(Caching::NoStore),
+    route("/admin/stats", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/status", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/users/duplicates", &[Method::GET]).caching(Caching::NoStore),
+    route("/admin/users/{keep}/merge/{remove}", &[Method::POST]).caching(Caching::NoStore),
+    route("/admin/webhooks", &[Method::GET, Method::POST]).caching(Caching::NoStore),
+    route("/admin/webhooks/{id}", &[Method::DELETE]).caching(Caching::NoStore),
+    route("/admin/webhooks/{id}/deliveries", &[Method::GET]).caching(Caching::NoStore),
+    route(
+        "/admin/webhooks/{id}/deliveries/{delivery_id}/retry",
+        &[Method::POST],
+    )
+    .caching(Caching::NoStore),
+    route("/changes", &[Method::GET]),
+];
+
+static COMPILED: OnceLock<Vec<(ResourceDef, &'static Route)>> = OnceLock::new();
+
+// The routes whose pattern matches `path`, in the order of ROUTES, the
+// patterns being compiled once
+pub fn matching(path: &str) -> impl Iterator<Item = &'static Route> + '_ {
+    COMPILED
+        .get_or_init(|| {
+            ROUTES
+                .iter()
+                .map(|route| (ResourceDef::new(route.pattern), route))
+                .collect()
+        })
+        .iter()
+        .filter(move |(pattern, _)| pattern.is_match(path))
+        .map(|(_, route)| *route)
+}
+
+// The first route of `path`
+pub fn find(path: &str) -> Option<&'static Route> {
+    matching(path).next()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{self};
+    use regex::Regex;
+    use std::fs;
+
+    use super::*;
+    use crate::app;
+    use crate::test_app::{admin, shared};
+
+    // The methods a route can be registered for, OPTIONS being answered by
+    // the CORS middleware
+    const METHODS: [Method; 6] = [
+        Method::GET,
+        Method::HEAD,
+        Method::POST,
+        Method::PUT,
+        Method::PATCH,
+        Method::DELETE,
+    ];
+
+    #[test]
+    fn every_registered_handler_is_listed() {
+        let handler = Regex::new(
+            r#"#\[(get|post|put|patch|delete)\("([^"]+)"|#\[route\("([^"]+)", method = "(\w+)""#,
+        )
+        .unwrap();
+        let mut handlers = 0;
+        for entry in fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/src")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("rs") {
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            for captures in handler.captures_iter(&source) {
+                let (method, pattern) = match (captures.get(1), captures.get(2)) {
+                    (Some(method), Some(pattern)) => (method.as_str(), pattern.as_str()),
+                    _ => (&captures[4], captures.get(3).unwrap().as_str()),
+                };
+                let method: Method = method.to_ascii_uppercase().parse().unwrap();
+                assert!(
+                    ROUTES
+                        .iter()
+                        .any(|route| route.pattern == pattern && route.methods.contains(&method)),
+                    "{} {} of {} isn't in ROUTES",
+                    method,
+                    pattern,
+                    path.display()
+                );
+                handlers += 1;
+            }
+        }
+        assert!(handlers >= ROUTES.len(), "only {} handlers found", handlers);
+    }
+
+    #[actix_web::test]
+    async fn routes_answer_their_methods_and_only_them() {
+        let app = test::init_service(app(&shared().await)).await;
+        let segment = Regex::new(r"\{[^}]+\}").unwrap();
+        for route in ROUTES {
+            let path = segment.replace_all(route.pattern, "0").into_owned();
+            let allowed: Vec<&Method> = matching(&path)
+                .flat_map(|route| route.methods.iter())
+                .collect();
+            for method in &METHODS {
+                let request = admin(method.as_str(), &path).to_request();
+                let response = test::call_service(&app, request).await;
+                let status = response.status();
+                // how the default service answers the paths of ROUTES
+                let unrouted = status == 405 && response.headers().contains_key(header::ALLOW);
+                if allowed.contains(&method) {
+                    assert!(!unrouted, "{} {} isn't registered", method, path);
+                } else {
+                    assert!(
+                        unrouted,
+                        "{} {} answered {}, registered but not in ROUTES",
+                        method, path, status
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde_json::{json, Map, Value};
+
+use crate::auth::MIN_PASSWORD_LENGTH;
+use crate::filter::{Filterable, MAX_CONDITIONS};
+use crate::models::{Access, AccountStatus, Attribute, User, UserField};
+use crate::pii;
+use crate::routes::ROUTES;
+
+// The constraints the handlers check on a field, beyond its type
+fn constraints(attribute: &Attribute) -> Map<String, Value> {
+    let mut constraints = Map::new();
+    match attribute.name {
+        // a hint for the input, addresses are normalized but not checked
+        "email" => {
+            constraints.insert("format".to_string(), json!("email"));
+        }
+        "password" => {
+            constraints.insert("min_length".to_string(), json!(MIN_PASSWORD_LENGTH));
+        }
+        "status" => {
+            let statuses: Vec<_> = AccountStatus::ALL.iter().map(AccountStatus::name).collect();
+            constraints.insert("enum".to_string(), json!(statuses));
+        }
+        _ => (),
+    }
+    constraints
+}
+
+fn field(attribute: &Attribute) -> Value {
+    let mut field = Map::new();
+    field.insert("name".to_string(), json!(attribute.name));
+    field.insert("type".to_string(), json!(attribute.kind.name()));
+    field.insert("required".to_string(), json!(attribute.required));
+    field.insert(
+        "read_only".to_string(),
+        json!(attribute.access == Access::ReadOnly),
+    );
+    field.insert(
+        "write_only".to_string(),
+        json!(attribute.access == Access::WriteOnly),
+    );
+    field.extend(constraints(attribute));
+    Value::Object(field)
+}
+
+// The routes under `path`, with their methods
+fn routes(path: &str) -> Vec<Value> {
+    ROUTES
+        .iter()
+        .filter(|route| {
+            route
+                .pattern
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .map(|route| {
+            let methods: Vec<_> = route.methods.iter().map(|method| method.as_str()).collect();
+            json!({ "path": route.pattern, "methods": methods })
+        })
+        .collect()
+}
+
+fn users() -> Value {
+    let fields: Vec<_> = User::ATTRIBUTES.iter().map(field).collect();
+    let selectable: Vec<_> = UserField::ALL.iter().map(UserField::column).collect();
+    // encrypted columns can't be compared in SQL
+    let filters: Vec<_> = User::FIELDS
+        .iter()
+        .filter(|field| !pii::is_encrypted(field.column))
+        .map(|field| {
+            let operators: Vec<_> = field.kind.operators().iter().map(|o| o.name()).collect();
+            json!({
+                "field": field.column,
+                "type": field.kind.name(),
+                "operators": operators,
+            })
+        })
+        .collect();
+    json!({
+        "path": "/users",
+        "routes": routes("/users"),
+        "fields": fields,
+        "selectable": selectable,
+        "filters": filters,
+        "max_filters": MAX_CONDITIONS,
+    })
+}
+
+// What the resources look like, for clients building their forms and
+// tables from it rather than hardcoding them: the fields of the bodies with
+// their types and constraints, the `?fields=` and `?column[operator]=` a
+// listing takes, and the routes. Derived from the definitions the handlers
+// use, so it can't drift from them.
+#[get("/api/v1/schema")]
+async fn get_schema() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "resources": { "users": users() } }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_schema);
+}
@@ -0,0 +1,303 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::env;
+use std::time::{Duration, SystemTime};
+
+use crate::crypto;
+use crate::db::Database;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where the values of secret settings are kept. A variable such as
+// `DATABASE_URL=vault:secret/data/crud#database_url` names a secret instead
+// of holding it: `<scheme>:<reference>`, sent to the provider of that scheme.
+#[async_trait(?Send)]
+pub trait SecretsProvider {
+    fn scheme(&self) -> &'static str;
+
+    async fn fetch(&self, reference: &str) -> Result<String, String>;
+}
+
+// `<path>#<field>` of a KV secret, version 1 or 2 (whose paths go through
+// `data/`), read with VAULT_TOKEN
+pub struct Vault {
+    addr: String,
+    token: String,
+    namespace: Option<String>,
+}
+
+impl Vault {
+    fn from_env() -> Option<Vault> {
+        let addr = env::var("VAULT_ADDR").ok()?;
+        Some(Vault {
+            addr: addr.trim_end_matches('/').to_string(),
+            token: env::var("VAULT_TOKEN").unwrap_or_default(),
+            namespace: env::var("VAULT_NAMESPACE").ok(),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl SecretsProvider for Vault {
+    fn scheme(&self) -> &'static str {
+        "vault"
+    }
+
+    async fn fetch(&self, reference: &str) -> Result<String, String> {
+        let (path, field) = reference
+            .rsplit_once('#')
+            .ok_or("expected <path>#<field>")?;
+        let http = awc::Client::builder().timeout(TIMEOUT).finish();
+        let mut request = http
+            .get(format!("{}/v1/{}", self.addr, path.trim_start_matches('/')))
+            .insert_header(("X-Vault-Token", self.token.as_str()));
+        if let Some(namespace) = &self.namespace {
+            request = request.insert_header(("X-Vault-Namespace", namespace.as_str()));
+        }
+        let mut response = request.send().await.map_err(|e| e.to_string())?;
+        let body = response.body().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Vault answered {}: {}",
+                response.status(),
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        let secret: Value = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+        // KV version 2 nests the fields under data.data
+        let data = match secret["data"].get("data") {
+            Some(data) if data.is_object() => data,
+            _ => &secret["data"],
+        };
+        field_value(data, field)
+    }
+}
+
+// `<secret id>` holding the value, or `<secret id>#<key>` of a JSON secret,
+// read with the usual AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY,
+// AWS_SESSION_TOKEN and AWS_REGION
+pub struct AwsSecretsManager {
+    endpoint: String,
+    host: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl AwsSecretsManager {
+    fn from_env() -> Option<AwsSecretsManager> {
+        let access_key_id = env::var("AWS_ACCESS_KEY_ID").ok()?;
+        let region = env::var("AWS_REGION")
+            .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_string());
+        let endpoint = env::var("AWS_SECRETS_MANAGER_ENDPOINT")
+            .unwrap_or_else(|_| format!("https://secretsmanager.{}.amazonaws.com", region));
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split_once("://")
+            .map(|(_, host)| host)
+            .unwrap_or(&endpoint)
+            .to_string();
+        Some(AwsSecretsManager {
+            endpoint,
+            host,
+            region,
+            access_key_id,
+            secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl SecretsProvider for AwsSecretsManager {
+    fn scheme(&self) -> &'static str {
+        "aws-sm"
+    }
+
+    // GetSecretValue, signed with AWS signature version 4
+    async fn fetch(&self, reference: &str) -> Result<String, String> {
+        let (id, key) = match reference.rsplit_once('#') {
+            Some((id, key)) => (id, Some(key)),
+            None => (reference, None),
+        };
+        let body = json!({ "SecretId": id }).to_string();
+        let payload_hash = crypto::sha256_hex(body.as_bytes());
+        let (amz_date, date) = crypto::amz_dates(SystemTime::now());
+        let target = "secretsmanager.GetSecretValue";
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1"),
+            ("host", self.host.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.as_str()));
+        }
+        headers.push(("x-amz-target", target));
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "POST\n/\n\n{}\n{}\n{}",
+            canonical_headers, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/secretsmanager/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            crypto::sha256_hex(canonical_request.as_bytes())
+        );
+        let signature = crypto::aws_signature(
+            &self.secret_access_key,
+            &date,
+            &self.region,
+            "secretsmanager",
+            &string_to_sign,
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        );
+
+        let http = awc::Client::builder().timeout(TIMEOUT).finish();
+        let mut request = http
+            .post(format!("{}/", self.endpoint))
+            .insert_header(("authorization", authorization.as_str()));
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.insert_header((*name, *value));
+        }
+        let mut response = request.send_body(body).await.map_err(|e| e.to_string())?;
+        let body = response.body().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Secrets Manager answered {}: {}",
+                response.status(),
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        let secret: Value = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+        let value = secret["SecretString"]
+            .as_str()
+            .ok_or("the secret has no SecretString")?;
+        match key {
+            None => Ok(value.to_string()),
+            Some(key) => {
+                let fields: Value = serde_json::from_str(value)
+                    .map_err(|e| format!("the secret is not JSON: {}", e))?;
+                field_value(&fields, key)
+            }
+        }
+    }
+}
+
+fn field_value(fields: &Value, field: &str) -> Result<String, String> {
+    match fields.get(field) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(Value::Null) | None => Err(format!("no field {} in the secret", field)),
+        Some(value) => Ok(value.to_string()),
+    }
+}
+
+// The providers configured in the environment, Vault with VAULT_ADDR and
+// Secrets Manager with AWS_ACCESS_KEY_ID, and the variables naming their
+// secrets
+pub struct Secrets {
+    providers: Vec<Box<dyn SecretsProvider>>,
+    // (variable, scheme, reference)
+    references: Vec<(String, String, String)>,
+}
+
+impl Secrets {
+    // Read before the configuration, which gets the resolved values
+    pub fn from_env() -> Secrets {
+        let mut providers: Vec<Box<dyn SecretsProvider>> = Vec::new();
+        if let Some(vault) = Vault::from_env() {
+            providers.push(Box::new(vault));
+        }
+        if let Some(aws) = AwsSecretsManager::from_env() {
+            providers.push(Box::new(aws));
+        }
+        let schemes: Vec<_> = ["vault", "aws-sm"]
+            .iter()
+            .map(|scheme| format!("{}:", scheme))
+            .collect();
+        let references = env::vars()
+            .filter_map(|(name, value)| {
+                let prefix = schemes.iter().find(|prefix| value.starts_with(*prefix))?;
+                let scheme = prefix.trim_end_matches(':').to_string();
+                let reference = value[prefix.len()..].to_string();
+                Some((name, scheme, reference))
+            })
+            .collect();
+        Secrets {
+            providers,
+            references,
+        }
+    }
+
+    async fn fetch(&self, scheme: &str, reference: &str) -> Result<String, String> {
+        let provider = self
+            .providers
+            .iter()
+            .find(|provider| provider.scheme() == scheme)
+            .ok_or_else(|| match scheme {
+                "vault" => "VAULT_ADDR is not set".to_string(),
+                _ => "AWS_ACCESS_KEY_ID is not set".to_string(),
+            })?;
+        provider.fetch(reference).await
+    }
+
+    // Replace the variables naming a secret by its value. Startup stops when
+    // a secret can't be fetched.
+    pub async fn resolve(&self) {
+        for (name, scheme, reference) in &self.references {
+            match self.fetch(scheme, reference).await {
+                Ok(value) => {
+                    info!("Read {} from {}", name, scheme);
+                    env::set_var(name, value);
+                }
+                Err(e) => panic!("Failed to read {} from {}: {}", name, scheme, e),
+            }
+        }
+    }
+}
+
+// Fetch DATABASE_URL again every `interval` when it names a secret, so that
+// connections opened after its password is rotated use the new one
+pub fn spawn_refresh(secrets: Secrets, db: Database, interval: Duration) {
+    let (scheme, reference) = match secrets
+        .references
+        .iter()
+        .find(|(name, _, _)| name == "DATABASE_URL")
+    {
+        Some((_, scheme, reference)) => (scheme.clone(), reference.clone()),
+        None => return,
+    };
+    if interval.is_zero() {
+        return;
+    }
+    actix_web::rt::spawn(async move {
+        let mut current = env::var("DATABASE_URL").unwrap_or_default();
+        loop {
+            tokio::time::sleep(interval).await;
+            match secrets.fetch(&scheme, &reference).await {
+                Ok(url) if url != current => {
+                    info!("DATABASE_URL changed in {}, new connections use it", scheme);
+                    db.set_url(&url);
+                    current = url;
+                }
+                Ok(_) => (),
+                Err(e) => warn!("Failed to refresh DATABASE_URL from {}: {}", scheme, e),
+            }
+        }
+    });
+}
@@ -0,0 +1,114 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::config::Config;
+
+// Hardening headers added to every response, errors included. A route that
+// sets one of them itself keeps its own value, which is how pages needing a
+// looser policy, such as an API explorer, override the CSP.
+pub struct SecurityHeaders {
+    headers: Rc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &Config) -> SecurityHeaders {
+        let mut headers = vec![
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+            (header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY")),
+            (
+                header::REFERRER_POLICY,
+                HeaderValue::from_static("no-referrer"),
+            ),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_str(&config.content_security_policy)
+                    .expect("CONTENT_SECURITY_POLICY is not a valid header value"),
+            ),
+        ];
+        if config.hsts_max_age.as_secs() > 0 {
+            let hsts = format!(
+                "max-age={}; includeSubDomains",
+                config.hsts_max_age.as_secs()
+            );
+            headers.push((
+                header::STRICT_TRANSPORT_SECURITY,
+                HeaderValue::from_str(&hsts).expect("Valid HSTS header"),
+            ));
+        }
+        SecurityHeaders {
+            headers: Rc::new(headers),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SecurityHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = SecurityHeadersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SecurityHeadersMiddleware {
+            service: Rc::new(service),
+            headers: self.headers.clone(),
+        }))
+    }
+}
+
+pub struct SecurityHeadersMiddleware<S> {
+    service: Rc<S>,
+    headers: Rc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl<S, B> Service<ServiceRequest> for SecurityHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let headers = self.headers.clone();
+        Box::pin(async move {
+            match service.call(req).await {
+                Ok(mut response) => {
+                    insert_missing(&headers, response.headers_mut());
+                    Ok(response)
+                }
+                // errors of inner middlewares only become responses further
+                // out, hand over the response they will become with the
+                // headers added
+                Err(e) => {
+                    let mut response = e.error_response();
+                    insert_missing(&headers, response.headers_mut());
+                    Err(InternalError::from_response(e, response).into())
+                }
+            }
+        })
+    }
+}
+
+fn insert_missing(headers: &[(HeaderName, HeaderValue)], response_headers: &mut HeaderMap) {
+    for (name, value) in headers {
+        if !response_headers.contains_key(name) {
+            response_headers.insert(name.clone(), value.clone());
+        }
+    }
+}
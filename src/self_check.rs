@@ -0,0 +1,295 @@
+use log::{error, info, warn};
+use std::path::Path;
+use std::process;
+
+use crate::config::Config;
+use crate::db::Database;
+use crate::repository::{self, SCHEMA_VERSION};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    // worth fixing, the server starts anyway
+    Warn,
+    // the server doesn't start
+    Fail,
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+// What the startup phase found, logged as a table before the port is bound.
+// A failed check stops the server there with what to fix, instead of the
+// first request that needs the missing piece.
+#[derive(Default)]
+pub struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report::default()
+    }
+
+    pub fn ok(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.push(name, Status::Ok, detail.into());
+    }
+
+    pub fn warn(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.push(name, Status::Warn, detail.into());
+    }
+
+    pub fn fail(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.push(name, Status::Fail, detail.into());
+    }
+
+    fn push(&mut self, name: &'static str, status: Status, detail: String) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail,
+        });
+    }
+
+    fn has_failures(&self) -> bool {
+        self.checks.iter().any(|check| check.status == Status::Fail)
+    }
+
+    fn print(&self) {
+        let width = self
+            .checks
+            .iter()
+            .map(|check| check.name.len())
+            .max()
+            .unwrap_or(0);
+        info!("Startup checks:");
+        for check in &self.checks {
+            match check.status {
+                Status::Ok => info!("  ok    {:width$}  {}", check.name, check.detail),
+                Status::Warn => warn!("  warn  {:width$}  {}", check.name, check.detail),
+                Status::Fail => error!("  FAIL  {:width$}  {}", check.name, check.detail),
+            }
+        }
+    }
+
+    // Log the table, and exit when a check failed
+    pub fn finish(&self) {
+        self.print();
+        if self.has_failures() {
+            error!("Not starting, fix the failed checks above");
+            process::exit(1);
+        }
+    }
+
+    // The checks left can't run without the one that just failed
+    pub fn abort(&self) -> ! {
+        self.finish();
+        process::exit(1);
+    }
+}
+
+// Settings that parse but can't work together, or not in production
+pub fn check_config(report: &mut Report, config: &Config) {
+    match url::Url::parse(&config.public_url) {
+        Ok(_) => report.ok("public url", config.public_url.as_str()),
+        Err(e) => report.fail(
+            "public url",
+            format!(
+                "PUBLIC_URL is not a URL ({}), links in emails would be broken",
+                e
+            ),
+        ),
+    }
+    if config.secret_key.len() < 32 {
+        report.warn(
+            "secret key",
+            "SECRET_KEY is shorter than 32 bytes, use e.g. `openssl rand -base64 32`",
+        );
+    }
+    if config.access_token_ttl >= config.refresh_token_ttl {
+        report.warn(
+            "token ttls",
+            "ACCESS_TOKEN_TTL_SECS is not shorter than REFRESH_TOKEN_TTL_SECS, refreshes are useless",
+        );
+    }
+    if config.session_cookies && !config.session_cookie_secure {
+        report.warn(
+            "session cookies",
+            "SESSION_COOKIE_SECURE=false sends the session cookies over plain HTTP",
+        );
+    }
+    if let Some(dir) = &config.static_dir {
+        if Path::new(dir).is_dir() {
+            report.ok("static dir", dir.as_str());
+        } else {
+            report.fail(
+                "static dir",
+                format!(
+                    "{} is not a directory, fix STATIC_DIR or build the frontend",
+                    dir
+                ),
+            );
+        }
+    }
+    match &config.s3_bucket {
+        Some(bucket)
+            if config.s3_access_key_id.is_none() || config.s3_secret_access_key.is_none() =>
+        {
+            report.fail(
+                "uploads",
+                format!(
+                    "S3_BUCKET {} needs S3_ACCESS_KEY_ID and S3_SECRET_ACCESS_KEY",
+                    bucket
+                ),
+            )
+        }
+        Some(bucket) => report.ok("uploads", format!("S3 bucket {}", bucket)),
+        None => match std::fs::create_dir_all(&config.upload_dir) {
+            Ok(()) => report.ok("uploads", config.upload_dir.as_str()),
+            Err(e) => report.fail(
+                "uploads",
+                format!(
+                    "can't create UPLOAD_DIR {} ({}), fix its permissions or set another",
+                    config.upload_dir, e
+                ),
+            ),
+        },
+    }
+    if config.smtp_username.is_some() && config.smtp_password.is_none() {
+        report.warn("smtp", "SMTP_USERNAME is set without SMTP_PASSWORD");
+    }
+}
+
+// Failing to connect leaves nothing else to check
+pub async fn check_connection(report: &mut Report, db: &Database) {
+    let version = db
+        .run(|client| async move {
+            let row = client.client.query_one("SHOW server_version", &[]).await?;
+            row.try_get::<_, String>("server_version")
+        })
+        .await;
+    match version {
+        Ok(version) => report.ok("database", format!("connected, Postgres {}", version)),
+        Err(e) => {
+            report.fail("database", e.to_string());
+            report.abort();
+        }
+    }
+}
+
+// The version the database was last migrated to, before the schemas are
+// applied: a newer one than this build knows is not touched
+pub async fn check_schema_version(report: &mut Report, db: &Database) {
+    let recorded = db
+        .run(|client| async move {
+            let naming = client.statements.naming();
+            if let Some(schema) = &naming.schema {
+                client
+                    .client
+                    .batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema))
+                    .await?;
+            }
+            client
+                .client
+                .batch_execute(&naming.apply(repository::SCHEMA_VERSION_TABLE))
+                .await?;
+            let sql = naming.apply("SELECT MAX(version) AS version FROM {prefix}schema_version");
+            let row = client.client.query_one(&sql, &[]).await?;
+            row.try_get::<_, Option<i32>>("version")
+        })
+        .await;
+    match recorded {
+        Ok(Some(version)) if version > SCHEMA_VERSION => {
+            report.fail(
+                "schema version",
+                format!(
+                    "the database is at version {}, newer than this build's {}: deploy the newer release",
+                    version, SCHEMA_VERSION
+                ),
+            );
+            report.abort();
+        }
+        Ok(Some(version)) if version == SCHEMA_VERSION => {
+            report.ok("schema version", format!("{}", version))
+        }
+        Ok(Some(version)) => report.ok(
+            "schema version",
+            format!("{}, migrating to {}", version, SCHEMA_VERSION),
+        ),
+        Ok(None) => report.ok(
+            "schema version",
+            format!("new database, creating version {}", SCHEMA_VERSION),
+        ),
+        Err(e) => {
+            report.fail(
+                "schema version",
+                format!("{}, the database user needs to create tables", e),
+            );
+            report.abort();
+        }
+    }
+}
+
+// Every table is there once the schemas are applied
+pub async fn check_tables(report: &mut Report, db: &Database) {
+    let tables = repository::tables();
+    let missing = db
+        .run(|client| {
+            let tables = tables.clone();
+            async move {
+                let naming = client.statements.naming();
+                let mut missing = Vec::new();
+                for table in tables {
+                    let name = naming.apply(&format!("{{prefix}}{}", table));
+                    let row = client
+                        .client
+                        .query_one("SELECT to_regclass($1)::TEXT AS regclass", &[&name])
+                        .await?;
+                    if row.try_get::<_, Option<String>>("regclass")?.is_none() {
+                        missing.push(name);
+                    }
+                }
+                Ok(missing)
+            }
+        })
+        .await;
+    match missing {
+        Ok(missing) if missing.is_empty() => {
+            report.ok("migrations", format!("{} tables applied", tables.len()))
+        }
+        Ok(missing) => report.fail(
+            "migrations",
+            format!(
+                "missing table(s) {}, check DATABASE_SCHEMA and the privileges of the database user",
+                missing.join(", ")
+            ),
+        ),
+        Err(e) => report.fail("migrations", e.to_string()),
+    }
+}
+
+// pg_trgm lets the duplicate search compare names, installed when the
+// database user may
+pub async fn check_trigrams(report: &mut Report, db: &Database) {
+    let installed = db
+        .run(|client| async move {
+            client
+                .client
+                .batch_execute("CREATE EXTENSION IF NOT EXISTS pg_trgm")
+                .await
+        })
+        .await;
+    match installed {
+        Ok(()) => report.ok("pg_trgm", "installed, duplicates are also matched by name"),
+        Err(e) => report.warn(
+            "pg_trgm",
+            format!(
+                "{}, duplicates are only matched by email: run CREATE EXTENSION pg_trgm as a superuser",
+                e
+            ),
+        ),
+    }
+}
@@ -0,0 +1,268 @@
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorForbidden;
+use actix_web::http::Method;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use serde_json::json;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+
+use crate::config::Config;
+use crate::crypto;
+
+// The access token, sent with every request
+pub const SESSION_COOKIE: &str = "session";
+// Only sent to the /auth routes, which trade it for the next access token
+pub const REFRESH_COOKIE: &str = "refresh_token";
+// Readable by the scripts of the site, which echo it in CSRF_HEADER
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+fn cookie<'a>(
+    config: &Config,
+    name: &'a str,
+    value: String,
+    path: &'a str,
+    max_age: Duration,
+) -> Cookie<'a> {
+    Cookie::build(name, value)
+        .path(path)
+        .secure(config.session_cookie_secure)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::seconds(max_age.as_secs() as i64))
+        .finish()
+}
+
+// Response of a login or refresh in session mode: the tokens go in HttpOnly
+// cookies that scripts can't read, along with a new CSRF token
+pub fn token_cookies(config: &Config, access_token: &str, refresh_token: &str) -> HttpResponse {
+    let csrf_token = crypto::random_token(32);
+    let mut session = cookie(
+        config,
+        SESSION_COOKIE,
+        access_token.to_string(),
+        "/",
+        config.access_token_ttl,
+    );
+    session.set_http_only(true);
+    let mut refresh = cookie(
+        config,
+        REFRESH_COOKIE,
+        refresh_token.to_string(),
+        "/auth",
+        config.refresh_token_ttl,
+    );
+    refresh.set_http_only(true);
+    let csrf = cookie(
+        config,
+        CSRF_COOKIE,
+        csrf_token.clone(),
+        "/",
+        config.refresh_token_ttl,
+    );
+    HttpResponse::Ok()
+        .cookie(session)
+        .cookie(refresh)
+        .cookie(csrf)
+        .json(json!({
+            "token_type": "cookie",
+            "expires_in": config.access_token_ttl.as_secs(),
+            "csrf_token": csrf_token,
+        }))
+}
+
+// Have the browser drop the session cookies, on logout
+pub fn clear_cookies(config: &Config, response: &mut HttpResponseBuilder) {
+    for (name, path) in [
+        (SESSION_COOKIE, "/"),
+        (REFRESH_COOKIE, "/auth"),
+        (CSRF_COOKIE, "/"),
+    ] {
+        let mut cookie = cookie(config, name, String::new(), path, Duration::ZERO);
+        cookie.make_removal();
+        response.cookie(cookie);
+    }
+}
+
+// The access token of the session cookie, in session mode
+pub fn access_token(req: &HttpRequest, config: &Config) -> Option<String> {
+    if !config.session_cookies {
+        return None;
+    }
+    req.cookie(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+}
+
+pub fn refresh_token(req: &HttpRequest, config: &Config) -> Option<String> {
+    if !config.session_cookies {
+        return None;
+    }
+    req.cookie(REFRESH_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+}
+
+// Double submit CSRF check of the requests that change state and carry
+// session cookies, which a browser attaches whatever page sent them: they
+// must repeat the csrf_token cookie in X-CSRF-Token, which only the scripts
+// of the site can read. Requests authenticated by a header are left alone,
+// another site can't make a browser send those. Off unless SESSION_COOKIES.
+pub struct Csrf {
+    enabled: bool,
+}
+
+impl Csrf {
+    pub fn new(config: &Config) -> Csrf {
+        Csrf {
+            enabled: config.session_cookies,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware {
+            service: Rc::new(service),
+            enabled: self.enabled,
+        }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+    enabled: bool,
+}
+
+fn changes_state(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn has_valid_token(req: &ServiceRequest) -> bool {
+    let expected = match req.cookie(CSRF_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return false,
+    };
+    let provided = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    !expected.is_empty() && bool::from(provided.as_bytes().ct_eq(expected.as_bytes()))
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let checked = self.enabled
+            && changes_state(req.method())
+            && (req.cookie(SESSION_COOKIE).is_some() || req.cookie(REFRESH_COOKIE).is_some());
+        if checked && !has_valid_token(&req) {
+            return Box::pin(ready(Err(ErrorForbidden("Missing or invalid CSRF token"))));
+        }
+        let service = self.service.clone();
+        Box::pin(async move { service.call(req).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App};
+
+    use super::*;
+
+    // The status of `request` to a route behind the middleware
+    async fn status(enabled: bool, request: TestRequest) -> u16 {
+        let app = test::init_service(
+            App::new()
+                .wrap(Csrf { enabled })
+                .route("/me", web::to(HttpResponse::NoContent)),
+        )
+        .await;
+        match test::try_call_service(&app, request.uri("/me").to_request()).await {
+            Ok(response) => response.status().as_u16(),
+            Err(e) => e.as_response_error().status_code().as_u16(),
+        }
+    }
+
+    fn with_session(method: Method) -> TestRequest {
+        TestRequest::default()
+            .method(method)
+            .cookie(Cookie::new(SESSION_COOKIE, "access-token"))
+            .cookie(Cookie::new(CSRF_COOKIE, "csrf-token"))
+    }
+
+    #[actix_web::test]
+    async fn session_writes_need_the_csrf_token() {
+        for method in [Method::POST, Method::PUT, Method::PATCH, Method::DELETE] {
+            assert_eq!(
+                status(true, with_session(method.clone())).await,
+                403,
+                "{}",
+                method
+            );
+            let request = with_session(method.clone()).insert_header((CSRF_HEADER, "csrf-token"));
+            assert_eq!(
+                status(true, request).await,
+                204,
+                "{} with the token",
+                method
+            );
+        }
+        let request = with_session(Method::PATCH).insert_header((CSRF_HEADER, "other-token"));
+        assert_eq!(status(true, request).await, 403, "another token");
+        let request = TestRequest::default()
+            .method(Method::POST)
+            .cookie(Cookie::new(SESSION_COOKIE, "access-token"))
+            .insert_header((CSRF_HEADER, ""));
+        assert_eq!(status(true, request).await, 403, "without the CSRF cookie");
+        let request = TestRequest::default()
+            .method(Method::POST)
+            .cookie(Cookie::new(REFRESH_COOKIE, "refresh-token"));
+        assert_eq!(status(true, request).await, 403, "the refresh cookie alone");
+    }
+
+    #[actix_web::test]
+    async fn reads_and_header_authenticated_writes_pass() {
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            assert_eq!(
+                status(true, with_session(method.clone())).await,
+                204,
+                "{}",
+                method
+            );
+        }
+        let request = TestRequest::default()
+            .method(Method::DELETE)
+            .insert_header((header::AUTHORIZATION, "Bearer access-token"));
+        assert_eq!(status(true, request).await, 204, "a bearer token");
+        assert_eq!(
+            status(false, with_session(Method::DELETE)).await,
+            204,
+            "session mode off"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use log::{error, info, warn};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::logging::ReloadableLogger;
+
+// What can change without a restart, by the names used in CONFIG_FILE:
+// LOG_LEVEL, LOGIN_MAX_FAILURES, LOGIN_MAX_FAILURES_PER_IP,
+// LOGIN_FAILURE_WINDOW_SECS, LOGIN_LOCKOUT_SECS and CORS_ALLOWED_ORIGINS
+#[derive(Clone, PartialEq, Serialize)]
+pub struct Settings {
+    pub log_level: String,
+    pub login_max_failures: i64,
+    pub login_max_failures_per_ip: i64,
+    pub login_failure_window_secs: u64,
+    pub login_lockout_secs: u64,
+    pub cors_allowed_origins: Vec<String>,
+}
+
+impl Settings {
+    pub fn login_failure_window(&self) -> Duration {
+        Duration::from_secs(self.login_failure_window_secs)
+    }
+
+    pub fn login_lockout(&self) -> Duration {
+        Duration::from_secs(self.login_lockout_secs)
+    }
+}
+
+// The settings in effect: those of the environment, overridden by the
+// `KEY=value` lines of CONFIG_FILE. A line removed from the file reverts to
+// the environment.
+pub struct RuntimeSettings {
+    file: Option<PathBuf>,
+    defaults: Settings,
+    current: RwLock<Arc<Settings>>,
+    logger: &'static ReloadableLogger,
+}
+
+impl RuntimeSettings {
+    pub fn new(config: &Config, logger: &'static ReloadableLogger) -> RuntimeSettings {
+        let defaults = Settings {
+            log_level: config.log_level.clone(),
+            login_max_failures: config.login_max_failures,
+            login_max_failures_per_ip: config.login_max_failures_per_ip,
+            login_failure_window_secs: config.login_failure_window.as_secs(),
+            login_lockout_secs: config.login_lockout.as_secs(),
+            cors_allowed_origins: config.cors_allowed_origins.clone(),
+        };
+        let settings = RuntimeSettings {
+            file: config.config_file.as_ref().map(PathBuf::from),
+            current: RwLock::new(Arc::new(defaults.clone())),
+            defaults,
+            logger,
+        };
+        if let Err(e) = settings.reload() {
+            panic!("Invalid configuration file: {}", e);
+        }
+        settings
+    }
+
+    pub fn current(&self) -> Arc<Settings> {
+        self.current.read().unwrap().clone()
+    }
+
+    // An invalid file leaves the settings as they were
+    pub fn reload(&self) -> Result<(), String> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let contents = std::fs::read_to_string(file)
+            .map_err(|e| format!("Can't read {}: {}", file.display(), e))?;
+        let settings = parse(&contents, self.defaults.clone())?;
+        let previous = self.current();
+        if *previous == settings {
+            return Ok(());
+        }
+        if previous.log_level != settings.log_level {
+            self.logger.set_filter(&settings.log_level);
+        }
+        *self.current.write().unwrap() = Arc::new(settings);
+        info!("Applied the settings of {}", file.display());
+        Ok(())
+    }
+}
+
+fn parse(contents: &str, mut settings: Settings) -> Result<Settings, String> {
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected KEY=value", index + 1))?;
+        let (key, value) = (key.trim(), value.trim());
+        let invalid = || format!("line {}: invalid value for {}", index + 1, key);
+        match key {
+            "LOG_LEVEL" => settings.log_level = value.to_string(),
+            "LOGIN_MAX_FAILURES" => {
+                settings.login_max_failures = value.parse().map_err(|_| invalid())?
+            }
+            "LOGIN_MAX_FAILURES_PER_IP" => {
+                settings.login_max_failures_per_ip = value.parse().map_err(|_| invalid())?
+            }
+            "LOGIN_FAILURE_WINDOW_SECS" => {
+                settings.login_failure_window_secs = value.parse().map_err(|_| invalid())?
+            }
+            "LOGIN_LOCKOUT_SECS" => {
+                settings.login_lockout_secs = value.parse().map_err(|_| invalid())?
+            }
+            "CORS_ALLOWED_ORIGINS" => {
+                settings.cors_allowed_origins = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            _ => warn!(
+                "line {}: {} can't be changed at runtime, ignored",
+                index + 1,
+                key
+            ),
+        }
+    }
+    Ok(settings)
+}
+
+// Reload the settings whenever CONFIG_FILE changes. The directory is
+// watched rather than the file, editors often replace the file on save.
+// Watching stops when the watcher is dropped.
+pub fn watch(settings: Arc<RuntimeSettings>) -> Option<RecommendedWatcher> {
+    let file = settings.file.clone()?;
+    let name = file.file_name()?.to_owned();
+    let directory = match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event)
+                if (event.kind.is_create() || event.kind.is_modify())
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name() == Some(name.as_os_str())) =>
+            {
+                if let Err(e) = settings.reload() {
+                    error!("Kept the previous settings: {}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to watch the configuration file: {}", e),
+        });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Failed to watch the configuration file: {}", e);
+            return None;
+        }
+    };
+    if let Err(e) = watcher.watch(Path::new(&directory), RecursiveMode::NonRecursive) {
+        error!("Failed to watch {}: {}", directory.display(), e);
+        return None;
+    }
+    info!("Watching {} for changes", file.display());
+    Some(watcher)
+}
+
+// The settings currently in effect
+#[get("/admin/config")]
+async fn get_config(_admin: Admin, settings: web::Data<RuntimeSettings>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "file": settings.file.as_ref().map(|file| file.display().to_string()),
+        "settings": *settings.current(),
+    }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_config);
+}
@@ -0,0 +1,478 @@
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{
+    ErrorForbidden, ErrorGatewayTimeout, ErrorInternalServerError, ErrorPayloadTooLarge,
+    ErrorServiceUnavailable, ErrorUnauthorized, PayloadError,
+};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{delete, get, post, web, HttpMessage, HttpResponse, Responder};
+use futures_util::stream::{self, Stream, StreamExt};
+use log::{error, info};
+use serde_json::json;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+use crate::admin::Admin;
+use crate::api_keys::Scope;
+use crate::config::Config;
+use crate::crypto;
+use crate::db::{Cluster, DbError};
+use crate::jsonapi::Format;
+use crate::normalize::SentUri;
+use crate::repository::Partner;
+use crate::tenant::Tenant;
+
+pub const PARTNER_HEADER: &str = "X-Partner-Id";
+// Unix time in seconds at which the request was signed
+pub const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+// `sha256=<hex HMAC-SHA256 of the string to sign>`
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+// The partner a request was signed by, in the request extensions
+pub struct Signed(pub Partner);
+
+impl Signed {
+    pub fn has(&self, scope: Scope) -> bool {
+        self.0.scopes.iter().any(|name| name == scope.name())
+    }
+}
+
+// What the partner signs: the timestamp, the method, the path with its
+// query and the hex SHA-256 of the body, one per line
+pub fn string_to_sign(timestamp: u64, method: &str, path_and_query: &str, body: &[u8]) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        timestamp,
+        method,
+        path_and_query,
+        crypto::sha256_hex(body)
+    )
+}
+
+pub fn sign(secret: &str, string_to_sign: &str) -> String {
+    format!(
+        "sha256={}",
+        crypto::hex(&crypto::hmac_sha256(
+            secret.as_bytes(),
+            string_to_sign.as_bytes()
+        ))
+    )
+}
+
+fn header<'a>(req: &'a ServiceRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+// Accepts requests signed by a partner as a credential, for server to
+// server calls: `X-Partner-Id`, `X-Signature-Timestamp` and `X-Signature`
+// over the request and its body with the secret of the partner. Requests
+// signed outside of the replay window, or whose signature was seen already,
+// are rejected, a valid one only reaches the routes the scopes of the
+// partner cover. Requests without `X-Partner-Id` go through untouched.
+pub struct RequestSignatures {
+    window: Duration,
+    limit: usize,
+}
+
+impl RequestSignatures {
+    pub fn new(config: &Config) -> RequestSignatures {
+        RequestSignatures {
+            window: config.signature_window,
+            limit: config.max_json_bytes.max(config.avatar_max_bytes),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestSignatures
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestSignaturesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestSignaturesMiddleware {
+            service: Rc::new(service),
+            window: self.window,
+            limit: self.limit,
+        }))
+    }
+}
+
+pub struct RequestSignaturesMiddleware<S> {
+    service: Rc<S>,
+    window: Duration,
+    limit: usize,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestSignaturesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let window = self.window;
+        let limit = self.limit;
+        Box::pin(async move {
+            let key_id = match req.headers().get(PARTNER_HEADER) {
+                Some(value) => value
+                    .to_str()
+                    .map_err(|_| ErrorUnauthorized("Invalid partner"))?
+                    .to_string(),
+                None => return service.call(req).await,
+            };
+            let timestamp = header(&req, TIMESTAMP_HEADER)
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .ok_or_else(|| {
+                    ErrorUnauthorized(format!("Missing or invalid {}", TIMESTAMP_HEADER))
+                })?;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default();
+            // clocks drift both ways
+            if now.abs_diff(timestamp) > window.as_secs() {
+                return Err(ErrorUnauthorized(
+                    "Signature timestamp outside of the replay window",
+                ));
+            }
+            let signature = header(&req, SIGNATURE_HEADER)
+                .ok_or_else(|| ErrorUnauthorized(format!("Missing {}", SIGNATURE_HEADER)))?
+                .trim()
+                .to_string();
+
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                body.extend_from_slice(&chunk?);
+                if body.len() > limit {
+                    return Err(ErrorPayloadTooLarge("Signed request body is too large"));
+                }
+            }
+            let body = body.freeze();
+
+            let db = req
+                .app_data::<web::Data<Cluster>>()
+                .ok_or_else(|| ErrorInternalServerError("Missing database"))?
+                .clone();
+            // the path as sent, the partner doesn't know how it is routed
+            let uri = match req.extensions().get::<SentUri>() {
+                Some(sent) => sent.0.clone(),
+                None => req.uri().clone(),
+            };
+            let to_sign = string_to_sign(
+                timestamp,
+                req.method().as_str(),
+                uri.path_and_query()
+                    .map_or(uri.path(), |path| path.as_str()),
+                &body,
+            );
+            // tells a replay by Database::run from one by a client
+            let attempt = crypto::random_token(16);
+            let (key_id, signature, to_sign, attempt) = (&key_id, &signature, &to_sign, &attempt);
+            let partner = db
+                .primary()
+                .run(|client| async move {
+                    let partner = match client.partners().find(key_id).await? {
+                        Some(partner) => partner,
+                        None => return Ok(Err("Invalid partner")),
+                    };
+                    let expected = sign(&partner.secret, to_sign);
+                    if !bool::from(expected.as_bytes().ct_eq(signature.as_bytes())) {
+                        return Ok(Err("Invalid signature"));
+                    }
+                    if !client
+                        .partners()
+                        .record_signature(partner.id, signature, attempt, window)
+                        .await?
+                    {
+                        return Ok(Err("Signature already used"));
+                    }
+                    Ok(Ok(partner))
+                })
+                .await
+                .map_err(|e| match e {
+                    DbError::Unavailable => ErrorServiceUnavailable("Database unavailable"),
+                    DbError::Timeout => ErrorGatewayTimeout("Database query timed out"),
+                    DbError::Query(e) => {
+                        error!("Failed to check request signature: {}", e);
+                        ErrorInternalServerError("Failed to check request signature")
+                    }
+                })?
+                .map_err(ErrorUnauthorized)?;
+            let signed = Signed(partner);
+            if let Some(scope) = Scope::required(req.method(), req.path()) {
+                if !signed.has(scope) {
+                    return Err(ErrorForbidden(format!(
+                        "Partner lacks the {} scope",
+                        scope.name()
+                    )));
+                }
+            }
+            req.extensions_mut().insert(signed);
+            let replay: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+                Box::pin(stream::once(ready(Ok(body))));
+            req.set_payload(Payload::from(replay));
+            service.call(req).await
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct NewPartner {
+    name: String,
+    scopes: Vec<String>,
+}
+
+#[post("/admin/partners")]
+async fn create_partner(
+    _admin: Admin,
+    body: web::Json<NewPartner>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let new_partner = body.into_inner();
+    if let Some(unknown) = new_partner
+        .scopes
+        .iter()
+        .find(|s| Scope::parse(s).is_none())
+    {
+        let scopes: Vec<_> = Scope::ALL.iter().map(Scope::name).collect();
+        return HttpResponse::BadRequest().body(format!(
+            "Unknown scope '{}', expected any of {}",
+            unknown,
+            scopes.join(", ")
+        ));
+    }
+    let key_id = &format!("ptn_{}", crypto::random_token(8));
+    let secret = &crypto::random_token(32);
+    let (new_partner, tenant) = (&new_partner, &tenant);
+    let result = db
+        .primary()
+        .run(move |client| async move {
+            client
+                .partners()
+                .create(
+                    &new_partner.name,
+                    key_id,
+                    secret,
+                    &new_partner.scopes,
+                    tenant.as_str(),
+                )
+                .await
+        })
+        .await;
+    match result {
+        Ok(partner) => {
+            info!("Registered partner {} ({})", partner.id, partner.name);
+            // the secret is only ever disclosed here
+            HttpResponse::Created().json(json!({
+                "id": partner.id,
+                "name": partner.name,
+                "key_id": partner.key_id,
+                "scopes": partner.scopes,
+                "tenant_id": partner.tenant_id,
+                "secret": partner.secret,
+            }))
+        }
+        Err(e) => Format::Json.db_error(e, "Failed to create partner"),
+    }
+}
+
+#[get("/admin/partners")]
+async fn get_partners(_admin: Admin, tenant: Tenant, db: web::Data<Cluster>) -> impl Responder {
+    let tenant = &tenant;
+    let result = db
+        .primary()
+        .run(|client| async move { client.partners().list(tenant).await })
+        .await;
+    match result {
+        Ok(partners) => HttpResponse::Ok().json(partners),
+        Err(e) => Format::Json.db_error(e, "Failed to retrieve partners"),
+    }
+}
+
+#[delete("/admin/partners/{id}")]
+async fn revoke_partner(
+    _admin: Admin,
+    path: web::Path<i32>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let (id, tenant) = (path.into_inner(), &tenant);
+    let result = db
+        .primary()
+        .run(|client| async move { client.partners().revoke(tenant, id).await })
+        .await;
+    match result {
+        Ok(true) => {
+            info!("Revoked partner {}", id);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().body(format!("Partner {} not found", id)),
+        Err(e) => Format::Json.db_error(e, &format!("Failed to revoke partner {}", id)),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_partner)
+        .service(get_partners)
+        .service(revoke_partner);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{self, TestRequest};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_app::{self, admin, request, shared};
+    use crate::{app, ids, Backend};
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    // `method` on `path` signed by `partner` at `timestamp` over `signed`,
+    // with `body` as the body sent
+    fn signed(
+        partner: &Value,
+        timestamp: u64,
+        (method, path): (&str, &str),
+        signed: &[u8],
+        body: &'static [u8],
+    ) -> TestRequest {
+        let to_sign = string_to_sign(timestamp, method, path, signed);
+        request(method, path)
+            .insert_header((PARTNER_HEADER, partner["key_id"].as_str().unwrap()))
+            .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+            .insert_header((
+                SIGNATURE_HEADER,
+                sign(partner["secret"].as_str().unwrap(), &to_sign),
+            ))
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(body)
+    }
+
+    // Each signature only goes through once
+    fn unique(path: &str) -> String {
+        format!("{}?nonce={}", path, ids::event_id())
+    }
+
+    #[actix_web::test]
+    async fn signatures_are_checked_over_the_request_as_sent() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| async {
+            match test::try_call_service(&app, request.to_request()).await {
+                Ok(response) => (response.status().as_u16(), String::new()),
+                Err(e) => (e.as_response_error().status_code().as_u16(), e.to_string()),
+            }
+        };
+        let new_partner = json!({ "name": "billing", "scopes": ["users:read", "users:write"] });
+        let (status, partner) = test_app::json(
+            test::call_service(
+                &app,
+                admin("POST", "/admin/partners")
+                    .set_json(&new_partner)
+                    .to_request(),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(status, 201, "POST /admin/partners: {}", partner);
+
+        let path = unique("/users");
+        let (status, _) = send(signed(&partner, now(), ("GET", &path), b"", b"")).await;
+        assert_eq!(status, 200, "a valid signature");
+        let (status, detail) = send(signed(&partner, now(), ("GET", &path), b"", b"")).await;
+        assert_eq!((status, detail.as_str()), (401, "Signature already used"));
+
+        let body = br#"{"name": "Jane Doe", "email": "jane@example.com"}"#;
+        let tampered = br#"{"name": "Jane Doe", "email": "mallory@example.com"}"#;
+        let path = unique("/users").replace('?', "?dry_run=true&");
+        let (status, detail) = send(signed(&partner, now(), ("POST", &path), body, tampered)).await;
+        assert_eq!(
+            (status, detail.as_str()),
+            (401, "Invalid signature"),
+            "a tampered body"
+        );
+
+        let window = Config::from_env(crate::DB_URL).signature_window.as_secs();
+        let expired = now() - window - 1;
+        let (status, detail) = send(signed(
+            &partner,
+            expired,
+            ("GET", &unique("/users")),
+            b"",
+            b"",
+        ))
+        .await;
+        assert_eq!(status, 401, "signed {} seconds ago", window + 1);
+        assert_eq!(detail, "Signature timestamp outside of the replay window");
+
+        // routed as /users, signed as sent
+        let path = unique("//users/");
+        let (status, _) = send(signed(&partner, now(), ("GET", &path), b"", b"")).await;
+        assert_eq!(status, 200, "a signature of {}", path);
+        let canonical = path.replace("//users/", "/users");
+        let mut request = signed(&partner, now(), ("GET", &canonical), b"", b"");
+        request = request.uri(&path);
+        let (status, _) = send(request).await;
+        assert_eq!(status, 401, "a signature of the canonical path of {}", path);
+    }
+
+    #[actix_web::test]
+    async fn a_signature_replayed_by_the_same_attempt_is_recorded() {
+        let shared = shared().await;
+        let db = match &shared.backend {
+            Backend::Postgres(db) => db.clone(),
+            _ => unreachable!("the tests run on Postgres"),
+        };
+        let window = Duration::from_secs(60);
+        let recorded = db
+            .primary()
+            .run(|client| async move {
+                let partners = client.partners();
+                let partner = partners
+                    .create(
+                        "replays",
+                        &format!("ptn_{}", ids::event_id()),
+                        "secret",
+                        &[],
+                        "default",
+                    )
+                    .await?;
+                let signature = &format!("sha256={}", ids::event_id());
+                let mut recorded = Vec::new();
+                for attempt in ["first", "first", "second"] {
+                    recorded.push(
+                        partners
+                            .record_signature(partner.id, signature, attempt, window)
+                            .await?,
+                    );
+                }
+                Ok(recorded)
+            })
+            .await
+            .unwrap();
+        assert_eq!(recorded, [true, true, false]);
+    }
+}
@@ -0,0 +1,128 @@
+use actix_web::http::{header, Method};
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::debug;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::fallback;
+use crate::jsonapi::Format;
+
+// Paths that belong to the API and never fall back to the frontend
+const API_PREFIXES: &[&str] = &[
+    "/api", "/users", "/auth", "/me", "/admin", "/changes", "/verify", "/healthz", "/readyz",
+    "/metrics", "/version",
+];
+
+// A frontend build served from `/` when STATIC_DIR or `--static-dir` is set.
+// Paths that are not files of the build get its `index.html`, so that the
+// frontend handles its own routes, unless they look like a missing asset.
+pub struct StaticSite {
+    root: Option<PathBuf>,
+    content_security_policy: String,
+}
+
+impl StaticSite {
+    pub fn from_config(config: &Config) -> StaticSite {
+        StaticSite {
+            root: config.static_dir.as_ref().map(PathBuf::from),
+            content_security_policy: config.static_content_security_policy.clone(),
+        }
+    }
+
+    async fn serve(&self, root: &Path, path: &str) -> Option<HttpResponse> {
+        let relative = path.trim_start_matches('/');
+        // nothing outside of the build, no hidden files
+        if relative
+            .split('/')
+            .any(|segment| segment.starts_with('.') || segment.contains('\\'))
+        {
+            return None;
+        }
+        let file = if relative.is_empty() || relative.ends_with('/') {
+            format!("{}index.html", relative)
+        } else {
+            relative.to_string()
+        };
+        match read(root, &file).await {
+            Some(data) => Some(self.respond(&file, data)),
+            None if has_extension(&file) => None,
+            None => read(root, "index.html")
+                .await
+                .map(|data| self.respond("index.html", data)),
+        }
+    }
+
+    fn respond(&self, file: &str, data: Vec<u8>) -> HttpResponse {
+        let mut response = HttpResponse::Ok();
+        response.content_type(content_type(file)).insert_header((
+            header::CONTENT_SECURITY_POLICY,
+            self.content_security_policy.as_str(),
+        ));
+        // the page names the current assets, it must not be kept around
+        if file.ends_with("index.html") {
+            response.insert_header((header::CACHE_CONTROL, "no-cache"));
+        }
+        response.body(data)
+    }
+}
+
+async fn read(root: &Path, file: &str) -> Option<Vec<u8>> {
+    match tokio::fs::read(root.join(file)).await {
+        Ok(data) => Some(data),
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            // directories among others
+            debug!("Not serving {}: {}", file, e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+fn has_extension(file: &str) -> bool {
+    file.rsplit('/')
+        .next()
+        .is_some_and(|name| name.contains('.'))
+}
+
+pub fn is_api_path(path: &str) -> bool {
+    API_PREFIXES.iter().any(|prefix| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+pub fn content_type(file: &str) -> &'static str {
+    match file.rsplit('.').next() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+// Default service: the frontend for GET requests outside of the API, the
+// 404 and 405 answers otherwise
+pub async fn fallback(
+    req: HttpRequest,
+    format: Format,
+    site: web::Data<StaticSite>,
+) -> HttpResponse {
+    if let Some(root) = &site.root {
+        if matches!(*req.method(), Method::GET | Method::HEAD) && !is_api_path(req.path()) {
+            if let Some(response) = site.serve(root, req.path()).await {
+                return response;
+            }
+        }
+    }
+    fallback::not_found(req, format).await
+}
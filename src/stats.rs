@@ -0,0 +1,111 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::admin::Admin;
+use crate::db::{Cluster, Database, DbError};
+use crate::jsonapi::Format;
+use crate::repository::UserStats;
+use crate::tenant::Tenant;
+
+const SIGNUP_DAYS: i32 = 30;
+
+// User aggregates by tenant, reused for `ttl` so that dashboards polling
+// the endpoint don't scan the table every time. A zero `ttl` disables it.
+pub struct StatsCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Value)>>,
+}
+
+impl StatsCache {
+    pub fn new(ttl: Duration) -> StatsCache {
+        StatsCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, tenant: &Tenant) -> Option<Value> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(tenant.as_str())
+            .filter(|(computed_at, _)| computed_at.elapsed() < self.ttl)
+            .map(|(_, stats)| stats.clone())
+    }
+
+    fn put(&self, tenant: &Tenant, stats: &Value) {
+        if !self.ttl.is_zero() {
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(tenant.to_string(), (Instant::now(), stats.clone()));
+        }
+    }
+}
+
+fn users_json(stats: &UserStats) -> Value {
+    let signups: Vec<_> = stats
+        .signups
+        .iter()
+        .map(|(day, count)| json!({ "date": day, "count": count }))
+        .collect();
+    json!({
+        "total": stats.total,
+        "deactivated": stats.deactivated,
+        "signups_per_day": signups,
+    })
+}
+
+fn pool_json(db: &Database) -> Value {
+    let pool = db.pool_stats();
+    json!({
+        "healthy": db.is_healthy(),
+        "breaker": db.breaker().state().name(),
+        "size": pool.size,
+        "in_use": pool.in_use,
+        "idle": pool.idle,
+    })
+}
+
+// Users of the tenant, signups of the last 30 days and the state of the
+// connection pools. Deactivated users are the soft-deleted ones.
+#[get("/admin/stats")]
+async fn get_stats(
+    _admin: Admin,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    cache: web::Data<StatsCache>,
+) -> impl Responder {
+    let users = match cache.get(&tenant) {
+        Some(users) => users,
+        None => {
+            let result: Result<UserStats, DbError> = db
+                .reader()
+                .transaction(&tenant, |uow| {
+                    Box::pin(async move { Ok(uow.users().stats(SIGNUP_DAYS).await?) })
+                })
+                .await;
+            match result {
+                Ok(stats) => {
+                    let users = users_json(&stats);
+                    cache.put(&tenant, &users);
+                    users
+                }
+                Err(e) => return Format::Json.db_error(e, "Failed to compute statistics"),
+            }
+        }
+    };
+    HttpResponse::Ok().json(json!({
+        "users": users,
+        "pools": {
+            "primary": pool_json(db.primary()),
+            "replicas": db.replicas().iter().map(pool_json).collect::<Vec<_>>(),
+        },
+    }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_stats);
+}
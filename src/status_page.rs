@@ -0,0 +1,181 @@
+use actix_web::http::header;
+use actix_web::{get, web, HttpResponse, Responder};
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::admin::Admin;
+use crate::breaker::BreakerState;
+use crate::build_info;
+use crate::concurrency::Limiter;
+use crate::db::{Cluster, Database};
+use crate::maintenance::Maintenance;
+use crate::metrics::{HttpMetrics, QueryMetrics, RECENT_MINUTES};
+
+// The page has its style inline and nothing else to load
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; style-src 'unsafe-inline'; frame-ancestors 'none'";
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+    table { border-collapse: collapse; margin-bottom: 2em; }
+    th, td { text-align: left; padding: 0.3em 1em; border-bottom: 1px solid #ddd; }
+    .ok { color: #1a7f37; } .bad { color: #cf222e; }";
+
+// Escaped for HTML text
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// e.g. `2d 3h 4m 5s`, the leading zero units left out
+fn uptime(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    let units = [
+        (seconds / 86400, "d"),
+        (seconds / 3600 % 24, "h"),
+        (seconds / 60 % 60, "m"),
+        (seconds % 60, "s"),
+    ];
+    let first = units
+        .iter()
+        .position(|(value, _)| *value > 0)
+        .unwrap_or(units.len() - 1);
+    units[first..]
+        .iter()
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+// `value` in green when `ok`, in red otherwise
+fn state(ok: bool, value: &str) -> String {
+    format!(
+        "<span class=\"{}\">{}</span>",
+        if ok { "ok" } else { "bad" },
+        escape(value)
+    )
+}
+
+// The time a trivial statement takes on `db`, None when it fails
+async fn ping(db: &Database) -> Option<Duration> {
+    let started = Instant::now();
+    db.run(|client| async move { client.client.simple_query("SELECT 1").await })
+        .await
+        .ok()
+        .map(|_| started.elapsed())
+}
+
+fn row(out: &mut String, name: &str, value: &str) {
+    let _ = writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", name, value);
+}
+
+// A page of the state of the server for operators without a dashboard: its
+// version, uptime, database latency and pools, and the error rate of the
+// last minutes, from the same counters as /metrics
+#[get("/admin/status")]
+async fn get_status(
+    _admin: Admin,
+    db: web::Data<Cluster>,
+    queries: web::Data<QueryMetrics>,
+    responses: web::Data<HttpMetrics>,
+    limiter: web::Data<Limiter>,
+    maintenance: web::Data<Maintenance>,
+) -> impl Responder {
+    let mut databases = vec![("primary".to_string(), db.primary())];
+    databases.extend(
+        db.replicas()
+            .iter()
+            .enumerate()
+            .map(|(index, replica)| (format!("replica{}", index), replica)),
+    );
+
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(
+        out,
+        "<title>rust-crud-api status</title>\n<style>{}</style>\n</head>\n<body>",
+        STYLE
+    );
+    out.push_str("<h1>rust-crud-api status</h1>\n<table>\n");
+    row(&mut out, "Version", &escape(build_info::VERSION));
+    row(&mut out, "Git commit", &escape(build_info::GIT_COMMIT));
+    row(&mut out, "Built", &escape(build_info::TIMESTAMP));
+    row(&mut out, "Uptime", &uptime(responses.uptime()));
+    let maintenance = match maintenance.is_enabled() {
+        true => state(false, "on, writes are refused"),
+        false => state(true, "off"),
+    };
+    row(&mut out, "Maintenance", &maintenance);
+    let (responses_count, errors) = responses.recent();
+    let rate = match responses_count {
+        0 => "no requests".to_string(),
+        count => format!(
+            "{:.2}% ({} of {} requests)",
+            errors as f64 * 100.0 / count as f64,
+            errors,
+            count
+        ),
+    };
+    row(
+        &mut out,
+        &format!("Errors, last {} minutes", RECENT_MINUTES),
+        &state(errors == 0, &rate),
+    );
+    let (statements, mean) = queries.summary();
+    let mean = mean.map_or_else(|| "none yet".to_string(), millis);
+    row(
+        &mut out,
+        "Statement latency",
+        &format!("{} mean over {} statements", mean, statements),
+    );
+    if limiter.is_enabled() {
+        row(
+            &mut out,
+            "Concurrency",
+            &format!(
+                "{} in flight, {} queued, {} rejected",
+                limiter.in_flight(),
+                limiter.queued(),
+                limiter.rejected.load(Ordering::Relaxed)
+            ),
+        );
+    }
+    out.push_str("</table>\n<h2>Databases</h2>\n<table>\n");
+    out.push_str(
+        "<tr><th>Database</th><th>Latency</th><th>Breaker</th>\
+         <th>Pool</th><th>In use</th><th>Idle</th></tr>\n",
+    );
+    for (name, database) in databases {
+        let latency = match ping(database).await {
+            Some(latency) => state(true, &millis(latency)),
+            None => state(false, "unavailable"),
+        };
+        let breaker = database.breaker().state();
+        let pool = database.pool_stats();
+        let _ = writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            name,
+            latency,
+            state(breaker == BreakerState::Closed, breaker.name()),
+            pool.size,
+            pool.in_use,
+            pool.idle
+        );
+    }
+    out.push_str("</table>\n</body>\n</html>\n");
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header((header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY))
+        .body(out)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_status);
+}
@@ -0,0 +1,222 @@
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use log::info;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+use crate::crypto;
+
+const S3_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Where uploaded files go, keyed by a path like `<tenant>/<user id>`
+#[async_trait(?Send)]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, content_type: &str, data: Bytes) -> Result<(), String>;
+
+    // None when there is nothing stored under `key`
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, String>;
+
+    async fn delete(&self, key: &str) -> Result<(), String>;
+}
+
+// S3 when S3_BUCKET is set, the local filesystem otherwise
+pub fn from_config(config: &Config) -> Arc<dyn BlobStore> {
+    match &config.s3_bucket {
+        Some(bucket) => {
+            info!("Storing uploads in the {} S3 bucket", bucket);
+            Arc::new(S3Store::new(bucket, config))
+        }
+        None => {
+            info!("Storing uploads in {}", config.upload_dir);
+            Arc::new(LocalStore {
+                root: PathBuf::from(&config.upload_dir),
+            })
+        }
+    }
+}
+
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait(?Send)]
+impl BlobStore for LocalStore {
+    async fn put(&self, key: &str, _content_type: &str, data: Bytes) -> Result<(), String> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        // written aside then renamed, so readers never see half a file
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, &data)
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, String> {
+        match tokio::fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Any S3 compatible service, addressed path-style and signed with AWS
+// signature version 4
+pub struct S3Store {
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl S3Store {
+    fn new(bucket: &str, config: &Config) -> S3Store {
+        let endpoint = config
+            .s3_endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", config.s3_region));
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split_once("://")
+            .map(|(_, host)| host)
+            .unwrap_or(&endpoint)
+            .to_string();
+        S3Store {
+            endpoint,
+            host,
+            bucket: bucket.to_string(),
+            region: config.s3_region.clone(),
+            access_key_id: config.s3_access_key_id.clone().unwrap_or_default(),
+            secret_access_key: config.s3_secret_access_key.clone().unwrap_or_default(),
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        let key: Vec<_> = key.split('/').map(uri_encode).collect();
+        format!("/{}/{}", uri_encode(&self.bucket), key.join("/"))
+    }
+
+    // Send a signed request, returns the status and the body of the response
+    async fn send(
+        &self,
+        method: &str,
+        key: &str,
+        content_type: Option<&str>,
+        body: Bytes,
+    ) -> Result<(StatusCode, Bytes), String> {
+        let path = self.path(key);
+        let payload_hash = crypto::sha256_hex(&body);
+        let (amz_date, date) = crypto::amz_dates(SystemTime::now());
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, path, self.host, payload_hash, amz_date, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            crypto::sha256_hex(canonical_request.as_bytes())
+        );
+        let signature = crypto::aws_signature(
+            &self.secret_access_key,
+            &date,
+            &self.region,
+            "s3",
+            &string_to_sign,
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            self.access_key_id, scope, signature
+        );
+
+        let http = awc::Client::builder().timeout(S3_TIMEOUT).finish();
+        let method = method
+            .parse()
+            .map_err(|_| format!("Invalid method {}", method))?;
+        let mut request = http
+            .request(method, format!("{}{}", self.endpoint, path))
+            .insert_header(("x-amz-content-sha256", payload_hash.as_str()))
+            .insert_header(("x-amz-date", amz_date.as_str()))
+            .insert_header(("authorization", authorization.as_str()));
+        if let Some(content_type) = content_type {
+            request = request.content_type(content_type);
+        }
+        let mut response = request.send_body(body).await.map_err(|e| e.to_string())?;
+        let body = response
+            .body()
+            .limit(64 * 1024 * 1024)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok((response.status(), body))
+    }
+}
+
+#[async_trait(?Send)]
+impl BlobStore for S3Store {
+    async fn put(&self, key: &str, content_type: &str, data: Bytes) -> Result<(), String> {
+        match self.send("PUT", key, Some(content_type), data).await? {
+            (status, _) if status.is_success() => Ok(()),
+            (status, body) => Err(s3_error(status, &body)),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, String> {
+        match self.send("GET", key, None, Bytes::new()).await? {
+            (status, body) if status.is_success() => Ok(Some(body)),
+            (StatusCode::NOT_FOUND, _) => Ok(None),
+            (status, body) => Err(s3_error(status, &body)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        match self.send("DELETE", key, None, Bytes::new()).await? {
+            (status, _) if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+            (status, body) => Err(s3_error(status, &body)),
+        }
+    }
+}
+
+fn s3_error(status: StatusCode, body: &[u8]) -> String {
+    format!("S3 answered {}: {}", status, String::from_utf8_lossy(body))
+}
+
+// Percent-encode all but the unreserved characters, as SigV4 expects
+fn uri_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,91 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use futures_util::{stream, Stream, StreamExt};
+use log::error;
+use serde_json::Value;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio_postgres::{Error, Row, RowStream};
+
+use crate::db::PooledClient;
+use crate::jsonapi::Format;
+
+// Rows of a query, keeping its connection out of the pool until the last
+// one is read
+struct OwnedRows {
+    rows: Pin<Box<RowStream>>,
+    _client: PooledClient,
+}
+
+impl Stream for OwnedRows {
+    type Item = Result<Row, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rows.as_mut().poll_next(cx)
+    }
+}
+
+// Respond with the list of `rows`, each converted by `to_value`. Lists of up
+// to `threshold` items are answered like any other, longer ones are sent as
+// the rows come in rather than collected in memory first.
+pub async fn respond_list<F>(
+    format: Format,
+    resource_type: &'static str,
+    client: PooledClient,
+    rows: RowStream,
+    threshold: usize,
+    to_value: F,
+) -> Result<HttpResponse, Error>
+where
+    F: Fn(Row) -> Result<Value, Error> + 'static,
+{
+    let mut rows = OwnedRows {
+        rows: Box::pin(rows),
+        _client: client,
+    };
+    let mut first = Vec::new();
+    while first.len() <= threshold {
+        match rows.next().await {
+            Some(row) => first.push(to_value(row?)?),
+            None => return Ok(format.respond(StatusCode::OK, resource_type, &first)),
+        }
+    }
+    let items =
+        stream::iter(first.into_iter().map(Ok)).chain(rows.map(move |row| row.and_then(&to_value)));
+    Ok(format.respond_stream(StatusCode::OK, resource_type, items))
+}
+
+// Every row always streamed, each encoded by `to_line`, after `preamble`. An
+// error once the response has started can only cut it short.
+pub fn respond_lines<F>(
+    content_type: &'static str,
+    filename: &str,
+    client: PooledClient,
+    rows: RowStream,
+    preamble: Option<Vec<u8>>,
+    to_line: F,
+) -> HttpResponse
+where
+    F: Fn(Row) -> Result<Vec<u8>, Error> + 'static,
+{
+    let rows = OwnedRows {
+        rows: Box::pin(rows),
+        _client: client,
+    };
+    let lines = rows.map(move |row| match row.and_then(&to_line) {
+        Ok(line) => Ok(Bytes::from(line)),
+        Err(e) => {
+            error!("Export cut short: {}", e);
+            Err(e)
+        }
+    });
+    let body = stream::iter(preamble.map(|preamble| Ok(Bytes::from(preamble)))).chain(lines);
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        ))
+        .streaming(body)
+}
@@ -0,0 +1,286 @@
+use actix_web::dev::Payload;
+use actix_web::error::{
+    ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized,
+};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use std::fmt;
+use std::future::{ready, Ready};
+
+use crate::admin;
+use crate::api_keys::Authenticated;
+use crate::auth;
+use crate::config::Config;
+use crate::signatures::Signed;
+
+pub const TENANT_HEADER: &str = "X-Tenant-Id";
+// Tenant of the requests that don't name one
+pub const DEFAULT_TENANT: &str = "default";
+
+// The tenant whose users a request sees, the one of its credential: the
+// `tid` claim of an access token, or the tenant an API key or a partner was
+// registered for. X-Tenant-Id may repeat it but not name another one. Only
+// the admin token acts on any tenant, the one of X-Tenant-Id. Requests
+// without a credential see the default tenant and may not name another one,
+// the sign-in routes aside, see NamedTenant.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tenant(String);
+
+impl Tenant {
+    // Letters, digits, `-` and `_`, so that a tenant fits in a token
+    pub fn parse(id: &str) -> Option<Tenant> {
+        let valid = !id.is_empty()
+            && id.len() <= 64
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if valid {
+            Some(Tenant(id.to_string()))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for Tenant {
+    fn default() -> Self {
+        Tenant(DEFAULT_TENANT.to_string())
+    }
+}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for Tenant {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(tenant_of(req, false))
+    }
+}
+
+// The tenant of the routes that hand a credential out, sign-ups, logins,
+// refreshes and password resets: a client without one names the tenant with
+// X-Tenant-Id, and gets a credential of that tenant only if its password or
+// token belongs to it. With a credential it is the tenant of the credential.
+pub struct NamedTenant(pub Tenant);
+
+impl FromRequest for NamedTenant {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(tenant_of(req, true).map(NamedTenant))
+    }
+}
+
+fn tenant_of(req: &HttpRequest, named: bool) -> Result<Tenant, actix_web::Error> {
+    let config = req
+        .app_data::<web::Data<Config>>()
+        .ok_or_else(|| ErrorInternalServerError("Missing configuration"))?;
+    let requested = match req.headers().get(TENANT_HEADER) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(Tenant::parse)
+                .ok_or_else(|| ErrorBadRequest("Invalid tenant id"))?,
+        ),
+        None => None,
+    };
+    // the middlewares checked the keys and the signatures already, checking
+    // the token is up to the Auth extractor, only its tenant matters
+    let bound = if let Some(key) = req.extensions().get::<Authenticated>() {
+        Tenant::parse(&key.0.tenant_id)
+    } else if let Some(partner) = req.extensions().get::<Signed>() {
+        Tenant::parse(&partner.0.tenant_id)
+    } else if admin::has_admin_token(req, config) {
+        return Ok(requested.unwrap_or_default());
+    } else {
+        auth::access_token(req, config).and_then(|token| auth::token_tenant(config, &token))
+    };
+    match (bound, requested) {
+        (Some(bound), Some(requested)) if bound != requested => {
+            Err(ErrorForbidden("The credential belongs to another tenant"))
+        }
+        (Some(tenant), _) => Ok(tenant),
+        (None, Some(tenant)) if named => Ok(tenant),
+        (None, Some(_)) => Err(ErrorUnauthorized(
+            "X-Tenant-Id needs a credential of the tenant",
+        )),
+        (None, None) => Ok(Tenant::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{self, TestRequest};
+    use serde_json::{json, Value};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+    use crate::api_keys::API_KEY_HEADER;
+    use crate::app;
+    use crate::signatures::{self, PARTNER_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+    use crate::test_app::{self, admin, email, login, request, shared, signed_in};
+
+    const PASSWORD: &str = "correct horse battery";
+
+    // A tenant of its own, the tests sharing the tables
+    fn tenant() -> String {
+        format!("t{}", crate::ids::event_id().to_lowercase())
+    }
+
+    // Whether `items` holds the one with this id
+    fn lists(items: &Value, id: &Value) -> bool {
+        items
+            .as_array()
+            .is_some_and(|items| items.iter().any(|item| item["id"] == *id))
+    }
+
+    #[actix_web::test]
+    async fn the_header_needs_a_credential_of_its_tenant() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let acme = tenant();
+        let jane = email("jane");
+        let user = json!({ "name": "Jane Doe", "email": jane, "password": PASSWORD });
+        let (status, body) = test_app::json(
+            send(
+                admin("POST", "/users")
+                    .insert_header((TENANT_HEADER, acme.as_str()))
+                    .set_json(&user),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(status, 201, "POST /users as the admin: {}", body);
+        let id = &body["id"];
+
+        let response =
+            send(request("GET", "/users").insert_header((TENANT_HEADER, acme.as_str()))).await;
+        assert_eq!(
+            response.status(),
+            401,
+            "GET /users naming the tenant anonymously"
+        );
+        let (status, users) = test_app::json(send(request("GET", "/users")).await).await;
+        assert_eq!(status, 200);
+        assert!(
+            !lists(&users, id),
+            "the default tenant lists a user of {}",
+            acme
+        );
+
+        // the sign-in routes take it, the password belongs to the tenant
+        let (status, _) = login(send, &jane, PASSWORD).await;
+        assert_eq!(status, 401, "login in the default tenant");
+        let credentials = json!({ "email": jane, "password": PASSWORD });
+        let (status, tokens) = test_app::json(
+            send(
+                request("POST", "/auth/login")
+                    .insert_header((TENANT_HEADER, acme.as_str()))
+                    .set_json(&credentials),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(status, 200, "login in {}: {}", acme, tokens);
+
+        let (status, users) = test_app::json(send(signed_in("GET", "/users", &tokens)).await).await;
+        assert_eq!(status, 200);
+        assert!(lists(&users, id), "the token sees the users of its tenant");
+        let response =
+            send(signed_in("GET", "/users", &tokens).insert_header((TENANT_HEADER, acme.as_str())))
+                .await;
+        assert_eq!(response.status(), 200, "the token with its own tenant");
+        let response = send(
+            signed_in("GET", "/users", &tokens).insert_header((TENANT_HEADER, DEFAULT_TENANT)),
+        )
+        .await;
+        assert_eq!(response.status(), 403, "the token with another tenant");
+    }
+
+    #[actix_web::test]
+    async fn api_keys_and_partners_act_on_their_tenant_only() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let acme = tenant();
+        let jane = email("jane");
+        let in_acme = |request: TestRequest| request.insert_header((TENANT_HEADER, acme.as_str()));
+        let user = json!({ "name": "Jane Doe", "email": jane });
+        let (status, user) =
+            test_app::json(send(in_acme(admin("POST", "/users")).set_json(&user)).await).await;
+        assert_eq!(status, 201, "POST /users as the admin: {}", user);
+        let id = &user["id"];
+
+        let new_key = json!({ "name": "ci", "scopes": ["users:read"] });
+        let (status, key) = test_app::json(
+            send(in_acme(admin("POST", "/admin/api-keys")).set_json(&new_key)).await,
+        )
+        .await;
+        assert_eq!(status, 201, "POST /admin/api-keys: {}", key);
+        assert_eq!(key["tenant_id"], json!(acme));
+        let with_key = |request: TestRequest| {
+            request.insert_header((API_KEY_HEADER, key["key"].as_str().unwrap()))
+        };
+        let (status, users) = test_app::json(send(with_key(request("GET", "/users"))).await).await;
+        assert_eq!(status, 200);
+        assert!(
+            lists(&users, id),
+            "the key sees the users of its tenant: {}",
+            users
+        );
+        let response =
+            send(with_key(request("GET", "/users")).insert_header((TENANT_HEADER, DEFAULT_TENANT)))
+                .await;
+        assert_eq!(response.status(), 403, "the key with another tenant");
+        let (_, keys) = test_app::json(send(admin("GET", "/admin/api-keys")).await).await;
+        assert!(!lists(&keys, &key["id"]), "listed in the default tenant");
+
+        let new_partner = json!({ "name": "billing", "scopes": ["users:read"] });
+        let (status, partner) = test_app::json(
+            send(in_acme(admin("POST", "/admin/partners")).set_json(&new_partner)).await,
+        )
+        .await;
+        assert_eq!(status, 201, "POST /admin/partners: {}", partner);
+        assert_eq!(partner["tenant_id"], json!(acme));
+        let signed = |tenant: Option<&str>| {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            // the signatures of the same second are replays
+            let path = format!("/users?nonce={}", crate::ids::event_id());
+            let to_sign = signatures::string_to_sign(timestamp, "GET", &path, b"");
+            let mut request = request("GET", &path)
+                .insert_header((PARTNER_HEADER, partner["key_id"].as_str().unwrap()))
+                .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+                .insert_header((
+                    SIGNATURE_HEADER,
+                    signatures::sign(partner["secret"].as_str().unwrap(), &to_sign),
+                ));
+            if let Some(tenant) = tenant {
+                request = request.insert_header((TENANT_HEADER, tenant));
+            }
+            request
+        };
+        let (status, users) = test_app::json(send(signed(None)).await).await;
+        assert_eq!(status, 200);
+        assert!(
+            lists(&users, id),
+            "the partner sees the users of its tenant"
+        );
+        let response = send(signed(Some(&acme))).await;
+        assert_eq!(response.status(), 200, "the partner with its own tenant");
+        let response = send(signed(Some(DEFAULT_TENANT))).await;
+        assert_eq!(response.status(), 403, "the partner with another tenant");
+    }
+}
@@ -0,0 +1,106 @@
+// The application over Postgres as main serves it, for the tests going
+// through its routes. Needs the database of DATABASE_URL, its tables are
+// prefixed with test_.
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{self, TestRequest};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::logging::{self, ReloadableLogger};
+use crate::metrics::QueryMetrics;
+use crate::{connect, self_check, Backend, Shared, DB_URL};
+
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+static LOGGER: OnceLock<&'static ReloadableLogger> = OnceLock::new();
+
+// Taken while connecting, tests creating the tables at once would conflict
+static SETUP: OnceLock<Mutex<()>> = OnceLock::new();
+
+pub async fn shared() -> Shared {
+    shared_with(|_| ()).await
+}
+
+// The application with the settings of `configure` on top of the ones of
+// the environment
+pub async fn shared_with(configure: impl FnOnce(&mut Config)) -> Shared {
+    let logger = LOGGER.get_or_init(|| logging::init(None));
+    let mut config = Config::from_env(DB_URL);
+    config.admin_token = Some(ADMIN_TOKEN.to_string());
+    config.naming.prefix = "test_".to_string();
+    configure(&mut config);
+    let query_metrics = Arc::new(QueryMetrics::new(config.slow_query_threshold));
+    let mut report = self_check::Report::new();
+    let db = {
+        let _setup = SETUP.get_or_init(Mutex::default).lock().await;
+        connect(&config, Arc::clone(&query_metrics), &mut report).await
+    };
+    let backend = Backend::Postgres(actix_web::web::Data::new(db));
+    Shared::new(config, backend, query_metrics, logger)
+}
+
+// A request to the application from a client, without credentials
+pub fn request(method: &str, path: &str) -> TestRequest {
+    TestRequest::default()
+        .method(method.parse().expect("invalid method"))
+        .uri(path)
+        .peer_addr("127.0.0.1:40000".parse().unwrap())
+}
+
+// A request to the application as the admin
+pub fn admin(method: &str, path: &str) -> TestRequest {
+    request(method, path).insert_header((header::AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN)))
+}
+
+// A unique email address, the tests sharing the tables
+pub fn email(name: &str) -> String {
+    format!(
+        "{}-{}@example.com",
+        name,
+        crate::ids::event_id().to_lowercase()
+    )
+}
+
+// The status and the JSON body of `response`, Null when it has none
+pub async fn json<B: MessageBody>(response: ServiceResponse<B>) -> (StatusCode, Value) {
+    let status = response.status();
+    let body = test::read_body(response).await;
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+// Create an active user with `password` as the admin, returns its id
+pub async fn create_user<F, B>(send: impl Fn(TestRequest) -> F, email: &str, password: &str) -> i64
+where
+    F: Future<Output = ServiceResponse<B>>,
+    B: MessageBody,
+{
+    let user = json!({ "name": "Jane Doe", "email": email, "password": password });
+    let (status, user) = json(send(admin("POST", "/users").set_json(user)).await).await;
+    assert_eq!(status, 201, "POST /users of {}: {}", email, user);
+    user["id"].as_i64().expect("the user created has no id")
+}
+
+// The status and the body of a login, the token pair when it succeeded
+pub async fn login<F, B>(
+    send: impl Fn(TestRequest) -> F,
+    email: &str,
+    password: &str,
+) -> (StatusCode, Value)
+where
+    F: Future<Output = ServiceResponse<B>>,
+    B: MessageBody,
+{
+    let credentials = json!({ "email": email, "password": password });
+    json(send(request("POST", "/auth/login").set_json(credentials)).await).await
+}
+
+// A request with the access token of a login
+pub fn signed_in(method: &str, path: &str, tokens: &Value) -> TestRequest {
+    let token = tokens["access_token"].as_str().expect("no access token");
+    request(method, path).insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+}
@@ -0,0 +1,102 @@
+use actix_web::dev::{
+    forward_ready, ResourceDef, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use log::warn;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::fallback;
+use crate::jsonapi::Format;
+
+// Answers 504 to requests whose response isn't ready within the timeout of
+// their route, the default one unless a `route` overrides it. Streamed
+// bodies are not covered once their first bytes are out.
+pub struct Timeouts {
+    default: Duration,
+    routes: Rc<Vec<(ResourceDef, Duration)>>,
+}
+
+impl Timeouts {
+    pub fn new(default: Duration) -> Timeouts {
+        Timeouts {
+            default,
+            routes: Rc::new(Vec::new()),
+        }
+    }
+
+    // The first matching pattern wins
+    pub fn route(mut self, pattern: &str, timeout: Duration) -> Timeouts {
+        Rc::make_mut(&mut self.routes).push((ResourceDef::new(pattern), timeout));
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Timeouts
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = TimeoutsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TimeoutsMiddleware {
+            service: Rc::new(service),
+            default: self.default,
+            routes: self.routes.clone(),
+        }))
+    }
+}
+
+pub struct TimeoutsMiddleware<S> {
+    service: Rc<S>,
+    default: Duration,
+    routes: Rc<Vec<(ResourceDef, Duration)>>,
+}
+
+impl<S, B> Service<ServiceRequest> for TimeoutsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let timeout = self
+            .routes
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(req.path()))
+            .map_or(self.default, |(_, timeout)| *timeout);
+        let service = self.service.clone();
+        let format = Format::of(req.request());
+        let description = format!("{} {}", req.method(), req.path());
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, service.call(req)).await {
+                Ok(response) => response,
+                Err(_) => {
+                    warn!("{} timed out after {:?}", description, timeout);
+                    Err(InternalError::from_response(
+                        "Request timed out",
+                        fallback::respond(
+                            format,
+                            StatusCode::GATEWAY_TIMEOUT,
+                            &format!("No response within {} seconds", timeout.as_secs()),
+                        ),
+                    )
+                    .into())
+                }
+            }
+        })
+    }
+}
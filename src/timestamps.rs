@@ -0,0 +1,94 @@
+// Dates and times as the filters and the `date` and `date-time` formats of
+// the JSON Schemas take them, each checking what it can't accept on top
+
+// Where a time of day stands from UTC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Offset {
+    // a local time
+    Unspecified,
+    // `Z`
+    Utc,
+    // `+02:00` or `-05:30`
+    Hours(u32, u32),
+}
+
+// A time of day, `HH:MM` or `HH:MM:SS` with a fraction of second, then `Z`
+// or an offset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Time {
+    pub second: Option<u32>,
+    pub fraction_digits: usize,
+    pub offset: Offset,
+}
+
+// `text` of `digits` digits, at most `max`
+fn number(text: &str, digits: usize, max: u32) -> Option<u32> {
+    if text.len() != digits || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok().filter(|n| *n <= max)
+}
+
+// `YYYY-MM-DD`, of a day that exists, from the year 1
+pub fn is_date(value: &str) -> bool {
+    let [year, month, day] = value.split('-').collect::<Vec<_>>()[..] else {
+        return false;
+    };
+    let (Some(year), Some(month)) = (number(year, 4, 9999), number(month, 2, 12)) else {
+        return false;
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    year >= 1 && month >= 1 && number(day, 2, days).is_some_and(|day| day >= 1)
+}
+
+// The time after the `T` of a timestamp, None when it isn't one. Seconds go
+// up to 60 for the leap seconds.
+pub fn parse_time(value: &str) -> Option<Time> {
+    let (clock, offset) = match value.find(['Z', '+', '-']) {
+        Some(at) => value.split_at(at),
+        None => (value, ""),
+    };
+    let (clock, fraction) = match clock.split_once('.') {
+        Some((clock, fraction)) => (clock, Some(fraction)),
+        None => (clock, None),
+    };
+    let second = match clock.split(':').collect::<Vec<_>>()[..] {
+        [hours, minutes] if fraction.is_none() => {
+            number(hours, 2, 23)?;
+            number(minutes, 2, 59)?;
+            None
+        }
+        [hours, minutes, seconds] => {
+            number(hours, 2, 23)?;
+            number(minutes, 2, 59)?;
+            Some(number(seconds, 2, 60)?)
+        }
+        _ => return None,
+    };
+    let fraction_digits = match fraction {
+        Some(fraction) if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) => {
+            return None
+        }
+        Some(fraction) => fraction.len(),
+        None => 0,
+    };
+    let offset = match offset {
+        "" => Offset::Unspecified,
+        "Z" => Offset::Utc,
+        offset => {
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            Offset::Hours(number(hours, 2, 23)?, number(minutes, 2, 59)?)
+        }
+    };
+    Some(Time {
+        second,
+        fraction_digits,
+        offset,
+    })
+}
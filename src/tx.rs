@@ -0,0 +1,268 @@
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{ErrorGatewayTimeout, ErrorInternalServerError, ErrorServiceUnavailable};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use log::{error, warn};
+use std::cell::Cell;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use tokio_postgres::{Client, Error};
+
+use crate::db::{self, Cluster, Database, DbError, PooledClient, RetryPolicy};
+use crate::dry_run::DryRun;
+use crate::events::Event;
+use crate::repository::{
+    AccountRepository, JobRepository, OutboxRepository, RefreshTokenRepository, UserRepository,
+    WebhookRepository,
+};
+use crate::tenant::Tenant;
+
+// A transaction spanning the whole request, for handlers composed of several
+// repository calls: begun on the primary when the handler takes a `Tx`,
+// committed by `Transactions` when it answers 2xx and rolled back otherwise,
+// or on a dry run.
+// Every `Tx` of a request is the same transaction.
+#[derive(Clone)]
+pub struct Tx(Rc<Inner>);
+
+struct Inner {
+    // a plain BEGIN, so that the transaction can outlive the handler
+    client: PooledClient,
+    tenant: Tenant,
+    policy: RetryPolicy,
+    // committed or rolled back
+    finished: Cell<bool>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // still inside the transaction, the connection can't go back to
+        // the pool
+        if !self.finished.get() {
+            self.client.discard();
+        }
+    }
+}
+
+impl Tx {
+    async fn begin(db: &Database, tenant: Tenant) -> Result<Tx, DbError> {
+        let client = db.checkout().await?;
+        let tx = Tx(Rc::new(Inner {
+            client,
+            tenant,
+            policy: db.policy().clone(),
+            finished: Cell::new(false),
+        }));
+        tx.start().await?;
+        Ok(tx)
+    }
+
+    async fn start(&self) -> Result<(), Error> {
+        self.client().batch_execute("BEGIN").await?;
+        self.client()
+            .execute(
+                "SELECT set_config('app.tenant_id', $1, true)",
+                &[&self.0.tenant.as_str()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Run `op` in the request transaction, replaying it like
+    // `Database::transaction` on serialization failures and deadlocks once
+    // the transaction is rolled back and begun again: `op` has to hold all
+    // the work of the request in the transaction. Lost connections aren't
+    // replayed, the transaction is gone with them.
+    pub async fn retry<T, F, Fut>(&self, op: F) -> Result<T, DbError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, DbError>>,
+    {
+        let policy = &self.0.policy;
+        let mut attempt = 0;
+        let mut backoff = policy.initial_backoff;
+        loop {
+            match op().await {
+                Err(DbError::Query(e))
+                    if db::is_transient(&e) && !e.is_closed() && attempt < policy.max_retries =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "Transient database error ({}), replaying the request transaction {}/{} in {:?}",
+                        e, attempt, policy.max_retries, backoff
+                    );
+                }
+                result => return result,
+            }
+            self.client().batch_execute("ROLLBACK").await?;
+            tokio::time::sleep(backoff).await;
+            backoff = policy.next_backoff(backoff);
+            self.start().await?;
+        }
+    }
+
+    fn client(&self) -> &Client {
+        &self.0.client.client
+    }
+
+    // Like `UnitOfWork::finish`, what a commit would have checked
+    async fn check_constraints(&self) -> Result<(), Error> {
+        self.client()
+            .batch_execute("SET CONSTRAINTS ALL IMMEDIATE")
+            .await
+    }
+
+    async fn finish(&self, commit: bool) -> Result<(), Error> {
+        self.0.finished.set(true);
+        self.client()
+            .batch_execute(if commit { "COMMIT" } else { "ROLLBACK" })
+            .await
+    }
+
+    pub fn users(&self) -> UserRepository<'_, Client> {
+        UserRepository::new(
+            self.client(),
+            &self.0.client.statements,
+            self.0.tenant.as_str(),
+        )
+    }
+
+    pub fn account(&self, user_id: i64) -> AccountRepository<'_, Client> {
+        AccountRepository::new(self.users(), user_id)
+    }
+
+    pub fn jobs(&self) -> JobRepository<'_, Client> {
+        JobRepository::new(self.client(), &self.0.client.statements)
+    }
+
+    pub fn refresh_tokens(&self) -> RefreshTokenRepository<'_, Client> {
+        RefreshTokenRepository::new(self.client(), &self.0.client.statements)
+    }
+
+    pub fn webhooks(&self) -> WebhookRepository<'_, Client> {
+        WebhookRepository::new(
+            self.client(),
+            &self.0.client.statements,
+            self.0.tenant.as_str(),
+        )
+    }
+
+    pub fn outbox(&self) -> OutboxRepository<'_, Client> {
+        OutboxRepository::new(self.client(), &self.0.client.statements)
+    }
+
+    // Like `UnitOfWork::publish`, the event is only sent if the request
+    // transaction commits
+    pub async fn publish(&self, event: &Event) -> Result<(), Error> {
+        self.outbox().append(event).await?;
+        self.webhooks().enqueue(event).await?;
+        Ok(())
+    }
+}
+
+impl FromRequest for Tx {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(tx) = req.extensions().get::<Tx>() {
+            return Box::pin(ready(Ok(tx.clone())));
+        }
+        let req = req.clone();
+        Box::pin(async move {
+            let tenant = Tenant::extract(&req).await?;
+            let db = req
+                .app_data::<web::Data<Cluster>>()
+                .ok_or_else(|| ErrorInternalServerError("Missing database"))?
+                .clone();
+            let tx = Tx::begin(db.primary(), tenant).await.map_err(|e| match e {
+                DbError::Unavailable => ErrorServiceUnavailable("Database unavailable"),
+                DbError::Timeout => ErrorGatewayTimeout("Database query timed out"),
+                DbError::Query(e) => {
+                    error!("Failed to begin the request transaction: {}", e);
+                    ErrorInternalServerError("Failed to begin the transaction")
+                }
+            })?;
+            req.extensions_mut().insert(tx.clone());
+            Ok(tx)
+        })
+    }
+}
+
+// Ends the transaction of the requests whose handler took a `Tx`, once the
+// handler answered. A failed commit turns the response into a 500.
+pub struct Transactions;
+
+impl<S, B> Transform<S, ServiceRequest> for Transactions
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = TransactionsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TransactionsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct TransactionsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for TransactionsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            // on an error the request goes away with its `Tx`, whose
+            // connection is closed and the transaction with it
+            let response = service.call(req).await?;
+            let tx = response.request().extensions_mut().remove::<Tx>();
+            let tx = match tx {
+                Some(tx) => tx,
+                None => return Ok(response),
+            };
+            let commit = response.status().is_success();
+            // a dry run leaves once the constraints are checked
+            if commit && DryRun::of(response.request()).is_set() {
+                let checked = tx.check_constraints().await;
+                if let Err(e) = tx.finish(false).await {
+                    error!("Failed to roll back the request transaction: {}", e);
+                }
+                return match checked {
+                    Ok(()) => Ok(response),
+                    Err(e) => {
+                        error!("Dry run failed the constraint checks: {}", e);
+                        Err(ErrorInternalServerError("Failed to commit the transaction"))
+                    }
+                };
+            }
+            match tx.finish(commit).await {
+                Ok(()) => Ok(response),
+                Err(e) if commit => {
+                    error!("Failed to commit the request transaction: {}", e);
+                    Err(ErrorInternalServerError("Failed to commit the transaction"))
+                }
+                Err(e) => {
+                    error!("Failed to roll back the request transaction: {}", e);
+                    Ok(response)
+                }
+            }
+        })
+    }
+}
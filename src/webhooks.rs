@@ -0,0 +1,479 @@
+use actix_web::http::Uri;
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use futures_util::stream::{self, StreamExt};
+use log::{info, warn};
+use serde_json::json;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::crypto;
+use crate::db::{Cluster, Database, DbError};
+use crate::events::EventKind;
+use crate::jsonapi::Format;
+use crate::repository::PendingDelivery;
+use crate::tenant::Tenant;
+
+// Headers sent with every callback. The signature is `sha256=` and the hex
+// HMAC-SHA256 of the timestamp, a `.` and the raw body, keyed with the
+// webhook secret: receivers refuse the old timestamps, so that a callback
+// can't be replayed.
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+// Unix time in seconds at which the callback was signed
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+pub const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+
+// Deliveries claimed by the worker per poll
+const BATCH_SIZE: i64 = 20;
+
+#[derive(Deserialize)]
+struct NewWebhook {
+    url: String,
+    event: String,
+}
+
+#[derive(Deserialize)]
+struct DeliveriesQuery {
+    status: Option<String>,
+}
+
+#[post("/admin/webhooks")]
+async fn create_webhook(
+    _admin: Admin,
+    body: web::Json<NewWebhook>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let webhook = body.into_inner();
+    if EventKind::parse(&webhook.event).is_none() {
+        let events: Vec<_> = EventKind::ALL.iter().map(EventKind::name).collect();
+        return HttpResponse::BadRequest().body(format!(
+            "Unknown event '{}', expected one of {}",
+            webhook.event,
+            events.join(", ")
+        ));
+    }
+    if let Err(e) = check_target(&webhook.url, config.webhook_private_targets).await {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let secret = crypto::random_token(32);
+    let new_webhook = &webhook;
+    let secret_ref = &secret;
+    let tenant = &tenant;
+    let result = db
+        .primary()
+        .run(move |client| async move {
+            client
+                .webhooks(tenant)
+                .create(&new_webhook.url, &new_webhook.event, secret_ref)
+                .await
+        })
+        .await;
+    match result {
+        Ok(webhook) => {
+            info!(
+                "Registered webhook {} for {} of tenant {}",
+                webhook.id, webhook.event, webhook.tenant_id
+            );
+            // the secret is only ever disclosed here
+            HttpResponse::Created().json(json!({
+                "id": webhook.id,
+                "tenant_id": webhook.tenant_id,
+                "url": webhook.url,
+                "event": webhook.event,
+                "secret": webhook.secret,
+            }))
+        }
+        Err(e) => Format::Json.db_error(e, "Failed to register webhook"),
+    }
+}
+
+#[get("/admin/webhooks")]
+async fn get_webhooks(_admin: Admin, tenant: Tenant, db: web::Data<Cluster>) -> impl Responder {
+    let tenant = &tenant;
+    let result = db
+        .primary()
+        .run(|client| async move { client.webhooks(tenant).list().await })
+        .await;
+    match result {
+        Ok(webhooks) => HttpResponse::Ok().json(webhooks),
+        Err(e) => Format::Json.db_error(e, "Failed to retrieve webhooks"),
+    }
+}
+
+#[delete("/admin/webhooks/{id}")]
+async fn delete_webhook(
+    _admin: Admin,
+    path: web::Path<i32>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let tenant = &tenant;
+    let result = db
+        .primary()
+        .run(|client| async move { client.webhooks(tenant).delete(id).await })
+        .await;
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("Webhook {} not found", id)),
+        Err(e) => Format::Json.db_error(e, &format!("Failed to delete webhook {}", id)),
+    }
+}
+
+#[get("/admin/webhooks/{id}/deliveries")]
+async fn get_deliveries(
+    _admin: Admin,
+    path: web::Path<i32>,
+    query: web::Query<DeliveriesQuery>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let status = query.status.as_deref();
+    let tenant = &tenant;
+    let result = db
+        .primary()
+        .run(|client| async move { client.webhooks(tenant).deliveries(id, status).await })
+        .await;
+    match result {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries),
+        Err(e) => Format::Json.db_error(e, "Failed to retrieve deliveries"),
+    }
+}
+
+// Requeue a dead-lettered delivery
+#[post("/admin/webhooks/{id}/deliveries/{delivery_id}/retry")]
+async fn retry_delivery(
+    _admin: Admin,
+    path: web::Path<(i32, i32)>,
+    tenant: Tenant,
+    db: web::Data<Cluster>,
+) -> impl Responder {
+    let (id, delivery_id) = path.into_inner();
+    let tenant = &tenant;
+    let result = db
+        .primary()
+        .run(|client| async move { client.webhooks(tenant).retry(id, delivery_id).await })
+        .await;
+    match result {
+        Ok(true) => HttpResponse::Accepted().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!(
+            "No dead delivery {} for webhook {}",
+            delivery_id, id
+        )),
+        Err(e) => Format::Json.db_error(e, "Failed to retry delivery"),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_webhook)
+        .service(get_webhooks)
+        .service(delete_webhook)
+        .service(get_deliveries)
+        .service(retry_delivery);
+}
+
+// Checks that callbacks to `url` go out of the network: an http or https URL
+// whose host only resolves to public addresses, unless `private_targets`.
+// Otherwise a webhook could have the worker call the services next to it,
+// the cloud metadata at 169.254.169.254 included, on behalf of its admin.
+async fn check_target(url: &str, private_targets: bool) -> Result<(), String> {
+    let invalid = || format!("Invalid callback URL {}", url);
+    let uri: Uri = url.parse().map_err(|_| invalid())?;
+    let default_port = match uri.scheme_str() {
+        Some("http") => 80,
+        Some("https") => 443,
+        _ => return Err(invalid()),
+    };
+    let authority = uri.authority().ok_or_else(invalid)?;
+    // credentials in the URL could make another host out of it
+    if authority.as_str().contains('@') || authority.host().is_empty() {
+        return Err(invalid());
+    }
+    if private_targets {
+        return Ok(());
+    }
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = authority.port_u16().unwrap_or(default_port);
+    let addresses = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Callback host {} can't be resolved: {}", host, e))?;
+    for address in addresses {
+        if !public(address.ip()) {
+            return Err(format!(
+                "Callback URL {} goes to {}, which is not a public address",
+                url,
+                address.ip()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Whether `address` is on the internet: not a loopback, private, link-local,
+// shared (100.64.0.0/10), unique local, multicast or reserved one
+fn public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            let [first, second, ..] = address.octets();
+            !(address.is_unspecified()
+                || address.is_loopback()
+                || address.is_private()
+                || address.is_link_local()
+                || address.is_broadcast()
+                || address.is_multicast()
+                || address.is_documentation()
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64)
+                || first >= 240)
+        }
+        IpAddr::V6(address) => {
+            let segments = address.segments();
+            // the IPv4 addresses mapped or translated (64:ff9b::/96) into IPv6
+            if let Some(mapped) = address.to_ipv4_mapped() {
+                return public(IpAddr::V4(mapped));
+            }
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let translated = (u32::from(segments[6]) << 16) | u32::from(segments[7]);
+                return public(IpAddr::V4(Ipv4Addr::from(translated)));
+            }
+            !(address.is_unspecified()
+                || address.is_loopback()
+                || address.is_multicast()
+                || segments[0] & 0xfe00 == 0xfc00
+                || segments[0] & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+// What a callback signs: its timestamp, a `.` and its body
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut signed = format!("{}.", timestamp).into_bytes();
+    signed.extend_from_slice(body);
+    format!(
+        "sha256={}",
+        crypto::hex(&crypto::hmac_sha256(secret.as_bytes(), &signed))
+    )
+}
+
+struct WorkerSettings {
+    max_attempts: i32,
+    poll_interval: Duration,
+    // a claimed delivery is handed out again if not settled within the
+    // lease. The deliveries of a batch are sent at the same time, so the
+    // batch takes about one timeout.
+    lease: Duration,
+    private_targets: bool,
+}
+
+// Background task sending the queued callbacks, with exponential backoff
+// between attempts and a dead-letter state after `webhook_max_attempts`
+pub fn spawn_worker(db: Database, config: &Config) {
+    let settings = WorkerSettings {
+        max_attempts: config.webhook_max_attempts,
+        poll_interval: config.webhook_poll_interval,
+        lease: config.webhook_timeout * 3,
+        private_targets: config.webhook_private_targets,
+    };
+    let http = awc::Client::builder()
+        .timeout(config.webhook_timeout)
+        .finish();
+    actix_web::rt::spawn(async move {
+        loop {
+            match deliver_due(&db, &http, &settings).await {
+                // a full batch, more deliveries may be due already
+                Ok(count) if count as i64 == BATCH_SIZE => {}
+                Ok(_) => tokio::time::sleep(settings.poll_interval).await,
+                Err(e) => {
+                    warn!("Webhook worker: {}", e);
+                    tokio::time::sleep(settings.poll_interval).await;
+                }
+            }
+        }
+    });
+}
+
+// Claims a batch and sends its deliveries concurrently, so that a slow
+// endpoint doesn't hold up the others past the lease
+async fn deliver_due(
+    db: &Database,
+    http: &awc::Client,
+    settings: &WorkerSettings,
+) -> Result<usize, DbError> {
+    let lease = settings.lease;
+    let batch = db
+        .run(|client| async move {
+            client
+                .webhook_deliveries()
+                .claim_due(BATCH_SIZE, lease)
+                .await
+        })
+        .await?;
+    let settled: Vec<Result<(), DbError>> = stream::iter(&batch)
+        .map(|delivery| deliver(db, http, settings, delivery))
+        .buffer_unordered(batch.len().max(1))
+        .collect()
+        .await;
+    settled.into_iter().collect::<Result<(), _>>()?;
+    Ok(batch.len())
+}
+
+async fn deliver(
+    db: &Database,
+    http: &awc::Client,
+    settings: &WorkerSettings,
+    delivery: &PendingDelivery,
+) -> Result<(), DbError> {
+    let body = delivery.payload.to_string();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let signature = sign(&delivery.secret, timestamp, body.as_bytes());
+    // checked again as the host may resolve elsewhere since the registration
+    let outcome = match check_target(&delivery.url, settings.private_targets).await {
+        Ok(()) => {
+            let result = http
+                .post(&delivery.url)
+                .content_type("application/json")
+                .insert_header((SIGNATURE_HEADER, signature))
+                .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+                .insert_header((EVENT_HEADER, delivery.event.as_str()))
+                .insert_header((DELIVERY_HEADER, delivery.id.to_string()))
+                .send_body(body)
+                .await;
+            match result {
+                Ok(response) if response.status().is_success() => Ok(()),
+                Ok(response) => Err(format!("HTTP {}", response.status())),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    let id = delivery.id;
+    match outcome {
+        Ok(()) => {
+            db.run(|client| async move { client.webhook_deliveries().mark_delivered(id).await })
+                .await
+        }
+        Err(error) => {
+            warn!(
+                "Delivery {} to {} failed (attempt {}): {}",
+                id,
+                delivery.url,
+                delivery.attempts + 1,
+                error
+            );
+            let error = &error;
+            let max_attempts = settings.max_attempts;
+            let backoff = retry_backoff(delivery.attempts + 1);
+            db.run(move |client| async move {
+                client
+                    .webhook_deliveries()
+                    .mark_failed(id, error, max_attempts, backoff)
+                    .await
+            })
+            .await
+        }
+    }
+}
+
+// 2, 4, 8... seconds, capped at an hour
+fn retry_backoff(attempts: i32) -> Duration {
+    Duration::from_secs(2u64.saturating_pow(attempts.max(1) as u32).min(3600))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{self, TestRequest};
+    use serde_json::json;
+
+    use super::*;
+    use crate::app;
+    use crate::test_app::{admin, shared, shared_with};
+
+    #[test]
+    fn only_public_addresses_take_callbacks() {
+        let public = |address: &str| public(address.parse().unwrap());
+        for address in [
+            "93.184.215.14",
+            "8.8.8.8",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(public(address), "{} is public", address);
+        }
+        for address in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "224.0.0.1",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!public(address), "{} is not public", address);
+        }
+    }
+
+    #[actix_web::test]
+    async fn names_are_resolved_before_the_check() {
+        let refused = check_target("http://localhost:8080/hook", false).await;
+        assert!(refused.unwrap_err().contains("not a public address"));
+        assert!(check_target("http://localhost:8080/hook", true)
+            .await
+            .is_ok());
+        assert!(check_target("https://8.8.8.8/hook", false).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn callbacks_to_the_internal_network_are_refused() {
+        let app = test::init_service(app(&shared().await)).await;
+        let send = |request: TestRequest| test::call_service(&app, request.to_request());
+        let register = |url: &str| {
+            admin("POST", "/admin/webhooks")
+                .set_json(json!({ "url": url, "event": "user.created" }))
+        };
+        for url in [
+            "http://127.0.0.1/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1/hook",
+            "http://[::1]:8080/hook",
+            "http://user@10.0.0.1@93.184.215.14/hook",
+            "ftp://93.184.215.14/hook",
+            "93.184.215.14/hook",
+        ] {
+            let response = send(register(url)).await;
+            assert_eq!(response.status(), 400, "{}", url);
+        }
+        let response = send(register("https://93.184.215.14/hook")).await;
+        assert_eq!(response.status(), 201);
+    }
+
+    #[actix_web::test]
+    async fn private_targets_take_the_receivers_of_the_network() {
+        let shared = shared_with(|config| config.webhook_private_targets = true).await;
+        let app = test::init_service(app(&shared)).await;
+        let webhook = json!({ "url": "http://127.0.0.1:9000/hook", "event": "user.created" });
+        let request = admin("POST", "/admin/webhooks").set_json(webhook);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), 201);
+    }
+}